@@ -1,274 +1,775 @@
-use std::borrow::Cow;
+use std::fmt;
+use std::time::Duration;
 
-use minicbor::{Decode, Encode};
+use minicbor::encode::{self, Write};
+use minicbor::{Decode, Decoder, Encode, Encoder};
 use serde::{Deserialize, Serialize};
+use zeroize::Zeroize;
 
+use ockam::identity::credential::Attributes;
+use ockam::identity::IdentityIdentifier;
+use ockam_core::api::Status;
+use ockam_core::errcode::{Kind, Origin};
 #[cfg(feature = "tag")]
 use ockam_core::TypeTag;
 
-#[derive(Encode, Decode, Serialize, Deserialize, Debug, Clone)]
+use crate::cloud::enroll::api_key::AuthenticateApiKey;
+use crate::cloud::enroll::client_certificate::AuthenticateClientCertificate;
+use crate::cloud::enroll::enrollment_token::{enter_field, EnrollmentToken};
+use crate::cloud::enroll::oidc::AuthenticateOidcToken;
+use crate::cloud::enroll::timings::EnrollTimings;
+use crate::cloud::CloudRequestWrapper;
+
+#[cfg(feature = "auth0")]
+pub mod auth0;
+#[cfg(feature = "blocking")]
+pub mod blocking;
+pub mod clock;
+pub mod coalescing_provider;
+pub mod concurrency;
+pub mod dedup_cache;
+pub mod encrypted_attributes;
+pub mod enrollment_token;
+pub mod events;
+pub mod metrics;
+#[cfg(any(test, feature = "testing"))]
+pub mod mock_authenticator;
+mod node;
+pub mod notifier;
+pub mod options;
+pub mod persisted;
+pub mod preflight;
+pub mod rate_limiter;
+pub mod replay_guard;
+pub mod revocation_list;
+pub mod route_builder;
+pub mod secure_channel_address;
+pub mod shared_channel;
+pub mod timings;
+pub mod token_cache;
+pub mod transport;
+
+/// Time given to the secure channel to an authenticator to be established
+pub const DEFAULT_SECURE_CHANNEL_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Time given to the teardown of an enrollment which reached its deadline,
+/// so that its secure channel can still be stopped
+pub const ENROLL_TEARDOWN_GRACE_PERIOD: Duration = Duration::from_secs(5);
+
+/// Maximum length of the tokens received by the enrollment endpoints, in bytes
+pub const DEFAULT_MAX_TOKEN_LENGTH: usize = 8 * 1024;
+
+/// Target of the enrollment logs, unless another one is chosen when building the crate
+pub const DEFAULT_LOG_TARGET: &str = "ockam_api::cloud::enroll";
+
+/// Target of the enrollment logs, so that they can be filtered separately.
+///
+/// Tracing targets are static: embedders which route these logs to their own
+/// namespace set the `OCKAM_ENROLL_LOG_TARGET` environment variable at build time.
+pub const TARGET: &str = log_target(option_env!("OCKAM_ENROLL_LOG_TARGET"));
+
+/// Return the `custom` target, or [`DEFAULT_LOG_TARGET`] if it is not set or empty
+pub const fn log_target(custom: Option<&'static str>) -> &'static str {
+    match custom {
+        Some(target) if !target.is_empty() => target,
+        _ => DEFAULT_LOG_TARGET,
+    }
+}
+
+/// A secret issued by an identity provider or by the Orchestrator.
+///
+/// Its value is redacted when the token is formatted, so that it can't leak in logs.
+#[derive(Encode, Decode, Serialize, Deserialize, Clone)]
 #[cfg_attr(test, derive(PartialEq, Eq))]
 #[cbor(transparent)]
 #[serde(transparent)]
-pub struct Token(#[n(0)] pub String);
+pub struct Token(#[n(0)] String);
 
 impl Token {
     pub fn new(token: impl Into<String>) -> Self {
         Self(token.into())
     }
+
+    /// Return the secret value of the token, when it needs to be sent to its verifier
+    pub fn reveal(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Token(***)")
+    }
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("***")
+    }
+}
+
+impl Zeroize for Token {
+    fn zeroize(&mut self) {
+        self.0.zeroize()
+    }
+}
+
+/// Tokens are secrets, so their contents are erased from memory once they are not used anymore
+impl Drop for Token {
+    fn drop(&mut self) {
+        self.zeroize()
+    }
 }
 
-mod node {
-    use std::time::Duration;
-
-    use minicbor::Decoder;
-    use tracing::trace;
-
-    use ockam::identity::credential::Attributes;
-    use ockam_core::api::Request;
-    use ockam_core::{self, Result};
-    use ockam_multiaddr::MultiAddr;
-    use ockam_node::Context;
-
-    use crate::cloud::enroll::auth0::{AuthenticateOidcToken, OidcToken};
-    use crate::cloud::enroll::enrollment_token::{EnrollmentToken, RequestEnrollmentToken};
-    use crate::cloud::{CloudRequestWrapper, ORCHESTRATOR_RESTART_TIMEOUT};
-    use crate::nodes::{NodeManager, NodeManagerWorker};
-
-    const TARGET: &str = "ockam_api::cloud::enroll";
-
-    impl NodeManager {
-        /// Executes an enrollment process to generate a new set of access tokens using the auth0 flow.
-        pub async fn enroll_auth0(
-            &self,
-            ctx: &Context,
-            route: &MultiAddr,
-            token: OidcToken,
-        ) -> Result<()> {
-            let request = CloudRequestWrapper::new(AuthenticateOidcToken::new(token), route, None);
-            self.enroll_auth0_response(ctx, request).await?;
-            Ok(())
+/// A token which can be exchanged with one of the Orchestrator authenticators
+#[derive(Debug)]
+pub enum AuthenticateToken {
+    #[cfg(feature = "auth0")]
+    Auth0(AuthenticateOidcToken),
+    /// Token issued by any OIDC provider, checked by the `authenticator` service
+    Oidc {
+        authenticator: String,
+        token: AuthenticateOidcToken,
+    },
+    EnrollmentToken(EnrollmentToken),
+    /// Long-lived key held by services which can't run an interactive flow
+    ApiKey(AuthenticateApiKey),
+    /// Client certificate presented by a device over mutual TLS
+    ClientCertificate(AuthenticateClientCertificate),
+}
+
+/// Names of the Orchestrator services authenticating each kind of token.
+///
+/// They can differ from the defaults in multi-tenant deployments.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuthenticatorServices {
+    #[cfg(feature = "auth0")]
+    pub auth0: String,
+    pub enrollment_token: String,
+    pub api_key: String,
+    pub client_certificate: String,
+}
+
+impl Default for AuthenticatorServices {
+    fn default() -> Self {
+        Self {
+            #[cfg(feature = "auth0")]
+            auth0: "auth0_authenticator".to_string(),
+            enrollment_token: "enrollment_token_authenticator".to_string(),
+            api_key: "api_key_authenticator".to_string(),
+            client_certificate: "mtls_authenticator".to_string(),
         }
+    }
+}
 
-        /// Executes an enrollment process to generate a new set of access tokens using the auth0 flow.
-        pub(crate) async fn enroll_auth0_response(
-            &self,
-            ctx: &Context,
-            req_wrapper: CloudRequestWrapper<AuthenticateOidcToken>,
-        ) -> Result<Vec<u8>> {
-            let route = req_wrapper.multiaddr()?;
-            let req_builder = Request::post("v0/enroll").body(req_wrapper.req);
-            let api_service = "auth0_authenticator";
-
-            trace!(target: TARGET, "executing auth0 flow");
-
-            self.request_controller_with_timeout(
-                ctx,
-                api_service,
-                None,
-                &route,
-                api_service,
-                req_builder,
-                None,
-                Duration::from_secs(ORCHESTRATOR_RESTART_TIMEOUT),
-            )
-            .await
+impl AuthenticateToken {
+    /// Name of the Orchestrator service authenticating this kind of token
+    pub fn api_service<'a>(&'a self, services: &'a AuthenticatorServices) -> &'a str {
+        match self {
+            #[cfg(feature = "auth0")]
+            AuthenticateToken::Auth0(_) => &services.auth0,
+            AuthenticateToken::Oidc { authenticator, .. } => authenticator,
+            AuthenticateToken::EnrollmentToken(_) => &services.enrollment_token,
+            AuthenticateToken::ApiKey(_) => &services.api_key,
+            AuthenticateToken::ClientCertificate(_) => &services.client_certificate,
         }
     }
 
-    impl NodeManagerWorker {
-        /// Executes an enrollment process to generate a new set of access tokens using the auth0 flow.
-        pub async fn enroll_auth0_response(
-            &self,
-            ctx: &Context,
-            req_wrapper: CloudRequestWrapper<AuthenticateOidcToken>,
-        ) -> Result<Vec<u8>> {
-            let node_manager = self.inner().read().await;
-            node_manager.enroll_auth0_response(ctx, req_wrapper).await
+    /// Name of the schema.cddl rule describing the encoded token
+    pub fn schema(&self) -> &'static str {
+        match self {
+            #[cfg(feature = "auth0")]
+            AuthenticateToken::Auth0(_) => "authenticate_oidc_token",
+            AuthenticateToken::Oidc { .. } => "authenticate_oidc_token",
+            AuthenticateToken::EnrollmentToken(_) => "enrollment_token",
+            AuthenticateToken::ApiKey(_) => "authenticate_api_key",
+            AuthenticateToken::ClientCertificate(_) => "authenticate_client_certificate",
         }
+    }
+}
 
-        /// Generates a token that will be associated to the passed attributes.
-        pub(crate) async fn generate_enrollment_token(
-            &mut self,
-            ctx: &mut Context,
-            dec: &mut Decoder<'_>,
-        ) -> Result<Vec<u8>> {
-            let req_wrapper: CloudRequestWrapper<Attributes> = dec.decode()?;
-            let cloud_multiaddr = req_wrapper.multiaddr()?;
-            let req_body: Attributes = req_wrapper.req;
-            let req_body = RequestEnrollmentToken::new(req_body);
-
-            let label = "enrollment_token_generator";
-            trace!(target: TARGET, "generating tokens");
-
-            let req_builder = Request::post("v0/").body(req_body);
-
-            self.request_controller(
-                ctx,
-                label,
-                "request_enrollment_token",
-                &cloud_multiaddr,
-                "projects",
-                req_builder,
-                None,
-            )
-            .await
+impl<C> Encode<C> for AuthenticateToken {
+    fn encode<W: Write>(
+        &self,
+        e: &mut Encoder<W>,
+        ctx: &mut C,
+    ) -> Result<(), encode::Error<W::Error>> {
+        match self {
+            #[cfg(feature = "auth0")]
+            AuthenticateToken::Auth0(token) => token.encode(e, ctx),
+            AuthenticateToken::Oidc { token, .. } => token.encode(e, ctx),
+            AuthenticateToken::EnrollmentToken(token) => token.encode(e, ctx),
+            AuthenticateToken::ApiKey(token) => token.encode(e, ctx),
+            AuthenticateToken::ClientCertificate(token) => token.encode(e, ctx),
         }
+    }
+}
 
-        /// Authenticates a token generated by `generate_enrollment_token`.
-        pub(crate) async fn authenticate_enrollment_token(
-            &mut self,
-            ctx: &mut Context,
-            dec: &mut Decoder<'_>,
-        ) -> Result<Vec<u8>> {
-            let req_wrapper: CloudRequestWrapper<EnrollmentToken> = dec.decode()?;
-            let cloud_multiaddr = req_wrapper.multiaddr()?;
-            let req_body: EnrollmentToken = req_wrapper.req;
-            let req_builder = Request::post("v0/enroll").body(req_body);
-            let api_service = "enrollment_token_authenticator";
-
-            trace!(target: TARGET, "authenticating token");
-            self.request_controller_with_timeout(
-                ctx,
-                api_service,
-                None,
-                &cloud_multiaddr,
-                api_service,
-                req_builder,
-                None,
-                Duration::from_secs(ORCHESTRATOR_RESTART_TIMEOUT),
-            )
-            .await
+/// Kind of the token held by an [`EnrollBody`]
+#[derive(Encode, Decode, Debug, Clone, Copy, PartialEq, Eq)]
+#[rustfmt::skip]
+#[cbor(index_only)]
+pub enum EnrollTokenKind {
+    #[n(0)] Auth0,
+    #[n(1)] EnrollmentToken,
+    #[n(2)] ApiKey,
+}
+
+/// Token sent to the unified enroll endpoint, with the kind of the token.
+///
+/// The kind is required because the bodies of several tokens have the same
+/// shape: an API key and an enrollment token are both encoded as `{1: token}`.
+#[derive(Encode, Decode, Debug)]
+#[rustfmt::skip]
+#[cbor(map)]
+pub struct EnrollBody<T> {
+    #[cfg(feature = "tag")]
+    #[n(0)] pub tag: TypeTag<7290451>,
+    #[n(1)] pub kind: EnrollTokenKind,
+    #[b(2)] pub token: T,
+}
+
+impl<T> EnrollBody<T> {
+    fn new(kind: EnrollTokenKind, token: T) -> Self {
+        Self {
+            #[cfg(feature = "tag")]
+            tag: TypeTag,
+            kind,
+            token,
         }
     }
 }
 
-pub mod auth0 {
-    use super::*;
+#[cfg(feature = "auth0")]
+impl EnrollBody<AuthenticateOidcToken> {
+    pub fn auth0(token: AuthenticateOidcToken) -> Self {
+        Self::new(EnrollTokenKind::Auth0, token)
+    }
+}
 
-    // Req/Res types
+impl EnrollBody<EnrollmentToken> {
+    pub fn enrollment_token(token: EnrollmentToken) -> Self {
+        Self::new(EnrollTokenKind::EnrollmentToken, token)
+    }
+}
+
+impl EnrollBody<AuthenticateApiKey> {
+    pub fn api_key(token: AuthenticateApiKey) -> Self {
+        Self::new(EnrollTokenKind::ApiKey, token)
+    }
+}
+
+/// Decode the body of a request to the unified enroll endpoint, without consuming `dec`.
+///
+/// The body is a `CloudRequestWrapper` of an [`EnrollBody`]. Its kind is read
+/// first, on a copy of the decoder, and the token is then decoded as the type of
+/// that kind: an `AuthenticateOidcToken` for the auth0 flow, an `EnrollmentToken`
+/// or an `AuthenticateApiKey`.
+///
+/// Without the `auth0` feature, the auth0 tokens are not decoded.
+pub fn try_decode_enroll_body(dec: &Decoder<'_>) -> Option<CloudRequestWrapper<AuthenticateToken>> {
+    fn decode<'b, T: Decode<'b, ()>>(
+        dec: &Decoder<'b>,
+        kind: EnrollTokenKind,
+    ) -> Option<CloudRequestWrapper<T>> {
+        let req_wrapper = dec
+            .clone()
+            .decode::<CloudRequestWrapper<EnrollBody<T>>>()
+            .ok()?;
+        (req_wrapper.req.kind == kind).then(|| req_wrapper.map(|body| body.token))
+    }
+
+    let mut kind = dec.clone();
+    if !(enter_field(&mut kind, 1) && enter_field(&mut kind, 1)) {
+        return None;
+    }
+    match kind.decode::<EnrollTokenKind>().ok()? {
+        #[cfg(feature = "auth0")]
+        EnrollTokenKind::Auth0 => {
+            decode(dec, EnrollTokenKind::Auth0).map(|w| w.map(AuthenticateToken::Auth0))
+        }
+        #[cfg(not(feature = "auth0"))]
+        EnrollTokenKind::Auth0 => None,
+        EnrollTokenKind::EnrollmentToken => decode(dec, EnrollTokenKind::EnrollmentToken)
+            .map(|w| w.map(AuthenticateToken::EnrollmentToken)),
+        EnrollTokenKind::ApiKey => {
+            decode(dec, EnrollTokenKind::ApiKey).map(|w| w.map(AuthenticateToken::ApiKey))
+        }
+    }
+}
+
+/// Claims returned by an Orchestrator authenticator which accepted a token
+#[derive(Encode, Decode, Debug, Clone, Default)]
+#[cfg_attr(test, derive(PartialEq, Eq))]
+#[rustfmt::skip]
+#[cbor(map)]
+pub struct EnrollClaims {
+    #[cfg(feature = "tag")]
+    #[n(0)] pub tag: TypeTag<5663877>,
+    /// Identity which was enrolled
+    #[n(1)] pub identity: Option<IdentityIdentifier>,
+    /// Attributes granted to the enrolled identity
+    #[b(2)] pub attributes: Option<Attributes>,
+    /// Unix time (in seconds) after which the enrollment is no longer valid
+    #[n(3)] pub expires_at: Option<u64>,
+    /// Unix time (in seconds) at which the credential issued to the enrolled identity expires
+    #[n(4)] pub credential_expires_at: Option<u64>,
+}
 
-    #[derive(serde::Deserialize, Debug, PartialEq, Eq)]
-    pub struct DeviceCode<'a> {
-        pub device_code: Cow<'a, str>,
-        pub user_code: Cow<'a, str>,
-        pub verification_uri: Cow<'a, str>,
-        pub verification_uri_complete: Cow<'a, str>,
-        pub expires_in: usize,
-        pub interval: usize,
+/// Successful response of an Orchestrator authenticator
+#[derive(Debug, Clone)]
+pub struct EnrollResponse {
+    /// The claims of the response body, unless it has no body or another shape
+    pub claims: Option<EnrollClaims>,
+    /// The response, as returned by the authenticator
+    pub raw: Vec<u8>,
+    /// Identity authenticated by the secure channel the token was sent over,
+    /// unless that channel is not known to the node anymore
+    pub authenticator: Option<IdentityIdentifier>,
+    /// Time spent in each phase of the attempt which succeeded, when the node
+    /// created the secure channel of the enrollment
+    pub timings: Option<EnrollTimings>,
+}
+
+impl EnrollResponse {
+    /// Decode the claims of the successful response `raw`
+    pub(crate) fn new(raw: Vec<u8>) -> Self {
+        let mut dec = Decoder::new(&raw);
+        let claims = match dec.decode::<ockam_core::api::Response>() {
+            Ok(header) if header.has_body() => dec.decode().ok(),
+            _ => None,
+        };
+        Self {
+            claims,
+            raw,
+            authenticator: None,
+            timings: None,
+        }
     }
 
-    #[derive(serde::Deserialize, Debug, PartialEq, Eq)]
-    pub struct AuthorizationCode {
-        pub code: String,
+    /// Unix time (in seconds) at which the credential issued by the authenticator expires,
+    /// so that the identity can enroll again before then
+    pub fn credential_expires_at(&self) -> Option<u64> {
+        self.claims.as_ref().and_then(|c| c.credential_expires_at)
     }
+}
+
+/// Errors which can occur while authenticating a token
+#[derive(Debug)]
+pub enum EnrollError {
+    /// The secure channel to the authenticator could not be established
+    SecureChannel(ockam_core::Error),
+    /// The secure channel to the authenticator was not established in time
+    SecureChannelTimeout(Duration),
+    /// The request could not be delivered or no response was received
+    Transport(ockam_core::Error),
+    /// The authenticator answered with a non-successful status
+    Rejected {
+        status: Status,
+        message: Option<String>,
+    },
+    /// The request was refused by this node with `status`, without reaching the authenticator
+    Refused { status: Status, message: String },
+    /// The authenticator response could not be decoded
+    Decode(minicbor::decode::Error),
+    /// The enrollment was cancelled by the caller before completing
+    Cancelled,
+    /// Too many enrollments are already waiting to run on this node
+    Overloaded,
+    /// The whole enrollment, including its retries, didn't complete within its deadline
+    DeadlineExceeded(Duration),
+    /// The clock of the authenticator is further than `max` from the clock of the node.
+    /// The `skew` is in seconds, negative when the authenticator is behind
+    ClockSkew { skew: i64, max: Duration },
+}
 
-    impl AuthorizationCode {
-        pub fn new(s: impl Into<String>) -> Self {
-            Self { code: s.into() }
+impl EnrollError {
+    /// Status reported to the node API client for this error
+    pub fn status(&self) -> Status {
+        match self {
+            EnrollError::Rejected {
+                status: Status::Forbidden,
+                ..
+            } => Status::Forbidden,
+            EnrollError::Rejected {
+                status: Status::InternalServerError | Status::NotImplemented,
+                ..
+            } => Status::InternalServerError,
+            EnrollError::Rejected { .. } => Status::Unauthorized,
+            EnrollError::Refused { status, .. } => *status,
+            EnrollError::SecureChannelTimeout(_) | EnrollError::DeadlineExceeded(_) => {
+                Status::RequestTimeout
+            }
+            EnrollError::Transport(e) if e.code().kind == Kind::Timeout => Status::RequestTimeout,
+            EnrollError::Transport(e) if e.code().kind == Kind::Io => Status::ServiceUnavailable,
+            EnrollError::Overloaded => Status::ServiceUnavailable,
+            EnrollError::ClockSkew { .. } => Status::Conflict,
+            EnrollError::SecureChannel(_)
+            | EnrollError::Transport(_)
+            | EnrollError::Decode(_)
+            | EnrollError::Cancelled => Status::InternalServerError,
         }
     }
 
-    #[derive(serde::Deserialize, Debug, PartialEq, Eq)]
-    pub struct TokensError<'a> {
-        pub error: Cow<'a, str>,
-        pub error_description: Cow<'a, str>,
+    /// Return true if a new attempt may succeed
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            EnrollError::Transport(_) | EnrollError::SecureChannelTimeout(_)
+        )
+    }
+
+    /// Check the header of an authenticator response, returning the
+    /// error it carries if its status is not `Ok`
+    pub(crate) fn check_response(res: &[u8]) -> Result<(), EnrollError> {
+        let mut dec = Decoder::new(res);
+        let header: ockam_core::api::Response = dec.decode().map_err(EnrollError::Decode)?;
+        if header.is_ok() {
+            return Ok(());
+        }
+        let message = if header.has_body() {
+            dec.decode::<ockam_core::api::Error>()
+                .ok()
+                .and_then(|e| e.message().map(String::from))
+        } else {
+            None
+        };
+        Err(EnrollError::Rejected {
+            status: header.status().unwrap_or(Status::InternalServerError),
+            message,
+        })
+    }
+}
+
+impl fmt::Display for EnrollError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EnrollError::SecureChannel(e) => {
+                write!(
+                    f,
+                    "failed to create a secure channel to the authenticator: {e}"
+                )
+            }
+            EnrollError::SecureChannelTimeout(timeout) => write!(
+                f,
+                "the secure channel to the authenticator was not established after {timeout:?}"
+            ),
+            EnrollError::Transport(e) => write!(f, "failed to reach the authenticator: {e}"),
+            EnrollError::Rejected {
+                status,
+                message: Some(message),
+            } => write!(f, "the token was rejected ({status}): {message}"),
+            EnrollError::Rejected {
+                status,
+                message: None,
+            } => write!(f, "the token was rejected ({status})"),
+            EnrollError::Refused { message, .. } => write!(f, "{message}"),
+            EnrollError::Decode(e) => write!(f, "failed to decode the authenticator response: {e}"),
+            EnrollError::Cancelled => write!(f, "the enrollment was cancelled"),
+            EnrollError::Overloaded => {
+                write!(f, "too many enrollments are running, try again later")
+            }
+            EnrollError::DeadlineExceeded(deadline) => {
+                write!(f, "the enrollment didn't complete within {deadline:?}")
+            }
+            EnrollError::ClockSkew { skew, max } => write!(
+                f,
+                "the clock of the authenticator is {skew}s off the clock of this node, more than {max:?}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for EnrollError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            EnrollError::SecureChannel(e) | EnrollError::Transport(e) => Some(e),
+            EnrollError::Decode(e) => Some(e),
+            EnrollError::SecureChannelTimeout(_)
+            | EnrollError::Rejected { .. }
+            | EnrollError::Refused { .. }
+            | EnrollError::Cancelled
+            | EnrollError::Overloaded
+            | EnrollError::DeadlineExceeded(_)
+            | EnrollError::ClockSkew { .. } => None,
+        }
+    }
+}
+
+impl From<EnrollError> for ockam_core::Error {
+    fn from(e: EnrollError) -> Self {
+        let kind = match &e {
+            EnrollError::SecureChannel(_) => Kind::Protocol,
+            EnrollError::SecureChannelTimeout(_) | EnrollError::DeadlineExceeded(_) => {
+                Kind::Timeout
+            }
+            EnrollError::Transport(_) => Kind::Io,
+            EnrollError::Rejected { .. }
+            | EnrollError::Refused { .. }
+            | EnrollError::ClockSkew { .. } => Kind::Invalid,
+            EnrollError::Decode(_) => Kind::Serialization,
+            EnrollError::Cancelled => Kind::Cancelled,
+            EnrollError::Overloaded => Kind::ResourceExhausted,
+        };
+        ockam_core::Error::new(Origin::Application, kind, e)
+    }
+}
+
+pub mod oidc {
+    use ockam_core::{async_trait, Result};
+
+    use super::*;
+
+    /// Source of tokens issued by an OpenID Connect provider.
+    ///
+    /// The tokens are exchanged for a project membership by the Orchestrator
+    /// authenticator associated with the provider.
+    #[async_trait]
+    pub trait OidcTokenProvider: Send + Sync + 'static {
+        /// Return a token issued by the provider for the current user
+        async fn token(&self) -> Result<OidcToken>;
     }
 
-    #[derive(serde::Deserialize, Debug, Clone)]
+    // Req/Res types
+
+    #[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
     #[cfg_attr(test, derive(PartialEq, Eq))]
     pub struct OidcToken {
         pub token_type: TokenType,
         pub access_token: Token,
+        /// Token which can be exchanged for a new access token, if the provider issued one
+        #[serde(default)]
+        pub refresh_token: Option<Token>,
+        /// Unix time, in seconds, after which the access token is not valid anymore
+        #[serde(default)]
+        pub expires_at: Option<u64>,
+        /// Unix time, in seconds, at which the token was received
+        #[serde(default)]
+        pub issued_at: Option<u64>,
     }
 
-    #[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
-    #[cfg_attr(test, derive(PartialEq, Eq))]
-    pub struct UserInfo {
-        pub sub: String,
-        pub nickname: String,
-        pub name: String,
-        pub picture: String,
-        pub updated_at: String,
-        pub email: String,
-        pub email_verified: bool,
+    impl OidcToken {
+        /// A token without an expiry is considered valid until it is rejected
+        pub fn is_expired(&self, now: u64) -> bool {
+            self.expires_at.map(|t| t <= now).unwrap_or(false)
+        }
     }
 
+    /// A token sent to an OIDC authenticator.
+    ///
+    /// The request is tagged with `TAG` when the `tag` feature is enabled, so that
+    /// deployments using their own schema registry can pick another tag.
     #[derive(Encode, Decode, Debug)]
     #[cfg_attr(test, derive(Clone))]
     #[rustfmt::skip]
     #[cbor(map)]
-    pub struct AuthenticateOidcToken {
+    pub struct AuthenticateOidcToken<const TAG: usize = 1058055> {
         #[cfg(feature = "tag")]
-        #[n(0)] pub tag: TypeTag<1058055>,
+        #[n(0)] pub tag: TypeTag<TAG>,
         #[n(1)] pub token_type: TokenType,
         #[n(2)] pub access_token: Token,
+        #[n(3)] pub refresh_token: Option<Token>,
     }
 
     impl AuthenticateOidcToken {
         pub fn new(token: OidcToken) -> Self {
+            Self::tagged(token)
+        }
+    }
+
+    impl<const TAG: usize> AuthenticateOidcToken<TAG> {
+        /// Same as `new`, for a request tagged with `TAG` rather than the default tag
+        pub fn tagged(token: OidcToken) -> Self {
             Self {
                 #[cfg(feature = "tag")]
                 tag: TypeTag,
                 token_type: token.token_type,
                 access_token: token.access_token,
+                refresh_token: token.refresh_token,
+            }
+        }
+
+        /// Return the token sent to the authenticator as an [`OidcToken`].
+        ///
+        /// Its expiry and issue time are not sent, so they are not set.
+        pub fn into_oidc_token(self) -> OidcToken {
+            OidcToken {
+                token_type: self.token_type,
+                access_token: self.access_token,
+                refresh_token: self.refresh_token,
+                expires_at: None,
+                issued_at: None,
             }
         }
     }
 
+    impl<const TAG: usize> From<OidcToken> for AuthenticateOidcToken<TAG> {
+        fn from(token: OidcToken) -> Self {
+            AuthenticateOidcToken::tagged(token)
+        }
+    }
+
     // Auxiliary types
 
-    #[derive(serde::Deserialize, Encode, Decode, Debug, Clone)]
+    #[derive(serde::Serialize, serde::Deserialize, Encode, Decode, Debug, Clone)]
     #[cfg_attr(test, derive(PartialEq, Eq))]
     #[rustfmt::skip]
     #[cbor(index_only)]
     pub enum TokenType {
         #[n(0)] Bearer,
+        /// Token bound to a key of the client, see https://datatracker.ietf.org/doc/html/rfc9449
+        #[serde(rename = "DPoP")]
+        #[n(1)] DPoP,
+        /// Token used with a MAC of the request, see https://datatracker.ietf.org/doc/html/draft-ietf-oauth-v2-http-mac
+        #[serde(rename = "mac")]
+        #[n(2)] Mac,
     }
-}
 
-pub mod enrollment_token {
-    use serde::Serialize;
+    #[cfg(test)]
+    mod tests {
+        use cddl_cat::validate_cbor_bytes;
 
-    use ockam::identity::credential::Attributes;
+        use crate::schema::SCHEMA;
 
-    use super::*;
+        use super::*;
 
-    // Main req/res types
+        struct StaticTokenProvider(OidcToken);
 
-    #[derive(Encode, Debug)]
-    #[cfg_attr(test, derive(Decode, Clone))]
-    #[rustfmt::skip]
-    #[cbor(map)]
-    pub struct RequestEnrollmentToken {
-        #[cfg(feature = "tag")]
-        #[n(0)] pub tag: TypeTag<8560526>,
-        #[b(1)] pub attributes: Attributes,
-    }
+        fn token(token_type: TokenType) -> OidcToken {
+            OidcToken {
+                token_type,
+                access_token: Token::new("access"),
+                refresh_token: None,
+                expires_at: None,
+                issued_at: None,
+            }
+        }
 
-    impl RequestEnrollmentToken {
-        pub fn new(attributes: Attributes) -> Self {
-            Self {
-                #[cfg(feature = "tag")]
-                tag: TypeTag,
-                attributes,
+        #[test]
+        fn token_types_have_stable_indices() {
+            let types = [
+                (TokenType::Bearer, 0u8),
+                (TokenType::DPoP, 1),
+                (TokenType::Mac, 2),
+            ];
+            for (token_type, index) in types {
+                let cbor = minicbor::to_vec(&token_type).unwrap();
+                assert_eq!(cbor, minicbor::to_vec(index).unwrap());
+                assert_eq!(minicbor::decode::<TokenType>(&cbor).unwrap(), token_type);
+            }
+            let unknown = minicbor::to_vec(3u8).unwrap();
+            assert!(minicbor::decode::<TokenType>(&unknown).is_err());
+        }
+
+        #[test]
+        fn token_type_is_sent_to_the_authenticator() {
+            for token_type in [TokenType::Bearer, TokenType::DPoP, TokenType::Mac] {
+                let request = AuthenticateOidcToken::new(token(token_type.clone()));
+                let cbor = minicbor::to_vec(&request).unwrap();
+                validate_cbor_bytes("authenticate_oidc_token", SCHEMA, &cbor).unwrap();
+
+                let decoded: AuthenticateOidcToken = minicbor::decode(&cbor).unwrap();
+                assert_eq!(decoded.token_type, token_type);
+            }
+        }
+
+        #[test]
+        fn tokens_roundtrip_through_their_wire_type() {
+            let token = OidcToken {
+                refresh_token: Some(Token::new("refresh")),
+                ..token(TokenType::DPoP)
+            };
+            let request: AuthenticateOidcToken = token.clone().into();
+            assert_eq!(request.into_oidc_token(), token);
+
+            let expiring = OidcToken {
+                expires_at: Some(100),
+                issued_at: Some(10),
+                ..token.clone()
+            };
+            let request: AuthenticateOidcToken = expiring.into();
+            assert_eq!(request.into_oidc_token(), token);
+        }
+
+        #[test]
+        fn requests_can_be_tagged_with_another_tag() {
+            let request = AuthenticateOidcToken::<42>::tagged(token(TokenType::Bearer));
+            let cbor = minicbor::to_vec(&request).unwrap();
+            let decoded: AuthenticateOidcToken<42> = minicbor::decode(&cbor).unwrap();
+            assert_eq!(decoded.access_token, Token::new("access"));
+
+            #[cfg(feature = "tag")]
+            {
+                let mut dec = minicbor::Decoder::new(&cbor);
+                dec.map().unwrap();
+                assert_eq!(dec.u8().unwrap(), 0);
+                assert_eq!(dec.u64().unwrap(), 42);
+
+                let default =
+                    minicbor::to_vec(AuthenticateOidcToken::new(token(TokenType::Bearer)));
+                assert!(minicbor::decode::<AuthenticateOidcToken<42>>(&default.unwrap()).is_err());
             }
         }
+
+        #[test]
+        fn token_types_use_their_oauth_names() {
+            let json = serde_json::to_string(&[TokenType::DPoP, TokenType::Mac]).unwrap();
+            assert_eq!(json, r#"["DPoP","mac"]"#);
+        }
+
+        #[async_trait]
+        impl OidcTokenProvider for StaticTokenProvider {
+            async fn token(&self) -> Result<OidcToken> {
+                Ok(self.0.clone())
+            }
+        }
+
+        #[tokio::test]
+        async fn oidc_tokens_are_sent_to_the_configured_authenticator() {
+            let provider = StaticTokenProvider(OidcToken {
+                token_type: TokenType::Bearer,
+                access_token: Token::new("access"),
+                refresh_token: None,
+                expires_at: None,
+                issued_at: None,
+            });
+            let token = AuthenticateOidcToken::new(provider.token().await.unwrap());
+            let expected = minicbor::to_vec(&token).unwrap();
+
+            let token = AuthenticateToken::Oidc {
+                authenticator: "okta_authenticator".into(),
+                token,
+            };
+            let services = AuthenticatorServices::default();
+            assert_eq!(token.api_service(&services), "okta_authenticator");
+            assert_eq!(minicbor::to_vec(&token).unwrap(), expected);
+        }
     }
+}
+
+pub mod api_key {
+    use super::*;
 
-    #[derive(Encode, Decode, Serialize, Debug)]
+    /// An API key sent to its authenticator, tagged with `TAG` when the `tag` feature is enabled
+    #[derive(Encode, Decode, Debug)]
     #[cfg_attr(test, derive(Clone))]
     #[rustfmt::skip]
     #[cbor(map)]
-    pub struct EnrollmentToken {
+    pub struct AuthenticateApiKey<const TAG: usize = 3211860> {
         #[cfg(feature = "tag")]
-        #[serde(skip)]
-        #[n(0)] pub tag: TypeTag<8932763>,
+        #[n(0)] pub tag: TypeTag<TAG>,
         #[n(1)] pub token: Token,
     }
 
-    impl EnrollmentToken {
+    impl AuthenticateApiKey {
         pub fn new(token: Token) -> Self {
+            Self::tagged(token)
+        }
+    }
+
+    impl<const TAG: usize> AuthenticateApiKey<TAG> {
+        /// Same as `new`, for a request tagged with `TAG` rather than the default tag
+        pub fn tagged(token: Token) -> Self {
             Self {
                 #[cfg(feature = "tag")]
                 tag: TypeTag,
@@ -276,24 +777,93 @@ pub mod enrollment_token {
             }
         }
     }
+}
+
+pub mod client_certificate {
+    use base64_url::base64::engine::general_purpose::STANDARD;
+    use base64_url::base64::Engine;
+    use minicbor::bytes::ByteVec;
+    use ockam_vault::Vault;
+
+    use crate::error::ApiError;
+
+    use super::*;
 
-    #[derive(Encode, Debug)]
-    #[cfg_attr(test, derive(Decode, Clone))]
+    /// Certificate a device presented to authenticate over mutual TLS, as received
+    /// by the node terminating the TLS connection
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub enum ClientCertificate {
+        Der(Vec<u8>),
+        /// A PEM `CERTIFICATE` block
+        Pem(String),
+    }
+
+    impl ClientCertificate {
+        /// DER encoding of the certificate, read from its PEM block if needed
+        pub fn to_der(&self) -> ockam_core::Result<Vec<u8>> {
+            match self {
+                ClientCertificate::Der(der) => Ok(der.clone()),
+                ClientCertificate::Pem(pem) => {
+                    let begin = "-----BEGIN CERTIFICATE-----";
+                    let end = "-----END CERTIFICATE-----";
+                    let body = pem
+                        .split_once(begin)
+                        .and_then(|(_, rest)| rest.split_once(end))
+                        .map(|(body, _)| body)
+                        .ok_or_else(|| ApiError::generic("no PEM certificate block"))?;
+                    let body: String = body.split_whitespace().collect();
+                    STANDARD.decode(body).map_err(ApiError::message)
+                }
+            }
+        }
+    }
+
+    /// A client certificate sent to its authenticator as DER, tagged with `TAG`
+    /// when the `tag` feature is enabled.
+    ///
+    /// The authenticator checks that the certificate was issued for the project,
+    /// and that it is not revoked.
+    #[derive(Encode, Decode, Debug)]
+    #[cfg_attr(test, derive(Clone))]
     #[rustfmt::skip]
     #[cbor(map)]
-    pub struct AuthenticateEnrollmentToken {
+    pub struct AuthenticateClientCertificate<const TAG: usize = 6129847> {
         #[cfg(feature = "tag")]
-        #[n(0)] pub tag: TypeTag<9463780>,
-        #[n(1)] pub token: Token,
+        #[n(0)] pub tag: TypeTag<TAG>,
+        #[b(1)] pub certificate: ByteVec,
     }
 
-    impl AuthenticateEnrollmentToken {
-        pub fn new(token: EnrollmentToken) -> Self {
-            Self {
+    impl AuthenticateClientCertificate {
+        pub fn new(certificate: &ClientCertificate) -> ockam_core::Result<Self> {
+            Self::tagged(certificate)
+        }
+    }
+
+    impl<const TAG: usize> AuthenticateClientCertificate<TAG> {
+        /// Same as `new`, for a request tagged with `TAG` rather than the default tag
+        pub fn tagged(certificate: &ClientCertificate) -> ockam_core::Result<Self> {
+            Ok(Self {
                 #[cfg(feature = "tag")]
                 tag: TypeTag,
-                token: token.token,
-            }
+                certificate: ByteVec::from(certificate.to_der()?),
+            })
+        }
+
+        /// SHA-256 fingerprint of the certificate, as lowercase hex
+        pub fn fingerprint(&self) -> String {
+            hex::encode(Vault::sha256(&self.certificate))
         }
     }
 }
+
+// Most of the flows are tested with auth0 tokens
+#[cfg(all(test, feature = "auth0"))]
+mod tests;
+
+#[cfg(all(test, not(feature = "auth0")))]
+mod without_auth0_tests;
+
+/// Property tests feeding malformed CBOR to the decoders of the enroll requests,
+/// which all receive untrusted input from the node API
+#[cfg(test)]
+mod decode_tests;
@@ -0,0 +1,1227 @@
+use std::borrow::Cow;
+use std::future::Future;
+
+use ockam_core::Result;
+use ockam_node::tokio;
+use ockam_node::tokio::sync::oneshot;
+use ockam_node::tokio::time::{sleep, Duration, Instant};
+use ockam_vault::Vault;
+use rand::{thread_rng, RngCore};
+use reqwest::StatusCode;
+use url::Url;
+
+use crate::cloud::enroll::clock::now;
+use crate::error::ApiError;
+
+pub use super::oidc::{AuthenticateOidcToken, OidcToken, TokenType};
+use super::*;
+
+// Req/Res types
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq, Eq)]
+pub struct DeviceCode<'a> {
+    pub device_code: Cow<'a, str>,
+    pub user_code: Cow<'a, str>,
+    pub verification_uri: Cow<'a, str>,
+    pub verification_uri_complete: Cow<'a, str>,
+    pub expires_in: usize,
+    pub interval: usize,
+    /// PKCE code verifier of the flow, sent with the token requests.
+    /// It is not returned by the provider but set by [`request_device_code`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub code_verifier: Option<String>,
+}
+
+impl DeviceCode<'_> {
+    /// Return what the user needs to approve this device code, received at the Unix time `now`
+    pub fn instructions(&self, now: u64) -> DeviceFlowInstructions {
+        let expires_in = self.expires_in as u64;
+        DeviceFlowInstructions {
+            user_code: self.user_code.to_string(),
+            verification_uri: self.verification_uri.to_string(),
+            verification_uri_complete: self.verification_uri_complete.to_string(),
+            expires_at: now + expires_in,
+            expires_in: human_duration(expires_in),
+        }
+    }
+}
+
+/// Instructions of a device flow, which a frontend can render to the user.
+///
+/// Unlike the [`DeviceCode`] they are derived from, they don't contain the
+/// secrets of the flow, and they have an absolute expiry.
+#[derive(serde::Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct DeviceFlowInstructions {
+    /// Code which the user must confirm on the verification page
+    pub user_code: String,
+    pub verification_uri: String,
+    /// Verification page with the user code already filled in
+    pub verification_uri_complete: String,
+    /// Unix time (in seconds) after which the device code can't be approved anymore
+    pub expires_at: u64,
+    /// Validity of the device code when it was issued, such as "15 minutes"
+    pub expires_in: String,
+}
+
+/// Format a number of seconds with its largest units, such as "1 minute 30 seconds"
+fn human_duration(seconds: u64) -> String {
+    let units = [(3600, "hour"), (60, "minute"), (1, "second")];
+    let mut remaining = seconds;
+    let mut parts = vec![];
+    for (length, unit) in units {
+        let count = remaining / length;
+        remaining %= length;
+        match count {
+            0 => {}
+            1 => parts.push(format!("1 {unit}")),
+            _ => parts.push(format!("{count} {unit}s")),
+        }
+    }
+    if parts.is_empty() {
+        "0 seconds".to_string()
+    } else {
+        parts.join(" ")
+    }
+}
+
+#[derive(serde::Deserialize, Debug, PartialEq, Eq)]
+pub struct AuthorizationCode {
+    pub code: String,
+}
+
+impl AuthorizationCode {
+    pub fn new(s: impl Into<String>) -> Self {
+        Self { code: s.into() }
+    }
+}
+
+#[derive(serde::Deserialize, Debug, PartialEq, Eq)]
+pub struct TokensError<'a> {
+    pub error: Cow<'a, str>,
+    pub error_description: Cow<'a, str>,
+}
+
+impl TokensError<'_> {
+    pub fn kind(&self) -> TokensErrorKind {
+        TokensErrorKind::from(self.error.as_ref())
+    }
+}
+
+/// Error codes returned by the token endpoint of an OIDC provider.
+/// See https://datatracker.ietf.org/doc/html/rfc6749#section-5.2
+/// and https://datatracker.ietf.org/doc/html/rfc8628#section-3.5
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TokensErrorKind {
+    /// The user has not completed the authentication yet
+    AuthorizationPending,
+    /// The token endpoint is polled too often
+    SlowDown,
+    /// The user denied the authorization request
+    AccessDenied,
+    /// The device code expired before the authentication was completed
+    ExpiredToken,
+    InvalidRequest,
+    InvalidClient,
+    InvalidGrant,
+    InvalidScope,
+    UnauthorizedClient,
+    UnsupportedGrantType,
+    /// An error code which is not defined by the specifications
+    Other(String),
+}
+
+impl From<&str> for TokensErrorKind {
+    fn from(error: &str) -> Self {
+        match error {
+            "authorization_pending" => TokensErrorKind::AuthorizationPending,
+            "slow_down" => TokensErrorKind::SlowDown,
+            "access_denied" => TokensErrorKind::AccessDenied,
+            "expired_token" => TokensErrorKind::ExpiredToken,
+            "invalid_request" => TokensErrorKind::InvalidRequest,
+            "invalid_client" => TokensErrorKind::InvalidClient,
+            "invalid_grant" => TokensErrorKind::InvalidGrant,
+            "invalid_scope" => TokensErrorKind::InvalidScope,
+            "unauthorized_client" => TokensErrorKind::UnauthorizedClient,
+            "unsupported_grant_type" => TokensErrorKind::UnsupportedGrantType,
+            other => TokensErrorKind::Other(other.to_string()),
+        }
+    }
+}
+
+/// Error returned when a device authorization flow can't be completed
+#[derive(Debug)]
+pub enum DeviceFlowError {
+    /// The token endpoint answered with an error ending the flow,
+    /// for example because the user denied the request
+    Tokens(TokensError<'static>),
+    /// The token endpoint couldn't be reached, its response couldn't be decoded,
+    /// or the flow was cancelled
+    Request(ockam_core::Error),
+}
+
+impl DeviceFlowError {
+    /// Return the error code sent by the token endpoint, if any
+    pub fn tokens_error_kind(&self) -> Option<TokensErrorKind> {
+        match self {
+            DeviceFlowError::Tokens(err) => Some(err.kind()),
+            DeviceFlowError::Request(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for DeviceFlowError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeviceFlowError::Tokens(err) => match err.kind() {
+                TokensErrorKind::AccessDenied => write!(f, "the authentication was denied"),
+                TokensErrorKind::ExpiredToken => write!(
+                    f,
+                    "the device code expired before the authentication was completed"
+                ),
+                _ => write!(
+                    f,
+                    "failed to receive tokens: {} ({})",
+                    err.error, err.error_description
+                ),
+            },
+            DeviceFlowError::Request(e) => write!(f, "failed to receive tokens: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for DeviceFlowError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DeviceFlowError::Tokens(_) => None,
+            DeviceFlowError::Request(e) => Some(e),
+        }
+    }
+}
+
+impl From<ockam_core::Error> for DeviceFlowError {
+    fn from(e: ockam_core::Error) -> Self {
+        DeviceFlowError::Request(e)
+    }
+}
+
+impl From<DeviceFlowError> for ockam_core::Error {
+    fn from(e: DeviceFlowError) -> Self {
+        let kind = match e {
+            DeviceFlowError::Request(e) => return e,
+            DeviceFlowError::Tokens(ref err) => match err.kind() {
+                TokensErrorKind::AccessDenied => Kind::Invalid,
+                TokensErrorKind::ExpiredToken => Kind::Timeout,
+                _ => Kind::Protocol,
+            },
+        };
+        ockam_core::Error::new(Origin::Application, kind, e)
+    }
+}
+
+/// Token returned by the token endpoint of an OIDC provider.
+///
+/// Its relative expiry is converted to an absolute one when
+/// it is turned into an [`OidcToken`].
+#[derive(serde::Deserialize, Debug)]
+struct TokenResponse {
+    token_type: TokenType,
+    access_token: Token,
+    refresh_token: Option<Token>,
+    expires_in: Option<u64>,
+}
+
+impl TokenResponse {
+    fn into_token(self, now: u64) -> OidcToken {
+        OidcToken {
+            token_type: self.token_type,
+            access_token: self.access_token,
+            refresh_token: self.refresh_token,
+            expires_at: self.expires_in.map(|expires_in| now + expires_in),
+            issued_at: Some(now),
+        }
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+#[cfg_attr(test, derive(PartialEq, Eq))]
+pub struct UserInfo {
+    pub sub: String,
+    pub nickname: String,
+    pub name: String,
+    pub picture: String,
+    pub updated_at: String,
+    pub email: String,
+    pub email_verified: bool,
+}
+
+// Device authorization flow
+
+/// Client id of the Ockam application registered with Auth0
+pub const OCKAM_CLIENT_ID: &str = "c1SAhEjrJAqEk6ArWjGjuWX11BD2gK8X";
+
+/// Auth0 endpoint used to start a device authorization flow
+pub const OCKAM_DEVICE_CODE_URL: &str = "https://account.ockam.io/oauth/device/code";
+
+/// Scopes requested for the tokens of the Ockam application
+pub const OCKAM_SCOPES: &str = "profile openid email";
+
+/// Auth0 endpoint used to exchange a device code for a token
+pub const OCKAM_TOKEN_URL: &str = "https://account.ockam.io/oauth/token";
+
+/// Domain of the Ockam Auth0 tenant
+pub const OCKAM_DOMAIN: &str = "account.ockam.io";
+
+/// Auth0 tenant and application used by the node manager auth0 flows
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Auth0Config {
+    /// Domain of the tenant, for example `account.ockam.io`
+    pub domain: String,
+    pub client_id: String,
+    /// API the tokens are issued for, the tenant default audience is used when not set
+    pub audience: Option<String>,
+    /// Space-separated scopes requested for the tokens
+    pub scope: String,
+    /// Proxy the requests to the tenant go through. When it is not set,
+    /// the proxy of the `HTTPS_PROXY` environment variable is used, if any
+    pub proxy: Option<Auth0Proxy>,
+    /// Maximum time to establish a connection to the tenant, or to the proxy
+    pub connect_timeout: Option<Duration>,
+    /// Maximum time of a whole request to the tenant, from connecting
+    /// to reading the end of the response
+    pub request_timeout: Option<Duration>,
+    /// Certificate authorities trusted in addition to the native roots of
+    /// the platform, for instance the one of a TLS-inspecting proxy
+    pub root_certificates: Vec<Auth0RootCertificate>,
+    /// Longest interval between two polls of the device flow, however
+    /// many times the tenant asks to slow down
+    pub max_polling_interval: Duration,
+}
+
+/// The Ockam tenant and application
+impl Default for Auth0Config {
+    fn default() -> Self {
+        Self {
+            domain: OCKAM_DOMAIN.to_string(),
+            client_id: OCKAM_CLIENT_ID.to_string(),
+            audience: None,
+            scope: OCKAM_SCOPES.to_string(),
+            proxy: None,
+            connect_timeout: None,
+            request_timeout: None,
+            root_certificates: vec![],
+            max_polling_interval: DEFAULT_MAX_POLLING_INTERVAL,
+        }
+    }
+}
+
+/// An HTTP proxy, with the credentials to authenticate to it if it requires them
+#[derive(Clone, PartialEq, Eq)]
+pub struct Auth0Proxy {
+    pub url: Url,
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+impl Auth0Proxy {
+    pub fn new(url: Url) -> Self {
+        Self {
+            url,
+            username: None,
+            password: None,
+        }
+    }
+
+    pub fn with_basic_auth(mut self, username: &str, password: &str) -> Self {
+        self.username = Some(username.to_string());
+        self.password = Some(password.to_string());
+        self
+    }
+}
+
+/// A certificate authority, checked to be valid when it is created
+#[derive(Clone, PartialEq, Eq)]
+pub struct Auth0RootCertificate {
+    der: Vec<u8>,
+}
+
+impl Auth0RootCertificate {
+    /// Read each certificate of a PEM bundle, which must contain at least one
+    pub fn from_pem(pem: &[u8]) -> Result<Vec<Self>> {
+        let invalid = |reason: &str| {
+            ApiError::message(format!(
+                "invalid auth0 configuration: the root certificates {reason}"
+            ))
+        };
+        let certificates =
+            rustls_pemfile::certs(&mut &pem[..]).map_err(|_| invalid("are not valid PEM"))?;
+        if certificates.is_empty() {
+            return Err(invalid("don't contain any certificate"));
+        }
+        certificates
+            .into_iter()
+            .map(|der| {
+                let certificate = Self { der };
+                // the certificate is only parsed when it is added to a client
+                reqwest::Client::builder()
+                    .tls_built_in_root_certs(false)
+                    .add_root_certificate(certificate.to_reqwest()?)
+                    .build()
+                    .map_err(|e| invalid(&format!("can't be parsed: {e}")))?;
+                Ok(certificate)
+            })
+            .collect()
+    }
+
+    fn to_reqwest(&self) -> Result<reqwest::Certificate> {
+        reqwest::Certificate::from_der(&self.der).map_err(ApiError::message)
+    }
+}
+
+impl fmt::Debug for Auth0RootCertificate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Auth0RootCertificate({} bytes)", self.der.len())
+    }
+}
+
+impl fmt::Debug for Auth0Proxy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Auth0Proxy")
+            .field("url", &self.url.as_str())
+            .field("username", &self.username)
+            .field("password", &self.password.as_ref().map(|_| "***"))
+            .finish()
+    }
+}
+
+impl Auth0Config {
+    /// Check that the fields needed to run a flow are set
+    pub fn validate(&self) -> Result<()> {
+        let missing = if self.domain.trim().is_empty() {
+            "domain"
+        } else if self.client_id.trim().is_empty() {
+            "client id"
+        } else if self.scope.trim().is_empty() {
+            "scope"
+        } else {
+            return Ok(());
+        };
+        Err(ApiError::message(format!(
+            "invalid auth0 configuration: the {missing} is missing"
+        )))
+    }
+
+    /// Trust the certificate authorities of a PEM bundle, in addition to the
+    /// ones already configured. An error is returned if the bundle is invalid
+    pub fn with_root_certificates_pem(mut self, pem: &[u8]) -> Result<Self> {
+        self.root_certificates
+            .extend(Auth0RootCertificate::from_pem(pem)?);
+        Ok(self)
+    }
+
+    /// Return a client sending its requests through the configured proxy,
+    /// with the configured timeouts and root certificates
+    pub fn http_client(&self) -> Result<reqwest::Client> {
+        let mut builder = reqwest::Client::builder();
+        if let Some(timeout) = self.connect_timeout {
+            builder = builder.connect_timeout(timeout);
+        }
+        if let Some(timeout) = self.request_timeout {
+            builder = builder.timeout(timeout);
+        }
+        for certificate in &self.root_certificates {
+            builder = builder.add_root_certificate(certificate.to_reqwest()?);
+        }
+        if let Some(proxy) = &self.proxy {
+            let mut http_proxy =
+                reqwest::Proxy::all(proxy.url.clone()).map_err(ApiError::message)?;
+            if let Some(username) = &proxy.username {
+                let password = proxy.password.as_deref().unwrap_or_default();
+                http_proxy = http_proxy.basic_auth(username, password);
+            }
+            builder = builder.proxy(http_proxy);
+        }
+        builder.build().map_err(ApiError::message)
+    }
+
+    /// Endpoint used to start a device authorization flow
+    pub fn device_code_url(&self) -> Result<Url> {
+        self.url("oauth/device/code")
+    }
+
+    /// Endpoint used to exchange a device code, or a refresh token, for a token
+    pub fn token_url(&self) -> Result<Url> {
+        self.url("oauth/token")
+    }
+
+    fn url(&self, path: &str) -> Result<Url> {
+        self.validate()?;
+        Url::parse(&format!("https://{}/{path}", self.domain)).map_err(|e| {
+            ApiError::message(format!(
+                "invalid auth0 configuration: the domain {} is invalid: {e}",
+                self.domain
+            ))
+        })
+    }
+}
+
+const DEVICE_CODE_GRANT_TYPE: &str = "urn:ietf:params:oauth:grant-type:device_code";
+
+const REFRESH_TOKEN_GRANT_TYPE: &str = "refresh_token";
+
+const CODE_CHALLENGE_METHOD: &str = "S256";
+
+/// Generate a random PKCE code verifier, from 32 random bytes.
+/// See https://datatracker.ietf.org/doc/html/rfc7636#section-4.1
+fn create_code_verifier() -> String {
+    let mut code_verifier = [0u8; 32];
+    thread_rng().fill_bytes(&mut code_verifier);
+    base64_url::encode(&code_verifier)
+}
+
+/// Return the S256 code challenge of a PKCE code verifier
+fn code_challenge(code_verifier: &str) -> String {
+    base64_url::encode(&Vault::sha256(code_verifier.as_bytes()))
+}
+
+/// Amount of time added to the polling interval every time the token endpoint
+/// answers with `slow_down`.
+/// See https://datatracker.ietf.org/doc/html/rfc8628#section-3.5
+const SLOW_DOWN_INCREMENT: Duration = Duration::from_secs(5);
+
+/// Longest interval between two polls of the token endpoint, unless another one is configured
+pub const DEFAULT_MAX_POLLING_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Start a device authorization flow with an OIDC provider.
+///
+/// The returned device code contains the user code and verification URI
+/// to display to the user, and must then be passed to [`poll_device_code`].
+///
+/// `audience` is the API the tokens are requested for, if the provider supports it.
+///
+/// A PKCE code challenge is sent with the request, and its verifier is kept
+/// in the returned device code for the token requests.
+pub async fn request_device_code(
+    client: &reqwest::Client,
+    device_code_url: &Url,
+    client_id: &str,
+    scopes: &str,
+    audience: Option<&str>,
+) -> Result<DeviceCode<'static>> {
+    let code_verifier = create_code_verifier();
+    let code_challenge = code_challenge(&code_verifier);
+    let mut form = vec![
+        ("client_id", client_id),
+        ("scope", scopes),
+        ("code_challenge", &code_challenge),
+        ("code_challenge_method", CODE_CHALLENGE_METHOD),
+    ];
+    if let Some(audience) = audience {
+        form.push(("audience", audience));
+    }
+    let res = client
+        .post(device_code_url.clone())
+        .header("content-type", "application/x-www-form-urlencoded")
+        .form(&form)
+        .send()
+        .await
+        .map_err(ApiError::message)?;
+    if res.status() == StatusCode::OK {
+        let device_code = res.json::<DeviceCode>().await.map_err(ApiError::message)?;
+        Ok(DeviceCode {
+            code_verifier: Some(code_verifier),
+            ..device_code
+        })
+    } else {
+        let err = res.json::<TokensError>().await.map_err(ApiError::message)?;
+        Err(ApiError::message(format!(
+            "failed to request a device code: {} ({})",
+            err.error, err.error_description
+        )))
+    }
+}
+
+/// Poll the token endpoint of an OIDC provider until the user approves the device code.
+///
+/// The endpoint is polled every `interval` seconds, as specified by the device code,
+/// and the interval is increased each time the endpoint asks us to slow down, up to
+/// `max_interval`. The polling never waits past the expiry of the device code.
+///
+/// A [`DeviceFlowError::Tokens`] error is returned if the device code expires
+/// before being approved, or if the endpoint returns any other error.
+pub async fn poll_device_code(
+    client: &reqwest::Client,
+    token_url: &Url,
+    client_id: &str,
+    device_code: &DeviceCode<'_>,
+    max_interval: Duration,
+) -> std::result::Result<OidcToken, DeviceFlowError> {
+    poll(device_code, max_interval, || {
+        request_token(client, token_url, client_id, device_code)
+    })
+    .await
+}
+
+/// Send a single token request for a device code
+async fn request_token(
+    client: &reqwest::Client,
+    token_url: &Url,
+    client_id: &str,
+    device_code: &DeviceCode<'_>,
+) -> Result<std::result::Result<OidcToken, TokensError<'static>>> {
+    let mut form = vec![
+        ("client_id", client_id),
+        ("grant_type", DEVICE_CODE_GRANT_TYPE),
+        ("device_code", device_code.device_code.as_ref()),
+    ];
+    if let Some(code_verifier) = &device_code.code_verifier {
+        form.push(("code_verifier", code_verifier));
+    }
+    let res = client
+        .post(token_url.clone())
+        .header("content-type", "application/x-www-form-urlencoded")
+        .form(&form)
+        .send()
+        .await
+        .map_err(ApiError::message)?;
+    parse_token_response(res).await
+}
+
+/// Exchange the refresh token of an OIDC token for a new access token.
+///
+/// The refresh token of `token` is kept if the provider doesn't issue a new one.
+pub async fn refresh_token(
+    client: &reqwest::Client,
+    token_url: &Url,
+    client_id: &str,
+    token: &OidcToken,
+) -> Result<OidcToken> {
+    let refresh_token = token
+        .refresh_token
+        .as_ref()
+        .ok_or_else(|| ApiError::generic("the token doesn't have a refresh token"))?;
+    let res = client
+        .post(token_url.clone())
+        .header("content-type", "application/x-www-form-urlencoded")
+        .form(&[
+            ("client_id", client_id),
+            ("grant_type", REFRESH_TOKEN_GRANT_TYPE),
+            ("refresh_token", refresh_token.reveal()),
+        ])
+        .send()
+        .await
+        .map_err(ApiError::message)?;
+    match parse_token_response(res).await? {
+        Ok(mut refreshed) => {
+            debug!(target: TARGET, "token refreshed");
+            if refreshed.refresh_token.is_none() {
+                refreshed.refresh_token = Some(refresh_token.clone());
+            }
+            Ok(refreshed)
+        }
+        Err(err) => Err(ApiError::message(format!(
+            "failed to refresh the token: {} ({})",
+            err.error, err.error_description
+        ))),
+    }
+}
+
+async fn parse_token_response(
+    res: reqwest::Response,
+) -> Result<std::result::Result<OidcToken, TokensError<'static>>> {
+    if res.status() == StatusCode::OK {
+        let token = res
+            .json::<TokenResponse>()
+            .await
+            .map_err(ApiError::message)?;
+        Ok(Ok(token.into_token(now()?)))
+    } else {
+        let err = res.json::<TokensError>().await.map_err(ApiError::message)?;
+        Ok(Err(err))
+    }
+}
+
+/// Run `flow` until it completes, unless a value is first received on `cancel`.
+///
+/// The flow is dropped when it is cancelled, so that it stops at its current step.
+pub(crate) async fn until_cancelled<T, E: From<ockam_core::Error>>(
+    flow: impl Future<Output = std::result::Result<T, E>>,
+    cancel: oneshot::Receiver<()>,
+) -> std::result::Result<T, E> {
+    tokio::select! {
+        biased;
+        Ok(()) = cancel => {
+            debug!(target: TARGET, "flow cancelled");
+            Err(ockam_core::Error::from(EnrollError::Cancelled).into())
+        }
+        res = flow => res,
+    }
+}
+
+async fn poll<F, Fut>(
+    device_code: &DeviceCode<'_>,
+    max_interval: Duration,
+    mut request_token: F,
+) -> std::result::Result<OidcToken, DeviceFlowError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<std::result::Result<OidcToken, TokensError<'static>>>>,
+{
+    let deadline = Instant::now() + Duration::from_secs(device_code.expires_in as u64);
+    let mut interval = capped_interval(
+        Duration::from_secs(device_code.interval as u64),
+        max_interval,
+    );
+    loop {
+        match request_token().await? {
+            Ok(token) => {
+                debug!(target: TARGET, "token received");
+                return Ok(token);
+            }
+            Err(err) => match err.kind() {
+                // Some providers answer with `invalid_request` while the
+                // user has not completed the authentication yet
+                TokensErrorKind::AuthorizationPending | TokensErrorKind::InvalidRequest => {
+                    trace!(target: TARGET, ?err, "token not yet received");
+                }
+                TokensErrorKind::SlowDown => {
+                    interval = capped_interval(interval + SLOW_DOWN_INCREMENT, max_interval);
+                    debug!(target: TARGET, ?interval, "slowing down the token polling");
+                }
+                _ => return Err(DeviceFlowError::Tokens(err)),
+            },
+        }
+        if Instant::now() + interval >= deadline {
+            return Err(DeviceFlowError::Tokens(TokensError {
+                error: "expired_token".into(),
+                error_description: "the device code expired before being approved".into(),
+            }));
+        }
+        sleep(interval).await;
+    }
+}
+
+/// Return `interval`, unless it is longer than `max_interval`
+fn capped_interval(interval: Duration, max_interval: Duration) -> Duration {
+    if interval > max_interval {
+        warn!(target: TARGET, ?interval, ?max_interval, "capping the token polling interval");
+        max_interval
+    } else {
+        interval
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use minicbor::Encoder;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio::task::JoinHandle;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn request_device_code_returns_the_device_code() {
+        let (url, requests) = http_stub(vec![(
+            200,
+            r#"{"device_code":"device_code","user_code":"user_code","verification_uri":"https://ockam.io/activate","verification_uri_complete":"https://ockam.io/activate?code=user_code","expires_in":60,"interval":2}"#,
+        )])
+        .await;
+
+        let code = request_device_code(
+            &reqwest::Client::new(),
+            &url,
+            "client",
+            "openid",
+            Some("api"),
+        )
+        .await
+        .unwrap();
+        let code_verifier = code.code_verifier.clone().unwrap();
+        assert_eq!(
+            code,
+            DeviceCode {
+                code_verifier: Some(code_verifier.clone()),
+                ..device_code(60, 2)
+            }
+        );
+
+        let requests = requests.await.unwrap();
+        assert!(requests[0].contains("client_id=client"));
+        assert!(requests[0].contains("scope=openid"));
+        assert!(requests[0].contains("audience=api"));
+        assert!(requests[0].contains("code_challenge_method=S256"));
+        assert!(requests[0].contains(&format!(
+            "code_challenge={}",
+            code_challenge(&code_verifier)
+        )));
+    }
+
+    #[test]
+    fn device_codes_give_instructions_without_their_secrets() {
+        let code = DeviceCode {
+            code_verifier: Some("verifier".to_string()),
+            ..device_code(900, 5)
+        };
+        let instructions = code.instructions(1000);
+        assert_eq!(instructions.user_code, "user_code");
+        assert_eq!(instructions.expires_at, 1900);
+        assert_eq!(instructions.expires_in, "15 minutes");
+
+        let json = serde_json::to_string(&instructions).unwrap();
+        assert!(json
+            .contains(r#""verification_uri_complete":"https://ockam.io/activate?code=user_code""#));
+        assert!(!json.contains("device_code") && !json.contains("verifier"));
+    }
+
+    #[test]
+    fn durations_are_formatted_with_their_largest_units() {
+        assert_eq!(human_duration(0), "0 seconds");
+        assert_eq!(human_duration(1), "1 second");
+        assert_eq!(human_duration(90), "1 minute 30 seconds");
+        assert_eq!(human_duration(7260), "2 hours 1 minute");
+    }
+
+    #[test]
+    fn code_challenges_are_the_s256_transform_of_the_verifier() {
+        // BASE64URL-ENCODE(SHA256(ASCII(code_verifier))), without padding
+        assert_eq!(
+            code_challenge("dBjftJeZ4CVP-mA3oJc6zhnP5Wh05jiX8XGa5WQ5M-Q"),
+            "CuTF6A7VGPa2nKsu3Iykq6NSB5BFTUYdUUiHWZLNucA"
+        );
+
+        let code_verifier = create_code_verifier();
+        assert_eq!(code_verifier.len(), 43);
+        assert_ne!(code_verifier, create_code_verifier());
+    }
+
+    #[tokio::test]
+    async fn request_device_code_fails_on_error_responses() {
+        let (url, _) = http_stub(vec![(
+            403,
+            r#"{"error":"unauthorized_client","error_description":"unknown client"}"#,
+        )])
+        .await;
+
+        let res =
+            request_device_code(&reqwest::Client::new(), &url, "client", "openid", None).await;
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn auth0_config_defaults_to_the_ockam_tenant() {
+        let config = Auth0Config::default();
+        assert_eq!(
+            config.device_code_url().unwrap().as_str(),
+            OCKAM_DEVICE_CODE_URL
+        );
+        assert_eq!(config.token_url().unwrap().as_str(), OCKAM_TOKEN_URL);
+    }
+
+    #[tokio::test]
+    async fn auth0_requests_go_through_the_configured_proxy() {
+        let (proxy_url, requests) = http_stub(vec![(
+            200,
+            r#"{"device_code":"device_code","user_code":"user_code","verification_uri":"https://ockam.io/activate","verification_uri_complete":"https://ockam.io/activate?code=user_code","expires_in":60,"interval":2}"#,
+        )])
+        .await;
+        let config = Auth0Config {
+            proxy: Some(Auth0Proxy::new(proxy_url).with_basic_auth("user", "secret")),
+            ..Default::default()
+        };
+        let url = Url::parse("http://auth0.example/oauth/device/code").unwrap();
+
+        let client = config.http_client().unwrap();
+        let code = request_device_code(&client, &url, "client", "openid", None)
+            .await
+            .unwrap();
+        assert_eq!(code.user_code, "user_code");
+
+        // the proxy receives the request for the tenant, with its credentials
+        let requests = requests.await.unwrap();
+        assert!(requests[0].starts_with("POST http://auth0.example/oauth/device/code"));
+        assert!(requests[0]
+            .to_lowercase()
+            .contains("proxy-authorization: basic dxnlcjpzzwnyzxq="));
+        assert!(!format!("{config:?}").contains("secret"));
+    }
+
+    /// Self-signed certificate authority, standing for the one of a TLS-inspecting proxy
+    const PROXY_CA: &str = "-----BEGIN CERTIFICATE-----
+MIIBhzCCAS2gAwIBAgIUA5gYcKMs76mDFDE6sB/EF3HXpxgwCgYIKoZIzj0EAwIw
+GDEWMBQGA1UEAwwNVGVzdCBQcm94eSBDQTAgFw0yNjEwMTQxMzA1NTBaGA8yMTI2
+MDkyMDEzMDU1MFowGDEWMBQGA1UEAwwNVGVzdCBQcm94eSBDQTBZMBMGByqGSM49
+AgEGCCqGSM49AwEHA0IABBhZYYxGGF1DiKDaIiPnA4m83JYSPpLZjFlsaNejO857
+pc8lckUjFTEmg9aOs6jWS7b/M6e4vbc0+FTd7QfmxY+jUzBRMB0GA1UdDgQWBBRG
+GDZPF+o9rTbYUfQrdVkInOdPejAfBgNVHSMEGDAWgBRGGDZPF+o9rTbYUfQrdVkI
+nOdPejAPBgNVHRMBAf8EBTADAQH/MAoGCCqGSM49BAMCA0gAMEUCIQC31tNyghvJ
+L5S+9XY90AT1NL7/rZ7BpX3LxTLFVyczKAIgViCb9khnFyS8WPOPpSgDR5S/VVHz
+gzziQtvWT2myRnI=
+-----END CERTIFICATE-----";
+
+    #[tokio::test]
+    async fn auth0_clients_use_the_configured_timeouts_and_roots() {
+        let config = Auth0Config {
+            connect_timeout: Some(Duration::from_secs(1)),
+            request_timeout: Some(Duration::from_millis(100)),
+            ..Default::default()
+        }
+        .with_root_certificates_pem(PROXY_CA.as_bytes())
+        .unwrap();
+        assert_eq!(config.root_certificates.len(), 1);
+        let client = config.http_client().unwrap();
+
+        // the server accepts connections but never answers
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("http://{}", listener.local_addr().unwrap())).unwrap();
+        let err = client.get(url).send().await.unwrap_err();
+        assert!(err.is_timeout());
+    }
+
+    #[test]
+    fn auth0_root_certificates_are_checked_when_configured() {
+        let err = Auth0Config::default()
+            .with_root_certificates_pem(b"not a certificate")
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("the root certificates don't contain any certificate"));
+
+        let corrupted = PROXY_CA.replace("MIIB", "MIIC");
+        let err = Auth0RootCertificate::from_pem(corrupted.as_bytes()).unwrap_err();
+        assert!(err.to_string().contains("can't be parsed"), "{err}");
+    }
+
+    #[test]
+    fn auth0_config_requires_a_domain_and_a_client_id() {
+        let config = Auth0Config {
+            client_id: "".to_string(),
+            ..Default::default()
+        };
+        let err = config.validate().unwrap_err();
+        assert!(err.to_string().contains("the client id is missing"));
+        assert!(config.token_url().is_err());
+
+        let config = Auth0Config {
+            domain: " ".to_string(),
+            ..Default::default()
+        };
+        assert!(config
+            .device_code_url()
+            .unwrap_err()
+            .to_string()
+            .contains("the domain is missing"));
+    }
+
+    #[test]
+    fn device_code_can_be_serialized() {
+        let json = serde_json::to_string(&device_code(60, 2)).unwrap();
+        let decoded: DeviceCode = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded, device_code(60, 2));
+    }
+
+    #[tokio::test]
+    async fn refresh_token_keeps_the_refresh_token() {
+        let (url, requests) = http_stub(vec![(
+            200,
+            r#"{"token_type":"Bearer","access_token":"new","expires_in":3600}"#,
+        )])
+        .await;
+        let mut token = approved().unwrap().unwrap();
+        token.refresh_token = Some(Token::new("refresh"));
+
+        let refreshed = refresh_token(&reqwest::Client::new(), &url, "client", &token)
+            .await
+            .unwrap();
+
+        assert_eq!(refreshed.access_token, Token::new("new"));
+        assert_eq!(refreshed.refresh_token, Some(Token::new("refresh")));
+        let expires_at = refreshed.expires_at.unwrap();
+        assert!(expires_at >= now().unwrap() + 3590);
+
+        let requests = requests.await.unwrap();
+        assert!(requests[0].contains("grant_type=refresh_token"));
+        assert!(requests[0].contains("refresh_token=refresh"));
+    }
+
+    #[tokio::test]
+    async fn token_requests_send_the_code_verifier() {
+        let (url, requests) = http_stub(vec![(
+            200,
+            r#"{"token_type":"Bearer","access_token":"access_token","expires_in":3600}"#,
+        )])
+        .await;
+        let device_code = DeviceCode {
+            code_verifier: Some("verifier".to_string()),
+            ..device_code(60, 1)
+        };
+
+        let token = request_token(&reqwest::Client::new(), &url, "client", &device_code)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(token.access_token, Token::new("access_token"));
+
+        let requests = requests.await.unwrap();
+        assert!(requests[0].contains("device_code=device_code"));
+        assert!(requests[0].contains("code_verifier=verifier"));
+    }
+
+    #[tokio::test]
+    async fn refresh_token_fails_without_a_refresh_token() {
+        let url = Url::parse("http://127.0.0.1:1").unwrap();
+        let token = approved().unwrap().unwrap();
+        assert!(
+            refresh_token(&reqwest::Client::new(), &url, "client", &token)
+                .await
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn authenticate_token_without_refresh_token_can_be_decoded() {
+        let mut bytes = vec![];
+        let mut e = Encoder::new(&mut bytes);
+        if cfg!(feature = "tag") {
+            e.map(3).unwrap();
+            e.u8(0).unwrap().u32(1058055).unwrap();
+        } else {
+            e.map(2).unwrap();
+        }
+        e.u8(1).unwrap().encode(TokenType::Bearer).unwrap();
+        e.u8(2).unwrap().encode(Token::new("access_token")).unwrap();
+
+        let decoded: AuthenticateOidcToken = minicbor::decode(&bytes).unwrap();
+        assert_eq!(decoded.access_token, Token::new("access_token"));
+        assert!(decoded.refresh_token.is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn poll_device_code_until_approved() {
+        let mut responses = VecDeque::from([pending(), pending(), approved()]);
+        let mut polls = 0;
+        let start = Instant::now();
+        let token = poll(&device_code(60, 2), DEFAULT_MAX_POLLING_INTERVAL, || {
+            polls += 1;
+            let res = responses.pop_front().unwrap();
+            async move { res }
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(token.access_token, Token::new("access_token"));
+        assert_eq!(polls, 3);
+        assert_eq!(start.elapsed(), Duration::from_secs(4));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn poll_device_code_slows_down() {
+        let mut responses = VecDeque::from([error("slow_down"), pending(), approved()]);
+        let start = Instant::now();
+        poll(&device_code(60, 1), DEFAULT_MAX_POLLING_INTERVAL, || {
+            let res = responses.pop_front().unwrap();
+            async move { res }
+        })
+        .await
+        .unwrap();
+
+        // the interval becomes 1 + 5 seconds after the first response
+        assert_eq!(start.elapsed(), Duration::from_secs(12));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn poll_device_code_caps_the_slowed_down_interval() {
+        let start = Instant::now();
+        let mut polls = vec![];
+        let max_interval = Duration::from_secs(8);
+        let res = poll(&device_code(30, 1), max_interval, || {
+            polls.push(start.elapsed().as_secs());
+            async { error("slow_down") }
+        })
+        .await;
+
+        assert_eq!(
+            res.unwrap_err().tokens_error_kind(),
+            Some(TokensErrorKind::ExpiredToken)
+        );
+        // the interval becomes 6 seconds, and then stays at 8 seconds
+        assert_eq!(polls, vec![0, 6, 14, 22]);
+        assert!(start.elapsed() <= Duration::from_secs(30));
+
+        // the interval of the device code is capped too
+        let mut polls = vec![];
+        poll(&device_code(60, 20), max_interval, || {
+            polls.push(start.elapsed());
+            let res = if polls.len() < 2 {
+                pending()
+            } else {
+                approved()
+            };
+            async move { res }
+        })
+        .await
+        .unwrap();
+        assert_eq!(polls[1] - polls[0], max_interval);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn poll_device_code_expires() {
+        let start = Instant::now();
+        let res = poll(
+            &device_code(10, 3),
+            DEFAULT_MAX_POLLING_INTERVAL,
+            || async { pending() },
+        )
+        .await;
+
+        assert_eq!(
+            res.unwrap_err().tokens_error_kind(),
+            Some(TokensErrorKind::ExpiredToken)
+        );
+        assert!(start.elapsed() < Duration::from_secs(10));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn poll_device_code_stops_on_denied_access() {
+        let mut polls = 0;
+        let res = poll(&device_code(60, 1), DEFAULT_MAX_POLLING_INTERVAL, || {
+            polls += 1;
+            async { error("access_denied") }
+        })
+        .await;
+
+        let err = res.unwrap_err();
+        assert_eq!(err.tokens_error_kind(), Some(TokensErrorKind::AccessDenied));
+        assert_eq!(err.to_string(), "the authentication was denied");
+        assert_eq!(ockam_core::Error::from(err).code().kind, Kind::Invalid);
+        assert_eq!(polls, 1);
+    }
+
+    #[test]
+    fn oauth_error_codes_are_mapped_to_kinds() {
+        assert_eq!(
+            TokensErrorKind::from("authorization_pending"),
+            TokensErrorKind::AuthorizationPending
+        );
+        assert_eq!(
+            TokensErrorKind::from("expired_token"),
+            TokensErrorKind::ExpiredToken
+        );
+        assert_eq!(
+            TokensErrorKind::from("unsupported_grant_type"),
+            TokensErrorKind::UnsupportedGrantType
+        );
+        assert_eq!(
+            TokensErrorKind::from("server_error"),
+            TokensErrorKind::Other("server_error".to_string())
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn poll_device_code_can_be_cancelled() {
+        let (cancel, cancelled) = oneshot::channel();
+        tokio::spawn(async move {
+            sleep(Duration::from_secs(3)).await;
+            cancel.send(()).unwrap();
+        });
+        let start = Instant::now();
+        let device_code = device_code(60, 1);
+        let poll = poll(&device_code, DEFAULT_MAX_POLLING_INTERVAL, || async {
+            pending()
+        });
+        let res = until_cancelled(poll, cancelled).await;
+
+        let err = ockam_core::Error::from(res.unwrap_err());
+        assert_eq!(err.code().kind, Kind::Cancelled);
+        assert_eq!(start.elapsed(), Duration::from_secs(3));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn dropping_the_cancel_sender_does_not_cancel() {
+        let (cancel, cancelled) = oneshot::channel();
+        drop(cancel);
+        let mut responses = VecDeque::from([pending(), approved()]);
+        let device_code = device_code(60, 1);
+        let poll = poll(&device_code, DEFAULT_MAX_POLLING_INTERVAL, || {
+            let res = responses.pop_front().unwrap();
+            async move { res }
+        });
+        assert!(until_cancelled(poll, cancelled).await.is_ok());
+    }
+
+    /// Start an HTTP server answering each request with the next (status, body) response.
+    /// The returned handle resolves to the requests received by the server.
+    async fn http_stub(responses: Vec<(u16, &'static str)>) -> (Url, JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("http://{}", listener.local_addr().unwrap())).unwrap();
+        let handle = tokio::spawn(async move {
+            let mut requests = vec![];
+            for (status, body) in responses {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = vec![];
+                let mut buf = [0u8; 1024];
+                // read the headers and as much of the body as announced
+                loop {
+                    let n = stream.read(&mut buf).await.unwrap();
+                    request.extend_from_slice(&buf[..n]);
+                    let text = String::from_utf8_lossy(&request).to_string();
+                    if let Some(end) = text.find("\r\n\r\n") {
+                        let content_length = text
+                            .lines()
+                            .find_map(|l| {
+                                l.to_lowercase()
+                                    .strip_prefix("content-length: ")
+                                    .map(|v| v.trim().parse::<usize>().unwrap())
+                            })
+                            .unwrap_or(0);
+                        if request.len() >= end + 4 + content_length {
+                            break;
+                        }
+                    }
+                }
+                let response = format!(
+                    "HTTP/1.1 {status} STATUS\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{body}",
+                    body.len()
+                );
+                stream.write_all(response.as_bytes()).await.unwrap();
+                requests.push(String::from_utf8_lossy(&request).to_string());
+            }
+            requests
+        });
+        (url, handle)
+    }
+
+    fn device_code(expires_in: usize, interval: usize) -> DeviceCode<'static> {
+        DeviceCode {
+            device_code: "device_code".into(),
+            user_code: "user_code".into(),
+            verification_uri: "https://ockam.io/activate".into(),
+            verification_uri_complete: "https://ockam.io/activate?code=user_code".into(),
+            expires_in,
+            interval,
+            code_verifier: None,
+        }
+    }
+
+    fn approved() -> Result<std::result::Result<OidcToken, TokensError<'static>>> {
+        Ok(Ok(OidcToken {
+            token_type: TokenType::Bearer,
+            access_token: Token::new("access_token"),
+            refresh_token: None,
+            expires_at: None,
+            issued_at: None,
+        }))
+    }
+
+    fn pending() -> Result<std::result::Result<OidcToken, TokensError<'static>>> {
+        error("authorization_pending")
+    }
+
+    fn error(error: &str) -> Result<std::result::Result<OidcToken, TokensError<'static>>> {
+        Ok(Err(TokensError {
+            error: error.to_string().into(),
+            error_description: "".into(),
+        }))
+    }
+}
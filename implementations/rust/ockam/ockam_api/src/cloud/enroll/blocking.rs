@@ -0,0 +1,113 @@
+//! Blocking versions of the enrollment flows of the [`NodeManager`], for the
+//! applications embedding a node which don't run in an async context.
+//!
+//! The flows still run on the runtime of the node executor, the calling thread
+//! only blocks until they complete. These methods must therefore be called from a
+//! thread which is not a worker of that runtime, such as the main thread of the
+//! application or a thread it spawned: calling them from async code panics.
+//! They can be called from several threads at once, each call running its own flow.
+//!
+//! The node manager can be locked from such a thread with `RwLock::blocking_read`.
+
+use ockam_core::Result;
+use ockam_multiaddr::MultiAddr;
+use ockam_node::Context;
+
+#[cfg(feature = "auth0")]
+use crate::cloud::enroll::oidc::OidcToken;
+use crate::cloud::enroll::oidc::OidcTokenProvider;
+use crate::cloud::enroll::persisted::PersistedEnrollment;
+use crate::cloud::enroll::Token;
+use crate::nodes::NodeManager;
+
+impl NodeManager {
+    /// Blocking version of [`NodeManager::enroll_auth0`]
+    #[cfg(feature = "auth0")]
+    pub fn enroll_auth0_blocking(
+        &self,
+        ctx: &Context,
+        identity_name: Option<String>,
+        route: &MultiAddr,
+        token: OidcToken,
+    ) -> Result<PersistedEnrollment> {
+        ctx.runtime()
+            .block_on(self.enroll_auth0(ctx, identity_name, route, token))
+    }
+
+    /// Blocking version of [`NodeManager::enroll_api_key`]
+    pub fn enroll_api_key_blocking(
+        &self,
+        ctx: &Context,
+        identity_name: Option<String>,
+        route: &MultiAddr,
+        api_key: Token,
+    ) -> Result<PersistedEnrollment> {
+        ctx.runtime()
+            .block_on(self.enroll_api_key(ctx, identity_name, route, api_key))
+    }
+
+    /// Blocking version of [`NodeManager::enroll_oidc`]
+    pub fn enroll_oidc_blocking(
+        &self,
+        ctx: &Context,
+        identity_name: Option<String>,
+        route: &MultiAddr,
+        authenticator: &str,
+        provider: &impl OidcTokenProvider,
+    ) -> Result<PersistedEnrollment> {
+        ctx.runtime()
+            .block_on(self.enroll_oidc(ctx, identity_name, route, authenticator, provider))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use minicbor::Decoder;
+
+    use ockam_core::api::{Request, Response};
+    use ockam_core::{async_trait, Routed, Worker};
+    use ockam_node::NodeBuilder;
+
+    use crate::test_utils::{start_controller_for_tests, start_manager_for_tests};
+
+    use super::*;
+
+    /// Stands for an Orchestrator authenticator accepting any token
+    struct Accepting;
+
+    #[async_trait]
+    impl Worker for Accepting {
+        type Context = Context;
+        type Message = Vec<u8>;
+
+        async fn handle_message(&mut self, ctx: &mut Context, msg: Routed<Vec<u8>>) -> Result<()> {
+            let req: Request = Decoder::new(msg.as_body()).decode()?;
+            ctx.send(msg.return_route(), Response::ok(req.id()).to_vec()?)
+                .await
+        }
+    }
+
+    #[test]
+    fn enrollments_can_be_run_outside_of_an_async_context() -> Result<()> {
+        let (mut ctx, mut executor) = NodeBuilder::new().no_logging().build();
+        let runtime = ctx.runtime().clone();
+        let node = thread::spawn(move || executor.execute(async {}));
+
+        let api_service = "api_key_authenticator";
+        let (handle, controller) = runtime.block_on(async {
+            let handle = start_manager_for_tests(&mut ctx).await?;
+            let controller = start_controller_for_tests(&ctx, &handle, &[api_service]).await?;
+            ctx.start_worker(api_service, Accepting).await?;
+            Ok::<_, ockam_core::Error>((handle, controller))
+        })?;
+
+        let node_manager = handle.node_manager.blocking_read();
+        node_manager.enroll_api_key_blocking(&ctx, None, &controller, Token::new("key"))?;
+        drop(node_manager);
+
+        runtime.block_on(ctx.stop())?;
+        node.join().unwrap()
+    }
+}
@@ -0,0 +1,89 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use ockam::identity::credential::Timestamp;
+use ockam_core::Result;
+
+use crate::error::ApiError;
+
+/// Source of the current time used to check the expiry of tokens
+pub trait Clock: Send + Sync + 'static {
+    /// Return the current Unix time, in seconds
+    fn now(&self) -> Result<u64>;
+}
+
+/// The time of the operating system
+#[derive(Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Result<u64> {
+        now()
+    }
+}
+
+/// Return the current Unix time of the operating system, in seconds
+pub(crate) fn now() -> Result<u64> {
+    Timestamp::now()
+        .map(|now| now.unix_time())
+        .ok_or_else(|| ApiError::generic("the current time is not available"))
+}
+
+/// A clock which only changes when it is set or advanced, so that
+/// expiries can be tested without waiting for them
+#[derive(Default)]
+pub struct ManualClock(AtomicU64);
+
+impl ManualClock {
+    pub fn new(now: u64) -> Self {
+        Self(AtomicU64::new(now))
+    }
+
+    pub fn set(&self, now: u64) {
+        self.0.store(now, Ordering::SeqCst)
+    }
+
+    pub fn advance(&self, seconds: u64) {
+        self.0.fetch_add(seconds, Ordering::SeqCst);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Result<u64> {
+        Ok(self.0.load(Ordering::SeqCst))
+    }
+}
+
+/// A clock offset from another one by a number of seconds, to compare the
+/// expiries set by a peer whose clock is skewed
+pub struct SkewedClock {
+    clock: Arc<dyn Clock>,
+    skew: i64,
+}
+
+impl SkewedClock {
+    pub fn new(clock: Arc<dyn Clock>, skew: i64) -> Self {
+        Self { clock, skew }
+    }
+}
+
+impl Clock for SkewedClock {
+    fn now(&self) -> Result<u64> {
+        Ok(self.clock.now()?.saturating_add_signed(self.skew))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn manual_clocks_only_change_when_told_to() {
+        let clock = ManualClock::new(100);
+        assert_eq!(clock.now().unwrap(), 100);
+        clock.advance(20);
+        assert_eq!(clock.now().unwrap(), 120);
+        clock.set(10);
+        assert_eq!(clock.now().unwrap(), 10);
+    }
+}
@@ -0,0 +1,128 @@
+use ockam_core::{async_trait, Result};
+use ockam_node::compat::tokio::sync::Mutex;
+
+use crate::cloud::enroll::clock;
+use crate::cloud::enroll::oidc::{OidcToken, OidcTokenProvider};
+
+/// Number of seconds before its expiry after which a token is refreshed
+pub const DEFAULT_REFRESH_MARGIN: u64 = 60;
+
+/// A token provider sharing the tokens of another provider between its callers.
+///
+/// Concurrent calls to [`OidcTokenProvider::token`] wait for a single request
+/// to the wrapped provider, and the token is then returned to all callers until
+/// it is about to expire. If the request fails, the next waiting caller runs a new one.
+pub struct CoalescingTokenProvider<P> {
+    provider: P,
+    refresh_margin: u64,
+    token: Mutex<Option<OidcToken>>,
+}
+
+impl<P: OidcTokenProvider> CoalescingTokenProvider<P> {
+    pub fn new(provider: P) -> Self {
+        Self {
+            provider,
+            refresh_margin: DEFAULT_REFRESH_MARGIN,
+            token: Mutex::new(None),
+        }
+    }
+
+    /// Refresh the token `refresh_margin` seconds before its expiry
+    pub fn with_refresh_margin(mut self, refresh_margin: u64) -> Self {
+        self.refresh_margin = refresh_margin;
+        self
+    }
+}
+
+#[async_trait]
+impl<P: OidcTokenProvider> OidcTokenProvider for CoalescingTokenProvider<P> {
+    async fn token(&self) -> Result<OidcToken> {
+        // the lock is kept during the request so that other callers wait for its result
+        let mut token = self.token.lock().await;
+        let refresh_at = clock::now()? + self.refresh_margin;
+        match token.as_ref() {
+            Some(token) if !token.is_expired(refresh_at) => Ok(token.clone()),
+            _ => {
+                let new_token = self.provider.token().await?;
+                *token = Some(new_token.clone());
+                Ok(new_token)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use futures::future::join_all;
+    use ockam_node::tokio::time::sleep;
+
+    use crate::cloud::enroll::oidc::TokenType;
+    use crate::cloud::enroll::Token;
+
+    use super::*;
+
+    /// Provider counting its requests, issuing tokens valid for `expires_in` seconds
+    struct CountingProvider {
+        requests: Arc<AtomicUsize>,
+        expires_in: u64,
+    }
+
+    #[async_trait]
+    impl OidcTokenProvider for CountingProvider {
+        async fn token(&self) -> Result<OidcToken> {
+            let n = self.requests.fetch_add(1, Ordering::SeqCst);
+            sleep(Duration::from_millis(50)).await;
+            Ok(OidcToken {
+                token_type: TokenType::Bearer,
+                access_token: Token::new(format!("access-{n}")),
+                refresh_token: None,
+                expires_at: Some(clock::now()? + self.expires_in),
+                issued_at: None,
+            })
+        }
+    }
+
+    fn provider(expires_in: u64) -> (CoalescingTokenProvider<CountingProvider>, Arc<AtomicUsize>) {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let provider = CountingProvider {
+            requests: requests.clone(),
+            expires_in,
+        };
+        (CoalescingTokenProvider::new(provider), requests)
+    }
+
+    #[tokio::test]
+    async fn concurrent_calls_share_one_request() {
+        let (provider, requests) = provider(3600);
+        let tokens = join_all((0..10).map(|_| provider.token())).await;
+
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+        for token in tokens {
+            assert_eq!(token.unwrap().access_token, Token::new("access-0"));
+        }
+    }
+
+    #[tokio::test]
+    async fn tokens_are_refreshed_near_their_expiry() {
+        let (provider, requests) = provider(30);
+        assert_eq!(
+            provider.token().await.unwrap().access_token,
+            Token::new("access-0")
+        );
+
+        // the token expires within the default refresh margin
+        assert_eq!(
+            provider.token().await.unwrap().access_token,
+            Token::new("access-1")
+        );
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+
+        let provider = provider.with_refresh_margin(0);
+        provider.token().await.unwrap();
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+    }
+}
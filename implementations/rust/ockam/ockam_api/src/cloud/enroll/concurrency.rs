@@ -0,0 +1,111 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use ockam_node::tokio::sync::{Semaphore, SemaphorePermit};
+
+/// Number of enrollments a node runs at once, the other ones wait for their turn
+pub const DEFAULT_MAX_CONCURRENT_ENROLLMENTS: usize = 256;
+
+/// Permits limiting the number of enrollments running at once, since each of
+/// them opens a secure channel to the Orchestrator.
+///
+/// The enrollments which can't run yet wait in a queue. When the length of that
+/// queue is limited, the enrollments arriving while it is full are refused.
+pub struct EnrollPermits {
+    semaphore: Semaphore,
+    max_waiting: Option<usize>,
+    waiting: AtomicUsize,
+}
+
+impl Default for EnrollPermits {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_CONCURRENT_ENROLLMENTS)
+    }
+}
+
+impl EnrollPermits {
+    /// Allow up to `max_concurrent` enrollments at once, with a queue of any length
+    pub fn new(max_concurrent: usize) -> Self {
+        Self {
+            semaphore: Semaphore::new(max_concurrent.max(1)),
+            max_waiting: None,
+            waiting: AtomicUsize::new(0),
+        }
+    }
+
+    /// Refuse the enrollments arriving when `max_waiting` of them already wait for a permit
+    pub fn with_max_waiting(mut self, max_waiting: usize) -> Self {
+        self.max_waiting = Some(max_waiting);
+        self
+    }
+
+    /// Wait for the permit to run an enrollment, which is released when dropped.
+    ///
+    /// `None` is returned right away if the queue is full.
+    pub async fn acquire(&self) -> Option<SemaphorePermit<'_>> {
+        if let Ok(permit) = self.semaphore.try_acquire() {
+            return Some(permit);
+        }
+        let waiting = Waiting::new(&self.waiting);
+        if self.max_waiting.map_or(false, |max| waiting.ahead >= max) {
+            return None;
+        }
+        // the semaphore is never closed
+        self.semaphore.acquire().await.ok()
+    }
+}
+
+/// An enrollment waiting for its permit, which leaves the queue when dropped,
+/// even if the enrollment is cancelled while waiting
+struct Waiting<'a> {
+    count: &'a AtomicUsize,
+    /// Number of enrollments which were already waiting
+    ahead: usize,
+}
+
+impl<'a> Waiting<'a> {
+    fn new(count: &'a AtomicUsize) -> Self {
+        let ahead = count.fetch_add(1, Ordering::SeqCst);
+        Self { count, ahead }
+    }
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.count.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn enrollments_are_refused_when_the_queue_is_full() {
+        let permits = EnrollPermits::new(1).with_max_waiting(1);
+        let running = permits.acquire().await.unwrap();
+
+        let waiting = permits.acquire();
+        tokio::pin!(waiting);
+        assert!(futures::poll!(&mut waiting).is_pending());
+        assert!(permits.acquire().await.is_none());
+
+        drop(running);
+        assert!(waiting.await.is_some());
+        assert!(permits.acquire().await.is_some());
+    }
+
+    #[tokio::test]
+    async fn cancelled_enrollments_leave_the_queue() {
+        let permits = EnrollPermits::new(1).with_max_waiting(1);
+        let _running = permits.acquire().await.unwrap();
+        {
+            let waiting = permits.acquire();
+            tokio::pin!(waiting);
+            assert!(futures::poll!(&mut waiting).is_pending());
+        }
+
+        let waiting = permits.acquire();
+        tokio::pin!(waiting);
+        assert!(futures::poll!(&mut waiting).is_pending());
+    }
+}
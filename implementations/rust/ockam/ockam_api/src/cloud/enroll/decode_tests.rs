@@ -0,0 +1,227 @@
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+use minicbor::bytes::ByteVec;
+use minicbor::{Decode, Decoder};
+use ockam::identity::credential::Attributes;
+use ockam_core::api::{Request, Response, Status};
+use ockam_multiaddr::MultiAddr;
+use quickcheck::{quickcheck, Arbitrary, Gen, TestResult};
+
+use crate::cloud::enroll::encrypted_attributes::EncryptedAttributes;
+use crate::cloud::enroll::enrollment_token::{
+    AttributeValue, AttributesLimits, AuthenticateEnrollmentToken, EnrollmentToken,
+    RequestEnrollmentToken,
+};
+use crate::cloud::enroll::node::{check_encoded_attributes, decode_request_body};
+use crate::cloud::enroll::oidc::{AuthenticateOidcToken, OidcToken, TokenType};
+use crate::cloud::CloudRequestWrapper;
+
+use super::*;
+
+impl Arbitrary for Token {
+    fn arbitrary(g: &mut Gen) -> Self {
+        Token::new(String::arbitrary(g))
+    }
+}
+
+impl Arbitrary for TokenType {
+    fn arbitrary(g: &mut Gen) -> Self {
+        g.choose(&[TokenType::Bearer, TokenType::DPoP, TokenType::Mac])
+            .unwrap()
+            .clone()
+    }
+}
+
+impl Arbitrary for AuthenticateOidcToken {
+    fn arbitrary(g: &mut Gen) -> Self {
+        AuthenticateOidcToken::new(OidcToken {
+            token_type: TokenType::arbitrary(g),
+            access_token: Token::arbitrary(g),
+            refresh_token: Option::arbitrary(g),
+            expires_at: None,
+            issued_at: None,
+        })
+    }
+}
+
+impl Arbitrary for AttributeValue {
+    fn arbitrary(g: &mut Gen) -> Self {
+        match u8::arbitrary(g) % 4 {
+            0 => AttributeValue::Bool(bool::arbitrary(g)),
+            1 => AttributeValue::Int(i64::arbitrary(g)),
+            2 => AttributeValue::Str(String::arbitrary(g)),
+            _ => AttributeValue::Bytes(Vec::arbitrary(g)),
+        }
+    }
+}
+
+impl Arbitrary for RequestEnrollmentToken {
+    fn arbitrary(g: &mut Gen) -> Self {
+        RequestEnrollmentToken {
+            usage_count: Option::arbitrary(g),
+            idempotency_key: Option::arbitrary(g),
+            expires_in: Option::arbitrary(g),
+            parent_token: Option::arbitrary(g),
+            typed_attributes: Option::arbitrary(g),
+            attributes_expires_in: Option::arbitrary(g),
+            bound_identifier: Option::arbitrary(g),
+            audience: Option::arbitrary(g),
+            label: Option::arbitrary(g),
+            encrypted_attributes: bool::arbitrary(g).then(|| EncryptedAttributes {
+                #[cfg(feature = "tag")]
+                tag: TypeTag,
+                ephemeral_key: ByteVec::from(Vec::<u8>::arbitrary(g)),
+                values: BTreeMap::<String, Vec<u8>>::arbitrary(g)
+                    .into_iter()
+                    .map(|(key, value)| (key, ByteVec::from(value)))
+                    .collect(),
+            }),
+            not_before: Option::arbitrary(g),
+            ..RequestEnrollmentToken::new(arbitrary_attributes(g))
+        }
+    }
+}
+
+impl Arbitrary for EnrollmentToken {
+    fn arbitrary(g: &mut Gen) -> Self {
+        EnrollmentToken {
+            expires_at: Option::arbitrary(g),
+            attributes: bool::arbitrary(g).then(|| arbitrary_attributes(g)),
+            signature: Option::<Vec<u8>>::arbitrary(g).map(ByteVec::from),
+            version: Option::arbitrary(g),
+            bound_identifier: Option::arbitrary(g),
+            device_identifier: Option::arbitrary(g),
+            audience: Option::arbitrary(g),
+            not_before: Option::arbitrary(g),
+            ..EnrollmentToken::new(Token::arbitrary(g))
+        }
+    }
+}
+
+impl Arbitrary for AuthenticateEnrollmentToken {
+    fn arbitrary(g: &mut Gen) -> Self {
+        AuthenticateEnrollmentToken::new(EnrollmentToken::arbitrary(g))
+    }
+}
+
+fn arbitrary_attributes(g: &mut Gen) -> Attributes {
+    let mut attributes = Attributes::new();
+    for (key, value) in BTreeMap::<String, Vec<u8>>::arbitrary(g) {
+        attributes.put(&key, &value);
+    }
+    attributes
+}
+
+/// Encode `req` as the body of a node API request
+fn wrapped<T: Encode<()>>(req: T) -> Vec<u8> {
+    let route = MultiAddr::from_str("/service/controller_api").unwrap();
+    minicbor::to_vec(CloudRequestWrapper::new(req, &route, None)).unwrap()
+}
+
+/// The versions of `body` with the byte at `index` replaced by `byte`,
+/// and truncated at `index`
+fn corruptions(mut body: Vec<u8>, index: usize, byte: u8) -> [Vec<u8>; 2] {
+    let index = index % body.len();
+    let truncated = body[..index].to_vec();
+    body[index] = byte;
+    [body, truncated]
+}
+
+/// Feed `body` to all the decoders of the enroll requests. They must only
+/// return errors, and the node API must answer with a `BadRequest` response
+fn decode_everything(body: &[u8]) -> TestResult {
+    let _ = minicbor::decode::<CloudRequestWrapper<AuthenticateOidcToken>>(body);
+    let _ = minicbor::decode::<CloudRequestWrapper<EnrollmentToken>>(body);
+    let _ = minicbor::decode::<CloudRequestWrapper<AuthenticateEnrollmentToken>>(body);
+    let _ = minicbor::decode::<AuthenticateEnrollmentToken>(body);
+    let _ = minicbor::decode::<CloudRequestWrapper<EnrollBody<EnrollmentToken>>>(body);
+    let _ = try_decode_enroll_body(&Decoder::new(body));
+
+    let req = Request::post("v0/enroll/token").into_parts().0;
+    let _ = check_encoded_attributes(&req, &Decoder::new(body), &AttributesLimits::default());
+    let decoded = decode_request_body::<CloudRequestWrapper<RequestEnrollmentToken>>(
+        &req,
+        &mut Decoder::new(body),
+    );
+    match decoded {
+        Ok(_) => TestResult::passed(),
+        Err(Ok(res)) => match Response::parse_response_header(&res) {
+            Ok((header, _)) if header.status() == Some(Status::BadRequest) => TestResult::passed(),
+            _ => TestResult::error("the decoding error is not a bad request"),
+        },
+        Err(Err(err)) => TestResult::error(err.to_string()),
+    }
+}
+
+/// Encode `value`, decode it back, and check that it is encoded the same way
+fn roundtrips<T>(value: T) -> TestResult
+where
+    T: Encode<()> + for<'b> Decode<'b, ()>,
+{
+    let cbor = minicbor::to_vec(value).unwrap();
+    match minicbor::decode::<T>(&cbor) {
+        Ok(decoded) if minicbor::to_vec(&decoded).unwrap() == cbor => TestResult::passed(),
+        Ok(_) => TestResult::error("the decoded value is encoded differently"),
+        Err(err) => TestResult::error(err.to_string()),
+    }
+}
+
+quickcheck! {
+    fn random_bytes_are_rejected_cleanly(body: Vec<u8>) -> TestResult {
+        decode_everything(&body)
+    }
+
+    fn enroll_requests_roundtrip(
+        oidc: AuthenticateOidcToken,
+        token: EnrollmentToken,
+        authenticate: AuthenticateEnrollmentToken,
+        request: RequestEnrollmentToken
+    ) -> TestResult {
+        for res in [
+            roundtrips(oidc),
+            roundtrips(token),
+            roundtrips(authenticate),
+            roundtrips(request),
+        ] {
+            if res.is_error() {
+                return res;
+            }
+        }
+        TestResult::passed()
+    }
+
+    fn corrupted_oidc_tokens_are_rejected_cleanly(
+        oidc: AuthenticateOidcToken,
+        index: usize,
+        byte: u8
+    ) -> TestResult {
+        corrupted(wrapped(oidc), index, byte)
+    }
+
+    fn corrupted_enrollment_tokens_are_rejected_cleanly(
+        token: EnrollmentToken,
+        index: usize,
+        byte: u8
+    ) -> TestResult {
+        corrupted(wrapped(token), index, byte)
+    }
+
+    fn corrupted_token_requests_are_rejected_cleanly(
+        request: RequestEnrollmentToken,
+        index: usize,
+        byte: u8
+    ) -> TestResult {
+        corrupted(wrapped(request), index, byte)
+    }
+}
+
+fn corrupted(body: Vec<u8>, index: usize, byte: u8) -> TestResult {
+    for body in corruptions(body, index, byte) {
+        let res = decode_everything(&body);
+        if res.is_error() {
+            return res;
+        }
+    }
+    TestResult::passed()
+}
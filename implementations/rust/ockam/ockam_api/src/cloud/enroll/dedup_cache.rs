@@ -0,0 +1,217 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use ockam_core::compat::sync::RwLock;
+use ockam_core::{async_trait, Result};
+use ockam_vault::Vault;
+
+use crate::cloud::enroll::enrollment_token::{EnrollmentToken, RequestEnrollmentToken};
+use crate::error::ApiError;
+
+/// Time within which the same requests get the same enrollment token.
+///
+/// It is zero by default, since every request for a single-use token usually
+/// stands for a different device.
+pub const DEFAULT_TOKEN_DEDUP_WINDOW: Duration = Duration::ZERO;
+
+/// Number of enrollment tokens kept by an [`InMemoryTokenDedupCache`]
+pub const DEFAULT_TOKEN_DEDUP_CAPACITY: usize = 10_000;
+
+/// An enrollment token, with the time at which it was generated
+#[derive(Debug, Clone)]
+pub struct IssuedToken {
+    pub token: EnrollmentToken,
+    /// Unix time (in seconds) at which the token was generated
+    pub issued_at: u64,
+}
+
+/// Storage for the last enrollment token generated for each request, so that
+/// repeated requests can be answered with it.
+///
+/// The tokens are stored by the fingerprint of their request, see [`fingerprint`].
+#[async_trait]
+pub trait TokenDedupCache: Send + Sync + 'static {
+    /// Return the token stored for `fingerprint`, if any
+    async fn load(&self, fingerprint: &str) -> Result<Option<IssuedToken>>;
+
+    /// Store `token` for `fingerprint`, replacing the previous one.
+    ///
+    /// The tokens issued at least `window` before `token` can't be returned anymore,
+    /// so they can be removed.
+    async fn store(&self, fingerprint: &str, token: &IssuedToken, window: Duration) -> Result<()>;
+
+    /// Remove the token stored for `fingerprint`
+    async fn clear(&self, fingerprint: &str) -> Result<()>;
+}
+
+/// Return a fingerprint of all the fields of `req` but its idempotency key, which
+/// is set on every request, so that only the requests for the same token share it.
+/// The attributes are kept by key, so it doesn't depend on the order of their insertion.
+pub fn fingerprint(req: &RequestEnrollmentToken) -> Result<String> {
+    let mut req = req.clone();
+    req.idempotency_key = None;
+    let encoded = minicbor::to_vec(&req).map_err(ApiError::wrap)?;
+    Ok(hex::encode(Vault::sha256(&encoded)))
+}
+
+/// Return the token stored for `fingerprint` if it was generated less than `window` before `now`.
+///
+/// Older tokens are removed from the cache.
+pub async fn load_recent_token(
+    cache: &dyn TokenDedupCache,
+    fingerprint: &str,
+    now: u64,
+    window: Duration,
+) -> Result<Option<EnrollmentToken>> {
+    match cache.load(fingerprint).await? {
+        Some(issued) if now < issued.issued_at + window.as_secs() => Ok(Some(issued.token)),
+        Some(_) => {
+            cache.clear(fingerprint).await?;
+            Ok(None)
+        }
+        None => Ok(None),
+    }
+}
+
+/// A cache keeping the tokens in memory while they are within the dedup window.
+///
+/// The tokens out of the window are removed when another token is stored. When the
+/// cache is full, the oldest token is forgotten to make room for a new one.
+pub struct InMemoryTokenDedupCache {
+    capacity: usize,
+    tokens: RwLock<HashMap<String, IssuedToken>>,
+}
+
+impl Default for InMemoryTokenDedupCache {
+    fn default() -> Self {
+        Self::new(DEFAULT_TOKEN_DEDUP_CAPACITY)
+    }
+}
+
+impl InMemoryTokenDedupCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            tokens: Default::default(),
+        }
+    }
+
+    /// Number of tokens currently kept
+    pub fn len(&self) -> usize {
+        self.tokens.read().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[async_trait]
+impl TokenDedupCache for InMemoryTokenDedupCache {
+    async fn load(&self, fingerprint: &str) -> Result<Option<IssuedToken>> {
+        Ok(self.tokens.read().unwrap().get(fingerprint).cloned())
+    }
+
+    async fn store(&self, fingerprint: &str, token: &IssuedToken, window: Duration) -> Result<()> {
+        let mut tokens = self.tokens.write().unwrap();
+        let window = window.as_secs();
+        tokens.retain(|_, issued| token.issued_at < issued.issued_at + window);
+        if !tokens.contains_key(fingerprint) && tokens.len() >= self.capacity {
+            let oldest = tokens
+                .iter()
+                .min_by_key(|(_, issued)| issued.issued_at)
+                .map(|(fingerprint, _)| fingerprint.clone());
+            if let Some(oldest) = oldest {
+                tokens.remove(&oldest);
+            }
+        }
+        tokens.insert(fingerprint.to_string(), token.clone());
+        Ok(())
+    }
+
+    async fn clear(&self, fingerprint: &str) -> Result<()> {
+        self.tokens.write().unwrap().remove(fingerprint);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ockam::identity::credential::Attributes;
+
+    use crate::cloud::enroll::Token;
+
+    use super::*;
+
+    #[test]
+    fn fingerprints_depend_on_the_request_but_not_its_idempotency_key() -> Result<()> {
+        let mut attributes = Attributes::new();
+        attributes.put("role", b"device").put("zone", b"eu");
+        let mut reordered = Attributes::new();
+        reordered.put("zone", b"eu").put("role", b"device");
+        let req = RequestEnrollmentToken::new(attributes.clone());
+        let same =
+            RequestEnrollmentToken::new(reordered.clone()).with_idempotency_key(Token::new("key"));
+        assert_eq!(fingerprint(&req)?, fingerprint(&same)?);
+
+        let usage_count = RequestEnrollmentToken::new(attributes).with_usage_count(2);
+        assert_ne!(fingerprint(&req)?, fingerprint(&usage_count)?);
+        reordered.put("zone", b"us");
+        let other = RequestEnrollmentToken::new(reordered);
+        assert_ne!(fingerprint(&req)?, fingerprint(&other)?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tokens_are_only_loaded_within_the_window() -> Result<()> {
+        let cache = InMemoryTokenDedupCache::default();
+        let issued = IssuedToken {
+            token: EnrollmentToken::new(Token::new("token")),
+            issued_at: 100,
+        };
+        let window = Duration::from_secs(10);
+        cache.store("fingerprint", &issued, window).await?;
+
+        let token = load_recent_token(&cache, "fingerprint", 109, window).await?;
+        assert_eq!(token.unwrap().token, Token::new("token"));
+
+        assert!(load_recent_token(&cache, "fingerprint", 110, window)
+            .await?
+            .is_none());
+        assert!(cache.load("fingerprint").await?.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tokens_out_of_the_window_are_removed_and_bounded() -> Result<()> {
+        let cache = InMemoryTokenDedupCache::new(100);
+        let window = Duration::from_secs(10);
+        let issued = |token: usize, issued_at: u64| IssuedToken {
+            token: EnrollmentToken::new(Token::new(format!("token-{token}"))),
+            issued_at,
+        };
+        // distinct requests, which are never loaded again
+        for token in 0..1000 {
+            let issued_at = 100 + token as u64 / 20;
+            cache
+                .store(
+                    &format!("fingerprint-{token}"),
+                    &issued(token, issued_at),
+                    window,
+                )
+                .await?;
+            assert!(cache.len() <= 100);
+        }
+        // 200 tokens are still within the window, of which only the newest 100 are kept
+        assert_eq!(cache.len(), 100);
+        assert!(cache.load("fingerprint-0").await?.is_none());
+        assert!(cache.load("fingerprint-999").await?.is_some());
+
+        // the tokens out of the window are removed when another one is stored
+        cache
+            .store("fingerprint", &issued(1000, 200), window)
+            .await?;
+        assert_eq!(cache.len(), 1);
+        Ok(())
+    }
+}
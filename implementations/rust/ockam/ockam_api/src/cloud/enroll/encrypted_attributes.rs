@@ -0,0 +1,239 @@
+use std::collections::BTreeMap;
+
+use minicbor::bytes::ByteVec;
+use minicbor::{Decode, Encode};
+
+use ockam::identity::credential::Attributes;
+use ockam_core::Result;
+#[cfg(feature = "tag")]
+use ockam_core::TypeTag;
+use ockam_vault::{
+    AsymmetricVault, KeyId, PublicKey, Secret, SecretAttributes, SecretType, SecretsStore,
+    SymmetricVault,
+};
+
+use crate::error::ApiError;
+
+/// Info of the HKDF deriving the key the attribute values are encrypted with
+const KDF_INFO: &[u8] = b"ockam-enrollment-encrypted-attributes";
+
+/// Length of the AES-GCM nonce prefixed to each encrypted value
+const NONCE_LENGTH: usize = 12;
+
+/// Attribute values of a token request encrypted for the authenticator of a
+/// project, so that the relays between the node and the authenticator can't
+/// read them even where the secure channel is terminated.
+///
+/// The values are encrypted with AES-GCM, using a key derived from an X25519
+/// exchange between an ephemeral secret and the public key of the project.
+/// The key of each attribute is authenticated with its value, so that values
+/// can't be swapped between attributes.
+#[derive(Encode, Decode, Debug, Clone, PartialEq, Eq)]
+#[rustfmt::skip]
+#[cbor(map)]
+pub struct EncryptedAttributes {
+    #[cfg(feature = "tag")]
+    #[n(0)] pub tag: TypeTag<4710395>,
+    /// X25519 public key of the ephemeral secret the values are encrypted with
+    #[b(1)] pub ephemeral_key: ByteVec,
+    /// Encrypted values, by attribute key, each prefixed with its nonce
+    #[b(2)] pub values: BTreeMap<String, ByteVec>,
+}
+
+impl EncryptedAttributes {
+    pub fn keys(&self) -> impl Iterator<Item = &String> {
+        self.values.keys()
+    }
+
+    pub fn contains(&self, key: &str) -> bool {
+        self.values.contains_key(key)
+    }
+}
+
+/// Encrypt the values of `attributes` for the holder of the X25519 secret of `public_key`
+pub async fn encrypt_attributes<V>(
+    vault: &V,
+    attributes: &Attributes,
+    public_key: &PublicKey,
+) -> Result<EncryptedAttributes>
+where
+    V: SecretsStore + AsymmetricVault + SymmetricVault + ?Sized,
+{
+    if public_key.stype() != SecretType::X25519 {
+        return Err(ApiError::generic(
+            "attributes can only be encrypted for an X25519 public key",
+        ));
+    }
+    let ephemeral = vault
+        .create_ephemeral_secret(SecretAttributes::X25519)
+        .await?;
+    let ephemeral_key = vault.get_public_key(&ephemeral).await?;
+    let key = derive_key(vault, &ephemeral, public_key, &ephemeral_key).await;
+    vault.delete_ephemeral_secret(ephemeral).await?;
+    let key = key?;
+
+    let mut values = BTreeMap::new();
+    let mut encrypted = Ok(());
+    for (name, value) in attributes.iter() {
+        let nonce = rand::random::<[u8; NONCE_LENGTH]>();
+        match vault
+            .aead_aes_gcm_encrypt(&key, value, &nonce, name.as_bytes())
+            .await
+        {
+            Ok(ciphertext) => {
+                let value = [nonce.as_slice(), ciphertext.as_slice()].concat();
+                values.insert(name.clone(), ByteVec::from(value));
+            }
+            Err(err) => {
+                encrypted = Err(err);
+                break;
+            }
+        }
+    }
+    vault.delete_ephemeral_secret(key).await?;
+    encrypted?;
+    Ok(EncryptedAttributes {
+        #[cfg(feature = "tag")]
+        tag: TypeTag,
+        ephemeral_key: ByteVec::from(ephemeral_key.data().to_vec()),
+        values,
+    })
+}
+
+/// Decrypt the values of `encrypted` with the X25519 secret `secret` they were encrypted for
+pub async fn decrypt_attributes<V>(
+    vault: &V,
+    encrypted: &EncryptedAttributes,
+    secret: &KeyId,
+) -> Result<Attributes>
+where
+    V: SecretsStore + AsymmetricVault + SymmetricVault + ?Sized,
+{
+    let public_key = vault.get_public_key(secret).await?;
+    let ephemeral_key = PublicKey::new(encrypted.ephemeral_key.to_vec(), SecretType::X25519);
+    let key = derive_key(vault, secret, &ephemeral_key, &ephemeral_key).await?;
+
+    let mut attributes = Attributes::new();
+    let mut decrypted = Ok(());
+    for (name, value) in &encrypted.values {
+        if value.len() < NONCE_LENGTH {
+            decrypted = Err(ApiError::message(format!(
+                "the encrypted attribute {name} is truncated"
+            )));
+            break;
+        }
+        let (nonce, ciphertext) = value.split_at(NONCE_LENGTH);
+        match vault
+            .aead_aes_gcm_decrypt(&key, ciphertext, nonce, name.as_bytes())
+            .await
+        {
+            Ok(plaintext) => {
+                attributes.put(name, &plaintext);
+            }
+            Err(_) => {
+                decrypted = Err(ApiError::message(format!(
+                    "the attribute {name} can't be decrypted with the key {public_key}"
+                )));
+                break;
+            }
+        }
+    }
+    vault.delete_ephemeral_secret(key).await?;
+    decrypted.map(|_| attributes)
+}
+
+/// Derive the AES key shared by `secret` and `peer_public_key`, salted with the
+/// public key of the ephemeral secret of the exchange
+async fn derive_key<V>(
+    vault: &V,
+    secret: &KeyId,
+    peer_public_key: &PublicKey,
+    ephemeral_key: &PublicKey,
+) -> Result<KeyId>
+where
+    V: SecretsStore + AsymmetricVault + ?Sized,
+{
+    let shared = vault.ec_diffie_hellman(secret, peer_public_key).await?;
+    let salt = ephemeral_key.data().to_vec();
+    let salt_attributes = SecretAttributes::Buffer(salt.len() as u32);
+    let salt = vault
+        .import_ephemeral_secret(Secret::new(salt), salt_attributes)
+        .await?;
+    let keys = vault
+        .hkdf_sha256(
+            &salt,
+            KDF_INFO,
+            Some(&shared),
+            vec![SecretAttributes::Aes256],
+        )
+        .await;
+    vault.delete_ephemeral_secret(salt).await?;
+    vault.delete_ephemeral_secret(shared).await?;
+    keys?
+        .pop()
+        .ok_or_else(|| ApiError::generic("no key was derived to encrypt the attributes"))
+}
+
+#[cfg(test)]
+mod tests {
+    use ockam_vault::{EphemeralSecretsStore, SecretsStoreReader, Vault};
+
+    use super::*;
+
+    fn attributes() -> Attributes {
+        let mut attributes = Attributes::new();
+        attributes.put("role", b"device");
+        attributes.put("device_secret", &[0xde, 0xad, 0xbe, 0xef]);
+        attributes
+    }
+
+    #[tokio::test]
+    async fn attributes_are_only_decrypted_by_their_recipient() -> Result<()> {
+        let vault = Vault::create();
+        let recipient = vault
+            .create_ephemeral_secret(SecretAttributes::X25519)
+            .await?;
+        let public_key = vault.get_public_key(&recipient).await?;
+
+        let encrypted = encrypt_attributes(vault.as_ref(), &attributes(), &public_key).await?;
+        assert_eq!(
+            encrypted.keys().collect::<Vec<_>>(),
+            vec!["device_secret", "role"]
+        );
+        assert!(encrypted.values.values().all(|value| !value
+            .windows(b"device".len())
+            .any(|window| window == b"device")));
+
+        let cbor = minicbor::to_vec(&encrypted)?;
+        let decoded: EncryptedAttributes = minicbor::decode(&cbor)?;
+        let decrypted = decrypt_attributes(vault.as_ref(), &decoded, &recipient).await?;
+        assert_eq!(decrypted, attributes());
+
+        // another secret can't decrypt the values
+        let other = vault
+            .create_ephemeral_secret(SecretAttributes::X25519)
+            .await?;
+        assert!(decrypt_attributes(vault.as_ref(), &decoded, &other)
+            .await
+            .is_err());
+
+        // the values are bound to their attribute
+        let mut swapped = decoded.clone();
+        let secret = swapped.values.remove("device_secret").unwrap();
+        swapped.values.insert("role".to_string(), secret);
+        assert!(decrypt_attributes(vault.as_ref(), &swapped, &recipient)
+            .await
+            .is_err());
+
+        let signing = vault
+            .create_ephemeral_secret(SecretAttributes::Ed25519)
+            .await?;
+        let signing_key = vault.get_public_key(&signing).await?;
+        assert!(
+            encrypt_attributes(vault.as_ref(), &attributes(), &signing_key)
+                .await
+                .is_err()
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,1848 @@
+use core::convert::Infallible;
+use std::collections::{BTreeMap, BTreeSet};
+
+use minicbor::bytes::ByteVec;
+use minicbor::data::Type;
+use minicbor::decode;
+use serde::Serialize;
+
+use ockam::identity::credential::Attributes;
+use ockam_vault::{AsymmetricVault, PublicKey, SecretsStore, SymmetricVault};
+
+use crate::cloud::enroll::encrypted_attributes::{encrypt_attributes, EncryptedAttributes};
+
+use super::*;
+
+// Main req/res types
+
+/// A request for an enrollment token, tagged with `TAG` when the `tag` feature is
+/// enabled, so that deployments using their own schema registry can pick another tag
+#[derive(Encode, Decode, Debug, Clone)]
+#[rustfmt::skip]
+#[cbor(map)]
+pub struct RequestEnrollmentToken<const TAG: usize = 8560526> {
+    #[cfg(feature = "tag")]
+    #[n(0)] pub tag: TypeTag<TAG>,
+    #[b(1)] pub attributes: Attributes,
+    /// How many times the token can be used, the authenticator issues
+    /// single-use tokens when this is not set
+    #[n(2)] pub usage_count: Option<u32>,
+    /// Key used by the authenticator to return the same token
+    /// when a request is retried
+    #[n(3)] pub idempotency_key: Option<Token>,
+    /// Number of seconds during which the token is valid, the
+    /// authenticator uses its own default when this is not set
+    #[n(4)] pub expires_in: Option<u64>,
+    /// Token from which the requested token is delegated. Its attributes
+    /// must then include the attributes of the requested token
+    #[n(5)] pub parent_token: Option<Token>,
+    /// Typed values of some of the attributes. Their text or bytes
+    /// representation is also part of `attributes`, for the authenticators
+    /// which don't support typed values
+    #[n(6)] pub typed_attributes: Option<BTreeMap<String, AttributeValue>>,
+    /// Number of seconds during which some of the attributes are granted,
+    /// after which the authenticator drops them from the enrollment
+    #[n(7)] pub attributes_expires_in: Option<BTreeMap<String, u64>>,
+    /// Identifier of the device allowed to use the token, for instance
+    /// a hardware id, so that a stolen token can't enroll other devices
+    #[n(8)] pub bound_identifier: Option<Token>,
+    /// Project in which the token can be used, identified like the
+    /// trust context of its nodes
+    #[n(9)] pub audience: Option<Token>,
+    /// Label identifying the token in the listings, like `warehouse-robots-batch-3`.
+    /// Unlike the attributes, it is not granted to the enrolled identities
+    #[n(10)] pub label: Option<String>,
+    /// Attributes whose values are encrypted for the authenticator, and
+    /// are then not part of `attributes`
+    #[n(11)] pub encrypted_attributes: Option<EncryptedAttributes>,
+    /// Unix time (in seconds) before which the token can't be used, so that
+    /// tokens can be handed out ahead of a scheduled provisioning
+    #[n(12)] pub not_before: Option<u64>,
+}
+
+impl RequestEnrollmentToken {
+    pub fn new(attributes: Attributes) -> Self {
+        Self::tagged(attributes)
+    }
+
+    pub fn builder() -> RequestEnrollmentTokenBuilder {
+        RequestEnrollmentTokenBuilder::default()
+    }
+}
+
+impl<const TAG: usize> RequestEnrollmentToken<TAG> {
+    /// Same as `new`, for a request tagged with `TAG` rather than the default tag
+    pub fn tagged(attributes: Attributes) -> Self {
+        Self {
+            #[cfg(feature = "tag")]
+            tag: TypeTag,
+            attributes,
+            usage_count: None,
+            idempotency_key: None,
+            expires_in: None,
+            parent_token: None,
+            typed_attributes: None,
+            attributes_expires_in: None,
+            bound_identifier: None,
+            audience: None,
+            label: None,
+            encrypted_attributes: None,
+            not_before: None,
+        }
+    }
+
+    /// Set the attribute `key` to a typed value, replacing its previous value
+    pub fn with_typed_attribute(mut self, key: &str, value: impl Into<AttributeValue>) -> Self {
+        let value = value.into();
+        self.attributes.put(key, &value.to_fallback());
+        self.typed_attributes
+            .get_or_insert_with(BTreeMap::new)
+            .insert(key.to_string(), value);
+        self
+    }
+
+    /// Return the value of the attribute `key`, read from its fallback
+    /// representation when it has no typed value
+    pub fn attribute_value(&self, key: &str) -> Option<AttributeValue> {
+        let typed = self.typed_attributes.as_ref().and_then(|t| t.get(key));
+        match typed {
+            Some(value) => Some(value.clone()),
+            None => self.attributes.get(key).map(AttributeValue::from_fallback),
+        }
+    }
+
+    /// Grant the attribute `key` for `expires_in` seconds only
+    pub fn with_attribute_expires_in(mut self, key: &str, expires_in: u64) -> Self {
+        self.attributes_expires_in
+            .get_or_insert_with(BTreeMap::new)
+            .insert(key.to_string(), expires_in);
+        self
+    }
+
+    /// Check that the expiring attributes are part of the request, and that
+    /// they don't outlive the token when its validity is set
+    pub fn check_attributes_expiries(&self) -> Result<(), InvalidTokenRequest> {
+        for (key, expires_in) in self.attributes_expires_in.iter().flatten() {
+            let encrypted = self.encrypted_attributes.as_ref();
+            if self.attributes.get(key).is_none() && !encrypted.map_or(false, |e| e.contains(key)) {
+                return Err(InvalidTokenRequest::UnknownExpiringAttribute(key.clone()));
+            }
+            if *expires_in == 0 {
+                return Err(InvalidTokenRequest::ZeroAttributeExpiry(key.clone()));
+            }
+            if self.expires_in.map_or(false, |token| *expires_in > token) {
+                return Err(InvalidTokenRequest::AttributeOutlivesToken(key.clone()));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn with_usage_count(mut self, usage_count: u32) -> Self {
+        self.usage_count = Some(usage_count);
+        self
+    }
+
+    pub fn with_idempotency_key(mut self, idempotency_key: Token) -> Self {
+        self.idempotency_key = Some(idempotency_key);
+        self
+    }
+
+    pub fn with_parent_token(mut self, parent_token: Token) -> Self {
+        self.parent_token = Some(parent_token);
+        self
+    }
+
+    /// Only allow the device presenting `bound_identifier` to use the token
+    pub fn with_bound_identifier(mut self, bound_identifier: Token) -> Self {
+        self.bound_identifier = Some(bound_identifier);
+        self
+    }
+
+    /// Only allow the nodes of the project `audience` to use the token
+    pub fn with_audience(mut self, audience: Token) -> Self {
+        self.audience = Some(audience);
+        self
+    }
+
+    pub fn with_label(mut self, label: impl Into<String>) -> Self {
+        self.label = Some(label.into());
+        self
+    }
+
+    /// Only allow the token to be used from the Unix time `not_before`, in seconds
+    pub fn with_not_before(mut self, not_before: u64) -> Self {
+        self.not_before = Some(not_before);
+        self
+    }
+
+    /// Encrypt the values of the attributes `keys` for the holder of the X25519 secret of
+    /// `public_key`, usually the project authenticator, and remove them from `attributes`.
+    ///
+    /// The attributes must be encrypted at once, after their typed values are set: the
+    /// typed values of the encrypted attributes are removed too.
+    pub async fn with_encrypted_attributes<V>(
+        mut self,
+        vault: &V,
+        keys: &[&str],
+        public_key: &PublicKey,
+    ) -> ockam_core::Result<Self>
+    where
+        V: SecretsStore + AsymmetricVault + SymmetricVault + ?Sized,
+    {
+        let mut sensitive = Attributes::new();
+        let mut attributes = Attributes::new();
+        for (key, value) in self.attributes.iter() {
+            if keys.contains(&key.as_str()) {
+                sensitive.put(key, value);
+            } else {
+                attributes.put(key, value);
+            }
+        }
+        if let Some(typed) = self.typed_attributes.as_mut() {
+            typed.retain(|key, _| !keys.contains(&key.as_str()));
+        }
+        self.encrypted_attributes = Some(encrypt_attributes(vault, &sensitive, public_key).await?);
+        self.attributes = attributes;
+        Ok(self)
+    }
+
+    /// Set a random idempotency key, unless the caller already chose one
+    pub fn with_default_idempotency_key(self) -> Self {
+        if self.idempotency_key.is_some() {
+            return self;
+        }
+        let key = hex::encode(rand::random::<[u8; 16]>());
+        self.with_idempotency_key(Token::new(key))
+    }
+}
+
+/// Value of an attribute of a requested token, so that policies can
+/// compare numbers and booleans rather than their text representation
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AttributeValue {
+    Bool(bool),
+    Int(i64),
+    Str(String),
+    Bytes(Vec<u8>),
+}
+
+impl AttributeValue {
+    /// Representation of the value for the authenticators which only support
+    /// untyped attributes: numbers and booleans are converted to text
+    pub fn to_fallback(&self) -> Vec<u8> {
+        match self {
+            AttributeValue::Bool(b) => b.to_string().into_bytes(),
+            AttributeValue::Int(i) => i.to_string().into_bytes(),
+            AttributeValue::Str(s) => s.as_bytes().to_vec(),
+            AttributeValue::Bytes(b) => b.clone(),
+        }
+    }
+
+    /// Read an untyped attribute as text if it is valid UTF-8, or as bytes otherwise
+    pub fn from_fallback(value: &[u8]) -> Self {
+        match std::str::from_utf8(value) {
+            Ok(s) => AttributeValue::Str(s.to_string()),
+            Err(_) => AttributeValue::Bytes(value.to_vec()),
+        }
+    }
+}
+
+impl From<bool> for AttributeValue {
+    fn from(value: bool) -> Self {
+        AttributeValue::Bool(value)
+    }
+}
+
+impl From<i64> for AttributeValue {
+    fn from(value: i64) -> Self {
+        AttributeValue::Int(value)
+    }
+}
+
+impl From<&str> for AttributeValue {
+    fn from(value: &str) -> Self {
+        AttributeValue::Str(value.to_string())
+    }
+}
+
+impl From<String> for AttributeValue {
+    fn from(value: String) -> Self {
+        AttributeValue::Str(value)
+    }
+}
+
+impl From<Vec<u8>> for AttributeValue {
+    fn from(value: Vec<u8>) -> Self {
+        AttributeValue::Bytes(value)
+    }
+}
+
+/// Attribute values are encoded as the matching CBOR data item
+impl<C> Encode<C> for AttributeValue {
+    fn encode<W: Write>(
+        &self,
+        e: &mut Encoder<W>,
+        _: &mut C,
+    ) -> Result<(), encode::Error<W::Error>> {
+        match self {
+            AttributeValue::Bool(b) => e.bool(*b)?,
+            AttributeValue::Int(i) => e.i64(*i)?,
+            AttributeValue::Str(s) => e.str(s)?,
+            AttributeValue::Bytes(b) => e.bytes(b)?,
+        };
+        Ok(())
+    }
+}
+
+impl<'b, C> Decode<'b, C> for AttributeValue {
+    fn decode(d: &mut Decoder<'b>, _: &mut C) -> Result<Self, decode::Error> {
+        match d.datatype()? {
+            Type::Bool => Ok(AttributeValue::Bool(d.bool()?)),
+            Type::U8
+            | Type::U16
+            | Type::U32
+            | Type::U64
+            | Type::I8
+            | Type::I16
+            | Type::I32
+            | Type::I64 => Ok(AttributeValue::Int(d.i64()?)),
+            Type::String => Ok(AttributeValue::Str(d.str()?.to_string())),
+            Type::Bytes => Ok(AttributeValue::Bytes(d.bytes()?.to_vec())),
+            t => Err(decode::Error::type_mismatch(t).with_message("invalid attribute value")),
+        }
+    }
+}
+
+/// Builder for a [`RequestEnrollmentToken`], checking its fields before creating it
+#[derive(Debug, Default)]
+pub struct RequestEnrollmentTokenBuilder {
+    attributes: Option<Attributes>,
+    typed_attributes: Vec<(String, AttributeValue)>,
+    usage_count: Option<u32>,
+    expires_in: Option<u64>,
+    idempotency_key: Option<Token>,
+    parent_token: Option<Token>,
+    attributes_expires_in: Vec<(String, u64)>,
+    bound_identifier: Option<Token>,
+    audience: Option<Token>,
+    label: Option<String>,
+    not_before: Option<u64>,
+}
+
+impl RequestEnrollmentTokenBuilder {
+    pub fn attributes(mut self, attributes: Attributes) -> Self {
+        self.attributes = Some(attributes);
+        self
+    }
+
+    /// Set the attribute `key` to a typed value, see [`RequestEnrollmentToken::with_typed_attribute`]
+    pub fn typed_attribute(mut self, key: &str, value: impl Into<AttributeValue>) -> Self {
+        self.typed_attributes.push((key.to_string(), value.into()));
+        self
+    }
+
+    pub fn usage_count(mut self, usage_count: u32) -> Self {
+        self.usage_count = Some(usage_count);
+        self
+    }
+
+    pub fn expires_in(mut self, expires_in: u64) -> Self {
+        self.expires_in = Some(expires_in);
+        self
+    }
+
+    pub fn idempotency_key(mut self, idempotency_key: Token) -> Self {
+        self.idempotency_key = Some(idempotency_key);
+        self
+    }
+
+    pub fn parent_token(mut self, parent_token: Token) -> Self {
+        self.parent_token = Some(parent_token);
+        self
+    }
+
+    pub fn bound_identifier(mut self, bound_identifier: Token) -> Self {
+        self.bound_identifier = Some(bound_identifier);
+        self
+    }
+
+    pub fn audience(mut self, audience: Token) -> Self {
+        self.audience = Some(audience);
+        self
+    }
+
+    pub fn label(mut self, label: impl Into<String>) -> Self {
+        self.label = Some(label.into());
+        self
+    }
+
+    pub fn not_before(mut self, not_before: u64) -> Self {
+        self.not_before = Some(not_before);
+        self
+    }
+
+    /// Grant the attribute `key` for `expires_in` seconds only
+    pub fn attribute_expires_in(mut self, key: &str, expires_in: u64) -> Self {
+        self.attributes_expires_in
+            .push((key.to_string(), expires_in));
+        self
+    }
+
+    /// Return the request, unless its attributes are missing, the requested
+    /// token could never be used or its attributes expiries are invalid
+    pub fn build(self) -> Result<RequestEnrollmentToken, InvalidTokenRequest> {
+        let attributes = match self.attributes {
+            Some(attributes) => attributes,
+            None if !self.typed_attributes.is_empty() => Attributes::new(),
+            None => return Err(InvalidTokenRequest::MissingAttributes),
+        };
+        if self.usage_count == Some(0) {
+            return Err(InvalidTokenRequest::ZeroUsageCount);
+        }
+        if self.expires_in == Some(0) {
+            return Err(InvalidTokenRequest::ZeroExpiry);
+        }
+        let request = RequestEnrollmentToken {
+            usage_count: self.usage_count,
+            expires_in: self.expires_in,
+            idempotency_key: self.idempotency_key,
+            parent_token: self.parent_token,
+            bound_identifier: self.bound_identifier,
+            audience: self.audience,
+            label: self.label,
+            not_before: self.not_before,
+            ..RequestEnrollmentToken::new(attributes)
+        };
+        let request = self
+            .typed_attributes
+            .into_iter()
+            .fold(request, |request, (key, value)| {
+                request.with_typed_attribute(&key, value)
+            });
+        let request = self
+            .attributes_expires_in
+            .into_iter()
+            .fold(request, |request, (key, expires_in)| {
+                request.with_attribute_expires_in(&key, expires_in)
+            });
+        request.check_attributes_expiries()?;
+        Ok(request)
+    }
+}
+
+/// Reason why [`RequestEnrollmentTokenBuilder::build`] fails
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InvalidTokenRequest {
+    MissingAttributes,
+    ZeroUsageCount,
+    ZeroExpiry,
+    /// An expiry is set for an attribute which is not requested
+    UnknownExpiringAttribute(String),
+    ZeroAttributeExpiry(String),
+    /// An attribute expires after the token
+    AttributeOutlivesToken(String),
+}
+
+impl fmt::Display for InvalidTokenRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InvalidTokenRequest::MissingAttributes => {
+                write!(f, "the token attributes must be set")
+            }
+            InvalidTokenRequest::ZeroUsageCount => {
+                write!(f, "the token must be usable at least once")
+            }
+            InvalidTokenRequest::ZeroExpiry => {
+                write!(f, "the token must be valid for at least one second")
+            }
+            InvalidTokenRequest::UnknownExpiringAttribute(key) => {
+                write!(f, "the expiring attribute {key} is not requested")
+            }
+            InvalidTokenRequest::ZeroAttributeExpiry(key) => {
+                write!(
+                    f,
+                    "the attribute {key} must be granted for at least one second"
+                )
+            }
+            InvalidTokenRequest::AttributeOutlivesToken(key) => {
+                write!(f, "the attribute {key} can't expire after the token")
+            }
+        }
+    }
+}
+
+impl std::error::Error for InvalidTokenRequest {}
+
+#[derive(Encode, Decode, Serialize, Debug, Clone)]
+#[rustfmt::skip]
+#[cbor(map)]
+pub struct EnrollmentToken {
+    #[cfg(feature = "tag")]
+    #[serde(skip)]
+    #[n(0)] pub tag: TypeTag<8932763>,
+    #[n(1)] pub token: Token,
+    /// Unix time (in seconds) after which the token is no longer valid
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[n(2)] pub expires_at: Option<u64>,
+    /// Attributes the token was generated for, only set on signed tokens
+    #[serde(skip)]
+    #[b(3)] pub attributes: Option<Attributes>,
+    /// Signature of the authenticator over `signed_data`, so that
+    /// the token can be verified by nodes which can't reach it
+    #[serde(skip)]
+    #[n(4)] pub signature: Option<ByteVec>,
+    /// Version of the token format, absent on the tokens
+    /// generated before it was introduced, which are version 1
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[n(5)] pub version: Option<u8>,
+    /// Identifier of the only device which can use the token, if it is bound to one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[n(6)] pub bound_identifier: Option<Token>,
+    /// Identifier presented by the device authenticating the token
+    #[serde(skip)]
+    #[n(7)] pub device_identifier: Option<Token>,
+    /// Project in which the token can be used, if it is restricted to one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[n(8)] pub audience: Option<Token>,
+    /// Unix time (in seconds) before which the token is not valid yet
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[n(9)] pub not_before: Option<u64>,
+}
+
+/// Version of the enrollment token format stamped on the generated tokens
+pub const ENROLLMENT_TOKEN_VERSION: u8 = 1;
+
+impl EnrollmentToken {
+    pub fn new(token: Token) -> Self {
+        Self {
+            #[cfg(feature = "tag")]
+            tag: TypeTag,
+            token,
+            expires_at: None,
+            attributes: None,
+            signature: None,
+            version: Some(ENROLLMENT_TOKEN_VERSION),
+            bound_identifier: None,
+            device_identifier: None,
+            audience: None,
+            not_before: None,
+        }
+    }
+
+    pub fn with_version(mut self, version: u8) -> Self {
+        self.version = Some(version);
+        self
+    }
+
+    /// Version of the token format, defaulting to the first one
+    pub fn version(&self) -> u8 {
+        self.version.unwrap_or(1)
+    }
+
+    /// Return true if this node can parse tokens of the version of this token
+    pub fn is_supported_version(&self) -> bool {
+        self.version() <= ENROLLMENT_TOKEN_VERSION
+    }
+
+    pub fn with_expires_at(mut self, expires_at: u64) -> Self {
+        self.expires_at = Some(expires_at);
+        self
+    }
+
+    pub fn with_attributes(mut self, attributes: Attributes) -> Self {
+        self.attributes = Some(attributes);
+        self
+    }
+
+    pub fn with_signature(mut self, signature: impl Into<ByteVec>) -> Self {
+        self.signature = Some(signature.into());
+        self
+    }
+
+    pub fn with_bound_identifier(mut self, bound_identifier: Token) -> Self {
+        self.bound_identifier = Some(bound_identifier);
+        self
+    }
+
+    /// Present the identifier of the device using the token
+    pub fn with_device_identifier(mut self, device_identifier: Token) -> Self {
+        self.device_identifier = Some(device_identifier);
+        self
+    }
+
+    /// A token which is not bound to a device can be used by any of them.
+    ///
+    /// Whoever presents the token can change its binding, so only the binding
+    /// known by its authenticator, or covered by its signature, can be trusted.
+    pub fn is_usable_by(&self, device_identifier: Option<&Token>) -> bool {
+        match &self.bound_identifier {
+            Some(bound) => device_identifier.map(Token::reveal) == Some(bound.reveal()),
+            None => true,
+        }
+    }
+
+    pub fn with_audience(mut self, audience: Token) -> Self {
+        self.audience = Some(audience);
+        self
+    }
+
+    /// A token without an audience can be used in any project, or outside of one.
+    ///
+    /// Like its binding, the audience of a token can only be trusted when it is
+    /// known by its authenticator, or covered by its signature.
+    pub fn is_usable_in(&self, project: Option<&str>) -> bool {
+        match &self.audience {
+            Some(audience) => project == Some(audience.reveal()),
+            None => true,
+        }
+    }
+
+    /// The bytes covered by the signature of the token: the CBOR encoding of
+    /// its format version followed by all its fields but the signature and the
+    /// identifier presented by the device, which are only set once it is signed.
+    ///
+    /// The token is destructured so that a new field can't be added to it
+    /// without deciding whether it is signed.
+    pub fn signed_data(&self) -> Result<Vec<u8>, encode::Error<Infallible>> {
+        let EnrollmentToken {
+            #[cfg(feature = "tag")]
+                tag: _,
+            token,
+            expires_at,
+            attributes,
+            signature: _,
+            version: _,
+            bound_identifier,
+            device_identifier: _,
+            audience,
+            not_before,
+        } = self;
+        minicbor::to_vec((
+            self.version(),
+            token,
+            expires_at,
+            attributes,
+            bound_identifier,
+            audience,
+            not_before,
+        ))
+    }
+
+    /// A token without an expiry is never considered expired
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.map(|t| t <= now).unwrap_or(false)
+    }
+
+    pub fn with_not_before(mut self, not_before: u64) -> Self {
+        self.not_before = Some(not_before);
+        self
+    }
+
+    /// A token without a not-before time is valid as soon as it is generated.
+    ///
+    /// Like its binding, the not-before time of a token can only be trusted when
+    /// it is known by its authenticator, or covered by its signature.
+    pub fn is_not_yet_valid(&self, now: u64) -> bool {
+        self.not_before.map(|t| now < t).unwrap_or(false)
+    }
+}
+
+/// An enrollment token sent to its authenticator, tagged with `TAG`
+/// when the `tag` feature is enabled
+#[derive(Encode, Debug)]
+#[cfg_attr(test, derive(Decode, Clone))]
+#[rustfmt::skip]
+#[cbor(map)]
+pub struct AuthenticateEnrollmentToken<const TAG: usize = 9463780> {
+    #[cfg(feature = "tag")]
+    #[n(0)] pub tag: TypeTag<TAG>,
+    #[n(1)] pub token: Token,
+    #[n(2)] pub device_identifier: Option<Token>,
+}
+
+impl AuthenticateEnrollmentToken {
+    pub fn new(token: EnrollmentToken) -> Self {
+        Self::tagged(token)
+    }
+}
+
+impl<const TAG: usize> AuthenticateEnrollmentToken<TAG> {
+    /// Same as `new`, for a request tagged with `TAG` rather than the default tag
+    pub fn tagged(token: EnrollmentToken) -> Self {
+        Self {
+            #[cfg(feature = "tag")]
+            tag: TypeTag,
+            token: token.token,
+            device_identifier: token.device_identifier,
+        }
+    }
+}
+
+#[derive(Encode, Decode, Debug)]
+#[cfg_attr(test, derive(Clone))]
+#[rustfmt::skip]
+#[cbor(map)]
+pub struct RevokeEnrollmentToken {
+    #[cfg(feature = "tag")]
+    #[n(0)] pub tag: TypeTag<4053824>,
+    #[n(1)] pub token: Token,
+}
+
+impl RevokeEnrollmentToken {
+    pub fn new(token: Token) -> Self {
+        Self {
+            #[cfg(feature = "tag")]
+            tag: TypeTag,
+            token,
+        }
+    }
+}
+
+#[derive(Encode, Decode, Debug)]
+#[cfg_attr(test, derive(Clone))]
+#[rustfmt::skip]
+#[cbor(map)]
+pub struct IntrospectEnrollmentToken {
+    #[cfg(feature = "tag")]
+    #[n(0)] pub tag: TypeTag<2860137>,
+    #[n(1)] pub token: Token,
+}
+
+impl IntrospectEnrollmentToken {
+    pub fn new(token: Token) -> Self {
+        Self {
+            #[cfg(feature = "tag")]
+            tag: TypeTag,
+            token,
+        }
+    }
+}
+
+/// Status of an enrollment token, as known by the authenticator
+#[derive(Encode, Decode, Debug)]
+#[cfg_attr(test, derive(Clone))]
+#[rustfmt::skip]
+#[cbor(map)]
+pub struct EnrollmentTokenIntrospection {
+    #[cfg(feature = "tag")]
+    #[n(0)] pub tag: TypeTag<6418375>,
+    /// False if the token is unknown, expired, revoked or used up
+    #[n(1)] pub active: bool,
+    #[b(2)] pub attributes: Option<Attributes>,
+    /// Unix time (in seconds) after which the token is no longer valid
+    #[n(3)] pub expires_at: Option<u64>,
+    /// How many times the token can still be used
+    #[n(4)] pub usage_remaining: Option<u32>,
+    /// Label the token was requested with
+    #[n(5)] pub label: Option<String>,
+}
+
+impl EnrollmentTokenIntrospection {
+    pub fn active(attributes: Attributes) -> Self {
+        Self {
+            #[cfg(feature = "tag")]
+            tag: TypeTag,
+            active: true,
+            attributes: Some(attributes),
+            expires_at: None,
+            usage_remaining: None,
+            label: None,
+        }
+    }
+
+    pub fn inactive() -> Self {
+        Self {
+            #[cfg(feature = "tag")]
+            tag: TypeTag,
+            active: false,
+            attributes: None,
+            expires_at: None,
+            usage_remaining: None,
+            label: None,
+        }
+    }
+
+    pub fn with_expires_at(mut self, expires_at: u64) -> Self {
+        self.expires_at = Some(expires_at);
+        self
+    }
+
+    pub fn with_usage_remaining(mut self, usage_remaining: u32) -> Self {
+        self.usage_remaining = Some(usage_remaining);
+        self
+    }
+
+    pub fn with_label(mut self, label: impl Into<String>) -> Self {
+        self.label = Some(label.into());
+        self
+    }
+}
+
+/// Description of the enrollment token which would be generated for a `RequestEnrollmentToken`
+#[derive(Encode, Decode, Debug)]
+#[cfg_attr(test, derive(Clone))]
+#[rustfmt::skip]
+#[cbor(map)]
+pub struct ValidatedEnrollmentToken {
+    #[cfg(feature = "tag")]
+    #[n(0)] pub tag: TypeTag<7812904>,
+    #[b(1)] pub attributes: Attributes,
+    /// Unix time (in seconds) after which the token would no longer be valid
+    #[n(2)] pub expires_at: Option<u64>,
+    /// How many times the token could be used
+    #[n(3)] pub usage_count: Option<u32>,
+}
+
+impl ValidatedEnrollmentToken {
+    pub fn new(attributes: Attributes) -> Self {
+        Self {
+            #[cfg(feature = "tag")]
+            tag: TypeTag,
+            attributes,
+            expires_at: None,
+            usage_count: None,
+        }
+    }
+
+    pub fn with_expires_at(mut self, expires_at: u64) -> Self {
+        self.expires_at = Some(expires_at);
+        self
+    }
+
+    pub fn with_usage_count(mut self, usage_count: u32) -> Self {
+        self.usage_count = Some(usage_count);
+        self
+    }
+}
+
+/// Request for a page of the enrollment tokens known by the authenticator
+#[derive(Encode, Decode, Debug)]
+#[cfg_attr(test, derive(Clone))]
+#[rustfmt::skip]
+#[cbor(map)]
+pub struct ListEnrollmentTokens {
+    #[cfg(feature = "tag")]
+    #[n(0)] pub tag: TypeTag<6735417>,
+    /// Index of the requested page, starting at 0
+    #[n(1)] pub page: u32,
+    #[n(2)] pub page_size: u32,
+}
+
+impl ListEnrollmentTokens {
+    pub fn new(page: u32, page_size: u32) -> Self {
+        Self {
+            #[cfg(feature = "tag")]
+            tag: TypeTag,
+            page,
+            page_size,
+        }
+    }
+}
+
+#[derive(Encode, Decode, Debug)]
+#[cfg_attr(test, derive(Clone))]
+#[rustfmt::skip]
+#[cbor(map)]
+pub struct EnrollmentTokenPage {
+    #[cfg(feature = "tag")]
+    #[n(0)] pub tag: TypeTag<2471093>,
+    #[n(1)] pub tokens: Vec<EnrollmentTokenMetadata>,
+    /// Page to request to get the next tokens, absent on the last page
+    #[n(2)] pub next_page: Option<u32>,
+}
+
+impl EnrollmentTokenPage {
+    pub fn new(tokens: Vec<EnrollmentTokenMetadata>, next_page: Option<u32>) -> Self {
+        Self {
+            #[cfg(feature = "tag")]
+            tag: TypeTag,
+            tokens,
+            next_page,
+        }
+    }
+}
+
+/// Description of an enrollment token, without the token secret
+#[derive(Encode, Decode, Debug)]
+#[cfg_attr(test, derive(Clone))]
+#[rustfmt::skip]
+#[cbor(map)]
+pub struct EnrollmentTokenMetadata {
+    #[cfg(feature = "tag")]
+    #[n(0)] pub tag: TypeTag<5390262>,
+    #[n(1)] pub id: String,
+    #[b(2)] pub attributes: Attributes,
+    /// Unix time (in seconds) after which the token is no longer valid
+    #[n(3)] pub expires_at: Option<u64>,
+    /// How many more times the token can be used
+    #[n(4)] pub usage_remaining: Option<u32>,
+    /// Label the token was requested with
+    #[n(5)] pub label: Option<String>,
+}
+
+impl EnrollmentTokenMetadata {
+    pub fn new(id: impl Into<String>, attributes: Attributes) -> Self {
+        Self {
+            #[cfg(feature = "tag")]
+            tag: TypeTag,
+            id: id.into(),
+            attributes,
+            expires_at: None,
+            usage_remaining: None,
+            label: None,
+        }
+    }
+
+    pub fn with_expires_at(mut self, expires_at: u64) -> Self {
+        self.expires_at = Some(expires_at);
+        self
+    }
+
+    pub fn with_usage_remaining(mut self, usage_remaining: u32) -> Self {
+        self.usage_remaining = Some(usage_remaining);
+        self
+    }
+
+    pub fn with_label(mut self, label: impl Into<String>) -> Self {
+        self.label = Some(label.into());
+        self
+    }
+}
+
+/// Outcome of the generation of one of the enrollment tokens of a batch
+#[derive(Encode, Decode, Debug)]
+#[rustfmt::skip]
+#[cbor(map)]
+pub struct GeneratedEnrollmentToken {
+    #[cfg(feature = "tag")]
+    #[n(0)] pub tag: TypeTag<3172654>,
+    /// Position of the token among the requested ones
+    #[n(1)] pub index: u32,
+    /// The generated token, absent when it couldn't be generated
+    #[n(2)] pub token: Option<EnrollmentToken>,
+    /// Status of the request, when the token couldn't be generated
+    #[n(3)] pub status: Option<Status>,
+    /// Why the token couldn't be generated
+    #[n(4)] pub error: Option<String>,
+}
+
+impl GeneratedEnrollmentToken {
+    pub fn generated(index: usize, token: EnrollmentToken) -> Self {
+        Self {
+            #[cfg(feature = "tag")]
+            tag: TypeTag,
+            index: index as u32,
+            token: Some(token),
+            status: None,
+            error: None,
+        }
+    }
+
+    pub fn failed(index: usize, status: Status, error: impl fmt::Display) -> Self {
+        Self {
+            #[cfg(feature = "tag")]
+            tag: TypeTag,
+            index: index as u32,
+            token: None,
+            status: Some(status),
+            error: Some(error.to_string()),
+        }
+    }
+}
+
+/// Named set of default attributes for the enrollment tokens of a class of devices
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EnrollmentTokenTemplate {
+    pub name: String,
+    pub attributes: Attributes,
+}
+
+impl EnrollmentTokenTemplate {
+    pub fn new(name: impl Into<String>, attributes: Attributes) -> Self {
+        Self {
+            name: name.into(),
+            attributes,
+        }
+    }
+
+    /// The attributes of the template, with the values of `overrides` replacing
+    /// the template values for the same keys
+    pub fn attributes_with(&self, overrides: &Attributes) -> Attributes {
+        let mut attributes = self.attributes.clone();
+        for (key, value) in overrides.iter() {
+            attributes.put(key, value);
+        }
+        attributes
+    }
+}
+
+/// Prefix of the attribute keys set by Ockam itself, like `ockam-role`.
+///
+/// Callers can't request enrollment tokens with these attributes, so that
+/// a token can't be used to obtain a privileged attribute.
+pub const RESERVED_ATTRIBUTES_PREFIX: &str = "ockam-";
+
+/// Limits checked on the attributes of an enrollment token before sending them to the Orchestrator
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AttributesLimits {
+    /// Maximum size of the CBOR encoded attributes, in bytes
+    pub max_size: usize,
+    /// Maximum number of attributes
+    pub max_keys: usize,
+}
+
+impl Default for AttributesLimits {
+    fn default() -> Self {
+        Self {
+            max_size: 4096,
+            max_keys: 32,
+        }
+    }
+}
+
+impl AttributesLimits {
+    /// Check the size of `attributes`, and that none of their keys is reserved
+    pub fn check(&self, attributes: &Attributes) -> Result<(), InvalidAttributes> {
+        let keys = attributes.iter().map(|(key, _)| key.as_str()).collect();
+        self.check_keys(keys, encoded_len(attributes))
+    }
+
+    /// Check the attributes of `req` together with its typed attributes: none of
+    /// their keys is reserved, and their keys and sizes are counted together
+    pub fn check_request(&self, req: &RequestEnrollmentToken) -> Result<(), InvalidAttributes> {
+        let mut keys: Vec<&str> = req.attributes.iter().map(|(key, _)| key.as_str()).collect();
+        let mut size = encoded_len(&req.attributes);
+        if let Some(typed) = &req.typed_attributes {
+            keys.extend(typed.keys().map(String::as_str));
+            size = size.saturating_add(encoded_len(typed));
+        }
+        let keys: BTreeSet<&str> = keys.into_iter().collect();
+        self.check_keys(keys.into_iter().collect(), size)
+    }
+
+    fn check_keys(&self, keys: Vec<&str>, size: usize) -> Result<(), InvalidAttributes> {
+        if keys.iter().any(|key| key.is_empty()) {
+            return Err(InvalidAttributes::EmptyKey);
+        }
+        if let Some(key) = keys
+            .iter()
+            .find(|key| key.starts_with(RESERVED_ATTRIBUTES_PREFIX))
+        {
+            return Err(InvalidAttributes::ReservedKey(key.to_string()));
+        }
+        if keys.len() > self.max_keys {
+            return Err(InvalidAttributes::TooManyKeys {
+                count: keys.len(),
+                max: self.max_keys,
+            });
+        }
+        if size > self.max_size {
+            return Err(InvalidAttributes::TooLarge {
+                size,
+                max: self.max_size,
+            });
+        }
+        Ok(())
+    }
+
+    /// Check the CBOR encoded attributes found at the position of `dec`, without
+    /// decoding them.
+    ///
+    /// The attributes are read one at a time and rejected as soon as there are too
+    /// many of them or they are too large, in which case the reported size is the
+    /// size read so far. Attributes which can't be read are accepted here, so that
+    /// decoding them reports why they are malformed.
+    pub fn check_encoded(&self, dec: &mut Decoder<'_>) -> Result<(), InvalidAttributes> {
+        let start = dec.position();
+        if !enter_field(dec, 1) {
+            return Ok(());
+        }
+        let mut size = dec.position() - start;
+        self.check_encoded_map(dec, &mut BTreeSet::new(), &mut size)
+    }
+
+    /// Same as [`AttributesLimits::check_encoded`], for the CBOR encoded
+    /// `RequestEnrollmentToken` found at the position of `dec`: like with
+    /// [`AttributesLimits::check_request`], its typed attributes are checked
+    /// together with its attributes.
+    pub fn check_encoded_request(&self, dec: &Decoder<'_>) -> Result<(), InvalidAttributes> {
+        let (mut keys, mut size) = (BTreeSet::new(), 0);
+        let mut attributes = dec.clone();
+        if enter_field(&mut attributes, 1) {
+            let start = attributes.position();
+            if enter_field(&mut attributes, 1) {
+                size = attributes.position() - start;
+                self.check_encoded_map(&mut attributes, &mut keys, &mut size)?;
+            }
+        }
+        let mut typed = dec.clone();
+        if enter_field(&mut typed, 6) {
+            self.check_encoded_map(&mut typed, &mut keys, &mut size)?;
+        }
+        Ok(())
+    }
+
+    /// Check the map of attributes found at the position of `dec`, whose keys are
+    /// added to `keys` and whose size read so far is added to `size`
+    fn check_encoded_map<'b>(
+        &self,
+        dec: &mut Decoder<'b>,
+        keys: &mut BTreeSet<&'b str>,
+        size: &mut usize,
+    ) -> Result<(), InvalidAttributes> {
+        let (start, read) = (dec.position(), *size);
+        let len = match dec.map() {
+            Ok(len) => len,
+            Err(_) => return Ok(()),
+        };
+        let max = self.max_keys;
+        if let Some(count) = len.map(|len| len as usize).filter(|count| *count > max) {
+            return Err(InvalidAttributes::TooManyKeys { count, max });
+        }
+        let mut count = 0;
+        while len.map_or_else(
+            || !matches!(dec.datatype(), Ok(Type::Break) | Err(_)),
+            |len| count < len,
+        ) {
+            count += 1;
+            let key = match dec.str() {
+                Ok(key) => key,
+                Err(_) => return Ok(()),
+            };
+            if key.is_empty() {
+                return Err(InvalidAttributes::EmptyKey);
+            }
+            if key.starts_with(RESERVED_ATTRIBUTES_PREFIX) {
+                return Err(InvalidAttributes::ReservedKey(key.to_string()));
+            }
+            keys.insert(key);
+            if keys.len() > max {
+                return Err(InvalidAttributes::TooManyKeys {
+                    count: keys.len(),
+                    max,
+                });
+            }
+            if dec.skip().is_err() {
+                return Ok(());
+            }
+            *size = read + (dec.position() - start);
+            if *size > self.max_size {
+                return Err(InvalidAttributes::TooLarge {
+                    size: *size,
+                    max: self.max_size,
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Size of `value` once CBOR encoded
+fn encoded_len<T: Encode<()>>(value: &T) -> usize {
+    minicbor::to_vec(value)
+        .map(|cbor| cbor.len())
+        .unwrap_or(usize::MAX)
+}
+
+/// Move `dec` to the value of the field `index` of the CBOR map found at its
+/// position, returning false if there is no such field
+pub(crate) fn enter_field(dec: &mut Decoder<'_>, index: u64) -> bool {
+    let len = match dec.map() {
+        Ok(Some(len)) => len,
+        _ => return false,
+    };
+    for _ in 0..len {
+        match dec.u64() {
+            Ok(key) if key == index => return true,
+            Ok(_) if dec.skip().is_ok() => {}
+            _ => return false,
+        }
+    }
+    false
+}
+
+/// Check that all the `attributes` are granted, with the same values, by `parent`
+pub fn check_delegated(
+    attributes: &Attributes,
+    parent: &Attributes,
+) -> Result<(), InvalidAttributes> {
+    match attributes
+        .iter()
+        .find(|(key, value)| parent.get(key) != Some(&***value))
+    {
+        Some((key, _)) => Err(InvalidAttributes::NotDelegated(key.to_string())),
+        None => Ok(()),
+    }
+}
+
+/// Reason why attributes are rejected by [`AttributesLimits::check`] or [`check_delegated`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InvalidAttributes {
+    EmptyKey,
+    ReservedKey(String),
+    NotDelegated(String),
+    TooManyKeys { count: usize, max: usize },
+    TooLarge { size: usize, max: usize },
+}
+
+impl fmt::Display for InvalidAttributes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InvalidAttributes::EmptyKey => write!(f, "attribute keys can't be empty"),
+            InvalidAttributes::ReservedKey(key) => {
+                write!(f, "the attribute {key} is reserved")
+            }
+            InvalidAttributes::NotDelegated(key) => {
+                write!(f, "the attribute {key} is not granted by the parent token")
+            }
+            InvalidAttributes::TooManyKeys { count, max } => {
+                write!(f, "too many attributes: {count} (the maximum is {max})")
+            }
+            InvalidAttributes::TooLarge { size, max } => {
+                write!(
+                    f,
+                    "the attributes are too large: {size} bytes (the maximum is {max})"
+                )
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use cddl_cat::validate_cbor_bytes;
+    use ockam_multiaddr::MultiAddr;
+    use ockam_vault::{EphemeralSecretsStore, SecretAttributes, SecretsStoreReader, Vault};
+
+    use crate::cloud::enroll::encrypted_attributes::decrypt_attributes;
+    use crate::schema::SCHEMA;
+
+    use super::*;
+
+    #[test]
+    fn attributes_within_the_limits_are_accepted() {
+        let mut attributes = Attributes::new();
+        attributes.put("role", b"device");
+        assert_eq!(AttributesLimits::default().check(&attributes), Ok(()));
+    }
+
+    #[test]
+    fn attributes_with_an_empty_key_are_rejected() {
+        let mut attributes = Attributes::new();
+        attributes.put("", b"device");
+        assert_eq!(
+            AttributesLimits::default().check(&attributes),
+            Err(InvalidAttributes::EmptyKey)
+        );
+    }
+
+    #[test]
+    fn overrides_replace_the_template_attributes() {
+        let mut defaults = Attributes::new();
+        defaults.put("role", b"device").put("zone", b"eu");
+        let template = EnrollmentTokenTemplate::new("sensor", defaults);
+
+        let mut overrides = Attributes::new();
+        overrides.put("zone", b"us").put("rack", b"12");
+        let attributes = template.attributes_with(&overrides);
+        assert_eq!(attributes.len(), 3);
+        assert_eq!(attributes.get("role"), Some(&b"device"[..]));
+        assert_eq!(attributes.get("zone"), Some(&b"us"[..]));
+        assert_eq!(attributes.get("rack"), Some(&b"12"[..]));
+    }
+
+    #[test]
+    fn reserved_attributes_are_rejected() {
+        let mut attributes = Attributes::new();
+        attributes
+            .put("role", b"device")
+            .put("ockam-role", b"enroller");
+        assert_eq!(
+            AttributesLimits::default().check(&attributes),
+            Err(InvalidAttributes::ReservedKey("ockam-role".to_string()))
+        );
+    }
+
+    #[test]
+    fn oversized_attributes_are_rejected() {
+        let mut attributes = Attributes::new();
+        attributes.put("role", &[0; 4096]);
+        assert!(matches!(
+            AttributesLimits::default().check(&attributes),
+            Err(InvalidAttributes::TooLarge { max: 4096, .. })
+        ));
+    }
+
+    fn encoded_request(attributes: Attributes) -> Vec<u8> {
+        let req = RequestEnrollmentToken::new(attributes);
+        let route = MultiAddr::from_str("/service/controller_api").unwrap();
+        minicbor::to_vec(CloudRequestWrapper::new(req, &route, None)).unwrap()
+    }
+
+    fn check_encoded_request(
+        limits: &AttributesLimits,
+        body: &[u8],
+    ) -> Result<(), InvalidAttributes> {
+        let mut dec = Decoder::new(body);
+        assert!(enter_field(&mut dec, 1) && enter_field(&mut dec, 1));
+        limits.check_encoded(&mut dec)
+    }
+
+    #[test]
+    fn encoded_attributes_are_checked_like_decoded_ones() {
+        let limits = AttributesLimits::default();
+        let mut valid = Attributes::new();
+        valid.put("role", b"device").put("zone", b"eu");
+        let mut empty_key = Attributes::new();
+        empty_key.put("", b"device");
+        let mut reserved = Attributes::new();
+        reserved.put("ockam-role", b"enroller");
+        let mut oversized = Attributes::new();
+        oversized.put("role", &[0; 4096]);
+        for attributes in [Attributes::new(), valid, empty_key, reserved, oversized] {
+            let body = encoded_request(attributes.clone());
+            let encoded = check_encoded_request(&limits, &body);
+            let decoded = limits.check(&attributes);
+            assert_eq!(encoded.is_ok(), decoded.is_ok(), "{encoded:?} {decoded:?}");
+            if let (Err(InvalidAttributes::TooLarge { .. }), Err(err)) = (&encoded, &decoded) {
+                assert!(matches!(err, InvalidAttributes::TooLarge { .. }));
+            } else {
+                assert_eq!(encoded, decoded);
+            }
+        }
+    }
+
+    #[test]
+    fn large_encoded_attributes_are_rejected_early() {
+        let limits = AttributesLimits::default();
+        let mut large = Attributes::new();
+        for key in 0..8 {
+            large.put(&format!("key-{key}"), &vec![0; 1024 * 1024]);
+        }
+        let body = encoded_request(large);
+        // the check stops at the first value which exceeds the limit
+        match check_encoded_request(&limits, &body) {
+            Err(InvalidAttributes::TooLarge { size, max: 4096 }) => {
+                assert!(size > 1024 * 1024 && size < 2 * 1024 * 1024, "{size}")
+            }
+            res => panic!("unexpected result {res:?}"),
+        }
+
+        let mut many = Attributes::new();
+        for key in 0..10_000 {
+            many.put(&format!("key-{key}"), b"value");
+        }
+        let body = encoded_request(many);
+        assert_eq!(
+            check_encoded_request(&limits, &body),
+            Err(InvalidAttributes::TooManyKeys {
+                count: 10_000,
+                max: 32
+            })
+        );
+    }
+
+    /// Return the encoded request of `req`, and its position in the encoding
+    fn encoded_token_request(req: RequestEnrollmentToken) -> (Vec<u8>, usize) {
+        let route = MultiAddr::from_str("/service/controller_api").unwrap();
+        let body = minicbor::to_vec(CloudRequestWrapper::new(req, &route, None)).unwrap();
+        let mut dec = Decoder::new(&body);
+        assert!(enter_field(&mut dec, 1));
+        let position = dec.position();
+        (body, position)
+    }
+
+    #[test]
+    fn typed_attributes_are_checked_with_the_attributes() {
+        let limits = AttributesLimits {
+            max_size: 4096,
+            max_keys: 2,
+        };
+        let typed = |key: &str| {
+            let mut attributes = Attributes::new();
+            attributes.put("role", b"device");
+            let mut req = RequestEnrollmentToken::new(attributes);
+            let value = AttributeValue::Str("enroller".to_string());
+            req.typed_attributes = Some(BTreeMap::from([(key.to_string(), value)]));
+            req
+        };
+        let check_encoded = |req: RequestEnrollmentToken| {
+            let (body, position) = encoded_token_request(req);
+            let mut dec = Decoder::new(&body);
+            dec.set_position(position);
+            limits.check_encoded_request(&dec)
+        };
+
+        // a reserved key can't be sent as a typed attribute only
+        let reserved = Err(InvalidAttributes::ReservedKey("ockam-role".to_string()));
+        assert_eq!(limits.check_request(&typed("ockam-role")), reserved);
+        assert_eq!(check_encoded(typed("ockam-role")), reserved);
+        assert_eq!(
+            limits.check_request(&typed("")),
+            Err(InvalidAttributes::EmptyKey)
+        );
+        assert_eq!(check_encoded(typed("")), Err(InvalidAttributes::EmptyKey));
+
+        // the keys are counted together, once when they have a typed value
+        assert_eq!(limits.check_request(&typed("role")), Ok(()));
+        assert_eq!(check_encoded(typed("role")), Ok(()));
+        let mut req = typed("zone");
+        req.attributes.put("rack", b"12");
+        let too_many = Err(InvalidAttributes::TooManyKeys { count: 3, max: 2 });
+        assert_eq!(limits.check_request(&req), too_many);
+        assert_eq!(check_encoded(req), too_many);
+
+        // and so are their sizes
+        let mut req = typed("zone");
+        let large = AttributeValue::Bytes(vec![0; 4096]);
+        req.typed_attributes = Some(BTreeMap::from([("zone".to_string(), large)]));
+        assert!(matches!(
+            limits.check_request(&req),
+            Err(InvalidAttributes::TooLarge { max: 4096, .. })
+        ));
+        assert!(matches!(
+            check_encoded(req),
+            Err(InvalidAttributes::TooLarge { max: 4096, .. })
+        ));
+    }
+
+    #[test]
+    fn malformed_encoded_attributes_are_left_to_the_decoder() {
+        let limits = AttributesLimits::default();
+        let mut dec = Decoder::new(&[0xa1, 0x01, 0x05]);
+        assert_eq!(limits.check_encoded(&mut dec), Ok(()));
+        let mut dec = Decoder::new(&[0xff]);
+        assert_eq!(limits.check_encoded(&mut dec), Ok(()));
+    }
+
+    #[test]
+    fn too_many_attributes_are_rejected() {
+        let limits = AttributesLimits {
+            max_keys: 1,
+            ..Default::default()
+        };
+        let mut attributes = Attributes::new();
+        attributes.put("role", b"device").put("zone", b"eu");
+        assert_eq!(
+            limits.check(&attributes),
+            Err(InvalidAttributes::TooManyKeys { count: 2, max: 1 })
+        );
+    }
+
+    #[test]
+    fn idempotency_key_is_kept_when_set() {
+        let key = Token::new("key");
+        let req = RequestEnrollmentToken::new(Attributes::new())
+            .with_idempotency_key(key.clone())
+            .with_default_idempotency_key();
+        assert_eq!(req.idempotency_key, Some(key));
+
+        let first = RequestEnrollmentToken::new(Attributes::new()).with_default_idempotency_key();
+        let second = RequestEnrollmentToken::new(Attributes::new()).with_default_idempotency_key();
+        assert!(first.idempotency_key.is_some());
+        assert_ne!(first.idempotency_key, second.idempotency_key);
+    }
+
+    #[test]
+    fn idempotency_key_is_optional_in_the_schema() {
+        let req = RequestEnrollmentToken::new(Attributes::new()).with_default_idempotency_key();
+        let cbor = minicbor::to_vec(req).unwrap();
+        validate_cbor_bytes("request_enrollment_token", SCHEMA, &cbor).unwrap();
+
+        // a request encoded without a key, as sent by older nodes
+        let mut old = vec![];
+        let mut e = Encoder::new(&mut old);
+        if cfg!(feature = "tag") {
+            e.map(2).unwrap();
+            e.u8(0).unwrap().u32(8560526).unwrap();
+        } else {
+            e.map(1).unwrap();
+        }
+        e.u8(1).unwrap().encode(Attributes::new()).unwrap();
+        let decoded: RequestEnrollmentToken = minicbor::decode(&old).unwrap();
+        assert_eq!(decoded.idempotency_key, None);
+    }
+
+    #[test]
+    fn request_enrollment_token_usage_count_roundtrip() {
+        let mut attributes = Attributes::new();
+        attributes.put("role", b"device");
+        let req = RequestEnrollmentToken::new(attributes).with_usage_count(50);
+        let cbor = minicbor::to_vec(req).unwrap();
+        validate_cbor_bytes("request_enrollment_token", SCHEMA, &cbor).unwrap();
+
+        let decoded: RequestEnrollmentToken = minicbor::decode(&cbor).unwrap();
+        assert_eq!(decoded.usage_count, Some(50));
+        assert_eq!(decoded.attributes.get("role"), Some(&b"device"[..]));
+    }
+
+    #[test]
+    fn labels_roundtrip() {
+        let req = RequestEnrollmentToken::new(Attributes::new()).with_label("robots");
+        let cbor = minicbor::to_vec(&req).unwrap();
+        validate_cbor_bytes("request_enrollment_token", SCHEMA, &cbor).unwrap();
+        let decoded: RequestEnrollmentToken = minicbor::decode(&cbor).unwrap();
+        assert_eq!(decoded.label.as_deref(), Some("robots"));
+
+        let introspection =
+            EnrollmentTokenIntrospection::active(Attributes::new()).with_label("robots");
+        let cbor = minicbor::to_vec(introspection).unwrap();
+        validate_cbor_bytes("enrollment_token_introspection", SCHEMA, &cbor).unwrap();
+        let decoded: EnrollmentTokenIntrospection = minicbor::decode(&cbor).unwrap();
+        assert_eq!(decoded.label.as_deref(), Some("robots"));
+
+        let metadata = EnrollmentTokenMetadata::new("id", Attributes::new()).with_label("robots");
+        let cbor = minicbor::to_vec(metadata).unwrap();
+        validate_cbor_bytes("enrollment_token_metadata", SCHEMA, &cbor).unwrap();
+        let decoded: EnrollmentTokenMetadata = minicbor::decode(&cbor).unwrap();
+        assert_eq!(decoded.label.as_deref(), Some("robots"));
+
+        // tokens requested before labels were introduced have none
+        let unlabeled = minicbor::to_vec(RequestEnrollmentToken::new(Attributes::new())).unwrap();
+        let decoded: RequestEnrollmentToken = minicbor::decode(&unlabeled).unwrap();
+        assert_eq!(decoded.label, None);
+        assert!(decoded.encrypted_attributes.is_none());
+    }
+
+    #[tokio::test]
+    async fn encrypted_attributes_roundtrip() -> ockam_core::Result<()> {
+        let vault = Vault::create();
+        let authenticator = vault
+            .create_ephemeral_secret(SecretAttributes::X25519)
+            .await?;
+        let public_key = vault.get_public_key(&authenticator).await?;
+
+        let mut attributes = Attributes::new();
+        attributes.put("role", b"device");
+        attributes.put("device_secret", b"s3cr3t");
+        let req = RequestEnrollmentToken::new(attributes)
+            .with_typed_attribute("device_secret", "s3cr3t")
+            .with_attribute_expires_in("device_secret", 60)
+            .with_encrypted_attributes(vault.as_ref(), &["device_secret"], &public_key)
+            .await?;
+        assert_eq!(req.attributes.get("device_secret"), None);
+        assert_eq!(req.attribute_value("device_secret"), None);
+        assert_eq!(req.check_attributes_expiries(), Ok(()));
+
+        let cbor = minicbor::to_vec(&req)?;
+        validate_cbor_bytes("request_enrollment_token", SCHEMA, &cbor).unwrap();
+        // the secret is not readable from the request
+        assert!(!cbor.windows(6).any(|window| window == b"s3cr3t"));
+
+        let decoded: RequestEnrollmentToken = minicbor::decode(&cbor)?;
+        assert_eq!(decoded.attributes.get("role"), Some(b"device".as_slice()));
+        let encrypted = decoded.encrypted_attributes.unwrap();
+        let decrypted = decrypt_attributes(vault.as_ref(), &encrypted, &authenticator).await?;
+        assert_eq!(decrypted.get("device_secret"), Some(b"s3cr3t".as_slice()));
+        assert_eq!(decrypted.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn request_enrollment_token_builder_checks_its_fields() {
+        let req = RequestEnrollmentToken::builder()
+            .attributes(Attributes::new())
+            .usage_count(3)
+            .expires_in(600)
+            .idempotency_key(Token::new("key"))
+            .build()
+            .unwrap();
+        assert_eq!(req.usage_count, Some(3));
+        assert_eq!(req.expires_in, Some(600));
+        assert_eq!(req.idempotency_key, Some(Token::new("key")));
+        let cbor = minicbor::to_vec(req).unwrap();
+        validate_cbor_bytes("request_enrollment_token", SCHEMA, &cbor).unwrap();
+
+        assert_eq!(
+            RequestEnrollmentToken::builder().build().unwrap_err(),
+            InvalidTokenRequest::MissingAttributes
+        );
+        assert_eq!(
+            RequestEnrollmentToken::builder()
+                .attributes(Attributes::new())
+                .usage_count(0)
+                .build()
+                .unwrap_err(),
+            InvalidTokenRequest::ZeroUsageCount
+        );
+        assert_eq!(
+            RequestEnrollmentToken::builder()
+                .attributes(Attributes::new())
+                .expires_in(0)
+                .build()
+                .unwrap_err(),
+            InvalidTokenRequest::ZeroExpiry
+        );
+    }
+
+    #[test]
+    fn attributes_expiries_roundtrip() {
+        let mut attributes = Attributes::new();
+        attributes.put("role", b"admin").put("zone", b"eu");
+        let req = RequestEnrollmentToken::builder()
+            .attributes(attributes)
+            .expires_in(600)
+            .attribute_expires_in("role", 60)
+            .build()
+            .unwrap();
+        let cbor = minicbor::to_vec(&req).unwrap();
+        validate_cbor_bytes("request_enrollment_token", SCHEMA, &cbor).unwrap();
+        let decoded: RequestEnrollmentToken = minicbor::decode(&cbor).unwrap();
+        let expected = BTreeMap::from([("role".to_string(), 60)]);
+        assert_eq!(decoded.attributes_expires_in, Some(expected));
+
+        let cbor = minicbor::to_vec(RequestEnrollmentToken::new(Attributes::new())).unwrap();
+        let decoded: RequestEnrollmentToken = minicbor::decode(&cbor).unwrap();
+        assert_eq!(decoded.attributes_expires_in, None);
+    }
+
+    #[test]
+    fn attributes_expiries_are_checked() {
+        let mut attributes = Attributes::new();
+        attributes.put("role", b"admin");
+        let build = |expires_in: Option<u64>, key: &str, attribute_expires_in: u64| {
+            let mut builder = RequestEnrollmentToken::builder()
+                .attributes(attributes.clone())
+                .attribute_expires_in(key, attribute_expires_in);
+            if let Some(expires_in) = expires_in {
+                builder = builder.expires_in(expires_in);
+            }
+            builder.build().map(|_| ())
+        };
+        assert_eq!(build(Some(60), "role", 60), Ok(()));
+        // the token validity is chosen by the authenticator when it is not set
+        assert_eq!(build(None, "role", 3600), Ok(()));
+        assert_eq!(
+            build(Some(60), "role", 61),
+            Err(InvalidTokenRequest::AttributeOutlivesToken(
+                "role".to_string()
+            ))
+        );
+        assert_eq!(
+            build(Some(60), "role", 0),
+            Err(InvalidTokenRequest::ZeroAttributeExpiry("role".to_string()))
+        );
+        assert_eq!(
+            build(Some(60), "zone", 30),
+            Err(InvalidTokenRequest::UnknownExpiringAttribute(
+                "zone".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn attribute_values_roundtrip() {
+        let values = [
+            AttributeValue::Bool(true),
+            AttributeValue::Int(-42),
+            AttributeValue::Int(i64::MAX),
+            AttributeValue::Str("device".to_string()),
+            AttributeValue::Bytes(vec![0, 1, 255]),
+        ];
+        for value in values {
+            let cbor = minicbor::to_vec(&value).unwrap();
+            validate_cbor_bytes("attribute_value", SCHEMA, &cbor).unwrap();
+            let decoded: AttributeValue = minicbor::decode(&cbor).unwrap();
+            assert_eq!(decoded, value);
+        }
+        let cbor = minicbor::to_vec(1.5f64).unwrap();
+        assert!(minicbor::decode::<AttributeValue>(&cbor).is_err());
+    }
+
+    #[test]
+    fn typed_attributes_have_a_fallback_representation() {
+        let req = RequestEnrollmentToken::new(Attributes::new())
+            .with_typed_attribute("admin", true)
+            .with_typed_attribute("max_connections", 10)
+            .with_typed_attribute("role", "device")
+            .with_typed_attribute("key", vec![1, 2]);
+        assert_eq!(req.attributes.get("admin"), Some(&b"true"[..]));
+        assert_eq!(req.attributes.get("max_connections"), Some(&b"10"[..]));
+        assert_eq!(req.attributes.get("role"), Some(&b"device"[..]));
+        assert_eq!(req.attributes.get("key"), Some(&[1, 2][..]));
+
+        let cbor = minicbor::to_vec(&req).unwrap();
+        validate_cbor_bytes("request_enrollment_token", SCHEMA, &cbor).unwrap();
+        let decoded: RequestEnrollmentToken = minicbor::decode(&cbor).unwrap();
+        assert_eq!(decoded.typed_attributes, req.typed_attributes);
+        assert_eq!(
+            decoded.attribute_value("max_connections"),
+            Some(AttributeValue::Int(10))
+        );
+        assert_eq!(
+            decoded.attribute_value("admin"),
+            Some(AttributeValue::Bool(true))
+        );
+
+        // untyped attributes are read from their fallback representation
+        let mut attributes = Attributes::new();
+        attributes.put("role", b"device").put("key", &[255]);
+        let req = RequestEnrollmentToken::new(attributes);
+        assert_eq!(req.attribute_value("role"), Some("device".into()));
+        assert_eq!(req.attribute_value("key"), Some(vec![255].into()));
+        assert_eq!(req.attribute_value("unknown"), None);
+    }
+
+    #[test]
+    fn typed_attributes_can_be_built() {
+        let req = RequestEnrollmentToken::builder()
+            .typed_attribute("max_connections", 10)
+            .build()
+            .unwrap();
+        assert_eq!(
+            req.attribute_value("max_connections"),
+            Some(AttributeValue::Int(10))
+        );
+        assert_eq!(req.attributes.get("max_connections"), Some(&b"10"[..]));
+    }
+
+    #[test]
+    fn delegated_attributes_must_be_granted_by_the_parent() {
+        let mut parent = Attributes::new();
+        parent.put("role", b"gateway").put("region", b"eu");
+        let mut child = Attributes::new();
+        child.put("region", b"eu");
+        assert_eq!(check_delegated(&child, &parent), Ok(()));
+        assert_eq!(check_delegated(&Attributes::new(), &parent), Ok(()));
+
+        child.put("region", b"us");
+        assert_eq!(
+            check_delegated(&child, &parent),
+            Err(InvalidAttributes::NotDelegated("region".to_string()))
+        );
+        let mut extra = Attributes::new();
+        extra.put("zone", b"1");
+        assert_eq!(
+            check_delegated(&extra, &parent),
+            Err(InvalidAttributes::NotDelegated("zone".to_string()))
+        );
+    }
+
+    /// Return the keys of the CBOR map `cbor`, in the order they are encoded
+    fn map_keys(cbor: &[u8]) -> Vec<u64> {
+        let mut dec = Decoder::new(cbor);
+        let len = dec.map().unwrap().unwrap();
+        (0..len)
+            .map(|_| {
+                let key = dec.u64().unwrap();
+                dec.skip().unwrap();
+                key
+            })
+            .collect()
+    }
+
+    #[test]
+    fn token_bodies_are_encoded_deterministically() {
+        let request = |keys: &[&str]| {
+            let mut attributes = Attributes::new();
+            for key in keys {
+                attributes.put(key, key.as_bytes());
+            }
+            RequestEnrollmentToken::builder()
+                .attributes(attributes)
+                .usage_count(2)
+                .expires_in(60)
+                .idempotency_key(Token::new("key"))
+                .parent_token(Token::new("parent"))
+                .build()
+                .unwrap()
+        };
+        // the attributes are encoded in the same order whatever their insertion order
+        let first = minicbor::to_vec(request(&["role", "zone", "name"])).unwrap();
+        let second = minicbor::to_vec(request(&["name", "zone", "role"])).unwrap();
+        assert_eq!(first, second);
+        let keys = map_keys(&first);
+        assert!(keys.windows(2).all(|w| w[0] < w[1]), "{keys:?}");
+
+        let token = || {
+            let mut attributes = Attributes::new();
+            attributes.put("role", b"device");
+            EnrollmentToken::new(Token::new("token"))
+                .with_expires_at(100)
+                .with_attributes(attributes)
+                .with_signature(vec![1, 2, 3])
+        };
+        let first = minicbor::to_vec(token()).unwrap();
+        assert_eq!(first, minicbor::to_vec(token()).unwrap());
+        let keys = map_keys(&first);
+        assert!(keys.windows(2).all(|w| w[0] < w[1]), "{keys:?}");
+
+        let authenticate = || AuthenticateEnrollmentToken::new(token());
+        let first = minicbor::to_vec(authenticate()).unwrap();
+        assert_eq!(first, minicbor::to_vec(authenticate()).unwrap());
+        let keys = map_keys(&first);
+        assert!(keys.windows(2).all(|w| w[0] < w[1]), "{keys:?}");
+    }
+
+    #[test]
+    fn request_enrollment_token_without_usage_count_decodes() {
+        let req = RequestEnrollmentToken::new(Attributes::new());
+        let cbor = minicbor::to_vec(req).unwrap();
+        let decoded: RequestEnrollmentToken = minicbor::decode(&cbor).unwrap();
+        assert_eq!(decoded.usage_count, None);
+    }
+
+    #[test]
+    fn enrollment_token_without_expiry_never_expires() {
+        let token = EnrollmentToken::new(Token::new("token"));
+        assert!(!token.is_expired(u64::MAX));
+    }
+
+    #[test]
+    fn enrollment_token_expires_at_its_expiry_time() {
+        let token = EnrollmentToken::new(Token::new("token")).with_expires_at(100);
+        assert!(!token.is_expired(99));
+        assert!(token.is_expired(100));
+    }
+
+    #[test]
+    fn enrollment_tokens_are_valid_from_their_not_before_time() {
+        let token = EnrollmentToken::new(Token::new("token"));
+        assert!(!token.is_not_yet_valid(0));
+        let token = token.with_not_before(100).with_expires_at(200);
+        assert!(token.is_not_yet_valid(99));
+        assert!(!token.is_not_yet_valid(100));
+
+        let cbor = minicbor::to_vec(&token).unwrap();
+        validate_cbor_bytes("enrollment_token", SCHEMA, &cbor).unwrap();
+        let decoded: EnrollmentToken = minicbor::decode(&cbor).unwrap();
+        assert_eq!(decoded.not_before, Some(100));
+
+        let req = RequestEnrollmentToken::builder()
+            .attributes(Attributes::new())
+            .not_before(100)
+            .build()
+            .unwrap();
+        let cbor = minicbor::to_vec(&req).unwrap();
+        validate_cbor_bytes("request_enrollment_token", SCHEMA, &cbor).unwrap();
+        let decoded: RequestEnrollmentToken = minicbor::decode(&cbor).unwrap();
+        assert_eq!(decoded.not_before, Some(100));
+    }
+
+    #[test]
+    fn enrollment_token_expiry_is_part_of_the_schema() {
+        let token = EnrollmentToken::new(Token::new("token")).with_expires_at(100);
+        let cbor = minicbor::to_vec(token).unwrap();
+        validate_cbor_bytes("enrollment_token", SCHEMA, &cbor).unwrap();
+
+        let decoded: EnrollmentToken = minicbor::decode(&cbor).unwrap();
+        assert_eq!(decoded.expires_at, Some(100));
+    }
+
+    #[test]
+    fn enrollment_tokens_without_a_version_are_version_1() {
+        let mut token = EnrollmentToken::new(Token::new("token"));
+        assert_eq!(token.version, Some(ENROLLMENT_TOKEN_VERSION));
+        let cbor = minicbor::to_vec(&token).unwrap();
+        validate_cbor_bytes("enrollment_token", SCHEMA, &cbor).unwrap();
+
+        token.version = None;
+        let cbor = minicbor::to_vec(&token).unwrap();
+        let decoded: EnrollmentToken = minicbor::decode(&cbor).unwrap();
+        assert_eq!(decoded.version(), 1);
+        assert!(decoded.is_supported_version());
+        assert!(!decoded.with_version(2).is_supported_version());
+    }
+}
@@ -0,0 +1,102 @@
+use futures::stream::{self, Stream};
+use tracing::warn;
+
+use ockam::identity::credential::Attributes;
+use ockam::identity::IdentityIdentifier;
+use ockam_core::api::Status;
+use ockam_node::tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::cloud::enroll::{AuthenticateToken, EnrollError, EnrollResponse, TARGET};
+
+/// Number of events kept for the subscribers which are behind,
+/// after which they miss the oldest ones
+pub const DEFAULT_ENROLL_EVENTS_CAPACITY: usize = 64;
+
+/// Kind of token an enrollment was attempted with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EnrollFlow {
+    #[cfg(feature = "auth0")]
+    Auth0,
+    Oidc,
+    EnrollmentToken,
+    ApiKey,
+    ClientCertificate,
+}
+
+impl From<&AuthenticateToken> for EnrollFlow {
+    fn from(token: &AuthenticateToken) -> Self {
+        match token {
+            #[cfg(feature = "auth0")]
+            AuthenticateToken::Auth0(_) => EnrollFlow::Auth0,
+            AuthenticateToken::Oidc { .. } => EnrollFlow::Oidc,
+            AuthenticateToken::EnrollmentToken(_) => EnrollFlow::EnrollmentToken,
+            AuthenticateToken::ApiKey(_) => EnrollFlow::ApiKey,
+            AuthenticateToken::ClientCertificate(_) => EnrollFlow::ClientCertificate,
+        }
+    }
+}
+
+/// Result of an enrollment attempt
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EnrollOutcome {
+    /// The token was accepted, for `identity` when the authenticator reports it
+    Enrolled {
+        identity: Option<IdentityIdentifier>,
+    },
+    /// The token was rejected, either by the node or by the authenticator
+    Rejected {
+        status: Status,
+        message: Option<String>,
+    },
+    /// The authenticator could not be reached, or its response could not be decoded
+    Failed { status: Status, error: String },
+}
+
+impl EnrollOutcome {
+    pub(crate) fn new(res: &Result<EnrollResponse, EnrollError>) -> Self {
+        match res {
+            Ok(enrolled) => EnrollOutcome::Enrolled {
+                identity: enrolled.claims.as_ref().and_then(|c| c.identity.clone()),
+            },
+            Err(EnrollError::Rejected { status, message }) => EnrollOutcome::Rejected {
+                status: *status,
+                message: message.clone(),
+            },
+            Err(err) => EnrollOutcome::Failed {
+                status: err.status(),
+                error: err.to_string(),
+            },
+        }
+    }
+}
+
+/// An enrollment attempt, as seen by the subscribers of
+/// `NodeManager::subscribe_enroll_events`.
+///
+/// Events never contain the token of the attempt.
+#[derive(Debug, Clone)]
+pub struct EnrollEvent {
+    pub flow: EnrollFlow,
+    /// The attributes granted by the authenticator, or the attributes
+    /// of the enrollment token when the authenticator doesn't return them
+    pub attributes: Option<Attributes>,
+    pub outcome: EnrollOutcome,
+}
+
+/// Return the events received by `receiver`, skipping the ones it missed
+pub(crate) fn subscribe(
+    receiver: broadcast::Receiver<EnrollEvent>,
+) -> impl Stream<Item = EnrollEvent> {
+    stream::unfold(receiver, |mut receiver| async move {
+        loop {
+            match receiver.recv().await {
+                Ok(event) => return Some((event, receiver)),
+                Err(RecvError::Lagged(missed)) => {
+                    warn!(target: TARGET, %missed, "enrollment events were missed by a subscriber");
+                }
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    })
+}
@@ -0,0 +1,29 @@
+/// Counters incremented by the node manager during the enrollment flows.
+///
+/// All the methods do nothing by default, so that implementations only
+/// need to provide the counters they export.
+pub trait EnrollMetrics: Send + Sync + 'static {
+    /// An auth0 token is sent to the Orchestrator
+    fn auth0_enrollment_attempted(&self) {}
+
+    /// An auth0 token is accepted by the Orchestrator
+    fn auth0_enrollment_succeeded(&self) {}
+
+    /// An auth0 enrollment fails, whether the token is rejected or the Orchestrator can't be reached
+    fn auth0_enrollment_failed(&self) {}
+
+    /// An enrollment token is generated
+    fn enrollment_token_generated(&self) {}
+
+    /// An enrollment token is accepted by the Orchestrator
+    fn enrollment_token_authenticated(&self) {}
+
+    /// An enrollment token is rejected, either by the node or by the Orchestrator
+    fn enrollment_token_rejected(&self) {}
+}
+
+/// Metrics which are not recorded anywhere
+#[derive(Default)]
+pub struct NoopEnrollMetrics;
+
+impl EnrollMetrics for NoopEnrollMetrics {}
@@ -0,0 +1,587 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use minicbor::{Decoder, Encode};
+
+use ockam::identity::credential::Attributes;
+use ockam::identity::{IdentityIdentifier, IdentitySecureChannelLocalInfo};
+use ockam_core::api::{Error, Id, Request, Response, Status};
+use ockam_core::{async_trait, Result, Route, Routed, Worker};
+use ockam_node::Context;
+
+#[cfg(feature = "tag")]
+use ockam_core::TypeTag;
+
+use crate::cloud::enroll::clock::{Clock, SystemClock};
+use crate::cloud::enroll::enrollment_token::{
+    EnrollmentToken, EnrollmentTokenIntrospection, EnrollmentTokenMetadata, EnrollmentTokenPage,
+    IntrospectEnrollmentToken, ListEnrollmentTokens, RequestEnrollmentToken, RevokeEnrollmentToken,
+    ValidatedEnrollmentToken,
+};
+use crate::cloud::enroll::preflight::ServerTime;
+use crate::cloud::enroll::{
+    AuthenticateApiKey, AuthenticateClientCertificate, EnrollClaims, Token,
+};
+
+const ENROLLMENT_TOKEN_AUTHENTICATOR: &str = "enrollment_token_authenticator";
+const API_KEY_AUTHENTICATOR: &str = "api_key_authenticator";
+const CLIENT_CERTIFICATE_AUTHENTICATOR: &str = "mtls_authenticator";
+
+/// State of a token generated by a [`MockAuthenticator`]
+#[derive(Debug, Clone)]
+struct GeneratedToken {
+    attributes: Attributes,
+    /// Unix time at which each of the expiring attributes is no longer granted
+    attributes_expire_at: BTreeMap<String, u64>,
+    usage_remaining: u32,
+    expires_at: Option<u64>,
+    not_before: Option<u64>,
+    bound_identifier: Option<Token>,
+    audience: Option<Token>,
+    label: Option<String>,
+    revoked: bool,
+}
+
+/// How a [`MockAuthenticator`] answers the requests it receives
+#[derive(Debug, Clone, Default)]
+pub enum MockMode {
+    /// Answer like the Orchestrator: the enrollment tokens are checked against
+    /// the state of the generated ones, the api keys and client certificates
+    /// are only accepted once allowed, and the OIDC tokens are all accepted
+    #[default]
+    Orchestrator,
+    /// Accept every request, answering with `claims` when they are set
+    Accept(Option<EnrollClaims>),
+    /// Accept every token, enrolling the identity on the other side of the secure channel
+    EnrollCaller,
+    /// Keep the requests without answering them, until [`MockAuthenticator::release_held`]
+    Hold,
+    /// Ignore every message, including the secure channel handshakes, like an
+    /// unreachable controller
+    Unreachable,
+}
+
+#[derive(Default)]
+struct State {
+    mode: MockMode,
+    tokens: BTreeMap<String, GeneratedToken>,
+    /// Tokens returned for the idempotency keys of the generation requests
+    idempotency_keys: BTreeMap<String, String>,
+    rejection: Option<Status>,
+    /// Lifetime of the credentials issued to the enrolled identities, in seconds
+    credential_lifetime: Option<u64>,
+    /// Project of the callers, whose tokens with an audience must be meant for it
+    project: Option<String>,
+    api_key: Option<Token>,
+    /// Fingerprint of the accepted client certificate
+    certificate_fingerprint: Option<String>,
+    /// Version of the generated tokens when it isn't the current one, `Some(None)`
+    /// for tokens without a version, like the ones generated before it was introduced
+    token_version: Option<Option<u8>>,
+    /// Index of the generation request which is rejected
+    failing_generation: Option<usize>,
+    generation_requests: Vec<(Request, RequestEnrollmentToken)>,
+    /// Return route and identifier of the requests held in [`MockMode::Hold`]
+    held: Vec<(Route, Id)>,
+}
+
+/// An in-memory stand-in for the Orchestrator services generating and
+/// authenticating tokens, for tests.
+///
+/// It keeps the enrollment tokens it generates, decrements their usage count
+/// when they are authenticated, and rejects the tokens which are used up, expired,
+/// revoked, presented by another device than the one they are bound to, or
+/// meant for another project than the one set with [`MockAuthenticator::serve_project`].
+/// All the requests can also be rejected with [`MockAuthenticator::reject_with`],
+/// or answered in another way with [`MockAuthenticator::set_mode`].
+///
+/// The same instance must be started at the addresses of [`MockAuthenticator::SERVICES`],
+/// which share its state. The tokens authenticated at the addresses of the
+/// enrollment token, api key and client certificate authenticators are checked,
+/// the other ones are taken for OIDC tokens. The tests get one started with
+/// their node manager and controller:
+///
+/// ```ignore
+/// let (handle, controller, authenticator) = start_mock_controller_for_tests(ctx).await?;
+/// authenticator.set_mode(MockMode::Hold);
+/// ```
+#[derive(Clone)]
+pub struct MockAuthenticator {
+    state: Arc<Mutex<State>>,
+    clock: Arc<Mutex<Arc<dyn Clock>>>,
+}
+
+impl Default for MockAuthenticator {
+    fn default() -> Self {
+        Self::new(Arc::new(SystemClock))
+    }
+}
+
+impl MockAuthenticator {
+    /// Addresses of the services generating and authenticating the tokens
+    pub const SERVICES: [&'static str; 5] = [
+        "projects",
+        ENROLLMENT_TOKEN_AUTHENTICATOR,
+        "auth0_authenticator",
+        API_KEY_AUTHENTICATOR,
+        CLIENT_CERTIFICATE_AUTHENTICATOR,
+    ];
+
+    /// Create an authenticator checking the token expiries against `clock`
+    pub fn new(clock: Arc<dyn Clock>) -> Self {
+        Self {
+            state: Default::default(),
+            clock: Arc::new(Mutex::new(clock)),
+        }
+    }
+
+    /// Check the token expiries against `clock` from now on
+    pub fn set_clock(&self, clock: Arc<dyn Clock>) {
+        *self.clock.lock().unwrap() = clock;
+    }
+
+    /// Start the authenticator at each of the addresses of [`MockAuthenticator::SERVICES`]
+    pub async fn start(&self, ctx: &Context) -> Result<()> {
+        for service in Self::SERVICES {
+            self.start_at(ctx, service).await?;
+        }
+        Ok(())
+    }
+
+    /// Start the authenticator at another address, such as the one of a custom authenticator
+    pub async fn start_at(&self, ctx: &Context, service: &str) -> Result<()> {
+        ctx.start_worker(service, self.clone()).await
+    }
+
+    /// Answer the following requests as set by `mode`
+    pub fn set_mode(&self, mode: MockMode) {
+        self.state().mode = mode;
+    }
+
+    /// Reject all the following requests with `status`, or stop rejecting them if it is not set
+    pub fn reject_with(&self, status: Option<Status>) {
+        self.state().rejection = status;
+    }
+
+    /// Accept the api key `key`, instead of rejecting all the api keys
+    pub fn accept_api_key(&self, key: Token) {
+        self.state().api_key = Some(key);
+    }
+
+    /// Accept the client certificates with the SHA-256 `fingerprint`, instead of
+    /// rejecting all the certificates
+    pub fn accept_client_certificate(&self, fingerprint: &str) {
+        self.state().certificate_fingerprint = Some(fingerprint.to_string());
+    }
+
+    /// Reject the generation request number `index`, counting from 0, with a
+    /// `400 BadRequest` status
+    pub fn fail_generation_at(&self, index: usize) {
+        self.state().failing_generation = Some(index);
+    }
+
+    /// Generate the following tokens with `version`, or without a version if it is not set
+    pub fn generate_version(&self, version: Option<u8>) {
+        self.state().token_version = Some(version);
+    }
+
+    /// Return the token generation requests received so far, with their header
+    pub fn generation_requests(&self) -> Vec<(Request, RequestEnrollmentToken)> {
+        self.state().generation_requests.clone()
+    }
+
+    /// Return the number of requests held in [`MockMode::Hold`]
+    pub fn held(&self) -> usize {
+        self.state().held.len()
+    }
+
+    /// Answer the requests held so far with a `200 OK` status, and return their number
+    pub async fn release_held(&self, ctx: &Context) -> Result<usize> {
+        let held: Vec<_> = self.state().held.drain(..).collect();
+        for (route, id) in &held {
+            ctx.send(route.clone(), Response::ok(*id).to_vec()?).await?;
+        }
+        Ok(held.len())
+    }
+
+    /// Report that the enrolled identities are issued credentials valid for `lifetime`
+    /// seconds, or don't report any credential expiry if it is not set
+    pub fn issue_credentials_for(&self, lifetime: Option<u64>) {
+        self.state().credential_lifetime = lifetime;
+    }
+
+    /// Authenticate the callers as members of `project`, so that only the tokens
+    /// without an audience or meant for `project` are accepted
+    pub fn serve_project(&self, project: Option<&str>) {
+        self.state().project = project.map(str::to_string);
+    }
+
+    /// Expire `token` now, whatever its validity
+    pub fn expire(&self, token: &Token) -> Result<()> {
+        let now = self.now()?;
+        if let Some(generated) = self.state().tokens.get_mut(token.reveal()) {
+            generated.expires_at = Some(now);
+        }
+        Ok(())
+    }
+
+    /// Return the tokens generated so far, in lexicographic order
+    pub fn generated_tokens(&self) -> Vec<Token> {
+        self.state().tokens.keys().map(Token::new).collect()
+    }
+
+    /// Return how many times `token` can still be used, if it was generated
+    pub fn usage_remaining(&self, token: &Token) -> Option<u32> {
+        let state = self.state();
+        state.tokens.get(token.reveal()).map(|t| t.usage_remaining)
+    }
+
+    fn now(&self) -> Result<u64> {
+        let clock = self.clock.lock().unwrap().clone();
+        clock.now()
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+
+    /// Return the response to `req`, sent to `service`, whose body is still to be
+    /// decoded from `dec`
+    fn respond(
+        &self,
+        req: &Request,
+        service: &str,
+        dec: &mut Decoder<'_>,
+        caller: Option<IdentityIdentifier>,
+    ) -> Result<Vec<u8>> {
+        let (mode, rejection) = {
+            let state = self.state();
+            (state.mode.clone(), state.rejection)
+        };
+        if let Some(status) = rejection {
+            return error(req, status, &format!("rejected with {status}"));
+        }
+        match mode {
+            MockMode::Accept(Some(claims)) => return ok(req, claims),
+            MockMode::Accept(None) => return Ok(Response::ok(req.id()).to_vec()?),
+            MockMode::EnrollCaller => return ok(req, caller_claims(caller)),
+            _ => (),
+        }
+        let now = self.now()?;
+        match req.path().rsplit('/').next() {
+            Some("") => self.generate(req, dec.decode()?, now),
+            Some("validate") => {
+                let body: RequestEnrollmentToken = dec.decode()?;
+                let mut validated = ValidatedEnrollmentToken::new(body.attributes)
+                    .with_usage_count(body.usage_count.unwrap_or(1));
+                if let Some(expires_in) = body.expires_in {
+                    validated = validated.with_expires_at(now + expires_in);
+                }
+                ok(req, validated)
+            }
+            Some("enroll") => match service {
+                ENROLLMENT_TOKEN_AUTHENTICATOR => {
+                    self.authenticate(req, dec.decode()?, now, caller)
+                }
+                API_KEY_AUTHENTICATOR => {
+                    let key: AuthenticateApiKey = dec.decode()?;
+                    let accepted = self.state().api_key.clone();
+                    if accepted.as_ref().map(Token::reveal) == Some(key.token.reveal()) {
+                        Ok(Response::ok(req.id()).to_vec()?)
+                    } else {
+                        error(req, Status::Forbidden, "unknown api key")
+                    }
+                }
+                CLIENT_CERTIFICATE_AUTHENTICATOR => {
+                    let certificate: AuthenticateClientCertificate = dec.decode()?;
+                    let fingerprint = Some(certificate.fingerprint());
+                    if self.state().certificate_fingerprint == fingerprint {
+                        Ok(Response::ok(req.id()).to_vec()?)
+                    } else {
+                        error(req, Status::Forbidden, "unknown client certificate")
+                    }
+                }
+                _ => Ok(Response::ok(req.id()).to_vec()?),
+            },
+            Some("revoke") => self.revoke(req, dec.decode()?),
+            Some("introspect") => self.introspect(req, dec.decode()?, now),
+            Some("list") => self.list(req, dec.decode()?),
+            Some("health") => Ok(Response::ok(req.id()).to_vec()?),
+            Some("time") => ok(req, ServerTime::new(now)),
+            _ => error(req, Status::NotFound, "unknown path"),
+        }
+    }
+
+    fn generate(&self, req: &Request, body: RequestEnrollmentToken, now: u64) -> Result<Vec<u8>> {
+        let mut state = self.state();
+        let index = state.generation_requests.len();
+        state.generation_requests.push((req.clone(), body.clone()));
+        if state.failing_generation == Some(index) {
+            return error(req, Status::BadRequest, "the token generation failed");
+        }
+        let key = body
+            .idempotency_key
+            .as_ref()
+            .map(|k| k.reveal().to_string());
+        let existing = key.as_ref().and_then(|k| state.idempotency_keys.get(k));
+        let token = match existing {
+            Some(token) => token.clone(),
+            None => {
+                let token = format!("token-{}", state.tokens.len());
+                let attributes_expire_at = body
+                    .attributes_expires_in
+                    .into_iter()
+                    .flatten()
+                    .map(|(key, expires_in)| (key, now + expires_in))
+                    .collect();
+                let generated = GeneratedToken {
+                    attributes: body.attributes,
+                    attributes_expire_at,
+                    usage_remaining: body.usage_count.unwrap_or(1),
+                    expires_at: body.expires_in.map(|expires_in| now + expires_in),
+                    not_before: body.not_before,
+                    bound_identifier: body.bound_identifier,
+                    audience: body.audience,
+                    label: body.label,
+                    revoked: false,
+                };
+                state.tokens.insert(token.clone(), generated);
+                if let Some(key) = key {
+                    state.idempotency_keys.insert(key, token.clone());
+                }
+                token
+            }
+        };
+        let mut generated = EnrollmentToken::new(Token::new(&token));
+        if let Some(expires_at) = state.tokens[&token].expires_at {
+            generated = generated.with_expires_at(expires_at);
+        }
+        if let Some(not_before) = state.tokens[&token].not_before {
+            generated = generated.with_not_before(not_before);
+        }
+        if let Some(bound_identifier) = &state.tokens[&token].bound_identifier {
+            generated = generated.with_bound_identifier(bound_identifier.clone());
+        }
+        if let Some(audience) = &state.tokens[&token].audience {
+            generated = generated.with_audience(audience.clone());
+        }
+        if let Some(version) = state.token_version {
+            generated.version = version;
+        }
+        ok(req, generated)
+    }
+
+    fn authenticate(
+        &self,
+        req: &Request,
+        body: EnrollmentToken,
+        now: u64,
+        caller: Option<IdentityIdentifier>,
+    ) -> Result<Vec<u8>> {
+        let mut state = self.state();
+        let credential_expires_at = state.credential_lifetime.map(|lifetime| now + lifetime);
+        let project = state.project.clone();
+        let Some(generated) = state.tokens.get_mut(body.token.reveal()) else {
+            return error(req, Status::Unauthorized, "unknown enrollment token");
+        };
+        if generated.revoked {
+            return error(
+                req,
+                Status::Unauthorized,
+                "the enrollment token was revoked",
+            );
+        }
+        if is_expired(generated, now) {
+            return error(
+                req,
+                Status::Unauthorized,
+                "the enrollment token has expired",
+            );
+        }
+        if generated.not_before.map(|t| now < t).unwrap_or(false) {
+            return error(
+                req,
+                Status::Forbidden,
+                "the enrollment token is not valid yet",
+            );
+        }
+        if generated.usage_remaining == 0 {
+            return error(
+                req,
+                Status::Unauthorized,
+                "the enrollment token was used up",
+            );
+        }
+        // the binding is checked against the generated token, since the one
+        // presented by the device may not report it
+        if let Some(bound) = &generated.bound_identifier {
+            let body = body.clone().with_bound_identifier(bound.clone());
+            if !body.is_usable_by(body.device_identifier.as_ref()) {
+                return error(
+                    req,
+                    Status::Forbidden,
+                    "the enrollment token is bound to another device",
+                );
+            }
+        }
+        // so is the audience, which the presented token may not report either
+        if let Some(audience) = &generated.audience {
+            let body = body.clone().with_audience(audience.clone());
+            if !body.is_usable_in(project.as_deref()) {
+                return error(
+                    req,
+                    Status::Forbidden,
+                    "the enrollment token is meant for another project",
+                );
+            }
+        }
+        generated.usage_remaining -= 1;
+        let claims = EnrollClaims {
+            #[cfg(feature = "tag")]
+            tag: TypeTag,
+            identity: caller,
+            attributes: Some(granted_attributes(generated, now)),
+            expires_at: generated.expires_at,
+            credential_expires_at,
+        };
+        ok(req, claims)
+    }
+
+    fn revoke(&self, req: &Request, body: RevokeEnrollmentToken) -> Result<Vec<u8>> {
+        let mut state = self.state();
+        match state.tokens.get_mut(body.token.reveal()) {
+            None => error(req, Status::NotFound, "unknown enrollment token"),
+            Some(generated) if generated.revoked => error(
+                req,
+                Status::Conflict,
+                "the enrollment token was already revoked",
+            ),
+            Some(generated) => {
+                generated.revoked = true;
+                Ok(Response::ok(req.id()).to_vec()?)
+            }
+        }
+    }
+
+    fn introspect(
+        &self,
+        req: &Request,
+        body: IntrospectEnrollmentToken,
+        now: u64,
+    ) -> Result<Vec<u8>> {
+        let state = self.state();
+        let introspection = match state.tokens.get(body.token.reveal()) {
+            Some(generated) if is_active(generated, now) => {
+                let mut introspection =
+                    EnrollmentTokenIntrospection::active(generated.attributes.clone())
+                        .with_usage_remaining(generated.usage_remaining);
+                if let Some(expires_at) = generated.expires_at {
+                    introspection = introspection.with_expires_at(expires_at);
+                }
+                if let Some(label) = &generated.label {
+                    introspection = introspection.with_label(label);
+                }
+                introspection
+            }
+            _ => EnrollmentTokenIntrospection::inactive(),
+        };
+        ok(req, introspection)
+    }
+
+    fn list(&self, req: &Request, body: ListEnrollmentTokens) -> Result<Vec<u8>> {
+        let state = self.state();
+        let page_size = body.page_size.max(1) as usize;
+        let start = body.page as usize * page_size;
+        let tokens: Vec<EnrollmentTokenMetadata> = state
+            .tokens
+            .iter()
+            .skip(start)
+            .take(page_size)
+            .map(|(id, generated)| {
+                let mut metadata = EnrollmentTokenMetadata::new(id, generated.attributes.clone())
+                    .with_usage_remaining(generated.usage_remaining);
+                if let Some(expires_at) = generated.expires_at {
+                    metadata = metadata.with_expires_at(expires_at);
+                }
+                if let Some(label) = &generated.label {
+                    metadata = metadata.with_label(label);
+                }
+                metadata
+            })
+            .collect();
+        let next_page = (start + page_size < state.tokens.len()).then_some(body.page + 1);
+        ok(req, EnrollmentTokenPage::new(tokens, next_page))
+    }
+}
+
+#[async_trait]
+impl Worker for MockAuthenticator {
+    type Context = Context;
+    type Message = Vec<u8>;
+
+    async fn handle_message(
+        &mut self,
+        ctx: &mut Context,
+        msg: Routed<Self::Message>,
+    ) -> Result<()> {
+        let mode = self.state().mode.clone();
+        if let MockMode::Unreachable = mode {
+            return Ok(());
+        }
+        let caller = IdentitySecureChannelLocalInfo::find_info(msg.local_message())
+            .ok()
+            .map(|info| info.their_identity_id());
+        let mut dec = Decoder::new(msg.as_body());
+        let req: Request = dec.decode()?;
+        if let MockMode::Hold = mode {
+            self.state().held.push((msg.return_route(), req.id()));
+            return Ok(());
+        }
+        let service = msg.msg_addr().address().to_string();
+        let res = self.respond(&req, &service, &mut dec, caller)?;
+        ctx.send(msg.return_route(), res).await
+    }
+}
+
+/// Claims enrolling the identity `caller`, without attributes
+fn caller_claims(caller: Option<IdentityIdentifier>) -> EnrollClaims {
+    EnrollClaims {
+        #[cfg(feature = "tag")]
+        tag: TypeTag,
+        identity: caller,
+        attributes: None,
+        expires_at: None,
+        credential_expires_at: None,
+    }
+}
+
+fn is_expired(generated: &GeneratedToken, now: u64) -> bool {
+    generated
+        .expires_at
+        .map_or(false, |expires_at| now >= expires_at)
+}
+
+/// Return the attributes of `generated` which are still granted at `now`
+fn granted_attributes(generated: &GeneratedToken, now: u64) -> Attributes {
+    let mut granted = Attributes::new();
+    for (key, value) in generated.attributes.iter() {
+        let expire_at = generated.attributes_expire_at.get(key);
+        if expire_at.map_or(true, |expire_at| now < *expire_at) {
+            granted.put(key, value);
+        }
+    }
+    granted
+}
+
+fn is_active(generated: &GeneratedToken, now: u64) -> bool {
+    !generated.revoked && !is_expired(generated, now) && generated.usage_remaining > 0
+}
+
+fn ok<T: Encode<()>>(req: &Request, body: T) -> Result<Vec<u8>> {
+    Ok(Response::ok(req.id()).body(body).to_vec()?)
+}
+
+fn error(req: &Request, status: Status, message: &str) -> Result<Vec<u8>> {
+    let body = Error::new(req.path()).with_message(message);
+    Ok(Response::builder(req.id(), status).body(body).to_vec()?)
+}
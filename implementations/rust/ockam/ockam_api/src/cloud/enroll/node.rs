@@ -0,0 +1,2272 @@
+use std::error::Error as _;
+use std::future::Future;
+use std::iter;
+use std::time::Duration;
+
+use futures::Stream;
+use minicbor::data::Type;
+use minicbor::{Decode, Decoder, Encode};
+use tracing::{debug, field, info_span, trace, warn, Instrument, Span};
+
+use ockam::identity::credential::Attributes;
+use ockam::identity::SecureChannel;
+use ockam_core::api::{Error, Id, Request, RequestBuilder, Response, Status};
+use ockam_core::{self, Result};
+use ockam_multiaddr::MultiAddr;
+use ockam_node::api::request_with_options;
+#[cfg(feature = "auth0")]
+use ockam_node::tokio::sync::oneshot;
+use ockam_node::tokio::time;
+use ockam_node::{Context, MessageSendReceiveOptions, DEFAULT_TIMEOUT};
+use ockam_vault::{PublicKey, Signature};
+
+use crate::cloud::enroll::api_key::AuthenticateApiKey;
+#[cfg(feature = "auth0")]
+use crate::cloud::enroll::auth0::{
+    poll_device_code, refresh_token, request_device_code, until_cancelled, DeviceCode,
+    DeviceFlowError, DeviceFlowInstructions,
+};
+use crate::cloud::enroll::client_certificate::{AuthenticateClientCertificate, ClientCertificate};
+use crate::cloud::enroll::dedup_cache::{fingerprint, load_recent_token, IssuedToken};
+use crate::cloud::enroll::enrollment_token::{
+    check_delegated, enter_field, AttributesLimits, EnrollmentToken, EnrollmentTokenIntrospection,
+    EnrollmentTokenPage, EnrollmentTokenTemplate, GeneratedEnrollmentToken,
+    IntrospectEnrollmentToken, ListEnrollmentTokens, RequestEnrollmentToken, RevokeEnrollmentToken,
+    ValidatedEnrollmentToken, ENROLLMENT_TOKEN_VERSION,
+};
+use crate::cloud::enroll::events::{self, EnrollEvent, EnrollFlow, EnrollOutcome};
+use crate::cloud::enroll::oidc::{AuthenticateOidcToken, OidcTokenProvider};
+use crate::cloud::enroll::persisted::PersistedEnrollment;
+use crate::cloud::enroll::preflight::{EnrollPreflight, ServerTime};
+use crate::cloud::enroll::rate_limiter::rate_limit_key;
+use crate::cloud::enroll::secure_channel_address::SecureChannelAddress;
+use crate::cloud::enroll::shared_channel::RetiredChannel;
+use crate::cloud::enroll::timings::EnrollTimings;
+#[cfg(feature = "auth0")]
+use crate::cloud::enroll::{oidc::OidcToken, token_cache::load_valid_token};
+use crate::cloud::retry::is_transient;
+use crate::cloud::{CloudRequestWrapper, ORCHESTRATOR_RESTART_TIMEOUT};
+use crate::error::ApiError;
+use crate::nodes::{NodeManager, NodeManagerWorker};
+
+use super::{
+    try_decode_enroll_body, AuthenticateToken, EnrollError, EnrollResponse, Token,
+    ENROLL_TEARDOWN_GRACE_PERIOD, TARGET,
+};
+
+/// Time at which an enrollment given `budget` to complete gives up
+#[derive(Debug, Clone, Copy)]
+struct Deadline {
+    budget: Duration,
+    at: time::Instant,
+}
+
+impl Deadline {
+    fn new(budget: Duration) -> Self {
+        Self {
+            budget,
+            at: time::Instant::now() + budget,
+        }
+    }
+
+    /// The same deadline, postponed by `grace`
+    fn extended(self, grace: Duration) -> Self {
+        Self {
+            at: self.at + grace,
+            ..self
+        }
+    }
+}
+
+/// Run `f`, giving up with `EnrollError::DeadlineExceeded` if it is still
+/// running at the `deadline`, when it is set
+async fn within<T>(
+    deadline: Option<Deadline>,
+    f: impl Future<Output = std::result::Result<T, EnrollError>>,
+) -> std::result::Result<T, EnrollError> {
+    match deadline {
+        Some(deadline) => time::timeout_at(deadline.at, f)
+            .await
+            .unwrap_or(Err(EnrollError::DeadlineExceeded(deadline.budget))),
+        None => f.await,
+    }
+}
+
+/// Request for an enrollment token which passed the checks of the node
+pub(crate) struct TokenRequest {
+    /// Body sent to the authenticator, the same one on every attempt
+    body: RequestEnrollmentToken,
+    /// Fingerprint of the request, when the generated tokens are deduplicated
+    fingerprint: Option<String>,
+}
+
+/// Outcome of the checks of a request for an enrollment token done by the node
+pub(crate) enum TokenCheck {
+    /// The request passed the checks and can be sent to the authenticator
+    Send(TokenRequest),
+    /// The token generated for the same request within the dedup window
+    Deduplicated(EnrollmentToken),
+    /// The request is refused by the node, with an [`EnrollError::Refused`] error
+    Refused(EnrollError),
+}
+
+/// Span covering an enrollment flow, so that the logs of concurrent flows can be told apart.
+///
+/// `request_id` is the id of the node API request which started the flow, if any.
+/// The outcome of the flow is recorded with `record_result` or `record_response`.
+fn enroll_span(flow: &'static str, request_id: Option<Id>) -> Span {
+    info_span!(
+        target: TARGET,
+        "enroll",
+        flow,
+        request_id = request_id.map(field::display),
+        outcome = field::Empty,
+        status = field::Empty,
+        channel_setup_ms = field::Empty,
+        request_ms = field::Empty,
+        teardown_ms = field::Empty,
+    )
+}
+
+/// Record the outcome of a flow which doesn't answer a node API request
+#[cfg(feature = "auth0")]
+fn record_result<T>(span: &Span, res: &Result<T>) {
+    span.record("outcome", if res.is_ok() { "ok" } else { "error" });
+}
+
+/// Record the outcome of a flow answering the node API request with `res`
+fn record_response(span: &Span, res: &Result<Vec<u8>>) {
+    let header = res
+        .as_ref()
+        .ok()
+        .and_then(|res| Response::parse_response_header(res).ok());
+    match header.map(|(header, _)| header.status()) {
+        Some(Some(status)) => {
+            let outcome = if status == Status::Ok { "ok" } else { "error" };
+            span.record("outcome", outcome);
+            span.record("status", field::display(status));
+        }
+        _ => {
+            span.record("outcome", "error");
+        }
+    }
+}
+
+/// Return the route to the Orchestrator of the node API request `req`.
+///
+/// If the route is empty or malformed, the error holds a `BadRequest` response.
+pub(crate) fn request_route<T>(
+    req: &Request,
+    req_wrapper: &CloudRequestWrapper<T>,
+) -> std::result::Result<MultiAddr, Result<Vec<u8>>> {
+    req_wrapper.multiaddr().map_err(|err| {
+        debug!(target: TARGET, %err, "invalid route");
+        // the cause describes the route issue, without the error code and location
+        let message = err
+            .source()
+            .map_or_else(|| err.to_string(), |e| e.to_string());
+        let body = Error::new(req.path()).with_message(message);
+        Ok(Response::bad_request(req.id()).body(body).to_vec()?)
+    })
+}
+
+/// Decode the body of the node API request `req`.
+///
+/// If the body is malformed, or its type tag doesn't match the `tag` feature
+/// of this node, the error holds a `BadRequest` response saying why.
+pub(crate) fn decode_request_body<'b, T: Decode<'b, ()>>(
+    req: &Request,
+    dec: &mut Decoder<'b>,
+) -> std::result::Result<T, Result<Vec<u8>>> {
+    let start = dec.position();
+    if let Some(message) = type_tag_mismatch(dec.input(), start, cfg!(feature = "tag")) {
+        debug!(target: TARGET, %message, "request with a mismatched type tag");
+        let body = Error::new(req.path()).with_message(message);
+        return Err(Response::bad_request(req.id())
+            .body(body)
+            .to_vec()
+            .map_err(Into::into));
+    }
+    dec.decode().map_err(|err: minicbor::decode::Error| {
+        // missing values are reported with their field name by the error itself, otherwise
+        // the decoder stops right after the first byte of the value which couldn't be decoded
+        let path = if err.is_missing_value() {
+            vec![]
+        } else {
+            field_path(dec.input(), start, dec.position().saturating_sub(1))
+        };
+        let message = if path.is_empty() {
+            format!("invalid request body: {err}")
+        } else {
+            format!("invalid request body at field {}: {err}", path.join("."))
+        };
+        debug!(target: TARGET, %message, "malformed request");
+        let body = Error::new(req.path()).with_message(message);
+        Ok(Response::bad_request(req.id()).body(body).to_vec()?)
+    })
+}
+
+/// Decode the body of a request for an enrollment token: a `RequestEnrollmentToken`,
+/// or only its attributes for the clients built before it was introduced.
+///
+/// The bodies can't be confused, since the attributes are keyed by their name and
+/// the fields of a `RequestEnrollmentToken` by their number. Like with
+/// [`decode_request_body`], the error holds a `BadRequest` response.
+pub(crate) fn decode_token_request(
+    req: &Request,
+    dec: &mut Decoder<'_>,
+) -> std::result::Result<CloudRequestWrapper<RequestEnrollmentToken>, Result<Vec<u8>>> {
+    let mut body = dec.clone();
+    if enter_field(&mut body, 1) && are_attributes(&body) {
+        let req_wrapper: CloudRequestWrapper<Attributes> = decode_request_body(req, dec)?;
+        return Ok(req_wrapper.map(RequestEnrollmentToken::new));
+    }
+    decode_request_body(req, dec)
+}
+
+/// Return true if the value found at the position of `dec` are attributes, whose
+/// field 1 maps their names to their values, rather than a `RequestEnrollmentToken`,
+/// whose field 1 holds its attributes
+fn are_attributes(dec: &Decoder<'_>) -> bool {
+    let mut dec = dec.clone();
+    if !enter_field(&mut dec, 1) {
+        return false;
+    }
+    match dec.map() {
+        Ok(Some(0)) => true,
+        Ok(_) => matches!(
+            dec.datatype(),
+            Ok(Type::String | Type::StringIndef | Type::Break)
+        ),
+        Err(_) => false,
+    }
+}
+
+/// Return why the body of a request, which starts at `start` of `input`, doesn't
+/// match the schema of this node if it has a type tag while this node was built
+/// without the `tag` feature, or the other way around. The tag is the field 0
+/// of the body: a node which doesn't `expect_tag` would otherwise ignore it, and
+/// a node which does would only report a missing field.
+///
+/// Bodies which are not maps are left for the decoder to reject.
+pub(crate) fn type_tag_mismatch(input: &[u8], start: usize, expect_tag: bool) -> Option<String> {
+    let mut dec = Decoder::new(input);
+    dec.set_position(start);
+    let mut remaining = dec.map().ok()?;
+    let mut tagged = false;
+    loop {
+        match remaining {
+            Some(0) => break,
+            Some(n) => remaining = Some(n - 1),
+            None if dec.datatype().ok()? == Type::Break => break,
+            None => {}
+        }
+        tagged |= dec.u32().ok()? == 0;
+        dec.skip().ok()?;
+    }
+    match (tagged, expect_tag) {
+        (true, false) => Some(
+            "the request body has a type tag: it was sent by a client built with the `tag` \
+             feature, which this node was built without"
+                .to_string(),
+        ),
+        (false, true) => Some(
+            "the request body has no type tag: it was sent by a client built without the \
+             `tag` feature, which this node was built with"
+                .to_string(),
+        ),
+        _ => None,
+    }
+}
+
+fn no_enroll_channel() -> EnrollError {
+    EnrollError::SecureChannel(ApiError::generic("no enrollment channel is open"))
+}
+
+/// Answer `req` once the last attempt at generating its token failed with `err`,
+/// with a timeout or an unavailable service status for the transport failures
+fn generation_failed_response(req: &Request, err: ockam_core::Error) -> Result<Vec<u8>> {
+    debug!(target: TARGET, %err, "enrollment token generation failed");
+    EnrollError::Transport(err).to_response(req)
+}
+
+/// Check the attributes of the body of `req`, a `RequestEnrollmentToken` or only
+/// its attributes like with [`decode_token_request`], against `limits`, while they
+/// are still encoded.
+///
+/// Like with [`decode_request_body`], the error holds a `BadRequest` response.
+/// A request with too many or too large attributes is then rejected before
+/// its attributes are decoded in memory.
+pub(crate) fn check_encoded_attributes(
+    req: &Request,
+    dec: &Decoder<'_>,
+    limits: &AttributesLimits,
+) -> std::result::Result<(), Result<Vec<u8>>> {
+    let mut dec = dec.clone();
+    // the attributes are the field 1 of the wrapper when they are sent alone,
+    // otherwise the request, with its typed attributes, is the field 1 of the wrapper
+    if !enter_field(&mut dec, 1) {
+        return Ok(());
+    }
+    let checked = if are_attributes(&dec) {
+        limits.check_encoded(&mut dec)
+    } else {
+        limits.check_encoded_request(&dec)
+    };
+    checked.map_err(|err| {
+        debug!(target: TARGET, %err, "oversized attributes");
+        let body = Error::new(req.path()).with_message(err.to_string());
+        Ok(Response::bad_request(req.id()).body(body).to_vec()?)
+    })
+}
+
+/// Return the keys of the nested maps leading to the value found at
+/// `pos` in `input`, starting from the map found at `start`.
+fn field_path(input: &[u8], start: usize, pos: usize) -> Vec<String> {
+    let mut path = Vec::new();
+    let mut dec = Decoder::new(input);
+    dec.set_position(start);
+    'maps: while let Ok(Some(len)) = dec.map() {
+        for _ in 0..len {
+            let key = match dec.datatype() {
+                Ok(Type::String) => dec.str().map(String::from),
+                _ => dec.u64().map(|k| k.to_string()),
+            };
+            let Ok(key) = key else { break 'maps };
+            let value = dec.position();
+            // a value which can't be skipped contains the error
+            let found = dec.skip().map(|_| pos < dec.position()).unwrap_or(true);
+            if value <= pos && found {
+                path.push(key);
+                dec.set_position(value);
+                continue 'maps;
+            }
+        }
+        break;
+    }
+    path
+}
+
+/// Decode the body of a successful response
+fn decode_body<'a, T: Decode<'a, ()>>(res: &'a [u8]) -> std::result::Result<T, EnrollError> {
+    let mut dec = Decoder::new(res);
+    dec.decode::<Response>()
+        .and_then(|_| dec.decode::<T>())
+        .map_err(EnrollError::Decode)
+}
+
+impl EnrollError {
+    /// Encode this error as a response to `req`
+    pub(crate) fn to_response(&self, req: &Request) -> Result<Vec<u8>> {
+        let err = Error::new(req.path()).with_message(self.to_string());
+        Ok(Response::builder(req.id(), self.status())
+            .body(err)
+            .to_vec()?)
+    }
+}
+
+impl EnrollResponse {
+    /// Forward the authenticator response as a response to `req`.
+    ///
+    /// The status and body are the ones of the authenticator, but the response
+    /// answers `req` rather than the request sent to the authenticator.
+    pub(crate) fn to_response(&self, req: &Request) -> Result<Vec<u8>> {
+        let (header, dec) = Response::parse_response_header(&self.raw)?;
+        let status = header.status().unwrap_or(Status::Ok);
+        let mut res = minicbor::to_vec(Response::new(req.id(), status, header.has_body()))?;
+        res.extend_from_slice(&self.raw[dec.position()..]);
+        Ok(res)
+    }
+}
+
+impl NodeManager {
+    #[cfg(feature = "auth0")]
+    /// Executes an enrollment process to generate a new set of access tokens using the auth0 flow.
+    ///
+    /// The identity named `identity_name` is enrolled with `token`, or the node identity if
+    /// it is not set. The token is cached for the enrolled identity, and the returned
+    /// enrollment can be saved to schedule the next one.
+    ///
+    /// A token which can't be cached is only logged, since the enrollment succeeded:
+    /// the next enrollment then runs a new flow.
+    pub async fn enroll_auth0(
+        &self,
+        ctx: &Context,
+        identity_name: Option<String>,
+        route: &MultiAddr,
+        token: OidcToken,
+    ) -> Result<PersistedEnrollment> {
+        let span = enroll_span("auth0", None);
+        let res = async {
+            let identifier = self.get_identifier(identity_name.clone()).await?;
+            let request = AuthenticateToken::Auth0(AuthenticateOidcToken::new(token.clone()));
+            let enrollment = self
+                .enroll_with_token(ctx, identity_name, route, request)
+                .await?;
+            if let Err(err) = self
+                .enroll_options
+                .token_cache
+                .store(&identifier, &token)
+                .await
+            {
+                warn!(target: TARGET, %err, "failed to cache the auth0 token");
+            }
+            Ok(enrollment)
+        }
+        .instrument(span.clone())
+        .await;
+        record_result(&span, &res);
+        res
+    }
+
+    #[cfg(feature = "auth0")]
+    /// Executes an enrollment process using the auth0 flow, reusing the token cached for
+    /// the enrolled identity when it is still valid.
+    ///
+    /// `new_token` is only called to run a new flow when there is no such token, or when
+    /// the cached token is rejected. The token used for a successful enrollment is cached.
+    pub async fn enroll_auth0_cached<F, Fut>(
+        &self,
+        ctx: &Context,
+        identity_name: Option<String>,
+        route: &MultiAddr,
+        new_token: F,
+    ) -> Result<PersistedEnrollment>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<OidcToken>>,
+    {
+        let identifier = self.get_identifier(identity_name.clone()).await?;
+        let cache = self.enroll_options.token_cache.as_ref();
+        let now = self.enroll_options.clock.now()?;
+        let cached =
+            load_valid_token(cache, &identifier, now, self.enroll_options.expiry_jitter).await;
+        let cached = cached.unwrap_or_else(|err| {
+            warn!(target: TARGET, %err, "failed to load the cached auth0 token");
+            None
+        });
+        if let Some(token) = cached {
+            trace!(target: TARGET, "using the cached auth0 token");
+            let request = AuthenticateToken::Auth0(AuthenticateOidcToken::new(token));
+            match self
+                .authenticate_token(ctx, identity_name.clone(), route, request, None)
+                .await
+            {
+                Ok(response) => {
+                    let now = self.enroll_options.clock.now()?;
+                    return Ok(PersistedEnrollment::new(EnrollFlow::Auth0, &response, now));
+                }
+                Err(EnrollError::Rejected { .. }) => {
+                    debug!(target: TARGET, "the cached auth0 token was rejected");
+                    if let Err(err) = cache.clear(&identifier).await {
+                        warn!(target: TARGET, %err, "failed to clear the cached auth0 token");
+                    }
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
+        self.enroll_auth0(ctx, identity_name, route, new_token().await?)
+            .await
+    }
+
+    #[cfg(feature = "auth0")]
+    /// Executes an enrollment process using the auth0 flow, with a token obtained
+    /// from the node `auth0_token_provider` rather than from a node API client.
+    ///
+    /// It fails if the node has no such provider.
+    pub async fn enroll_auth0_with_provider(
+        &self,
+        ctx: &Context,
+        identity_name: Option<String>,
+        route: &MultiAddr,
+    ) -> Result<PersistedEnrollment> {
+        let provider = self
+            .enroll_options
+            .auth0_token_provider
+            .as_ref()
+            .ok_or_else(|| ApiError::generic("the node has no auth0 token provider"))?;
+        self.enroll_auth0_cached(ctx, identity_name, route, || {
+            trace!(target: TARGET, "getting an auth0 token from the node provider");
+            provider.token()
+        })
+        .await
+    }
+
+    /// Executes an enrollment process with an API key issued by the Orchestrator.
+    pub async fn enroll_api_key(
+        &self,
+        ctx: &Context,
+        identity_name: Option<String>,
+        route: &MultiAddr,
+        api_key: Token,
+    ) -> Result<PersistedEnrollment> {
+        let token = AuthenticateToken::ApiKey(AuthenticateApiKey::new(api_key));
+        trace!(target: TARGET, "executing api key flow");
+        self.enroll_with_token(ctx, identity_name, route, token)
+            .await
+    }
+
+    /// Executes an enrollment process with the client certificate a device presented
+    /// over mutual TLS, checked by the Orchestrator "mtls_authenticator" service.
+    pub async fn enroll_client_certificate(
+        &self,
+        ctx: &Context,
+        identity_name: Option<String>,
+        route: &MultiAddr,
+        certificate: &ClientCertificate,
+    ) -> Result<PersistedEnrollment> {
+        let token = AuthenticateClientCertificate::new(certificate)?;
+        trace!(target: TARGET, fingerprint = %token.fingerprint(), "executing client certificate flow");
+        let token = AuthenticateToken::ClientCertificate(token);
+        self.enroll_with_token(ctx, identity_name, route, token)
+            .await
+    }
+
+    /// Executes an enrollment process with a token issued by any OIDC provider.
+    ///
+    /// `authenticator` is the name of the Orchestrator service checking the tokens of that provider.
+    pub async fn enroll_oidc(
+        &self,
+        ctx: &Context,
+        identity_name: Option<String>,
+        route: &MultiAddr,
+        authenticator: &str,
+        provider: &impl OidcTokenProvider,
+    ) -> Result<PersistedEnrollment> {
+        let token = AuthenticateToken::Oidc {
+            authenticator: authenticator.to_string(),
+            token: AuthenticateOidcToken::new(provider.token().await?),
+        };
+        trace!(target: TARGET, %authenticator, "executing oidc flow");
+        self.enroll_with_token(ctx, identity_name, route, token)
+            .await
+    }
+
+    /// Authenticates `token`, and records what the enrollment granted
+    async fn enroll_with_token(
+        &self,
+        ctx: &Context,
+        identity_name: Option<String>,
+        route: &MultiAddr,
+        token: AuthenticateToken,
+    ) -> Result<PersistedEnrollment> {
+        let flow = EnrollFlow::from(&token);
+        let response = self
+            .authenticate_token(ctx, identity_name, route, token, None)
+            .await?;
+        Ok(PersistedEnrollment::new(
+            flow,
+            &response,
+            self.enroll_options.clock.now()?,
+        ))
+    }
+
+    #[cfg(feature = "auth0")]
+    /// Executes an enrollment process to generate a new set of access tokens using the auth0 flow.
+    pub(crate) async fn enroll_auth0_response(
+        &self,
+        ctx: &Context,
+        req: &Request,
+        req_wrapper: CloudRequestWrapper<AuthenticateOidcToken>,
+    ) -> Result<Vec<u8>> {
+        let token = &req_wrapper.req;
+        let tokens = iter::once(&token.access_token).chain(&token.refresh_token);
+        if let Some(message) = self.check_token_lengths(tokens) {
+            let err = Error::new(req.path()).with_message(message);
+            return Ok(Response::bad_request(req.id()).body(err).to_vec()?);
+        }
+        let route = match request_route(req, &req_wrapper) {
+            Ok(route) => route,
+            Err(res) => return res,
+        };
+        let identity_name = req_wrapper.identity_name;
+        self.authenticate_auth0_token_as(ctx, req, identity_name, req_wrapper.req, &route)
+            .await
+    }
+
+    #[cfg(feature = "auth0")]
+    /// Authenticates an auth0 token obtained by the caller, for the node identity,
+    /// and returns the response to `req`.
+    ///
+    /// Unlike the `enroll` endpoint, the token is not decoded from the request body,
+    /// so that it can be passed by the applications embedding the node.
+    pub async fn authenticate_auth0_token(
+        &self,
+        ctx: &Context,
+        req: &Request,
+        token: OidcToken,
+        cloud_route: &MultiAddr,
+    ) -> Result<Vec<u8>> {
+        let token = AuthenticateOidcToken::new(token);
+        self.authenticate_auth0_token_as(ctx, req, None, token, cloud_route)
+            .await
+    }
+
+    #[cfg(feature = "auth0")]
+    async fn authenticate_auth0_token_as(
+        &self,
+        ctx: &Context,
+        req: &Request,
+        identity_name: Option<String>,
+        token: AuthenticateOidcToken,
+        route: &MultiAddr,
+    ) -> Result<Vec<u8>> {
+        let span = enroll_span("auth0", Some(req.id()));
+        let res = async {
+            trace!(target: TARGET, "executing auth0 flow");
+            let token = AuthenticateToken::Auth0(token);
+            match self
+                .authenticate_token(ctx, identity_name, route, token, Some(req.id()))
+                .await
+            {
+                Ok(res) => res.to_response(req),
+                Err(err) => {
+                    debug!(target: TARGET, %err, "auth0 flow failed");
+                    err.to_response(req)
+                }
+            }
+        }
+        .instrument(span.clone())
+        .await;
+        record_response(&span, &res);
+        res
+    }
+
+    /// Executes an enrollment process with an API key sent to the unified enroll endpoint.
+    async fn enroll_api_key_response(
+        &self,
+        ctx: &Context,
+        req: &Request,
+        req_wrapper: CloudRequestWrapper<AuthenticateApiKey>,
+    ) -> Result<Vec<u8>> {
+        if let Some(message) = self.check_token_lengths([&req_wrapper.req.token]) {
+            let err = Error::new(req.path()).with_message(message);
+            return Ok(Response::bad_request(req.id()).body(err).to_vec()?);
+        }
+        let route = match request_route(req, &req_wrapper) {
+            Ok(route) => route,
+            Err(res) => return res,
+        };
+        let span = enroll_span("api_key", Some(req.id()));
+        let res = async {
+            trace!(target: TARGET, "executing api key flow");
+            let token = AuthenticateToken::ApiKey(req_wrapper.req);
+            match self
+                .authenticate_token(
+                    ctx,
+                    req_wrapper.identity_name,
+                    &route,
+                    token,
+                    Some(req.id()),
+                )
+                .await
+            {
+                Ok(res) => res.to_response(req),
+                Err(err) => {
+                    debug!(target: TARGET, %err, "api key flow failed");
+                    err.to_response(req)
+                }
+            }
+        }
+        .instrument(span.clone())
+        .await;
+        record_response(&span, &res);
+        res
+    }
+
+    /// Authenticates a token generated by `generate_enrollment_token`.
+    ///
+    /// Tokens which are known to be expired or revoked are rejected without
+    /// contacting the authenticator. So are the tokens which are already being
+    /// authenticated by this node, with a `409 Conflict` status, so that a replayed
+    /// single-use token can't race with its first use.
+    ///
+    /// `device_identifier` is the identifier presented by the device, or the one
+    /// of the request body if it is not set. A token bound to a device is rejected
+    /// with a `403 Forbidden` status when the identifiers don't match. So is a
+    /// token with an audience, unless it is the project of the node trust context.
+    ///
+    /// The binding, the audience and the not-before time of the request body are
+    /// set by the device, which can strip them, so checking them here only fails
+    /// fast: the authenticator enforces those of the token it generated.
+    pub(crate) async fn authenticate_enrollment_token_response(
+        &self,
+        ctx: &Context,
+        req: &Request,
+        req_wrapper: CloudRequestWrapper<EnrollmentToken>,
+        device_identifier: Option<Token>,
+    ) -> Result<Vec<u8>> {
+        let span = enroll_span("enrollment_token", Some(req.id()));
+        let res = async {
+            let cloud_multiaddr = match request_route(req, &req_wrapper) {
+                Ok(route) => route,
+                Err(res) => return res,
+            };
+            let mut req_body: EnrollmentToken = req_wrapper.req;
+            if device_identifier.is_some() {
+                req_body.device_identifier = device_identifier;
+            }
+            let now = self.enroll_options.clock.now()?;
+            let rejection =
+                if let Some(message) = self.check_token_lengths(iter::once(&req_body.token)) {
+                    Some((Status::BadRequest, message))
+                } else if !req_body.is_supported_version() {
+                    let message = format!(
+                    "unsupported enrollment token version {}, the latest supported version is {}",
+                    req_body.version(),
+                    ENROLLMENT_TOKEN_VERSION
+                );
+                    Some((Status::BadRequest, message))
+                } else if req_body.is_expired(now) {
+                    let message = "the enrollment token has expired".to_string();
+                    Some((Status::Unauthorized, message))
+                } else if let Some(not_before) = req_body
+                    .not_before
+                    .filter(|_| req_body.is_not_yet_valid(now))
+                {
+                    let message = format!(
+                        "the enrollment token can't be used before {not_before}, {}s from now",
+                        not_before - now
+                    );
+                    Some((Status::Forbidden, message))
+                } else if self.is_enrollment_token_revoked(&req_body.token, now) {
+                    let message = "the enrollment token was revoked".to_string();
+                    Some((Status::Unauthorized, message))
+                } else if !req_body.is_usable_by(req_body.device_identifier.as_ref()) {
+                    let message = "the enrollment token is bound to another device".to_string();
+                    Some((Status::Forbidden, message))
+                } else if !req_body.is_usable_in(self.trust_context().ok().map(|tc| tc.id())) {
+                    let message = "the enrollment token is meant for another project".to_string();
+                    Some((Status::Forbidden, message))
+                } else {
+                    None
+                };
+            // the token stays claimed until the end of its authentication
+            let claim = match rejection {
+                Some(_) => None,
+                None => self
+                    .enrollment_token_replay_guard
+                    .claim(&req_body.token, now),
+            };
+            let rejection = rejection.or_else(|| {
+                claim.is_none().then(|| {
+                    let message = "the enrollment token is already being used".to_string();
+                    (Status::Conflict, message)
+                })
+            });
+            if let Some((status, message)) = rejection {
+                self.enroll_options
+                    .enroll_metrics
+                    .enrollment_token_rejected();
+                let err = Error::new(req.path()).with_message(&message);
+                self.emit_enroll_event(
+                    EnrollFlow::EnrollmentToken,
+                    req_body.attributes.as_ref(),
+                    EnrollOutcome::Rejected {
+                        status,
+                        message: Some(message),
+                    },
+                );
+                return Ok(Response::builder(req.id(), status).body(err).to_vec()?);
+            }
+
+            trace!(target: TARGET, "authenticating token");
+            let token = AuthenticateToken::EnrollmentToken(req_body);
+            let identity_name = req_wrapper.identity_name;
+            match self
+                .authenticate_token(ctx, identity_name, &cloud_multiaddr, token, Some(req.id()))
+                .await
+            {
+                Ok(res) => res.to_response(req),
+                Err(err) => {
+                    debug!(target: TARGET, %err, "enrollment token authentication failed");
+                    err.to_response(req)
+                }
+            }
+        }
+        .instrument(span.clone())
+        .await;
+        record_response(&span, &res);
+        res
+    }
+
+    /// Enrolls with the token of the request body, running the flow matching its type.
+    ///
+    /// See `try_decode_enroll_body` for the accepted bodies.
+    pub async fn enroll(
+        &self,
+        ctx: &Context,
+        req: &Request,
+        dec: &mut Decoder<'_>,
+    ) -> Result<Vec<u8>> {
+        match try_decode_enroll_body(dec).map(CloudRequestWrapper::split) {
+            #[cfg(feature = "auth0")]
+            Some((AuthenticateToken::Auth0(token), req_wrapper)) => {
+                let req_wrapper = req_wrapper.map(|()| token);
+                self.enroll_auth0_response(ctx, req, req_wrapper).await
+            }
+            Some((AuthenticateToken::EnrollmentToken(token), req_wrapper)) => {
+                let req_wrapper = req_wrapper.map(|()| token);
+                self.authenticate_enrollment_token_response(ctx, req, req_wrapper, None)
+                    .await
+            }
+            Some((AuthenticateToken::ApiKey(token), req_wrapper)) => {
+                let req_wrapper = req_wrapper.map(|()| token);
+                self.enroll_api_key_response(ctx, req, req_wrapper).await
+            }
+            _ => {
+                let err = Error::new(req.path()).with_message(
+                    "the request body is not an auth0 token, an enrollment token or an api key",
+                );
+                Ok(Response::bad_request(req.id()).body(err).to_vec()?)
+            }
+        }
+    }
+
+    /// Sends a token to its Orchestrator authenticator over a dedicated secure channel.
+    ///
+    /// The authenticator response is returned with its decoded claims when it is successful.
+    ///
+    /// Attempts failing because the authenticator could not be reached are
+    /// retried according to the node retry policy.
+    ///
+    /// The number of tokens authenticated at once is limited by the node
+    /// `enroll_permits`: the other ones wait for their turn, or fail with
+    /// [`EnrollError::Overloaded`] when too many of them are already waiting.
+    ///
+    /// The channel is created with the identity named `identity_name`, or the node
+    /// identity if it is not set, so that several identities can enroll through one node.
+    ///
+    /// `request_id` is the id of the node API request which received the token, if any.
+    /// It is sent as the correlation id of the request to the authenticator.
+    ///
+    /// The whole authentication is bounded by the node `enroll_deadline`, if it is set.
+    pub(crate) async fn authenticate_token(
+        &self,
+        ctx: &Context,
+        identity_name: Option<String>,
+        route: &MultiAddr,
+        token: AuthenticateToken,
+        request_id: Option<Id>,
+    ) -> std::result::Result<EnrollResponse, EnrollError> {
+        let deadline = self.enroll_options.enroll_deadline;
+        self.authenticate_token_within(ctx, identity_name, route, token, request_id, deadline)
+            .await
+    }
+
+    /// Same as `authenticate_token`, but fails with [`EnrollError::DeadlineExceeded`]
+    /// if the authentication didn't complete after `deadline`, when it is set.
+    ///
+    /// The deadline covers the wait for a permit, the creation of the secure channels,
+    /// the requests and the delays between the attempts. The secure channel of an
+    /// attempt cancelled at the deadline is still stopped, within
+    /// [`ENROLL_TEARDOWN_GRACE_PERIOD`].
+    pub(crate) async fn authenticate_token_within(
+        &self,
+        ctx: &Context,
+        identity_name: Option<String>,
+        route: &MultiAddr,
+        token: AuthenticateToken,
+        request_id: Option<Id>,
+        deadline: Option<Duration>,
+    ) -> std::result::Result<EnrollResponse, EnrollError> {
+        let deadline = deadline.map(Deadline::new);
+        let metrics = self.enroll_options.enroll_metrics.as_ref();
+        #[cfg(feature = "auth0")]
+        if let AuthenticateToken::Auth0(_) = token {
+            metrics.auth0_enrollment_attempted();
+        }
+        let attempts = async {
+            match self.enroll_permits.acquire().await {
+                Some(_permit) => {
+                    self.enroll_options
+                        .retry_policy
+                        .retry(
+                            || {
+                                let identity_name = identity_name.clone();
+                                self.authenticate_token_once(
+                                    ctx,
+                                    identity_name,
+                                    route,
+                                    &token,
+                                    request_id,
+                                    deadline,
+                                )
+                            },
+                            EnrollError::is_transient,
+                        )
+                        .await
+                }
+                None => Err(EnrollError::Overloaded),
+            }
+        };
+        // each attempt gives up at the deadline, this one only bounds
+        // the teardown of the last attempt and the delays between them
+        let grace = ENROLL_TEARDOWN_GRACE_PERIOD;
+        let res = within(deadline.map(|d| d.extended(grace)), attempts).await;
+        match (&token, &res) {
+            #[cfg(feature = "auth0")]
+            (AuthenticateToken::Auth0(_), Ok(_)) => metrics.auth0_enrollment_succeeded(),
+            #[cfg(feature = "auth0")]
+            (AuthenticateToken::Auth0(_), Err(_)) => metrics.auth0_enrollment_failed(),
+            (AuthenticateToken::EnrollmentToken(_), Ok(_)) => {
+                metrics.enrollment_token_authenticated()
+            }
+            (AuthenticateToken::EnrollmentToken(_), Err(EnrollError::Rejected { .. })) => {
+                metrics.enrollment_token_rejected()
+            }
+            _ => {}
+        }
+        let granted = res
+            .as_ref()
+            .ok()
+            .and_then(|enrolled| enrolled.claims.as_ref())
+            .and_then(|claims| claims.attributes.as_ref());
+        let attributes = match (granted, &token) {
+            (Some(attributes), _) => Some(attributes),
+            (None, AuthenticateToken::EnrollmentToken(token)) => token.attributes.as_ref(),
+            _ => None,
+        };
+        if let Ok(enrolled) = &res {
+            if let Err(err) = self
+                .enroll_options
+                .enroll_notifier
+                .on_enrolled(attributes, enrolled)
+                .await
+            {
+                warn!(target: TARGET, %err, "failed to notify the enrollment");
+            }
+        }
+        self.emit_enroll_event(
+            EnrollFlow::from(&token),
+            attributes,
+            EnrollOutcome::new(&res),
+        );
+        res
+    }
+
+    /// Returns why one of `tokens` is rejected, if it is longer than `max_token_length`
+    fn check_token_lengths<'a>(
+        &self,
+        tokens: impl IntoIterator<Item = &'a Token>,
+    ) -> Option<String> {
+        let max = self.enroll_options.max_token_length;
+        tokens
+            .into_iter()
+            .map(|token| token.reveal().len())
+            .find(|length| *length > max)
+            .map(|length| format!("the token is too long ({length} bytes, at most {max})"))
+    }
+
+    /// Sends an event to the subscribers of `subscribe_enroll_events`, if any
+    fn emit_enroll_event(
+        &self,
+        flow: EnrollFlow,
+        attributes: Option<&Attributes>,
+        outcome: EnrollOutcome,
+    ) {
+        let event = EnrollEvent {
+            flow,
+            attributes: attributes.cloned(),
+            outcome,
+        };
+        // sending only fails when there are no subscribers
+        let _ = self.enroll_events.send(event);
+    }
+
+    /// Returns the outcomes of the enrollments run by this node from now on,
+    /// whatever their flow.
+    ///
+    /// A subscriber which doesn't keep up with the enrollments misses the oldest events.
+    pub fn subscribe_enroll_events(&self) -> impl Stream<Item = EnrollEvent> {
+        events::subscribe(self.enroll_events.subscribe())
+    }
+
+    /// Runs a single attempt of `authenticate_token_within`, until the `deadline`.
+    ///
+    /// The secure channel is stopped after the deadline if the attempt reaches it.
+    /// The time spent in each phase of the attempt is returned with its response,
+    /// and recorded on the enrollment span.
+    async fn authenticate_token_once(
+        &self,
+        ctx: &Context,
+        identity_name: Option<String>,
+        route: &MultiAddr,
+        token: &AuthenticateToken,
+        request_id: Option<Id>,
+        deadline: Option<Deadline>,
+    ) -> std::result::Result<EnrollResponse, EnrollError> {
+        let started = time::Instant::now();
+        let sc = within(
+            deadline,
+            self.create_authenticator_secure_channel(ctx, identity_name, route),
+        )
+        .await?;
+        let channel_setup = started.elapsed();
+        let channel = SecureChannelAddress::of(&sc);
+        let mut request = Duration::ZERO;
+        let mut responded = None;
+        let authenticate = async {
+            let sent = time::Instant::now();
+            let res = self
+                .authenticate_token_over(ctx, &channel, token, request_id)
+                .await;
+            request = sent.elapsed();
+            responded = Some(time::Instant::now());
+            res
+        };
+        let res = self
+            .stop_secure_channel_after(ctx, &channel, within(deadline, authenticate))
+            .await;
+        let timings = EnrollTimings {
+            channel_setup,
+            request,
+            teardown: responded.map(|t| t.elapsed()).unwrap_or_default(),
+            total: started.elapsed(),
+        };
+        let span = Span::current();
+        span.record("channel_setup_ms", timings.channel_setup.as_millis() as u64);
+        span.record("request_ms", timings.request.as_millis() as u64);
+        span.record("teardown_ms", timings.teardown.as_millis() as u64);
+        trace!(target: TARGET, ?timings, "enrollment attempt completed");
+        res.map(|res| EnrollResponse {
+            timings: Some(timings),
+            ..res
+        })
+    }
+
+    /// Sends a token to its Orchestrator authenticator over the secure channel
+    /// to the controller whose encryptor address is `channel`.
+    ///
+    /// The channel is left open, so that callers can send other requests over it.
+    /// The route to the authenticator stays cached until the node stops the channel.
+    ///
+    /// The response records the identity the channel authenticated, for auditing.
+    pub async fn authenticate_token_over(
+        &self,
+        ctx: &Context,
+        channel: &SecureChannelAddress,
+        token: &AuthenticateToken,
+        request_id: Option<Id>,
+    ) -> std::result::Result<EnrollResponse, EnrollError> {
+        let api_service = token.api_service(&self.enroll_options.authenticator_services);
+        let req = self.authenticate_token_request(token, request_id);
+        let options = MessageSendReceiveOptions::new()
+            .with_timeout(Duration::from_secs(ORCHESTRATOR_RESTART_TIMEOUT));
+        let route = self.route_cache.route(
+            self.enroll_options.route_builder.as_ref(),
+            channel,
+            api_service,
+        );
+        let res = request_with_options(ctx, api_service, token.schema(), route, req, options)
+            .await
+            .map_err(EnrollError::Transport)?;
+        EnrollError::check_response(&res)?;
+        let authenticator = self
+            .secure_channels
+            .secure_channel_registry()
+            .get_channel_by_encryptor_address(channel.address())
+            .map(|entry| entry.their_id());
+        Ok(EnrollResponse {
+            authenticator,
+            ..EnrollResponse::new(res)
+        })
+    }
+
+    /// Opens a secure channel to the controller at `route`, shared by the following
+    /// enrollments of `authenticate_token_over_enroll_channel`.
+    ///
+    /// The channel opened before, if any, is replaced like with `rotate_enroll_channel`.
+    pub async fn open_enroll_channel(
+        &self,
+        ctx: &Context,
+        route: &MultiAddr,
+    ) -> std::result::Result<SecureChannelAddress, EnrollError> {
+        let sc = self
+            .create_authenticator_secure_channel(ctx, None, route)
+            .await?;
+        let channel = SecureChannelAddress::of(&sc);
+        debug!(target: TARGET, sc = %channel, "opened the enrollment channel");
+        if let Some(retired) = self.enroll_channel.swap(route.clone(), channel.clone()) {
+            self.stop_retired_channel(ctx, retired).await;
+        }
+        Ok(channel)
+    }
+
+    /// Replaces the channel opened by `open_enroll_channel` with a new channel to the
+    /// same controller, so that a long provisioning session doesn't keep using the keys
+    /// of a single channel.
+    ///
+    /// The enrollments started after the swap use the new channel. The previous channel
+    /// is stopped once the enrollments still using it complete, and only then is the
+    /// address of the new channel returned.
+    pub async fn rotate_enroll_channel(
+        &self,
+        ctx: &Context,
+    ) -> std::result::Result<SecureChannelAddress, EnrollError> {
+        let route = self.enroll_channel.route().ok_or_else(no_enroll_channel)?;
+        self.open_enroll_channel(ctx, &route).await
+    }
+
+    /// Stops the channel opened by `open_enroll_channel`, once the enrollments using it complete
+    pub async fn close_enroll_channel(&self, ctx: &Context) {
+        if let Some(retired) = self.enroll_channel.take() {
+            self.stop_retired_channel(ctx, retired).await;
+        }
+    }
+
+    /// Sends a token to its authenticator over the channel opened by `open_enroll_channel`.
+    ///
+    /// The channel can't be stopped by a rotation until the response is received.
+    pub async fn authenticate_token_over_enroll_channel(
+        &self,
+        ctx: &Context,
+        token: &AuthenticateToken,
+        request_id: Option<Id>,
+    ) -> std::result::Result<EnrollResponse, EnrollError> {
+        let lease = self.enroll_channel.lease().ok_or_else(no_enroll_channel)?;
+        self.authenticate_token_over(ctx, lease.address(), token, request_id)
+            .await
+    }
+
+    async fn stop_retired_channel(&self, ctx: &Context, retired: RetiredChannel) {
+        let channel = retired.released().await;
+        self.route_cache.forget(&channel);
+        if let Err(err) = self
+            .secure_channels
+            .stop_secure_channel(ctx, channel.address())
+            .await
+        {
+            warn!(target: TARGET, %err, sc = %channel, "failed to stop the enrollment channel");
+        }
+    }
+
+    /// Builds the request sending `token` to its authenticator, correlated
+    /// with the node API request `request_id` if it is set
+    pub(crate) fn authenticate_token_request<'a>(
+        &self,
+        token: &'a AuthenticateToken,
+        request_id: Option<Id>,
+    ) -> RequestBuilder<&'a AuthenticateToken> {
+        let req = Request::post(self.cloud_api_version.path("enroll"));
+        let req = match request_id {
+            Some(id) => req.correlation_id(id),
+            None => req,
+        };
+        self.add_request_metadata(req).body(token)
+    }
+
+    /// Adds the node request metadata to a request sent to the Orchestrator
+    fn add_request_metadata<T>(&self, req: RequestBuilder<T>) -> RequestBuilder<T> {
+        self.enroll_options
+            .request_metadata
+            .iter()
+            .fold(req, |req, (key, value)| req.metadata(key, value))
+    }
+
+    /// Creates a secure channel to the controller at `route` for the identity
+    /// `identity_name`, giving up if it is not established after `secure_channel_timeout`.
+    pub(crate) async fn create_authenticator_secure_channel(
+        &self,
+        ctx: &Context,
+        identity_name: Option<String>,
+        route: &MultiAddr,
+    ) -> std::result::Result<SecureChannel, EnrollError> {
+        let timeout = self.enroll_options.secure_channel_timeout;
+        match time::timeout(
+            timeout,
+            self.create_controller_secure_channel(ctx, identity_name, route),
+        )
+        .await
+        {
+            Ok(sc) => sc.map_err(EnrollError::SecureChannel),
+            Err(_) => {
+                debug!(target: TARGET, ?timeout, "secure channel creation timed out");
+                Err(EnrollError::SecureChannelTimeout(timeout))
+            }
+        }
+    }
+
+    /// Generates `count` enrollment tokens for each of the attributes of `attributes_list`,
+    /// reusing a single secure channel to the controller.
+    ///
+    /// The response body reports the outcome of each token, in the order of the
+    /// requests: a token which can't be generated doesn't stop the rest of the batch.
+    pub async fn generate_enrollment_tokens(
+        &self,
+        ctx: &Context,
+        req: &Request,
+        route: &MultiAddr,
+        attributes_list: Vec<Attributes>,
+        count: usize,
+    ) -> Result<Vec<u8>> {
+        for (index, attributes) in attributes_list.iter().enumerate() {
+            if let Err(err) = self.enroll_options.attributes_limits.check(attributes) {
+                let message = format!("invalid attributes {index}: {err}");
+                let body = Error::new(req.path()).with_message(message);
+                return Ok(Response::bad_request(req.id()).body(body).to_vec()?);
+            }
+        }
+        let results = match self
+            .generate_enrollment_token_batch(ctx, route, &attributes_list, count)
+            .await?
+        {
+            Ok(results) => results,
+            Err(err) => return err.to_response(req),
+        };
+        let report: Vec<GeneratedEnrollmentToken> = results
+            .into_iter()
+            .enumerate()
+            .map(|(index, result)| match result {
+                Ok(token) => GeneratedEnrollmentToken::generated(index, token),
+                Err(err) => GeneratedEnrollmentToken::failed(index, err.status(), err),
+            })
+            .collect();
+        Ok(Response::ok(req.id()).body(report).to_vec()?)
+    }
+
+    /// Generates `count` enrollment tokens for each of the attributes of `attributes_list`
+    /// over a single secure channel to the controller, and returns the result of each
+    /// token in the order of the requests.
+    ///
+    /// Each token goes through the same checks as a single token, see `check_token_request`,
+    /// and is refused with an [`EnrollError::Refused`] error if it doesn't pass them. Since
+    /// the `count` tokens of the same attributes are different tokens, only the first one
+    /// is deduplicated.
+    ///
+    /// Only the creation of the secure channel fails the whole batch. Like for a
+    /// single token, the creation of the channel and each request are retried
+    /// according to the node retry policy when they fail with a transient error.
+    pub async fn generate_enrollment_token_batch(
+        &self,
+        ctx: &Context,
+        route: &MultiAddr,
+        attributes_list: &[Attributes],
+        count: usize,
+    ) -> Result<
+        std::result::Result<Vec<std::result::Result<EnrollmentToken, EnrollError>>, EnrollError>,
+    > {
+        let mut checks = Vec::new();
+        for attributes in attributes_list {
+            for copy in 0..count {
+                let req_body = RequestEnrollmentToken::new(attributes.clone());
+                checks.push(self.check_token_request(req_body, copy == 0).await?);
+            }
+        }
+        let sc = match self
+            .enroll_options
+            .retry_policy
+            .retry(
+                || self.create_authenticator_secure_channel(ctx, None, route),
+                EnrollError::is_transient,
+            )
+            .await
+        {
+            Ok(sc) => sc,
+            Err(err) => return Ok(Err(err)),
+        };
+        let channel = SecureChannelAddress::of(&sc);
+        let path = self.cloud_api_version.path("");
+        let api_service = "projects";
+
+        trace!(target: TARGET, count = checks.len(), "generating tokens");
+        let generate = async {
+            let mut results = Vec::new();
+            for (index, check) in checks.into_iter().enumerate() {
+                let token_request = match check {
+                    TokenCheck::Send(token_request) => token_request,
+                    TokenCheck::Deduplicated(token) => {
+                        results.push(Ok(token));
+                        continue;
+                    }
+                    TokenCheck::Refused(err) => {
+                        debug!(target: TARGET, %index, %err, "enrollment token refused");
+                        results.push(Err(err));
+                        continue;
+                    }
+                };
+                let token = self
+                    .enroll_options
+                    .retry_policy
+                    .retry(
+                        || {
+                            self.request_controller_service_over(
+                                ctx,
+                                &channel,
+                                api_service,
+                                "request_enrollment_token",
+                                Request::post(&path).body(&token_request.body),
+                            )
+                        },
+                        EnrollError::is_transient,
+                    )
+                    .await
+                    .and_then(|res| decode_body::<EnrollmentToken>(&res));
+                match token {
+                    Ok(token) => {
+                        results.push(Ok(self.keep_generated_token(&token_request, token).await?))
+                    }
+                    Err(err) => {
+                        debug!(target: TARGET, %index, %err, "enrollment token generation failed");
+                        results.push(Err(err));
+                    }
+                }
+            }
+            Ok::<_, ockam_core::Error>(results)
+        };
+        Ok(Ok(self
+            .stop_secure_channel_after(ctx, &channel, generate)
+            .await?))
+    }
+
+    /// Revokes an enrollment token generated by `generate_enrollment_token`, so that
+    /// it can't be used anymore, even if it is not expired yet.
+    ///
+    /// The response status is `Conflict` if the token was already revoked,
+    /// and `NotFound` if the authenticator doesn't know the token. The token is
+    /// then rejected locally by this node, see `is_enrollment_token_revoked`.
+    pub async fn revoke_enrollment_token(
+        &self,
+        ctx: &Context,
+        req: &Request,
+        route: &MultiAddr,
+        token: &Token,
+    ) -> Result<Vec<u8>> {
+        let api_service = self
+            .enroll_options
+            .authenticator_services
+            .enrollment_token
+            .as_str();
+        let revoke = Request::post(self.cloud_api_version.path("revoke"))
+            .body(RevokeEnrollmentToken::new(token.clone()));
+
+        trace!(target: TARGET, "revoking token");
+        let res = self
+            .request_controller_service(ctx, route, api_service, "revoke_enrollment_token", revoke)
+            .await;
+        let (status, message) = match res {
+            Ok(_) => {
+                self.revoked_enrollment_tokens
+                    .revoke(token, self.enroll_options.clock.now()?);
+                return Ok(Response::ok(req.id()).to_vec()?);
+            }
+            Err(EnrollError::Rejected {
+                status: Status::Conflict,
+                ..
+            }) => {
+                self.revoked_enrollment_tokens
+                    .revoke(token, self.enroll_options.clock.now()?);
+                (Status::Conflict, "the enrollment token was already revoked")
+            }
+            Err(EnrollError::Rejected {
+                status: Status::NotFound,
+                ..
+            }) => (Status::NotFound, "unknown enrollment token"),
+            Err(err) => {
+                debug!(target: TARGET, %err, "enrollment token revocation failed");
+                return err.to_response(req);
+            }
+        };
+        let body = Error::new(req.path()).with_message(message);
+        Ok(Response::builder(req.id(), status).body(body).to_vec()?)
+    }
+
+    /// Lists a page of the enrollment tokens known by the authenticator.
+    ///
+    /// The response body is an `EnrollmentTokenPage`. Only the metadata
+    /// of the tokens is returned, not the tokens themselves.
+    pub async fn list_enrollment_tokens(
+        &self,
+        ctx: &Context,
+        req: &Request,
+        route: &MultiAddr,
+        page: u32,
+        page_size: u32,
+    ) -> Result<Vec<u8>> {
+        let api_service = self
+            .enroll_options
+            .authenticator_services
+            .enrollment_token
+            .as_str();
+        let list = Request::get(self.cloud_api_version.path("list"))
+            .body(ListEnrollmentTokens::new(page, page_size));
+
+        trace!(target: TARGET, %page, %page_size, "listing tokens");
+        let page = self
+            .request_controller_service(ctx, route, api_service, "list_enrollment_tokens", list)
+            .await
+            .and_then(|res| decode_body::<EnrollmentTokenPage>(&res));
+        match page {
+            Ok(page) => Ok(Response::ok(req.id()).body(page).to_vec()?),
+            Err(err) => {
+                debug!(target: TARGET, %err, "enrollment token listing failed");
+                err.to_response(req)
+            }
+        }
+    }
+
+    /// Returns the status of an enrollment token, as known by the authenticator.
+    ///
+    /// Unlike `authenticate_enrollment_token_response`, no identity is enrolled
+    /// and none of the uses of the token is consumed. The response body is an
+    /// `EnrollmentTokenIntrospection`.
+    pub async fn introspect_enrollment_token(
+        &self,
+        ctx: &Context,
+        req: &Request,
+        route: &MultiAddr,
+        token: &Token,
+    ) -> Result<Vec<u8>> {
+        let api_service = self
+            .enroll_options
+            .authenticator_services
+            .enrollment_token
+            .as_str();
+        let introspect = Request::post(self.cloud_api_version.path("introspect"))
+            .body(IntrospectEnrollmentToken::new(token.clone()));
+
+        trace!(target: TARGET, "introspecting token");
+        let introspection = self
+            .request_controller_service(
+                ctx,
+                route,
+                api_service,
+                "introspect_enrollment_token",
+                introspect,
+            )
+            .await
+            .and_then(|res| decode_body::<EnrollmentTokenIntrospection>(&res));
+        match introspection {
+            Ok(introspection) => Ok(Response::ok(req.id()).body(introspection).to_vec()?),
+            Err(err) => {
+                debug!(target: TARGET, %err, "enrollment token introspection failed");
+                err.to_response(req)
+            }
+        }
+    }
+
+    /// Checks that the enrollment token authenticator of the Orchestrator at `route`
+    /// is available, and returns the round-trip time of a health request sent to it.
+    ///
+    /// The request is sent over a secure channel created for the check and stopped
+    /// after it. The creation of the channel is not part of the returned duration.
+    pub async fn ping_enrollment_authenticator(
+        &self,
+        ctx: &Context,
+        route: &MultiAddr,
+    ) -> Result<Duration> {
+        let sc = self
+            .create_authenticator_secure_channel(ctx, None, route)
+            .await?;
+        let channel = &SecureChannelAddress::of(&sc);
+        let ping = async {
+            let api_service = self
+                .enroll_options
+                .authenticator_services
+                .enrollment_token
+                .as_str();
+            let route = self.route_cache.route(
+                self.enroll_options.route_builder.as_ref(),
+                channel,
+                api_service,
+            );
+            let req =
+                self.add_request_metadata(Request::get(self.cloud_api_version.path("health")));
+            let options = MessageSendReceiveOptions::new();
+            let start = time::Instant::now();
+            let res = request_with_options(ctx, api_service, None, route, req, options)
+                .await
+                .map_err(EnrollError::Transport)?;
+            EnrollError::check_response(&res)?;
+            Ok::<_, EnrollError>(start.elapsed())
+        };
+        let rtt = self.stop_secure_channel_after(ctx, channel, ping).await?;
+        trace!(target: TARGET, ?rtt, "enrollment authenticator is available");
+        Ok(rtt)
+    }
+
+    /// Checks that an enrollment through the Orchestrator at `route` can succeed
+    /// before running it: the route must lead to the enrollment token authenticator,
+    /// over a secure channel, and the clocks of the node and of the authenticator
+    /// must not be further apart than `max_clock_skew`.
+    ///
+    /// The returned skew can be used to compare the expiries set by the authenticator,
+    /// see [`EnrollPreflight::adjusted_clock`].
+    pub async fn enroll_preflight(
+        &self,
+        ctx: &Context,
+        route: &MultiAddr,
+    ) -> Result<EnrollPreflight> {
+        if route.is_empty() {
+            return Err(ApiError::generic("the route to the Orchestrator is empty"));
+        }
+        let sc = self
+            .create_authenticator_secure_channel(ctx, None, route)
+            .await?;
+        let channel = &SecureChannelAddress::of(&sc);
+        let preflight = async {
+            let api_service = self
+                .enroll_options
+                .authenticator_services
+                .enrollment_token
+                .as_str();
+            let route = self.route_cache.route(
+                self.enroll_options.route_builder.as_ref(),
+                channel,
+                api_service,
+            );
+            let req = self.add_request_metadata(Request::get(self.cloud_api_version.path("time")));
+            let options = MessageSendReceiveOptions::new();
+            let sent_at = self.enroll_options.clock.now()?;
+            let start = time::Instant::now();
+            let res = request_with_options(ctx, api_service, None, route, req, options)
+                .await
+                .map_err(EnrollError::Transport)?;
+            let rtt = start.elapsed();
+            EnrollError::check_response(&res)?;
+            let server = decode_body::<ServerTime>(&res)?;
+            let received_at = self.enroll_options.clock.now()?;
+            Ok::<_, ockam_core::Error>(EnrollPreflight::measure(
+                sent_at,
+                received_at,
+                server.now,
+                rtt,
+            ))
+        };
+        let preflight = self
+            .stop_secure_channel_after(ctx, channel, preflight)
+            .await?;
+        let max = self.enroll_options.max_clock_skew;
+        if preflight.exceeds(max) {
+            warn!(target: TARGET, skew = preflight.skew, ?max, "the clock of the authenticator is skewed");
+            return Err(EnrollError::ClockSkew {
+                skew: preflight.skew,
+                max,
+            }
+            .into());
+        }
+        trace!(target: TARGET, skew = preflight.skew, rtt = ?preflight.rtt, "enrollment preflight passed");
+        Ok(preflight)
+    }
+
+    /// Checks that an enrollment token could be generated for `body`, without generating it.
+    ///
+    /// The response body is a `ValidatedEnrollmentToken` describing the token
+    /// which would be generated.
+    pub async fn validate_enrollment_token_request(
+        &self,
+        ctx: &Context,
+        req: &Request,
+        route: &MultiAddr,
+        body: RequestEnrollmentToken,
+    ) -> Result<Vec<u8>> {
+        let body = self.with_project_attributes(body);
+        if let Err(err) = self.enroll_options.attributes_limits.check_request(&body) {
+            let body = Error::new(req.path()).with_message(err.to_string());
+            return Ok(Response::bad_request(req.id()).body(body).to_vec()?);
+        }
+        let validate = Request::post(self.cloud_api_version.path("validate")).body(body);
+
+        trace!(target: TARGET, "validating token request");
+        let validated = self
+            .request_controller_service(
+                ctx,
+                route,
+                "projects",
+                "request_enrollment_token",
+                validate,
+            )
+            .await
+            .and_then(|res| decode_body::<ValidatedEnrollmentToken>(&res));
+        match validated {
+            Ok(validated) => Ok(Response::ok(req.id()).body(validated).to_vec()?),
+            Err(err) => {
+                debug!(target: TARGET, %err, "enrollment token request validation failed");
+                err.to_response(req)
+            }
+        }
+    }
+
+    /// Sends `req` to the controller service `api_service` over a secure channel
+    /// dedicated to this request, and returns the response if its status is `Ok`.
+    async fn request_controller_service<T: Encode<()>>(
+        &self,
+        ctx: &Context,
+        route: &MultiAddr,
+        api_service: &str,
+        schema: &str,
+        req: RequestBuilder<T>,
+    ) -> std::result::Result<Vec<u8>, EnrollError> {
+        let sc = self
+            .create_authenticator_secure_channel(ctx, None, route)
+            .await?;
+        let channel = SecureChannelAddress::of(&sc);
+        self.stop_secure_channel_after(
+            ctx,
+            &channel,
+            self.request_controller_service_over(ctx, &channel, api_service, schema, req),
+        )
+        .await
+    }
+
+    /// Sends `req` to the controller service `api_service` over the existing
+    /// secure channel `channel`, and returns the response if its status is `Ok`.
+    async fn request_controller_service_over<T: Encode<()>>(
+        &self,
+        ctx: &Context,
+        channel: &SecureChannelAddress,
+        api_service: &str,
+        schema: &str,
+        req: RequestBuilder<T>,
+    ) -> std::result::Result<Vec<u8>, EnrollError> {
+        let route = self.route_cache.route(
+            self.enroll_options.route_builder.as_ref(),
+            channel,
+            api_service,
+        );
+        let req = self.add_request_metadata(req);
+        let options =
+            MessageSendReceiveOptions::new().with_timeout(Duration::from_secs(DEFAULT_TIMEOUT));
+        let res = request_with_options(ctx, api_service, schema, route, req, options)
+            .await
+            .map_err(EnrollError::Transport)?;
+        EnrollError::check_response(&res)?;
+        Ok(res)
+    }
+
+    /// Verifies a signed enrollment token without contacting its authenticator,
+    /// and returns the attributes it was generated for.
+    ///
+    /// `public_key` is the key of the authenticator which signed the token.
+    /// Unsigned, expired and revoked tokens are rejected, as well as the
+    /// tokens of a format version this node doesn't support. Like when they
+    /// are authenticated, the tokens which are not valid yet, meant for another
+    /// project or bound to another device than the one they present are rejected.
+    pub async fn verify_enrollment_token_offline(
+        &self,
+        token: &EnrollmentToken,
+        public_key: &PublicKey,
+    ) -> Result<Attributes> {
+        if !token.is_supported_version() {
+            return Err(ApiError::message(format!(
+                "unsupported enrollment token version {}",
+                token.version()
+            )));
+        }
+        let (Some(attributes), Some(signature)) = (&token.attributes, &token.signature) else {
+            return Err(ApiError::generic("the enrollment token is not signed"));
+        };
+        let data = token.signed_data().map_err(ApiError::wrap)?;
+        let signature = Signature::new(signature.to_vec());
+        let vault = self.secure_channels.identities().vault();
+        if !vault.verify(public_key, &data, &signature).await? {
+            return Err(ApiError::generic("invalid enrollment token signature"));
+        }
+        let now = self.enroll_options.clock.now()?;
+        if token.is_expired(now) {
+            return Err(ApiError::generic("the enrollment token has expired"));
+        }
+        if token.is_not_yet_valid(now) {
+            return Err(ApiError::generic("the enrollment token is not valid yet"));
+        }
+        if self.is_enrollment_token_revoked(&token.token, now) {
+            return Err(ApiError::generic("the enrollment token was revoked"));
+        }
+        if !token.is_usable_by(token.device_identifier.as_ref()) {
+            return Err(ApiError::generic(
+                "the enrollment token is bound to another device",
+            ));
+        }
+        if !token.is_usable_in(self.trust_context().ok().map(|tc| tc.id())) {
+            return Err(ApiError::generic(
+                "the enrollment token is meant for another project",
+            ));
+        }
+        Ok(attributes.clone())
+    }
+
+    /// Generates a token that will be associated to the attributes of `req_body`.
+    ///
+    /// Unless the token is delegated, the project default attributes which are not
+    /// part of `req_body` are added to them, see `inherit_project_attributes`.
+    ///
+    /// The token generated for the same request less than `token_dedup_window` ago,
+    /// if any, is returned instead of a new one. Otherwise the request is rejected if
+    /// the `token_rate_limiter` doesn't allow another token for its rate limit attribute.
+    pub(crate) async fn generate_enrollment_token(
+        &self,
+        ctx: &Context,
+        req: &Request,
+        route: &MultiAddr,
+        req_body: RequestEnrollmentToken,
+    ) -> Result<Vec<u8>> {
+        let token_request = match self.prepare_token_request(req, req_body).await? {
+            Ok(token_request) => token_request,
+            Err(res) => return Ok(res),
+        };
+        let res = match self
+            .enroll_options
+            .retry_policy
+            .retry(
+                || self.request_enrollment_token(ctx, route, &token_request.body),
+                is_transient,
+            )
+            .await
+        {
+            Ok(res) => res,
+            Err(err) => return generation_failed_response(req, err),
+        };
+        self.generated_token_response(req, &token_request, &res)
+            .await
+    }
+
+    /// Checks a request for an enrollment token before sending it to the authenticator.
+    ///
+    /// The response to `req` is returned instead if the request is refused, or
+    /// if a token was already generated for it.
+    pub(crate) async fn prepare_token_request(
+        &self,
+        req: &Request,
+        req_body: RequestEnrollmentToken,
+    ) -> Result<std::result::Result<TokenRequest, Vec<u8>>> {
+        match self.check_token_request(req_body, true).await? {
+            TokenCheck::Send(token_request) => Ok(Ok(token_request)),
+            TokenCheck::Deduplicated(token) => {
+                Ok(Err(Response::ok(req.id()).body(token).to_vec()?))
+            }
+            TokenCheck::Refused(err) => Ok(Err(err.to_response(req)?)),
+        }
+    }
+
+    /// Checks a request for an enrollment token, after adding the project attributes
+    /// to it: the limits and expiries of its attributes, and the rate limit of its
+    /// tenant. When `dedup` is set, the token generated for the same request within
+    /// the dedup window is returned instead, if any.
+    pub(crate) async fn check_token_request(
+        &self,
+        req_body: RequestEnrollmentToken,
+        dedup: bool,
+    ) -> Result<TokenCheck> {
+        let refused =
+            |status, message: String| TokenCheck::Refused(EnrollError::Refused { status, message });
+        let req_body = self.with_project_attributes(req_body);
+        if let Err(err) = self
+            .enroll_options
+            .attributes_limits
+            .check_request(&req_body)
+        {
+            return Ok(refused(Status::BadRequest, err.to_string()));
+        }
+        if let Err(err) = req_body.check_attributes_expiries() {
+            return Ok(refused(Status::BadRequest, err.to_string()));
+        }
+        let fingerprint = if !dedup || self.enroll_options.token_dedup_window.is_zero() {
+            None
+        } else {
+            Some(fingerprint(&req_body)?)
+        };
+        if let Some(fingerprint) = &fingerprint {
+            let cache = self.enroll_options.token_dedup_cache.as_ref();
+            let (now, window) = (
+                self.enroll_options.clock.now()?,
+                self.enroll_options.token_dedup_window,
+            );
+            if let Some(token) = load_recent_token(cache, fingerprint, now, window).await? {
+                debug!(target: TARGET, "returning the token generated for the same request");
+                return Ok(TokenCheck::Deduplicated(token));
+            }
+        }
+        if let Some(limiter) = &self.enroll_options.token_rate_limiter {
+            let key = rate_limit_key(
+                &req_body.attributes,
+                &self.enroll_options.rate_limit_attribute,
+            );
+            if !limiter
+                .try_acquire(&key, self.enroll_options.clock.now()?)
+                .await?
+            {
+                debug!(target: TARGET, %key, "enrollment token generation rate limited");
+                let message = format!("too many enrollment tokens were requested for {key:?}");
+                return Ok(refused(Status::TooManyRequests, message));
+            }
+        }
+        // the same key is sent on every attempt so that retries don't create new tokens
+        let body = req_body.with_default_idempotency_key();
+        Ok(TokenCheck::Send(TokenRequest { body, fingerprint }))
+    }
+
+    /// Makes a single attempt at generating the token requested by `req_body`
+    pub(crate) async fn request_enrollment_token(
+        &self,
+        ctx: &Context,
+        route: &MultiAddr,
+        req_body: &RequestEnrollmentToken,
+    ) -> Result<Vec<u8>> {
+        trace!(target: TARGET, "generating tokens");
+        let path = self.cloud_api_version.path("");
+        self.request_controller(
+            ctx,
+            "enrollment_token_generator",
+            "request_enrollment_token",
+            route,
+            "projects",
+            self.add_request_metadata(Request::post(&path).body(req_body)),
+            None,
+        )
+        .await
+    }
+
+    /// Answers `req` with the token found in the authenticator response `res`,
+    /// and keeps it for the next identical requests
+    pub(crate) async fn generated_token_response(
+        &self,
+        req: &Request,
+        token_request: &TokenRequest,
+        res: &[u8],
+    ) -> Result<Vec<u8>> {
+        // errors are answered with the status and message of the authenticator,
+        // but for this request rather than for the authenticator request
+        match EnrollError::check_response(res).and_then(|()| decode_body::<EnrollmentToken>(res)) {
+            Ok(token) => {
+                let token = self.keep_generated_token(token_request, token).await?;
+                Ok(Response::ok(req.id()).body(token).to_vec()?)
+            }
+            Err(EnrollError::Rejected { status, message }) => {
+                debug!(target: TARGET, %status, "enrollment token generation rejected");
+                let mut body = Error::new(req.path());
+                if let Some(message) = message {
+                    body = body.with_message(message);
+                }
+                Ok(Response::builder(req.id(), status).body(body).to_vec()?)
+            }
+            Err(err) => {
+                debug!(target: TARGET, %err, "enrollment token generation failed");
+                err.to_response(req)
+            }
+        }
+    }
+
+    /// Counts `token`, generated by the authenticator for `token_request`, and keeps
+    /// it for the next identical requests
+    async fn keep_generated_token(
+        &self,
+        token_request: &TokenRequest,
+        token: EnrollmentToken,
+    ) -> Result<EnrollmentToken> {
+        self.enroll_options
+            .enroll_metrics
+            .enrollment_token_generated();
+        // the version of the authenticator is kept, the tokens without one are
+        // generated by an authenticator which doesn't know about versions yet
+        let token = match token.version {
+            Some(_) => token,
+            None => token.with_version(ENROLLMENT_TOKEN_VERSION),
+        };
+        if let Some(fingerprint) = &token_request.fingerprint {
+            let issued = IssuedToken {
+                token: token.clone(),
+                issued_at: self.enroll_options.clock.now()?,
+            };
+            self.enroll_options
+                .token_dedup_cache
+                .store(fingerprint, &issued, self.enroll_options.token_dedup_window)
+                .await?;
+        }
+        Ok(token)
+    }
+
+    /// Generates a token delegated from `parent`, for the attributes of `req_body`.
+    ///
+    /// When the attributes of `parent` are known, the request is rejected without
+    /// reaching the authenticator unless they include the requested attributes.
+    pub async fn generate_delegated_enrollment_token(
+        &self,
+        ctx: &Context,
+        req: &Request,
+        route: &MultiAddr,
+        parent: &EnrollmentToken,
+        req_body: RequestEnrollmentToken,
+    ) -> Result<Vec<u8>> {
+        if let Some(parent_attributes) = &parent.attributes {
+            if let Err(err) = check_delegated(&req_body.attributes, parent_attributes) {
+                let body = Error::new(req.path()).with_message(err.to_string());
+                return Ok(Response::bad_request(req.id()).body(body).to_vec()?);
+            }
+        }
+        let req_body = req_body.with_parent_token(parent.token.clone());
+        self.generate_enrollment_token(ctx, req, route, req_body)
+            .await
+    }
+
+    /// Adds the project default attributes to the attributes of `req_body`, with the
+    /// requested values replacing the project values for the same keys.
+    ///
+    /// Delegated tokens only get the attributes of their parent, which can't be
+    /// checked for the project attributes.
+    fn with_project_attributes(
+        &self,
+        mut req_body: RequestEnrollmentToken,
+    ) -> RequestEnrollmentToken {
+        if self.enroll_options.inherit_project_attributes
+            && req_body.parent_token.is_none()
+            && !self.enroll_options.project_attributes.is_empty()
+        {
+            let mut attributes = self.enroll_options.project_attributes.clone();
+            for (key, value) in req_body.attributes.iter() {
+                attributes.put(key, value);
+            }
+            req_body.attributes = attributes;
+        }
+        req_body
+    }
+
+    /// Registers `template`, replacing the template with the same name if any
+    pub fn add_enrollment_token_template(&self, template: EnrollmentTokenTemplate) {
+        self.enrollment_token_templates
+            .lock()
+            .unwrap()
+            .insert(template.name.clone(), template);
+    }
+
+    /// Generates a token with the attributes of the template `template_name`,
+    /// where `overrides` replace or complete the template attributes.
+    ///
+    /// The response status is `NotFound` if there is no such template.
+    pub async fn generate_enrollment_token_from_template(
+        &self,
+        ctx: &Context,
+        req: &Request,
+        route: &MultiAddr,
+        template_name: &str,
+        overrides: &Attributes,
+    ) -> Result<Vec<u8>> {
+        let attributes = match self
+            .enrollment_token_templates
+            .lock()
+            .unwrap()
+            .get(template_name)
+        {
+            Some(template) => template.attributes_with(overrides),
+            None => {
+                let message = format!("unknown enrollment token template {template_name}");
+                let body = Error::new(req.path()).with_message(message);
+                return Ok(Response::not_found(req.id()).body(body).to_vec()?);
+            }
+        };
+        let req_body = RequestEnrollmentToken::new(attributes);
+        self.generate_enrollment_token(ctx, req, route, req_body)
+            .await
+    }
+
+    /// Return true if `token` was revoked by this node, at most the TTL of its
+    /// revocation list before `now`.
+    ///
+    /// The tokens revoked by other nodes are only rejected by the authenticator.
+    pub(crate) fn is_enrollment_token_revoked(&self, token: &Token, now: u64) -> bool {
+        self.revoked_enrollment_tokens.is_revoked(token, now)
+    }
+
+    #[cfg(feature = "auth0")]
+    /// Starts a device authorization flow with the Auth0 tenant of the node configuration.
+    ///
+    /// The returned device code can be forwarded to another process, before calling
+    /// `poll_auth0_device_code`. The returned instructions are meant to be displayed
+    /// to the user, and expire with the device code.
+    pub async fn start_auth0_device_flow(
+        &self,
+    ) -> Result<(DeviceCode<'static>, DeviceFlowInstructions)> {
+        trace!(target: TARGET, "requesting auth0 device code");
+        let config = &self.enroll_options.auth0_config;
+        let device_code_url = config.device_code_url()?;
+        let device_code = request_device_code(
+            &config.http_client()?,
+            &device_code_url,
+            &config.client_id,
+            &config.scope,
+            config.audience.as_deref(),
+        )
+        .await?;
+        let instructions = device_code.instructions(self.enroll_options.clock.now()?);
+        Ok((device_code, instructions))
+    }
+
+    #[cfg(feature = "auth0")]
+    /// Polls the Auth0 tenant until the user approves the device code,
+    /// and returns the resulting token which can then be used with `enroll_auth0`.
+    ///
+    /// Sending a value on `cancel` stops the polling, and an `EnrollError::Cancelled`
+    /// error is returned. Dropping the sender doesn't cancel the flow.
+    ///
+    /// Unlike the enrollment methods, it takes no `Context`: the polling only sends
+    /// HTTPS requests to the Auth0 tenant, and no message to a worker of the node.
+    pub async fn poll_auth0_device_code(
+        &self,
+        device_code: &DeviceCode<'_>,
+        cancel: oneshot::Receiver<()>,
+    ) -> std::result::Result<OidcToken, DeviceFlowError> {
+        trace!(target: TARGET, "polling auth0 token");
+        let config = &self.enroll_options.auth0_config;
+        let token_url = config.token_url()?;
+        let client = config.http_client()?;
+        let poll = poll_device_code(
+            &client,
+            &token_url,
+            &config.client_id,
+            device_code,
+            config.max_polling_interval,
+        );
+        until_cancelled(poll, cancel).await
+    }
+
+    #[cfg(feature = "auth0")]
+    /// Exchanges the refresh token of a token issued by the Auth0 tenant
+    /// for a new access token.
+    pub async fn refresh_auth0_token(&self, token: &OidcToken) -> Result<OidcToken> {
+        trace!(target: TARGET, "refreshing auth0 token");
+        let config = &self.enroll_options.auth0_config;
+        let token_url = config.token_url()?;
+        refresh_token(&config.http_client()?, &token_url, &config.client_id, token).await
+    }
+}
+
+impl NodeManagerWorker {
+    #[cfg(feature = "auth0")]
+    /// Executes an enrollment process to generate a new set of access tokens using the auth0 flow.
+    pub async fn enroll_auth0_response(
+        &self,
+        ctx: &Context,
+        req: &Request,
+        dec: &mut Decoder<'_>,
+    ) -> Result<Vec<u8>> {
+        let req_wrapper = match decode_request_body(req, dec) {
+            Ok(req_wrapper) => req_wrapper,
+            Err(res) => return res,
+        };
+        let node_manager = self.inner().read().await;
+        node_manager
+            .enroll_auth0_response(ctx, req, req_wrapper)
+            .await
+    }
+
+    /// Generates a token that will be associated to the passed attributes.
+    pub(crate) async fn generate_enrollment_token(
+        &mut self,
+        ctx: &mut Context,
+        req: &Request,
+        dec: &mut Decoder<'_>,
+    ) -> Result<Vec<u8>> {
+        let span = enroll_span("generate_enrollment_token", Some(req.id()));
+        let res = async {
+            let limits = self
+                .inner()
+                .read()
+                .await
+                .enroll_options
+                .attributes_limits
+                .clone();
+            if let Err(res) = check_encoded_attributes(req, dec, &limits) {
+                return res;
+            }
+            let req_wrapper = match decode_token_request(req, dec) {
+                Ok(req_wrapper) => req_wrapper,
+                Err(res) => return res,
+            };
+            let cloud_multiaddr = match request_route(req, &req_wrapper) {
+                Ok(route) => route,
+                Err(res) => return res,
+            };
+            let (token_request, retry_policy) = {
+                let node_manager = self.inner().read().await;
+                match node_manager
+                    .prepare_token_request(req, req_wrapper.req)
+                    .await?
+                {
+                    Ok(token_request) => (
+                        token_request,
+                        node_manager.enroll_options.retry_policy.clone(),
+                    ),
+                    Err(res) => return Ok(res),
+                }
+            };
+            // the node manager is locked for each attempt only, so that it can
+            // be modified while waiting for the next attempt
+            let ctx: &Context = ctx;
+            let res = retry_policy
+                .retry(
+                    || async {
+                        let node_manager = self.inner().read().await;
+                        node_manager
+                            .request_enrollment_token(ctx, &cloud_multiaddr, &token_request.body)
+                            .await
+                    },
+                    is_transient,
+                )
+                .await;
+            let res = match res {
+                Ok(res) => res,
+                Err(err) => return generation_failed_response(req, err),
+            };
+            let node_manager = self.inner().read().await;
+            node_manager
+                .generated_token_response(req, &token_request, &res)
+                .await
+        }
+        .instrument(span.clone())
+        .await;
+        record_response(&span, &res);
+        res
+    }
+
+    /// Revokes a token generated by `generate_enrollment_token`.
+    pub(crate) async fn revoke_enrollment_token(
+        &mut self,
+        ctx: &mut Context,
+        req: &Request,
+        dec: &mut Decoder<'_>,
+    ) -> Result<Vec<u8>> {
+        let span = enroll_span("revoke_enrollment_token", Some(req.id()));
+        let res = async {
+            let req_wrapper: CloudRequestWrapper<Token> = match decode_request_body(req, dec) {
+                Ok(req_wrapper) => req_wrapper,
+                Err(res) => return res,
+            };
+            let cloud_multiaddr = match request_route(req, &req_wrapper) {
+                Ok(route) => route,
+                Err(res) => return res,
+            };
+            let node_manager = self.inner().read().await;
+            node_manager
+                .revoke_enrollment_token(ctx, req, &cloud_multiaddr, &req_wrapper.req)
+                .await
+        }
+        .instrument(span.clone())
+        .await;
+        record_response(&span, &res);
+        res
+    }
+
+    /// Checks that an enrollment token could be generated, without generating it.
+    pub(crate) async fn validate_enrollment_token_request(
+        &mut self,
+        ctx: &mut Context,
+        req: &Request,
+        dec: &mut Decoder<'_>,
+    ) -> Result<Vec<u8>> {
+        let span = enroll_span("validate_enrollment_token", Some(req.id()));
+        let res = async {
+            let limits = self
+                .inner()
+                .read()
+                .await
+                .enroll_options
+                .attributes_limits
+                .clone();
+            if let Err(res) = check_encoded_attributes(req, dec, &limits) {
+                return res;
+            }
+            let req_wrapper: CloudRequestWrapper<RequestEnrollmentToken> =
+                match decode_request_body(req, dec) {
+                    Ok(req_wrapper) => req_wrapper,
+                    Err(res) => return res,
+                };
+            let cloud_multiaddr = match request_route(req, &req_wrapper) {
+                Ok(route) => route,
+                Err(res) => return res,
+            };
+            let node_manager = self.inner().read().await;
+            node_manager
+                .validate_enrollment_token_request(ctx, req, &cloud_multiaddr, req_wrapper.req)
+                .await
+        }
+        .instrument(span.clone())
+        .await;
+        record_response(&span, &res);
+        res
+    }
+
+    /// Returns the status of a token generated by `generate_enrollment_token`.
+    pub(crate) async fn introspect_enrollment_token(
+        &mut self,
+        ctx: &mut Context,
+        req: &Request,
+        dec: &mut Decoder<'_>,
+    ) -> Result<Vec<u8>> {
+        let span = enroll_span("introspect_enrollment_token", Some(req.id()));
+        let res = async {
+            let req_wrapper: CloudRequestWrapper<Token> = match decode_request_body(req, dec) {
+                Ok(req_wrapper) => req_wrapper,
+                Err(res) => return res,
+            };
+            let cloud_multiaddr = match request_route(req, &req_wrapper) {
+                Ok(route) => route,
+                Err(res) => return res,
+            };
+            let node_manager = self.inner().read().await;
+            node_manager
+                .introspect_enrollment_token(ctx, req, &cloud_multiaddr, &req_wrapper.req)
+                .await
+        }
+        .instrument(span.clone())
+        .await;
+        record_response(&span, &res);
+        res
+    }
+
+    /// Lists a page of the tokens generated by `generate_enrollment_token`.
+    pub(crate) async fn list_enrollment_tokens(
+        &mut self,
+        ctx: &mut Context,
+        req: &Request,
+        dec: &mut Decoder<'_>,
+    ) -> Result<Vec<u8>> {
+        let span = enroll_span("list_enrollment_tokens", Some(req.id()));
+        let res = async {
+            let req_wrapper: CloudRequestWrapper<ListEnrollmentTokens> =
+                match decode_request_body(req, dec) {
+                    Ok(req_wrapper) => req_wrapper,
+                    Err(res) => return res,
+                };
+            let cloud_multiaddr = match request_route(req, &req_wrapper) {
+                Ok(route) => route,
+                Err(res) => return res,
+            };
+            let list = req_wrapper.req;
+            let node_manager = self.inner().read().await;
+            node_manager
+                .list_enrollment_tokens(ctx, req, &cloud_multiaddr, list.page, list.page_size)
+                .await
+        }
+        .instrument(span.clone())
+        .await;
+        record_response(&span, &res);
+        res
+    }
+
+    /// Authenticates a token generated by `generate_enrollment_token`.
+    pub(crate) async fn authenticate_enrollment_token(
+        &mut self,
+        ctx: &mut Context,
+        req: &Request,
+        dec: &mut Decoder<'_>,
+    ) -> Result<Vec<u8>> {
+        let req_wrapper = match decode_request_body(req, dec) {
+            Ok(req_wrapper) => req_wrapper,
+            Err(res) => return res,
+        };
+        let node_manager = self.inner().read().await;
+        node_manager
+            .authenticate_enrollment_token_response(ctx, req, req_wrapper, None)
+            .await
+    }
+
+    /// Enrolls with any of the tokens accepted by `NodeManager::enroll`.
+    pub(crate) async fn enroll(
+        &mut self,
+        ctx: &mut Context,
+        req: &Request,
+        dec: &mut Decoder<'_>,
+    ) -> Result<Vec<u8>> {
+        let node_manager = self.inner().read().await;
+        node_manager.enroll(ctx, req, dec).await
+    }
+}
@@ -0,0 +1,93 @@
+use ockam::identity::credential::Attributes;
+use ockam_core::{async_trait, Result};
+
+use crate::cloud::enroll::EnrollResponse;
+
+/// Notified by the node manager every time a token is accepted by an authenticator,
+/// so that other systems can be told about the enrolled identity.
+///
+/// The errors returned by the notifier are logged, and don't fail the enrollment.
+#[async_trait]
+pub trait EnrollNotifier: Send + Sync + 'static {
+    /// `attributes` are the attributes granted by the authenticator, or the
+    /// attributes of the enrollment token when the authenticator doesn't return them
+    async fn on_enrolled(
+        &self,
+        attributes: Option<&Attributes>,
+        outcome: &EnrollResponse,
+    ) -> Result<()>;
+}
+
+/// A notifier which doesn't notify anyone
+#[derive(Default)]
+pub struct NoopEnrollNotifier;
+
+#[async_trait]
+impl EnrollNotifier for NoopEnrollNotifier {
+    async fn on_enrolled(&self, _: Option<&Attributes>, _: &EnrollResponse) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(feature = "enroll-webhook")]
+pub use webhook::HttpEnrollNotifier;
+
+#[cfg(feature = "enroll-webhook")]
+mod webhook {
+    use std::collections::BTreeMap;
+
+    use reqwest::Client;
+    use url::Url;
+
+    use crate::error::ApiError;
+
+    use super::*;
+
+    /// Posts a JSON description of each enrollment to a URL.
+    ///
+    /// The attribute values are sent as strings, with invalid UTF-8 sequences replaced.
+    pub struct HttpEnrollNotifier {
+        client: Client,
+        url: Url,
+    }
+
+    impl HttpEnrollNotifier {
+        pub fn new(url: Url) -> Self {
+            Self {
+                client: Client::new(),
+                url,
+            }
+        }
+    }
+
+    #[async_trait]
+    impl EnrollNotifier for HttpEnrollNotifier {
+        async fn on_enrolled(
+            &self,
+            attributes: Option<&Attributes>,
+            outcome: &EnrollResponse,
+        ) -> Result<()> {
+            let attributes: BTreeMap<&str, String> = attributes
+                .into_iter()
+                .flat_map(|a| a.iter())
+                .map(|(k, v)| (k.as_str(), String::from_utf8_lossy(v).to_string()))
+                .collect();
+            let claims = outcome.claims.as_ref();
+            let body = serde_json::json!({
+                "identity": claims
+                    .and_then(|c| c.identity.as_ref())
+                    .map(|i| i.to_string()),
+                "attributes": attributes,
+                "expires_at": claims.and_then(|c| c.expires_at),
+            });
+            self.client
+                .post(self.url.clone())
+                .json(&body)
+                .send()
+                .await
+                .and_then(|res| res.error_for_status())
+                .map_err(ApiError::message)?;
+            Ok(())
+        }
+    }
+}
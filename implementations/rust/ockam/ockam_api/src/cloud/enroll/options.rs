@@ -0,0 +1,256 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use ockam::identity::credential::Attributes;
+
+#[cfg(feature = "auth0")]
+use crate::cloud::enroll::auth0::Auth0Config;
+use crate::cloud::enroll::clock::{Clock, SystemClock};
+use crate::cloud::enroll::concurrency::DEFAULT_MAX_CONCURRENT_ENROLLMENTS;
+use crate::cloud::enroll::dedup_cache::{
+    InMemoryTokenDedupCache, TokenDedupCache, DEFAULT_TOKEN_DEDUP_WINDOW,
+};
+use crate::cloud::enroll::enrollment_token::AttributesLimits;
+use crate::cloud::enroll::metrics::{EnrollMetrics, NoopEnrollMetrics};
+use crate::cloud::enroll::notifier::{EnrollNotifier, NoopEnrollNotifier};
+#[cfg(feature = "auth0")]
+use crate::cloud::enroll::oidc::OidcTokenProvider;
+use crate::cloud::enroll::preflight::DEFAULT_MAX_CLOCK_SKEW;
+use crate::cloud::enroll::rate_limiter::{RateLimiter, DEFAULT_RATE_LIMIT_ATTRIBUTE};
+use crate::cloud::enroll::route_builder::{DirectRoute, RouteBuilder};
+use crate::cloud::enroll::token_cache::{InMemoryTokenCache, TokenCache, DEFAULT_EXPIRY_JITTER};
+use crate::cloud::enroll::{
+    AuthenticatorServices, DEFAULT_MAX_TOKEN_LENGTH, DEFAULT_SECURE_CHANNEL_TIMEOUT,
+};
+use crate::cloud::retry::RetryPolicy;
+
+/// Settings of the enrollments and of the generation of enrollment tokens
+/// done by a node manager
+pub struct EnrollOptions {
+    pub(crate) retry_policy: RetryPolicy,
+    // The cached tokens are only used by the auth0 flows
+    #[cfg_attr(not(feature = "auth0"), allow(dead_code))]
+    pub(crate) token_cache: Arc<dyn TokenCache>,
+    #[cfg_attr(not(feature = "auth0"), allow(dead_code))]
+    pub(crate) expiry_jitter: f64,
+    pub(crate) token_dedup_cache: Arc<dyn TokenDedupCache>,
+    pub(crate) token_dedup_window: Duration,
+    pub(crate) token_rate_limiter: Option<Arc<dyn RateLimiter>>,
+    pub(crate) rate_limit_attribute: String,
+    pub(crate) clock: Arc<dyn Clock>,
+    pub(crate) max_clock_skew: Duration,
+    pub(crate) secure_channel_timeout: Duration,
+    pub(crate) attributes_limits: AttributesLimits,
+    pub(crate) project_attributes: Attributes,
+    pub(crate) inherit_project_attributes: bool,
+    pub(crate) max_token_length: usize,
+    pub(crate) max_concurrent_enrollments: usize,
+    pub(crate) max_waiting_enrollments: Option<usize>,
+    pub(crate) enroll_deadline: Option<Duration>,
+    pub(crate) authenticator_services: AuthenticatorServices,
+    #[cfg(feature = "auth0")]
+    pub(crate) auth0_config: Auth0Config,
+    #[cfg(feature = "auth0")]
+    pub(crate) auth0_token_provider: Option<Arc<dyn OidcTokenProvider>>,
+    pub(crate) enroll_metrics: Arc<dyn EnrollMetrics>,
+    pub(crate) enroll_notifier: Arc<dyn EnrollNotifier>,
+    pub(crate) route_builder: Arc<dyn RouteBuilder>,
+    pub(crate) request_metadata: BTreeMap<String, String>,
+}
+
+impl Default for EnrollOptions {
+    fn default() -> Self {
+        Self {
+            retry_policy: RetryPolicy::default(),
+            token_cache: Arc::new(InMemoryTokenCache::default()),
+            expiry_jitter: DEFAULT_EXPIRY_JITTER,
+            token_dedup_cache: Arc::new(InMemoryTokenDedupCache::default()),
+            token_dedup_window: DEFAULT_TOKEN_DEDUP_WINDOW,
+            token_rate_limiter: None,
+            rate_limit_attribute: DEFAULT_RATE_LIMIT_ATTRIBUTE.to_string(),
+            clock: Arc::new(SystemClock),
+            max_clock_skew: DEFAULT_MAX_CLOCK_SKEW,
+            secure_channel_timeout: DEFAULT_SECURE_CHANNEL_TIMEOUT,
+            attributes_limits: AttributesLimits::default(),
+            project_attributes: Attributes::new(),
+            inherit_project_attributes: true,
+            max_token_length: DEFAULT_MAX_TOKEN_LENGTH,
+            max_concurrent_enrollments: DEFAULT_MAX_CONCURRENT_ENROLLMENTS,
+            max_waiting_enrollments: None,
+            enroll_deadline: None,
+            authenticator_services: AuthenticatorServices::default(),
+            #[cfg(feature = "auth0")]
+            auth0_config: Auth0Config::default(),
+            #[cfg(feature = "auth0")]
+            auth0_token_provider: None,
+            enroll_metrics: Arc::new(NoopEnrollMetrics),
+            enroll_notifier: Arc::new(NoopEnrollNotifier),
+            route_builder: Arc::new(DirectRoute),
+            request_metadata: BTreeMap::new(),
+        }
+    }
+}
+
+impl EnrollOptions {
+    /// Set how Orchestrator requests failing with transient errors are retried
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    /// Set where the tokens obtained during enrollment are kept
+    pub fn with_token_cache(mut self, token_cache: Arc<dyn TokenCache>) -> Self {
+        self.token_cache = token_cache;
+        self
+    }
+
+    /// Set the fraction of the lifetime of the cached tokens within which they are
+    /// refreshed, at a random time before their expiry
+    pub fn with_expiry_jitter(mut self, expiry_jitter: f64) -> Self {
+        self.expiry_jitter = expiry_jitter;
+        self
+    }
+
+    /// Set where the enrollment tokens recently generated for each request are kept
+    pub fn with_token_dedup_cache(mut self, token_dedup_cache: Arc<dyn TokenDedupCache>) -> Self {
+        self.token_dedup_cache = token_dedup_cache;
+        self
+    }
+
+    /// Answer the same request as the one of an enrollment token generated less
+    /// than `token_dedup_window` ago with that token, instead of generating a new one
+    pub fn with_token_dedup_window(mut self, token_dedup_window: Duration) -> Self {
+        self.token_dedup_window = token_dedup_window;
+        self
+    }
+
+    /// Limit the number of enrollment tokens generated for each value of the
+    /// rate limit attribute. The generation is not limited by default.
+    pub fn with_token_rate_limiter(mut self, token_rate_limiter: Arc<dyn RateLimiter>) -> Self {
+        self.token_rate_limiter = Some(token_rate_limiter);
+        self
+    }
+
+    /// Use another attribute than `tenant` to rate limit the generation of enrollment tokens
+    pub fn with_rate_limit_attribute(mut self, rate_limit_attribute: &str) -> Self {
+        self.rate_limit_attribute = rate_limit_attribute.to_string();
+        self
+    }
+
+    /// Set the clock used to check the expiry of the enrollment tokens and of the cached tokens
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Set how far the clock of an authenticator can be from the clock of the node
+    /// for the enrollment preflight to pass
+    pub fn with_max_clock_skew(mut self, max_clock_skew: Duration) -> Self {
+        self.max_clock_skew = max_clock_skew;
+        self
+    }
+
+    /// Set how long to wait for the secure channel to an Orchestrator authenticator
+    pub fn with_secure_channel_timeout(mut self, secure_channel_timeout: Duration) -> Self {
+        self.secure_channel_timeout = secure_channel_timeout;
+        self
+    }
+
+    /// Set the limits checked on the attributes of the enrollment tokens to generate
+    pub fn with_attributes_limits(mut self, attributes_limits: AttributesLimits) -> Self {
+        self.attributes_limits = attributes_limits;
+        self
+    }
+
+    /// Set the default attributes of the enrollment tokens generated for the project of the node
+    pub fn with_project_attributes(mut self, project_attributes: Attributes) -> Self {
+        self.project_attributes = project_attributes;
+        self
+    }
+
+    /// Set whether the enrollment tokens generated by the node get the project default
+    /// attributes which they are not requested with. They do unless this is disabled
+    pub fn with_inherit_project_attributes(mut self, inherit_project_attributes: bool) -> Self {
+        self.inherit_project_attributes = inherit_project_attributes;
+        self
+    }
+
+    /// Set the maximum length, in bytes, of the tokens received by the enrollment endpoints
+    pub fn with_max_token_length(mut self, max_token_length: usize) -> Self {
+        self.max_token_length = max_token_length;
+        self
+    }
+
+    /// Set the number of enrollments running at once, the other ones wait for their turn
+    pub fn with_max_concurrent_enrollments(mut self, max_concurrent_enrollments: usize) -> Self {
+        self.max_concurrent_enrollments = max_concurrent_enrollments;
+        self
+    }
+
+    /// Refuse the enrollments, with a `503 ServiceUnavailable` status, when
+    /// `max_waiting_enrollments` of them are already waiting for their turn
+    pub fn with_max_waiting_enrollments(mut self, max_waiting_enrollments: usize) -> Self {
+        self.max_waiting_enrollments = Some(max_waiting_enrollments);
+        self
+    }
+
+    /// Fail the enrollments, with a `408 RequestTimeout` status, when they don't complete
+    /// within `enroll_deadline`, whatever the timeouts of their secure channels and requests
+    pub fn with_enroll_deadline(mut self, enroll_deadline: Duration) -> Self {
+        self.enroll_deadline = Some(enroll_deadline);
+        self
+    }
+
+    /// Use other Orchestrator services than the default ones to authenticate tokens
+    pub fn with_authenticator_services(
+        mut self,
+        authenticator_services: AuthenticatorServices,
+    ) -> Self {
+        self.authenticator_services = authenticator_services;
+        self
+    }
+
+    #[cfg(feature = "auth0")]
+    /// Use another Auth0 tenant or application than the Ockam ones for the auth0 flows
+    pub fn with_auth0_config(mut self, auth0_config: Auth0Config) -> Self {
+        self.auth0_config = auth0_config;
+        self
+    }
+
+    #[cfg(feature = "auth0")]
+    /// Set the source of the Auth0 tokens used by `NodeManager::enroll_auth0_with_provider`,
+    /// for embedders which obtain them without a node API client
+    pub fn with_auth0_token_provider(
+        mut self,
+        auth0_token_provider: Arc<dyn OidcTokenProvider>,
+    ) -> Self {
+        self.auth0_token_provider = Some(auth0_token_provider);
+        self
+    }
+
+    /// Set the counters incremented during the enrollment flows
+    pub fn with_enroll_metrics(mut self, enroll_metrics: Arc<dyn EnrollMetrics>) -> Self {
+        self.enroll_metrics = enroll_metrics;
+        self
+    }
+
+    /// Set the notifier called after each successful enrollment
+    pub fn with_enroll_notifier(mut self, enroll_notifier: Arc<dyn EnrollNotifier>) -> Self {
+        self.enroll_notifier = enroll_notifier;
+        self
+    }
+
+    /// Set how the routes to the Orchestrator services are built from the secure channels to the Orchestrator
+    pub fn with_route_builder(mut self, route_builder: Arc<dyn RouteBuilder>) -> Self {
+        self.route_builder = route_builder;
+        self
+    }
+
+    /// Set metadata fields added to the requests sent to the Orchestrator authenticators,
+    /// for instance a tenant hint used to route them
+    pub fn with_request_metadata(mut self, request_metadata: BTreeMap<String, String>) -> Self {
+        self.request_metadata = request_metadata;
+        self
+    }
+}
@@ -0,0 +1,214 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use ockam::identity::credential::{Attributes, AttributesDiff};
+use ockam::identity::IdentityIdentifier;
+use ockam_core::Result;
+
+use crate::cloud::enroll::events::EnrollFlow;
+use crate::cloud::enroll::EnrollResponse;
+use crate::error::ApiError;
+
+/// What a node received from a successful enrollment, so that it can be inspected
+/// later on, and the identity enrolled again before its enrollment expires.
+///
+/// It never contains the token the identity enrolled with, nor the credential
+/// it was issued: it can be stored without protecting it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct PersistedEnrollment {
+    pub flow: EnrollFlow,
+    /// Identity which was enrolled, when the authenticator reports it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub identity: Option<IdentityIdentifier>,
+    /// Attributes granted to the enrolled identity. The values which are
+    /// not valid UTF-8 are stored with replacement characters
+    #[serde(default)]
+    pub attributes: BTreeMap<String, String>,
+    /// Unix time (in seconds) of the enrollment
+    pub enrolled_at: u64,
+    /// Unix time (in seconds) after which the enrollment is no longer valid
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>,
+    /// Unix time (in seconds) at which the credential issued to the enrolled identity expires
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub credential_expires_at: Option<u64>,
+    /// Attributes which changed since the previous enrollment of the node,
+    /// when there was one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub changes: Option<AttributesDiff>,
+}
+
+impl PersistedEnrollment {
+    /// Record the enrollment of `response` with a `flow` token, at the Unix time `now`
+    pub fn new(flow: EnrollFlow, response: &EnrollResponse, now: u64) -> Self {
+        let claims = response.claims.clone().unwrap_or_default();
+        let attributes = claims
+            .attributes
+            .iter()
+            .flat_map(|attributes| attributes.iter())
+            .map(|(key, value)| (key.clone(), String::from_utf8_lossy(value).into_owned()))
+            .collect();
+        Self {
+            flow,
+            identity: claims.identity,
+            attributes,
+            enrolled_at: now,
+            expires_at: claims.expires_at,
+            credential_expires_at: claims.credential_expires_at,
+            changes: None,
+        }
+    }
+
+    /// Compare the attributes of this enrollment with the ones of the `previous` enrollment
+    pub fn with_previous(mut self, previous: &PersistedEnrollment) -> Self {
+        self.changes = Some(self.granted().diff(&previous.granted()));
+        self
+    }
+
+    /// Save the enrollment to `path`, after comparing its attributes with the
+    /// enrollment previously saved there, if any
+    pub fn record(self, path: &Path) -> Result<Self> {
+        let enrollment = match Self::load(path)? {
+            Some(previous) => self.with_previous(&previous),
+            None => self,
+        };
+        enrollment.save(path)?;
+        Ok(enrollment)
+    }
+
+    fn granted(&self) -> Attributes {
+        let mut attributes = Attributes::new();
+        for (key, value) in &self.attributes {
+            attributes.put(key, value.as_bytes());
+        }
+        attributes
+    }
+
+    /// Unix time (in seconds) before which the identity must enroll again,
+    /// unless neither the enrollment nor its credential expire
+    pub fn renew_before(&self) -> Option<u64> {
+        match (self.expires_at, self.credential_expires_at) {
+            (Some(enrollment), Some(credential)) => Some(enrollment.min(credential)),
+            (enrollment, credential) => enrollment.or(credential),
+        }
+    }
+
+    /// Write the enrollment to `path` as JSON, replacing the previous one.
+    ///
+    /// The file is replaced at once, so that a node stopped while saving
+    /// it doesn't leave a partial enrollment behind.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let contents = serde_json::to_vec_pretty(self).map_err(ApiError::message)?;
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        fs::write(&tmp, contents).map_err(ApiError::message)?;
+        fs::rename(&tmp, path).map_err(ApiError::message)
+    }
+
+    /// Read the enrollment saved at `path`, if there is one
+    pub fn load(path: &Path) -> Result<Option<Self>> {
+        match fs::read(path) {
+            Ok(contents) => serde_json::from_slice(&contents)
+                .map(Some)
+                .map_err(ApiError::message),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(ApiError::message(err)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use crate::cloud::enroll::EnrollClaims;
+
+    use super::*;
+
+    fn enrollment() -> PersistedEnrollment {
+        enrollment_with(&[("role", b"device"), ("raw", &[0xff])])
+    }
+
+    fn enrollment_with(granted: &[(&str, &[u8])]) -> PersistedEnrollment {
+        let mut attributes = Attributes::new();
+        for (key, value) in granted {
+            attributes.put(key, value);
+        }
+        let identity = IdentityIdentifier::from_str(
+            "Pe92f183eb4c324804ef4d62962dea94cf095a265d4d28500c34e1a4e0d5ef638",
+        )
+        .unwrap();
+        let claims = EnrollClaims {
+            #[cfg(feature = "tag")]
+            tag: ockam_core::TypeTag,
+            identity: Some(identity),
+            attributes: Some(attributes),
+            expires_at: Some(2000),
+            credential_expires_at: Some(1500),
+        };
+        let response = EnrollResponse {
+            claims: Some(claims),
+            raw: vec![],
+            authenticator: None,
+            timings: None,
+        };
+        PersistedEnrollment::new(EnrollFlow::EnrollmentToken, &response, 1000)
+    }
+
+    #[test]
+    fn enrollments_roundtrip_through_json() {
+        let enrollment = enrollment();
+        assert_eq!(enrollment.attributes["role"], "device");
+        assert_eq!(enrollment.attributes["raw"], "\u{fffd}");
+        assert_eq!(enrollment.renew_before(), Some(1500));
+
+        let json = serde_json::to_string(&enrollment).unwrap();
+        assert!(json.contains(r#""flow":"enrollment_token""#), "{json}");
+        let decoded: PersistedEnrollment = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded, enrollment);
+
+        let minimal = r#"{"flow":"api_key","enrolled_at":1000}"#;
+        let decoded: PersistedEnrollment = serde_json::from_str(minimal).unwrap();
+        assert!(decoded.attributes.is_empty());
+        assert_eq!(decoded.renew_before(), None);
+    }
+
+    #[test]
+    fn enrollments_are_saved_and_loaded() -> Result<()> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("enrollment.json");
+        assert_eq!(PersistedEnrollment::load(&path)?, None);
+
+        let enrollment = enrollment();
+        enrollment.save(&path)?;
+        assert_eq!(PersistedEnrollment::load(&path)?, Some(enrollment));
+
+        fs::write(&path, "{").unwrap();
+        assert!(PersistedEnrollment::load(&path).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn enrollments_report_the_attributes_changed_since_the_previous_one() -> Result<()> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("enrollment.json");
+
+        let first = enrollment().record(&path)?;
+        assert_eq!(first.changes, None);
+
+        let second = enrollment_with(&[("role", b"admin"), ("zone", b"eu")]).record(&path)?;
+        let changes = second.changes.clone().unwrap();
+        assert_eq!(changes.added, ["zone"]);
+        assert_eq!(changes.removed, ["raw"]);
+        assert_eq!(changes.changed, ["role"]);
+        assert_eq!(PersistedEnrollment::load(&path)?, Some(second.clone()));
+
+        let third = enrollment_with(&[("role", b"admin"), ("zone", b"eu")]).record(&path)?;
+        assert!(third.changes.unwrap().is_empty());
+        Ok(())
+    }
+}
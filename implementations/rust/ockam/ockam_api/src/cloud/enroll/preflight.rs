@@ -0,0 +1,91 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use minicbor::{Decode, Encode};
+
+#[cfg(feature = "tag")]
+use ockam_core::TypeTag;
+
+use crate::cloud::enroll::clock::{Clock, SkewedClock};
+
+/// Largest difference tolerated between the clock of a node and the clock of
+/// the authenticator it enrolls with
+pub const DEFAULT_MAX_CLOCK_SKEW: Duration = Duration::from_secs(5 * 60);
+
+/// Current time of an Orchestrator authenticator
+#[derive(Encode, Decode, Debug, Clone, PartialEq, Eq)]
+#[rustfmt::skip]
+#[cbor(map)]
+pub struct ServerTime {
+    #[cfg(feature = "tag")]
+    #[n(0)] pub tag: TypeTag<5893017>,
+    /// Unix time, in seconds
+    #[n(1)] pub now: u64,
+}
+
+impl ServerTime {
+    pub fn new(now: u64) -> Self {
+        Self {
+            #[cfg(feature = "tag")]
+            tag: TypeTag,
+            now,
+        }
+    }
+}
+
+/// What a node measured of the authenticator it is about to enroll with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EnrollPreflight {
+    /// Round-trip time of the request for the time of the authenticator
+    pub rtt: Duration,
+    /// Seconds by which the clock of the authenticator is ahead of the clock
+    /// of the node, negative when it is behind
+    pub skew: i64,
+}
+
+impl EnrollPreflight {
+    /// Compare the `server` time with the local time halfway between `sent_at`
+    /// and `received_at`, the local times of the request and of its response
+    pub fn measure(sent_at: u64, received_at: u64, server: u64, rtt: Duration) -> Self {
+        let local = sent_at + received_at.saturating_sub(sent_at) / 2;
+        Self {
+            rtt,
+            skew: server as i64 - local as i64,
+        }
+    }
+
+    /// Return true if the skew is larger than `max`, in either direction
+    pub fn exceeds(&self, max: Duration) -> bool {
+        self.skew.unsigned_abs() > max.as_secs()
+    }
+
+    /// Return `clock` set to the time of the authenticator, to compare the
+    /// expiries it sets
+    pub fn adjusted_clock(&self, clock: Arc<dyn Clock>) -> SkewedClock {
+        SkewedClock::new(clock, self.skew)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cloud::enroll::clock::ManualClock;
+
+    use super::*;
+
+    #[test]
+    fn skews_are_measured_halfway_through_the_request() {
+        let rtt = Duration::from_secs(4);
+        let ahead = EnrollPreflight::measure(1000, 1004, 1302, rtt);
+        assert_eq!(ahead.skew, 300);
+        assert!(!ahead.exceeds(DEFAULT_MAX_CLOCK_SKEW));
+        assert!(ahead.exceeds(Duration::from_secs(299)));
+
+        let behind = EnrollPreflight::measure(1000, 1000, 400, rtt);
+        assert_eq!(behind.skew, -600);
+        assert!(behind.exceeds(DEFAULT_MAX_CLOCK_SKEW));
+
+        let clock = Arc::new(ManualClock::new(1000));
+        assert_eq!(ahead.adjusted_clock(clock.clone()).now().unwrap(), 1300);
+        assert_eq!(behind.adjusted_clock(clock).now().unwrap(), 400);
+    }
+}
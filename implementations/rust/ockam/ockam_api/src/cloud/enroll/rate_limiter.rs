@@ -0,0 +1,224 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use ockam::identity::credential::Attributes;
+use ockam_core::compat::sync::Mutex;
+use ockam_core::{async_trait, Result};
+
+/// Attribute of the token requests by which their generation is rate limited
+pub const DEFAULT_RATE_LIMIT_ATTRIBUTE: &str = "tenant";
+
+/// Number of keys whose buckets are kept by a [`TokenBucketRateLimiter`]
+pub const DEFAULT_RATE_LIMIT_BUCKETS_CAPACITY: usize = 10_000;
+
+/// Number of requests allowed for a key: up to `capacity` at once,
+/// and then one more each `refill_period`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    pub capacity: u32,
+    pub refill_period: Duration,
+}
+
+impl RateLimit {
+    pub fn new(capacity: u32, refill_period: Duration) -> Self {
+        Self {
+            capacity,
+            refill_period,
+        }
+    }
+}
+
+/// Limiter of the number of enrollment tokens generated for each key,
+/// usually the value of a tenant attribute
+#[async_trait]
+pub trait RateLimiter: Send + Sync + 'static {
+    /// Return true if another request for `key` is allowed at the Unix time `now`,
+    /// in which case it is counted against the limit of `key`
+    async fn try_acquire(&self, key: &str, now: u64) -> Result<bool>;
+}
+
+/// Return the key by which a token request for `attributes` is rate limited.
+///
+/// The requests which don't have the `attribute` all share the same key.
+pub fn rate_limit_key(attributes: &Attributes, attribute: &str) -> String {
+    attributes
+        .get(attribute)
+        .map(|value| String::from_utf8_lossy(value).into_owned())
+        .unwrap_or_default()
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    available: u32,
+    /// Unix time (in seconds) at which `available` was last refilled
+    refilled_at: u64,
+}
+
+/// A token bucket for each key.
+///
+/// The buckets which are full again are removed, since they allow as many requests
+/// as a new bucket. When there are too many buckets, the one refilled the longest
+/// ago is forgotten to make room for a new one. The requests which don't have the
+/// rate limit attribute all share the bucket of the `""` key, whose limit can be
+/// set with [`TokenBucketRateLimiter::with_limit`].
+pub struct TokenBucketRateLimiter {
+    default_limit: RateLimit,
+    limits: HashMap<String, RateLimit>,
+    capacity: usize,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl TokenBucketRateLimiter {
+    /// Create a limiter applying `default_limit` to the keys which don't have their own
+    pub fn new(default_limit: RateLimit) -> Self {
+        Self {
+            default_limit,
+            limits: HashMap::new(),
+            capacity: DEFAULT_RATE_LIMIT_BUCKETS_CAPACITY,
+            buckets: Default::default(),
+        }
+    }
+
+    /// Apply `limit` to the requests for `key` rather than the default limit
+    pub fn with_limit(mut self, key: &str, limit: RateLimit) -> Self {
+        self.limits.insert(key.to_string(), limit);
+        self
+    }
+
+    /// Keep the buckets of at most `capacity` keys
+    pub fn with_capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity.max(1);
+        self
+    }
+
+    /// Number of keys whose bucket is currently kept
+    pub fn len(&self) -> usize {
+        self.buckets.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn limit(&self, key: &str) -> RateLimit {
+        self.limits.get(key).copied().unwrap_or(self.default_limit)
+    }
+}
+
+impl Bucket {
+    /// Refill the bucket with the requests allowed by `limit` until `now`
+    fn refill(&mut self, limit: RateLimit, now: u64) {
+        let period = limit.refill_period.as_secs().max(1);
+        let refills = now.saturating_sub(self.refilled_at) / period;
+        if refills > 0 {
+            let refilled = u64::from(self.available) + refills;
+            self.available = refilled.min(u64::from(limit.capacity)) as u32;
+            self.refilled_at += refills * period;
+        }
+    }
+}
+
+#[async_trait]
+impl RateLimiter for TokenBucketRateLimiter {
+    async fn try_acquire(&self, key: &str, now: u64) -> Result<bool> {
+        let limit = self.limit(key);
+        let mut buckets = self.buckets.lock().unwrap();
+        buckets.retain(|key, bucket| {
+            let limit = self.limit(key);
+            bucket.refill(limit, now);
+            bucket.available < limit.capacity
+        });
+        if !buckets.contains_key(key) && buckets.len() >= self.capacity {
+            let oldest = buckets
+                .iter()
+                .min_by_key(|(_, bucket)| bucket.refilled_at)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                buckets.remove(&oldest);
+            }
+        }
+        let bucket = buckets.entry(key.to_string()).or_insert(Bucket {
+            available: limit.capacity,
+            refilled_at: now,
+        });
+        if bucket.available == 0 {
+            return Ok(false);
+        }
+        bucket.available -= 1;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn buckets_are_refilled_over_time() -> Result<()> {
+        let limiter = TokenBucketRateLimiter::new(RateLimit::new(2, Duration::from_secs(10)));
+        assert!(limiter.try_acquire("acme", 100).await?);
+        assert!(limiter.try_acquire("acme", 101).await?);
+        assert!(!limiter.try_acquire("acme", 109).await?);
+
+        assert!(limiter.try_acquire("acme", 110).await?);
+        assert!(!limiter.try_acquire("acme", 111).await?);
+
+        // the bucket is not refilled beyond its capacity
+        assert!(limiter.try_acquire("acme", 200).await?);
+        assert!(limiter.try_acquire("acme", 200).await?);
+        assert!(!limiter.try_acquire("acme", 200).await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn limits_can_be_set_per_key() -> Result<()> {
+        let limiter = TokenBucketRateLimiter::new(RateLimit::new(1, Duration::from_secs(60)))
+            .with_limit("acme", RateLimit::new(3, Duration::from_secs(60)));
+        for _ in 0..3 {
+            assert!(limiter.try_acquire("acme", 100).await?);
+        }
+        assert!(!limiter.try_acquire("acme", 100).await?);
+
+        assert!(limiter.try_acquire("initech", 100).await?);
+        assert!(!limiter.try_acquire("initech", 100).await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn full_buckets_are_removed_and_bounded() -> Result<()> {
+        let limiter = TokenBucketRateLimiter::new(RateLimit::new(2, Duration::from_secs(10)))
+            .with_capacity(100);
+        // distinct keys, which are never used again
+        for key in 0..1000 {
+            assert!(limiter.try_acquire(&format!("key-{key}"), 100).await?);
+            assert!(limiter.len() <= 100);
+        }
+        assert_eq!(limiter.len(), 100);
+
+        // the buckets are full again after a refill period
+        assert!(limiter.try_acquire("acme", 110).await?);
+        assert_eq!(limiter.len(), 1);
+        assert!(limiter.try_acquire("acme", 110).await?);
+        assert!(!limiter.try_acquire("acme", 110).await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn requests_without_the_attribute_share_a_bucket() -> Result<()> {
+        let limiter = TokenBucketRateLimiter::new(RateLimit::new(1, Duration::from_secs(60)))
+            .with_limit("", RateLimit::new(2, Duration::from_secs(60)));
+        let key = rate_limit_key(&Attributes::new(), DEFAULT_RATE_LIMIT_ATTRIBUTE);
+        assert!(limiter.try_acquire(&key, 100).await?);
+        assert!(limiter.try_acquire(&key, 100).await?);
+        assert!(!limiter.try_acquire(&key, 100).await?);
+        Ok(())
+    }
+
+    #[test]
+    fn requests_are_keyed_by_their_attribute() {
+        let mut attributes = Attributes::new();
+        attributes.put("tenant", b"acme").put("role", b"device");
+        assert_eq!(rate_limit_key(&attributes, "tenant"), "acme");
+        assert_eq!(rate_limit_key(&attributes, "zone"), "");
+    }
+}
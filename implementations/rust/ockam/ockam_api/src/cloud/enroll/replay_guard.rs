@@ -0,0 +1,156 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use ockam_core::compat::sync::Mutex;
+use ockam_vault::Vault;
+
+use crate::cloud::enroll::Token;
+
+/// Number of enrollment tokens which can be authenticated at once by a node
+pub const DEFAULT_IN_FLIGHT_TOKENS_CAPACITY: usize = 10_000;
+
+/// Time after which a token is considered abandoned by its authentication,
+/// if it was not released before
+pub const DEFAULT_IN_FLIGHT_TOKEN_TTL: Duration = Duration::from_secs(5 * 60);
+
+/// The enrollment tokens being authenticated by a node, so that a token replayed
+/// while its first authentication is still running is rejected locally, rather
+/// than racing with it to the authenticator.
+///
+/// Only a digest of the tokens is kept. Tokens are released when their
+/// authentication completes, or at the latest after the TTL. When the guard
+/// is full, the oldest token is forgotten to make room for a new one.
+pub struct ReplayGuard {
+    capacity: usize,
+    ttl: Duration,
+    in_flight: Mutex<InFlight>,
+}
+
+#[derive(Default)]
+struct InFlight {
+    /// Claims of the tokens, by digest of the tokens
+    claims: HashMap<String, Claimed>,
+    next_id: u64,
+}
+
+struct Claimed {
+    /// Unix time (in seconds) at which the token was claimed
+    at: u64,
+    /// Identifier of the claim, so that a claim which expired doesn't
+    /// release the next claim of the same token when it is dropped
+    id: u64,
+}
+
+impl Default for ReplayGuard {
+    fn default() -> Self {
+        Self::new(
+            DEFAULT_IN_FLIGHT_TOKENS_CAPACITY,
+            DEFAULT_IN_FLIGHT_TOKEN_TTL,
+        )
+    }
+}
+
+impl ReplayGuard {
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            ttl,
+            in_flight: Default::default(),
+        }
+    }
+
+    /// Claim `token` at the Unix time `now`, for the duration of its authentication.
+    ///
+    /// `None` is returned if the token is already claimed, until the returned claim
+    /// is dropped or the TTL elapses.
+    pub fn claim(&self, token: &Token, now: u64) -> Option<TokenClaim<'_>> {
+        let digest = hex::encode(Vault::sha256(token.reveal().as_bytes()));
+        let mut in_flight = self.in_flight.lock().unwrap();
+        let claims = &mut in_flight.claims;
+        let ttl = self.ttl.as_secs();
+        claims.retain(|_, claimed| now < claimed.at + ttl);
+        if claims.contains_key(&digest) {
+            return None;
+        }
+        if claims.len() >= self.capacity {
+            let oldest = claims
+                .iter()
+                .min_by_key(|(_, claimed)| claimed.at)
+                .map(|(digest, _)| digest.clone());
+            if let Some(oldest) = oldest {
+                claims.remove(&oldest);
+            }
+        }
+        let id = in_flight.next_id;
+        in_flight.next_id += 1;
+        in_flight
+            .claims
+            .insert(digest.clone(), Claimed { at: now, id });
+        Some(TokenClaim {
+            guard: self,
+            digest,
+            id,
+        })
+    }
+
+    /// Number of tokens currently claimed
+    pub fn len(&self) -> usize {
+        self.in_flight.lock().unwrap().claims.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// A token claimed with [`ReplayGuard::claim`], released when dropped
+pub struct TokenClaim<'a> {
+    guard: &'a ReplayGuard,
+    digest: String,
+    id: u64,
+}
+
+impl Drop for TokenClaim<'_> {
+    fn drop(&mut self) {
+        let claims = &mut self.guard.in_flight.lock().unwrap().claims;
+        if claims.get(&self.digest).map(|claimed| claimed.id) == Some(self.id) {
+            claims.remove(&self.digest);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokens_can_only_be_claimed_once_at_a_time() {
+        let guard = ReplayGuard::default();
+        let token = Token::new("token");
+        let claim = guard.claim(&token, 100).unwrap();
+        assert!(guard.claim(&token, 100).is_none());
+        assert!(guard.claim(&Token::new("other"), 100).is_some());
+
+        drop(claim);
+        assert!(guard.claim(&token, 100).is_some());
+        assert!(guard.is_empty());
+    }
+
+    #[test]
+    fn claims_expire_and_are_bounded() {
+        let guard = ReplayGuard::new(2, Duration::from_secs(10));
+        let (first, second, third) = (Token::new("1"), Token::new("2"), Token::new("3"));
+        let expired = guard.claim(&first, 100).unwrap();
+        assert!(guard.claim(&first, 109).is_none());
+        let _first = guard.claim(&first, 110).unwrap();
+        drop(expired);
+        assert!(guard.claim(&first, 110).is_none());
+
+        let _second = guard.claim(&second, 111).unwrap();
+        let _third = guard.claim(&third, 112).unwrap();
+        assert_eq!(guard.len(), 2);
+        // the oldest claim was forgotten to make room for the third token
+        assert!(guard.claim(&second, 112).is_none());
+        assert!(guard.claim(&first, 112).is_some());
+    }
+}
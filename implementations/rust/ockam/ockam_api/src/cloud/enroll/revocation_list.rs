@@ -0,0 +1,109 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use ockam_core::compat::sync::Mutex;
+use ockam_vault::Vault;
+
+use crate::cloud::enroll::Token;
+
+/// Number of revoked enrollment tokens remembered by a node
+pub const DEFAULT_REVOKED_TOKENS_CAPACITY: usize = 10_000;
+
+/// Time during which a node remembers that it revoked a token
+pub const DEFAULT_REVOKED_TOKEN_TTL: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// The enrollment tokens revoked by a node, so that it rejects them locally
+/// rather than sending them to the authenticator.
+///
+/// The revocations only apply on the node which made them: the other nodes
+/// rely on the authenticator, which rejects the revoked tokens anyway. Only a
+/// digest of the tokens is kept. A revocation is forgotten after the TTL, and
+/// the oldest one is forgotten to make room for a new one when the list is full.
+pub struct RevocationList {
+    capacity: usize,
+    ttl: Duration,
+    /// Unix time (in seconds) at which the tokens were revoked, by digest of the tokens
+    revoked: Mutex<HashMap<String, u64>>,
+}
+
+impl Default for RevocationList {
+    fn default() -> Self {
+        Self::new(DEFAULT_REVOKED_TOKENS_CAPACITY, DEFAULT_REVOKED_TOKEN_TTL)
+    }
+}
+
+impl RevocationList {
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            ttl,
+            revoked: Default::default(),
+        }
+    }
+
+    /// Remember that `token` was revoked at the Unix time `now`
+    pub fn revoke(&self, token: &Token, now: u64) {
+        let mut revoked = self.revoked.lock().unwrap();
+        self.forget_expired(&mut revoked, now);
+        if revoked.len() >= self.capacity {
+            let oldest = revoked
+                .iter()
+                .min_by_key(|(_, at)| **at)
+                .map(|(digest, _)| digest.clone());
+            if let Some(oldest) = oldest {
+                revoked.remove(&oldest);
+            }
+        }
+        revoked.insert(digest(token), now);
+    }
+
+    /// Return true if `token` was revoked less than the TTL before `now`
+    pub fn is_revoked(&self, token: &Token, now: u64) -> bool {
+        let mut revoked = self.revoked.lock().unwrap();
+        self.forget_expired(&mut revoked, now);
+        revoked.contains_key(&digest(token))
+    }
+
+    /// Number of revocations currently remembered
+    pub fn len(&self) -> usize {
+        self.revoked.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn forget_expired(&self, revoked: &mut HashMap<String, u64>, now: u64) {
+        let ttl = self.ttl.as_secs();
+        revoked.retain(|_, at| now < *at + ttl);
+    }
+}
+
+fn digest(token: &Token) -> String {
+    hex::encode(Vault::sha256(token.reveal().as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn revocations_expire_and_are_bounded() {
+        let list = RevocationList::new(2, Duration::from_secs(10));
+        let (first, second, third) = (Token::new("1"), Token::new("2"), Token::new("3"));
+        list.revoke(&first, 100);
+        assert!(list.is_revoked(&first, 109));
+        assert!(!list.is_revoked(&second, 109));
+        assert!(!list.is_revoked(&first, 110));
+        assert!(list.is_empty());
+
+        list.revoke(&first, 110);
+        list.revoke(&second, 111);
+        list.revoke(&third, 112);
+        assert_eq!(list.len(), 2);
+        // the oldest revocation was forgotten to make room for the third token
+        assert!(!list.is_revoked(&first, 112));
+        assert!(list.is_revoked(&second, 112));
+        assert!(list.is_revoked(&third, 112));
+    }
+}
@@ -0,0 +1,114 @@
+use std::collections::HashMap;
+
+use ockam_core::compat::sync::Mutex;
+use ockam_core::{route, Route};
+
+use crate::cloud::enroll::secure_channel_address::SecureChannelAddress;
+
+/// Builds the route to an Orchestrator service, such as an authenticator,
+/// from the secure channel `channel` opened to the Orchestrator.
+///
+/// Deployments can provide their own implementation to insert relay hops,
+/// or hops using a specific transport, before the service. Closures taking
+/// the channel and the service name can be used as well.
+pub trait RouteBuilder: Send + Sync + 'static {
+    fn route(&self, channel: &SecureChannelAddress, service: &str) -> Route;
+}
+
+/// Send the messages to the service right after the secure channel
+#[derive(Default)]
+pub struct DirectRoute;
+
+impl RouteBuilder for DirectRoute {
+    fn route(&self, channel: &SecureChannelAddress, service: &str) -> Route {
+        route![channel.address().clone(), service]
+    }
+}
+
+impl<F> RouteBuilder for F
+where
+    F: Fn(&SecureChannelAddress, &str) -> Route + Send + Sync + 'static,
+{
+    fn route(&self, channel: &SecureChannelAddress, service: &str) -> Route {
+        self(channel, service)
+    }
+}
+
+/// The routes built for each secure channel and service, so that the requests
+/// sent over the same channel don't build the same route again.
+///
+/// The routes of a channel must be forgotten when the channel is stopped,
+/// since its address can't be used anymore.
+#[derive(Default)]
+pub struct RouteCache {
+    routes: Mutex<HashMap<(SecureChannelAddress, String), Route>>,
+}
+
+impl RouteCache {
+    /// Return the route to `service` over `channel`, built by `builder` if it is not cached yet
+    pub fn route(
+        &self,
+        builder: &dyn RouteBuilder,
+        channel: &SecureChannelAddress,
+        service: &str,
+    ) -> Route {
+        let mut routes = self.routes.lock().unwrap();
+        routes
+            .entry((channel.clone(), service.to_string()))
+            .or_insert_with(|| builder.route(channel, service))
+            .clone()
+    }
+
+    /// Remove the routes built for `channel`
+    pub fn forget(&self, channel: &SecureChannelAddress) {
+        let mut routes = self.routes.lock().unwrap();
+        routes.retain(|(cached, _), _| cached != channel);
+    }
+
+    /// Return true if no routes are cached
+    pub fn is_empty(&self) -> bool {
+        self.routes.lock().unwrap().is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use ockam_core::Address;
+
+    use super::*;
+
+    #[test]
+    fn routes_are_built_once_per_channel_and_service() {
+        let built = Arc::new(AtomicUsize::new(0));
+        let counted = built.clone();
+        let builder = move |channel: &SecureChannelAddress, service: &str| {
+            counted.fetch_add(1, Ordering::SeqCst);
+            DirectRoute.route(channel, service)
+        };
+        let cache = RouteCache::default();
+        let channel = SecureChannelAddress::new(Address::from_string("sc"));
+        let other = SecureChannelAddress::new(Address::from_string("other"));
+
+        let route = cache.route(&builder, &channel, "projects");
+        assert_eq!(route, route!["sc", "projects"]);
+        assert_eq!(cache.route(&builder, &channel, "projects"), route);
+        assert_eq!(built.load(Ordering::SeqCst), 1);
+
+        cache.route(&builder, &channel, "enrollment_token_authenticator");
+        cache.route(&builder, &other, "projects");
+        assert_eq!(built.load(Ordering::SeqCst), 3);
+
+        cache.forget(&channel);
+        cache.route(&builder, &other, "projects");
+        assert_eq!(built.load(Ordering::SeqCst), 3);
+        cache.route(&builder, &channel, "projects");
+        assert_eq!(built.load(Ordering::SeqCst), 4);
+
+        cache.forget(&channel);
+        cache.forget(&other);
+        assert!(cache.is_empty());
+    }
+}
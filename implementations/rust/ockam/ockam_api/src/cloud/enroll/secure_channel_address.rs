@@ -0,0 +1,49 @@
+use core::fmt;
+
+use ockam::identity::SecureChannel;
+use ockam_core::Address;
+
+/// Encryptor address of a secure channel to the controller.
+///
+/// The enrollment methods take this rather than a bare [`Address`], so that the
+/// address of a service or of a worker can't be passed where the channel to send
+/// a request over is expected:
+///
+/// ```compile_fail
+/// use ockam_api::cloud::enroll::route_builder::{DirectRoute, RouteBuilder};
+/// use ockam_core::Address;
+///
+/// let channel = Address::from_string("sc");
+/// DirectRoute.route(&channel, "projects");
+/// ```
+///
+/// ```compile_fail
+/// use ockam_api::cloud::enroll::secure_channel_address::SecureChannelAddress;
+/// use ockam_core::Address;
+///
+/// let channel: SecureChannelAddress = Address::from_string("sc").into();
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SecureChannelAddress(Address);
+
+impl SecureChannelAddress {
+    /// Address of the encryptor of the secure channel `encryptor`
+    pub fn new(encryptor: Address) -> Self {
+        Self(encryptor)
+    }
+
+    /// Address of the encryptor of `sc`
+    pub fn of(sc: &SecureChannel) -> Self {
+        Self(sc.encryptor_address().clone())
+    }
+
+    pub fn address(&self) -> &Address {
+        &self.0
+    }
+}
+
+impl fmt::Display for SecureChannelAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
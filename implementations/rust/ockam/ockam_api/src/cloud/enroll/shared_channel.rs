@@ -0,0 +1,135 @@
+use std::sync::Arc;
+
+use ockam_core::compat::sync::Mutex;
+use ockam_multiaddr::MultiAddr;
+use ockam_node::tokio::sync::{OwnedRwLockReadGuard, RwLock};
+
+use crate::cloud::enroll::secure_channel_address::SecureChannelAddress;
+
+/// The secure channel to the controller shared by the enrollments of a
+/// provisioning session, so that they don't each create their own channel.
+///
+/// The channel can be swapped for a new one at any time. Enrollments lease the
+/// current channel for the duration of their request, and a channel which was
+/// swapped out must only be stopped once all its leases are released.
+#[derive(Default)]
+pub struct SharedChannel {
+    current: Mutex<Option<Arc<Channel>>>,
+}
+
+struct Channel {
+    route: MultiAddr,
+    address: SecureChannelAddress,
+    /// Held for reading by the leases of the channel
+    leases: Arc<RwLock<()>>,
+}
+
+impl SharedChannel {
+    /// Lease the current channel, if there is one
+    pub fn lease(&self) -> Option<ChannelLease> {
+        let current = self.current.lock().unwrap();
+        let channel = current.as_ref()?;
+        // the lock is only held for writing once the channel is swapped out
+        let lease = channel.leases.clone().try_read_owned().ok()?;
+        Some(ChannelLease {
+            address: channel.address.clone(),
+            _lease: lease,
+        })
+    }
+
+    /// Route to the controller of the current channel, if there is one
+    pub fn route(&self) -> Option<MultiAddr> {
+        let current = self.current.lock().unwrap();
+        current.as_ref().map(|channel| channel.route.clone())
+    }
+
+    /// Replace the current channel with the channel at `address`, to the controller at `route`
+    pub fn swap(&self, route: MultiAddr, address: SecureChannelAddress) -> Option<RetiredChannel> {
+        let channel = Channel {
+            route,
+            address,
+            leases: Default::default(),
+        };
+        let previous = self.current.lock().unwrap().replace(Arc::new(channel));
+        previous.map(RetiredChannel::new)
+    }
+
+    /// Remove the current channel, so that it can't be leased anymore
+    pub fn take(&self) -> Option<RetiredChannel> {
+        let previous = self.current.lock().unwrap().take();
+        previous.map(RetiredChannel::new)
+    }
+}
+
+/// A channel of a [`SharedChannel`] leased by an enrollment, released when dropped
+pub struct ChannelLease {
+    address: SecureChannelAddress,
+    _lease: OwnedRwLockReadGuard<()>,
+}
+
+impl ChannelLease {
+    /// Encryptor address of the leased channel
+    pub fn address(&self) -> &SecureChannelAddress {
+        &self.address
+    }
+}
+
+/// A channel swapped out of a [`SharedChannel`], which may still be leased
+pub struct RetiredChannel {
+    address: SecureChannelAddress,
+    leases: Arc<RwLock<()>>,
+}
+
+impl RetiredChannel {
+    fn new(channel: Arc<Channel>) -> Self {
+        Self {
+            address: channel.address.clone(),
+            leases: channel.leases.clone(),
+        }
+    }
+
+    /// Wait until the leases of the channel are released, and return its encryptor address
+    pub async fn released(self) -> SecureChannelAddress {
+        let _released = self.leases.write().await;
+        self.address
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use futures::FutureExt;
+    use ockam_core::Address;
+
+    use super::*;
+
+    fn channel(address: &str) -> SecureChannelAddress {
+        SecureChannelAddress::new(Address::from_string(address))
+    }
+
+    #[test]
+    fn retired_channels_are_released_with_their_leases() {
+        let shared = SharedChannel::default();
+        assert!(shared.lease().is_none());
+        let route = MultiAddr::from_str("/service/controller_api").unwrap();
+        assert!(shared.swap(route.clone(), channel("first")).is_none());
+        assert_eq!(shared.route(), Some(route.clone()));
+
+        let lease = shared.lease().unwrap();
+        assert_eq!(lease.address(), &channel("first"));
+        let retired = shared.swap(route, channel("second")).unwrap();
+        // new leases are on the new channel
+        assert_eq!(shared.lease().unwrap().address(), &channel("second"));
+
+        let mut released = Box::pin(retired.released());
+        assert!((&mut released).now_or_never().is_none());
+        drop(lease);
+        assert_eq!(released.now_or_never(), Some(channel("first")));
+
+        let retired = shared.take().unwrap();
+        assert_eq!(retired.released().now_or_never(), Some(channel("second")));
+        assert!(shared.lease().is_none());
+        assert!(shared.route().is_none());
+    }
+}
@@ -0,0 +1,3268 @@
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use cddl_cat::validate_cbor_bytes;
+use futures::StreamExt;
+use ockam::identity::credential::Attributes;
+use ockam::identity::TrustContext;
+use ockam_core::api::{Cbor, Error, Id, Request, Response};
+use ockam_core::{async_trait, route, Address, Any, AsyncTryClone, Route, Routed, Worker};
+use ockam_multiaddr::MultiAddr;
+use ockam_node::tokio::sync::oneshot;
+use ockam_node::tokio::time::{sleep, Instant};
+use ockam_node::Context;
+use ockam_vault::{SecretAttributes, Vault};
+
+use crate::cli_state::{traits::*, IdentityConfig};
+use crate::cloud::enroll::auth0::{Auth0Config, DeviceCode};
+use crate::cloud::enroll::client_certificate::ClientCertificate;
+use crate::cloud::enroll::clock::{Clock, ManualClock};
+use crate::cloud::enroll::concurrency::EnrollPermits;
+use crate::cloud::enroll::enrollment_token::{
+    AttributeValue, AuthenticateEnrollmentToken, EnrollmentTokenIntrospection,
+    EnrollmentTokenMetadata, EnrollmentTokenPage, EnrollmentTokenTemplate,
+    GeneratedEnrollmentToken, IntrospectEnrollmentToken, ListEnrollmentTokens,
+    RequestEnrollmentToken, RevokeEnrollmentToken, ValidatedEnrollmentToken,
+    ENROLLMENT_TOKEN_VERSION,
+};
+use crate::cloud::enroll::events::{EnrollFlow, EnrollOutcome};
+use crate::cloud::enroll::metrics::EnrollMetrics;
+use crate::cloud::enroll::mock_authenticator::{MockAuthenticator, MockMode};
+use crate::cloud::enroll::notifier::EnrollNotifier;
+use crate::cloud::enroll::oidc::{OidcToken, OidcTokenProvider, TokenType};
+use crate::cloud::enroll::preflight::ServerTime;
+use crate::cloud::enroll::rate_limiter::{RateLimit, TokenBucketRateLimiter};
+use crate::cloud::enroll::secure_channel_address::SecureChannelAddress;
+use crate::cloud::enroll::token_cache::TokenCache;
+use crate::cloud::enroll::transport::{CloudTransport, TcpCloudTransport};
+use crate::cloud::retry::RetryPolicy;
+use crate::cloud::CloudRequestWrapper;
+use crate::error::ApiError;
+use crate::nodes::{NodeManager, NODEMANAGER_ADDR};
+use crate::schema::SCHEMA;
+use crate::test_utils::{
+    start_controller_for_tests, start_manager_for_tests, start_mock_controller_for_tests,
+};
+
+use super::*;
+
+fn attributes(role: &str) -> Attributes {
+    let mut attributes = Attributes::new();
+    attributes.put("role", role.as_bytes());
+    attributes
+}
+
+#[ockam_macros::test(timeout = 5000)]
+async fn enrollment_tokens_are_generated_in_batch(context: &mut Context) -> ockam::Result<()> {
+    let (handle, controller, _) = start_mock_controller_for_tests(context).await?;
+
+    let node_manager = handle.node_manager.read().await;
+    let req = Request::get("v0/enroll/tokens").into_parts().0;
+    let attributes_list = vec![attributes("device"), attributes("gateway")];
+    let res = node_manager
+        .generate_enrollment_tokens(context, &req, &controller, attributes_list, 2)
+        .await?;
+
+    let report: Vec<GeneratedEnrollmentToken> = Response::parse_response_body(&res)?;
+    let tokens: Vec<String> = report
+        .into_iter()
+        .map(|generated| generated.token.unwrap().token.0.clone())
+        .collect();
+    assert_eq!(tokens, vec!["token-0", "token-1", "token-2", "token-3"]);
+
+    drop(node_manager);
+    context.stop().await
+}
+
+#[ockam_macros::test(timeout = 5000)]
+async fn batch_routes_are_built_once_per_channel(context: &mut Context) -> ockam::Result<()> {
+    let (handle, controller, _) = start_mock_controller_for_tests(context).await?;
+    let built = Arc::new(Mutex::new(0));
+    let counted = built.clone();
+    handle
+        .node_manager
+        .write()
+        .await
+        .enroll_options
+        .route_builder = Arc::new(move |channel: &SecureChannelAddress, service: &str| {
+        *counted.lock().unwrap() += 1;
+        route![channel.address().clone(), service]
+    });
+
+    let node_manager = handle.node_manager.read().await;
+    let req = Request::get("v0/enroll/tokens").into_parts().0;
+    for _ in 0..2 {
+        let res = node_manager
+            .generate_enrollment_tokens(context, &req, &controller, vec![attributes("device")], 3)
+            .await?;
+        let report: Vec<GeneratedEnrollmentToken> = Response::parse_response_body(&res)?;
+        assert_eq!(report.len(), 3);
+        // the routes of the stopped channel are not kept
+        assert!(node_manager.route_cache.is_empty());
+    }
+    assert_eq!(*built.lock().unwrap(), 2);
+
+    drop(node_manager);
+    context.stop().await
+}
+
+#[ockam_macros::test(timeout = 5000)]
+async fn enrollment_tokens_can_be_revoked(context: &mut Context) -> ockam::Result<()> {
+    let (handle, controller, _) = start_mock_controller_for_tests(context).await?;
+
+    let node_manager = handle.node_manager.read().await;
+    let req = Request::delete("v0/enroll/token").into_parts().0;
+    let status = |res: Vec<u8>| Response::parse_response_header(&res).unwrap().0.status();
+
+    let generate = Request::get("v0/enroll/token").into_parts().0;
+    let body = RequestEnrollmentToken::new(attributes("device"));
+    let res = node_manager
+        .generate_enrollment_token(context, &generate, &controller, body)
+        .await?;
+    let token = Response::parse_response_body::<EnrollmentToken>(&res)?.token;
+    let res = node_manager
+        .revoke_enrollment_token(context, &req, &controller, &token)
+        .await?;
+    assert_eq!(status(res), Some(Status::Ok));
+    let now = node_manager.enroll_options.clock.now()?;
+    assert!(node_manager.is_enrollment_token_revoked(&token, now));
+
+    let res = node_manager
+        .revoke_enrollment_token(context, &req, &controller, &token)
+        .await?;
+    assert_eq!(status(res), Some(Status::Conflict));
+
+    let unknown = Token::new("unknown");
+    let res = node_manager
+        .revoke_enrollment_token(context, &req, &controller, &unknown)
+        .await?;
+    assert_eq!(status(res), Some(Status::NotFound));
+    assert!(!node_manager.is_enrollment_token_revoked(&unknown, now));
+
+    drop(node_manager);
+    context.stop().await
+}
+
+#[ockam_macros::test(timeout = 5000)]
+async fn enroll_with_a_client_certificate(context: &mut Context) -> ockam::Result<()> {
+    let (handle, controller, authenticator) = start_mock_controller_for_tests(context).await?;
+    let der = b"device certificate".to_vec();
+    let fingerprint = hex::encode(Vault::sha256(&der));
+    authenticator.accept_client_certificate(&fingerprint);
+
+    let node_manager = handle.node_manager.read().await;
+    let enrollment = node_manager
+        .enroll_client_certificate(context, None, &controller, &ClientCertificate::Der(der))
+        .await?;
+    assert_eq!(enrollment.flow, EnrollFlow::ClientCertificate);
+
+    // PEM certificates are sent as DER
+    let pem = ClientCertificate::Pem(
+        "-----BEGIN CERTIFICATE-----\nZGV2aWNlIGNl\ncnRpZmljYXRl\n-----END CERTIFICATE-----\n"
+            .to_string(),
+    );
+    assert_eq!(
+        AuthenticateClientCertificate::new(&pem)?.fingerprint(),
+        fingerprint
+    );
+    node_manager
+        .enroll_client_certificate(context, None, &controller, &pem)
+        .await?;
+
+    let unknown = ClientCertificate::Der(b"another certificate".to_vec());
+    assert!(node_manager
+        .enroll_client_certificate(context, None, &controller, &unknown)
+        .await
+        .is_err());
+    let malformed = ClientCertificate::Pem("not a certificate".to_string());
+    assert!(node_manager
+        .enroll_client_certificate(context, None, &controller, &malformed)
+        .await
+        .is_err());
+
+    drop(node_manager);
+    context.stop().await
+}
+
+#[ockam_macros::test(timeout = 5000)]
+async fn enroll_with_an_api_key(context: &mut Context) -> ockam::Result<()> {
+    let (handle, controller, authenticator) = start_mock_controller_for_tests(context).await?;
+    authenticator.accept_api_key(Token::new("key"));
+
+    let node_manager = handle.node_manager.read().await;
+    let enrollment = node_manager
+        .enroll_api_key(context, None, &controller, Token::new("key"))
+        .await?;
+    assert_eq!(enrollment.flow, EnrollFlow::ApiKey);
+    assert_eq!(
+        enrollment.enrolled_at,
+        node_manager.enroll_options.clock.now()?
+    );
+    assert!(node_manager
+        .enroll_api_key(context, None, &controller, Token::new("unknown"))
+        .await
+        .is_err());
+
+    drop(node_manager);
+    context.stop().await
+}
+
+#[test]
+fn enroll_bodies_are_decoded_without_consuming_the_decoder() {
+    let route = MultiAddr::from_str("/service/controller_api").unwrap();
+    let body = EnrollBody::auth0(oidc_token(None));
+    let body = minicbor::to_vec(CloudRequestWrapper::new(body, &route, None)).unwrap();
+    let dec = Decoder::new(&body);
+    let decoded = try_decode_enroll_body(&dec).unwrap();
+    assert!(matches!(decoded.req, AuthenticateToken::Auth0(_)));
+    assert_eq!(decoded.multiaddr().unwrap(), route);
+    assert_eq!(dec.position(), 0);
+
+    let body = EnrollBody::enrollment_token(EnrollmentToken::new(Token::new("token")));
+    let body = minicbor::to_vec(CloudRequestWrapper::new(body, &route, None)).unwrap();
+    let decoded = try_decode_enroll_body(&Decoder::new(&body)).unwrap();
+    assert!(matches!(
+        decoded.req,
+        AuthenticateToken::EnrollmentToken(token) if token.token == Token::new("token")
+    ));
+
+    let body = EnrollBody::api_key(AuthenticateApiKey::new(Token::new("key")));
+    let body = minicbor::to_vec(CloudRequestWrapper::new(body, &route, None)).unwrap();
+    let decoded = try_decode_enroll_body(&Decoder::new(&body)).unwrap();
+    assert!(matches!(
+        decoded.req,
+        AuthenticateToken::ApiKey(token) if token.token == Token::new("key")
+    ));
+
+    // the kind is required, and must match the token
+    let token = EnrollmentToken::new(Token::new("token"));
+    let body = minicbor::to_vec(CloudRequestWrapper::new(token, &route, None)).unwrap();
+    assert!(try_decode_enroll_body(&Decoder::new(&body)).is_none());
+    let mut body = EnrollBody::enrollment_token(EnrollmentToken::new(Token::new("token")));
+    body.kind = EnrollTokenKind::Auth0;
+    let body = minicbor::to_vec(CloudRequestWrapper::new(body, &route, None)).unwrap();
+    assert!(try_decode_enroll_body(&Decoder::new(&body)).is_none());
+    let body = minicbor::to_vec(CloudRequestWrapper::new(42u32, &route, None)).unwrap();
+    assert!(try_decode_enroll_body(&Decoder::new(&body)).is_none());
+}
+
+#[test]
+fn enroll_logs_can_use_a_custom_target() {
+    const CUSTOM: &str = log_target(Some("embedder::enroll"));
+    assert_eq!(CUSTOM, "embedder::enroll");
+    assert_eq!(log_target(Some("")), DEFAULT_LOG_TARGET);
+    assert_eq!(log_target(None), DEFAULT_LOG_TARGET);
+    assert_eq!(TARGET, log_target(option_env!("OCKAM_ENROLL_LOG_TARGET")));
+    // a custom target is a valid static target for the tracing macros
+    tracing::debug!(target: CUSTOM, "custom enroll target");
+}
+
+#[ockam_macros::test(timeout = 5000)]
+async fn enroll_dispatches_on_the_token_type(context: &mut Context) -> ockam::Result<()> {
+    let (handle, controller, _) = start_mock_controller_for_tests(context).await?;
+
+    let node_manager = handle.node_manager.read().await;
+    let req = Request::post("v0/enroll").into_parts().0;
+    let status = |res: Vec<u8>| Response::parse_response_header(&res).unwrap().0.status();
+
+    let body = EnrollBody::auth0(oidc_token(None));
+    let body = minicbor::to_vec(CloudRequestWrapper::new(body, &controller, None))?;
+    let res = node_manager
+        .enroll(context, &req, &mut Decoder::new(&body))
+        .await?;
+    assert_eq!(status(res), Some(Status::Ok));
+
+    // the expiry is checked before contacting the enrollment token authenticator
+    let token = EnrollmentToken::new(Token::new("token")).with_expires_at(1);
+    let body = EnrollBody::enrollment_token(token);
+    let body = minicbor::to_vec(CloudRequestWrapper::new(body, &controller, None))?;
+    let res = node_manager
+        .enroll(context, &req, &mut Decoder::new(&body))
+        .await?;
+    assert_eq!(status(res), Some(Status::Unauthorized));
+
+    let body = minicbor::to_vec(CloudRequestWrapper::new(42u32, &controller, None))?;
+    let res = node_manager
+        .enroll(context, &req, &mut Decoder::new(&body))
+        .await?;
+    assert_eq!(status(res), Some(Status::BadRequest));
+
+    drop(node_manager);
+    context.stop().await
+}
+
+#[ockam_macros::test(timeout = 5000)]
+async fn prefetched_auth0_tokens_are_authenticated(context: &mut Context) -> ockam::Result<()> {
+    let (handle, controller, authenticator) = start_mock_controller_for_tests(context).await?;
+
+    let node_manager = handle.node_manager.read().await;
+    let req = Request::post("v0/enroll/auth0").into_parts().0;
+    let token = OidcToken {
+        token_type: TokenType::Bearer,
+        access_token: Token::new("access"),
+        refresh_token: None,
+        expires_at: None,
+        issued_at: None,
+    };
+    let res = node_manager
+        .authenticate_auth0_token(context, &req, token.clone(), &controller)
+        .await?;
+    let (header, _) = Response::parse_response_header(&res)?;
+    assert_eq!(header.status(), Some(Status::Ok));
+
+    authenticator.reject_with(Some(Status::Unauthorized));
+    let res = node_manager
+        .authenticate_auth0_token(context, &req, token, &controller)
+        .await?;
+    let (header, _) = Response::parse_response_header(&res)?;
+    assert_eq!(header.re(), req.id());
+    assert_eq!(header.status(), Some(Status::Unauthorized));
+
+    drop(node_manager);
+    context.stop().await
+}
+
+#[ockam_macros::test(timeout = 5000)]
+async fn tokens_are_sent_to_custom_authenticators(context: &mut Context) -> ockam::Result<()> {
+    let handle = start_manager_for_tests(context).await?;
+    let api_service = "tenant_auth0_authenticator";
+    let controller = start_controller_for_tests(context, &handle, &[api_service]).await?;
+    MockAuthenticator::default()
+        .start_at(context, api_service)
+        .await?;
+    handle
+        .node_manager
+        .write()
+        .await
+        .enroll_options
+        .authenticator_services = AuthenticatorServices {
+        auth0: api_service.to_string(),
+        ..Default::default()
+    };
+
+    let node_manager = handle.node_manager.read().await;
+    let token = AuthenticateToken::Auth0(oidc_token(None));
+    node_manager
+        .authenticate_token(context, None, &controller, token, None)
+        .await?;
+
+    drop(node_manager);
+    context.stop().await
+}
+
+#[ockam_macros::test(timeout = 5000)]
+async fn authenticator_requests_carry_the_node_request_id(
+    context: &mut Context,
+) -> ockam::Result<()> {
+    let handle = start_manager_for_tests(context).await?;
+    let node_manager = handle.node_manager.read().await;
+    let token = AuthenticateToken::Auth0(oidc_token(None));
+
+    let req = Request::post("v0/enroll/auth0").into_parts().0;
+    let outgoing = node_manager.authenticate_token_request(&token, Some(req.id()));
+    assert_eq!(outgoing.header().correlation_id(), Some(req.id()));
+    assert_ne!(outgoing.header().id(), req.id());
+
+    let outgoing = node_manager.authenticate_token_request(&token, None);
+    assert_eq!(outgoing.header().correlation_id(), None);
+
+    drop(node_manager);
+    context.stop().await
+}
+
+#[ockam_macros::test(timeout = 5000)]
+async fn authenticator_requests_carry_the_node_metadata(
+    context: &mut Context,
+) -> ockam::Result<()> {
+    let (handle, controller, authenticator) = start_mock_controller_for_tests(context).await?;
+    let token = AuthenticateToken::Auth0(oidc_token(None));
+    let generate = Request::get("v0/enroll/token").into_parts().0;
+
+    // no metadata is sent by default
+    let node_manager = handle.node_manager.read().await;
+    let outgoing = node_manager.authenticate_token_request(&token, None);
+    assert_eq!(outgoing.header().metadata("tenant"), None);
+    let body = RequestEnrollmentToken::new(attributes("device"));
+    let res = node_manager
+        .generate_enrollment_token(context, &generate, &controller, body)
+        .await?;
+    let generated: EnrollmentToken = Response::parse_response_body(&res)?;
+    assert_eq!(generated.token, Token::new("token-0"));
+    drop(node_manager);
+
+    handle
+        .node_manager
+        .write()
+        .await
+        .enroll_options
+        .request_metadata = BTreeMap::from([("tenant".to_string(), "acme".to_string())]);
+    let node_manager = handle.node_manager.read().await;
+    let outgoing = node_manager.authenticate_token_request(&token, None);
+    assert_eq!(outgoing.header().metadata("tenant"), Some("acme"));
+    let body = RequestEnrollmentToken::new(attributes("device"));
+    node_manager
+        .generate_enrollment_token(context, &generate, &controller, body)
+        .await?;
+    let tenants: Vec<Option<String>> = authenticator
+        .generation_requests()
+        .iter()
+        .map(|(req, _)| req.metadata("tenant").map(str::to_string))
+        .collect();
+    assert_eq!(tenants, vec![None, Some("acme".to_string())]);
+
+    drop(node_manager);
+    context.stop().await
+}
+
+#[ockam_macros::test(timeout = 5000)]
+async fn authenticator_claims_are_decoded(context: &mut Context) -> ockam::Result<()> {
+    let (handle, controller, authenticator) = start_mock_controller_for_tests(context).await?;
+    let claims = EnrollClaims {
+        #[cfg(feature = "tag")]
+        tag: TypeTag,
+        identity: Some(handle.identifier.clone()),
+        attributes: Some(attributes("device")),
+        expires_at: Some(100),
+        credential_expires_at: None,
+    };
+    authenticator.set_mode(MockMode::Accept(Some(claims.clone())));
+
+    let node_manager = handle.node_manager.read().await;
+    let token = AuthenticateToken::Auth0(oidc_token(None));
+    let res = node_manager
+        .authenticate_token(context, None, &controller, token, None)
+        .await?;
+    assert_eq!(res.claims, Some(claims.clone()));
+    let raw: EnrollClaims = Response::parse_response_body(&res.raw)?;
+    assert_eq!(raw, claims);
+
+    drop(node_manager);
+    context.stop().await
+}
+
+#[ockam_macros::test(timeout = 5000)]
+async fn enroll_responses_answer_the_node_request(context: &mut Context) -> ockam::Result<()> {
+    let (handle, controller, authenticator) = start_mock_controller_for_tests(context).await?;
+    let claims = EnrollClaims {
+        #[cfg(feature = "tag")]
+        tag: TypeTag,
+        identity: Some(handle.identifier.clone()),
+        attributes: Some(attributes("device")),
+        expires_at: None,
+        credential_expires_at: None,
+    };
+    authenticator.set_mode(MockMode::Accept(Some(claims.clone())));
+
+    let node_manager = handle.node_manager.read().await;
+    let req = Request::post("v0/enroll").into_parts().0;
+    let body = EnrollBody::enrollment_token(EnrollmentToken::new(Token::new("token")));
+    let body = minicbor::to_vec(CloudRequestWrapper::new(body, &controller, None))?;
+    let res = node_manager
+        .enroll(context, &req, &mut Decoder::new(&body))
+        .await?;
+
+    let (header, mut dec) = Response::parse_response_header(&res)?;
+    assert_eq!(header.status(), Some(Status::Ok));
+    assert_eq!(header.re(), req.id());
+    let forwarded: EnrollClaims = dec.decode()?;
+    assert_eq!(forwarded, claims);
+
+    drop(node_manager);
+    context.stop().await
+}
+
+#[cfg(not(feature = "tag"))]
+#[ockam_macros::test(timeout = 5000)]
+async fn api_keys_are_sent_to_their_authenticator(context: &mut Context) -> ockam::Result<()> {
+    let (handle, controller, authenticator) = start_mock_controller_for_tests(context).await?;
+    authenticator.accept_api_key(Token::new("key"));
+
+    // without a tag, an api key is encoded like an enrollment token
+    let api_key = minicbor::to_vec(AuthenticateApiKey::new(Token::new("key")))?;
+    let token: EnrollmentToken = minicbor::decode(&api_key)?;
+    assert_eq!(token.token, Token::new("key"));
+
+    let node_manager = handle.node_manager.read().await;
+    let req = Request::post("v0/enroll").into_parts().0;
+    let body = EnrollBody::api_key(AuthenticateApiKey::new(Token::new("key")));
+    let body = minicbor::to_vec(CloudRequestWrapper::new(body, &controller, None))?;
+    let res = node_manager
+        .enroll(context, &req, &mut Decoder::new(&body))
+        .await?;
+    let (header, _) = Response::parse_response_header(&res)?;
+    assert_eq!(header.status(), Some(Status::Ok));
+
+    let body = EnrollBody::enrollment_token(EnrollmentToken::new(Token::new("key")));
+    let body = minicbor::to_vec(CloudRequestWrapper::new(body, &controller, None))?;
+    let res = node_manager
+        .enroll(context, &req, &mut Decoder::new(&body))
+        .await?;
+    let (header, _) = Response::parse_response_header(&res)?;
+    assert_eq!(header.status(), Some(Status::Unauthorized));
+
+    drop(node_manager);
+    context.stop().await
+}
+
+#[ockam_macros::test(timeout = 5000)]
+async fn identities_enroll_independently(context: &mut Context) -> ockam::Result<()> {
+    let (handle, controller, authenticator) = start_mock_controller_for_tests(context).await?;
+    authenticator.set_mode(MockMode::EnrollCaller);
+    let other = handle
+        .secure_channels
+        .identities()
+        .identities_creation()
+        .create_identity()
+        .await?;
+    let config = IdentityConfig::new(&other.identifier()).await;
+    handle.cli_state.identities.create("other", config)?;
+
+    let node_manager = handle.node_manager.read().await;
+    for (identity_name, identifier) in [
+        (None, handle.identifier.clone()),
+        (Some("other".to_string()), other.identifier()),
+    ] {
+        let token = AuthenticateToken::Auth0(oidc_token(None));
+        let res = node_manager
+            .authenticate_token(context, identity_name, &controller, token, None)
+            .await?;
+        assert_eq!(res.claims.and_then(|c| c.identity), Some(identifier));
+    }
+
+    // the token is only cached for the enrolled identity
+    let token = OidcToken {
+        token_type: TokenType::Bearer,
+        access_token: Token::new("access"),
+        refresh_token: None,
+        expires_at: None,
+        issued_at: None,
+    };
+    node_manager
+        .enroll_auth0(
+            context,
+            Some("other".to_string()),
+            &controller,
+            token.clone(),
+        )
+        .await?;
+    let cache = node_manager.enroll_options.token_cache.as_ref();
+    assert_eq!(cache.load(&other.identifier()).await?, Some(token));
+    assert_eq!(cache.load(&handle.identifier).await?, None);
+
+    drop(node_manager);
+    context.stop().await
+}
+
+/// Source of Auth0 tokens standing for an SDK or a CI secret, counting the tokens it returns
+#[derive(Default)]
+struct CountingTokenProvider(Mutex<usize>);
+
+#[async_trait]
+impl OidcTokenProvider for CountingTokenProvider {
+    async fn token(&self) -> ockam::Result<OidcToken> {
+        *self.0.lock().unwrap() += 1;
+        Ok(oidc_token(None).into_oidc_token())
+    }
+}
+
+#[ockam_macros::test(timeout = 5000)]
+async fn auth0_tokens_can_be_obtained_from_a_node_provider(
+    context: &mut Context,
+) -> ockam::Result<()> {
+    let (handle, controller, _) = start_mock_controller_for_tests(context).await?;
+
+    let err = handle
+        .node_manager
+        .read()
+        .await
+        .enroll_auth0_with_provider(context, None, &controller)
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("no auth0 token provider"));
+
+    let provider = Arc::new(CountingTokenProvider::default());
+    handle
+        .node_manager
+        .write()
+        .await
+        .enroll_options
+        .auth0_token_provider = Some(provider.clone());
+    let node_manager = handle.node_manager.read().await;
+    node_manager
+        .enroll_auth0_with_provider(context, None, &controller)
+        .await?;
+    assert_eq!(*provider.0.lock().unwrap(), 1);
+    let cached = node_manager
+        .enroll_options
+        .token_cache
+        .load(&handle.identifier)
+        .await?;
+    assert_eq!(cached, Some(oidc_token(None).into_oidc_token()));
+
+    // the provider isn't asked for another token while the cached one is valid
+    node_manager
+        .enroll_auth0_with_provider(context, None, &controller)
+        .await?;
+    assert_eq!(*provider.0.lock().unwrap(), 1);
+
+    drop(node_manager);
+    context.stop().await
+}
+
+/// Cache failing to load, store or clear any token
+struct FailingTokenCache;
+
+#[async_trait]
+impl TokenCache for FailingTokenCache {
+    async fn load(&self, _: &IdentityIdentifier) -> ockam::Result<Option<OidcToken>> {
+        Err(ApiError::generic("the disk is unreadable"))
+    }
+
+    async fn store(&self, _: &IdentityIdentifier, _: &OidcToken) -> ockam::Result<()> {
+        Err(ApiError::generic("the disk is full"))
+    }
+
+    async fn clear(&self, _: &IdentityIdentifier) -> ockam::Result<()> {
+        Err(ApiError::generic("the disk is unreadable"))
+    }
+}
+
+#[ockam_macros::test(timeout = 5000)]
+async fn auth0_enrollments_use_the_given_token(context: &mut Context) -> ockam::Result<()> {
+    let (handle, controller, _) = start_mock_controller_for_tests(context).await?;
+    let token = |access_token: &str| OidcToken {
+        token_type: TokenType::Bearer,
+        access_token: Token::new(access_token),
+        refresh_token: None,
+        expires_at: None,
+        issued_at: None,
+    };
+
+    let node_manager = handle.node_manager.read().await;
+    let cache = node_manager.enroll_options.token_cache.as_ref();
+    cache.store(&handle.identifier, &token("cached")).await?;
+    node_manager
+        .enroll_auth0(context, None, &controller, token("new"))
+        .await?;
+    // the token obtained by the caller is used rather than the cached one, which
+    // may have been issued to another user
+    let cached = cache.load(&handle.identifier).await?;
+    assert_eq!(cached, Some(token("new")));
+    drop(node_manager);
+
+    // a token which can't be cached doesn't fail the enrollment
+    handle.node_manager.write().await.enroll_options.token_cache = Arc::new(FailingTokenCache);
+    let node_manager = handle.node_manager.read().await;
+    node_manager
+        .enroll_auth0(context, None, &controller, token("new"))
+        .await?;
+
+    // and neither does a cache which can't be read, a new flow is run instead
+    node_manager
+        .enroll_auth0_cached(context, None, &controller, || async { Ok(token("new")) })
+        .await?;
+
+    drop(node_manager);
+    context.stop().await
+}
+
+/// Notifier keeping the roles it is notified of, and failing every notification
+#[derive(Default)]
+struct FailingNotifier(Mutex<Vec<Option<String>>>);
+
+#[async_trait]
+impl EnrollNotifier for FailingNotifier {
+    async fn on_enrolled(
+        &self,
+        attributes: Option<&Attributes>,
+        _: &EnrollResponse,
+    ) -> ockam::Result<()> {
+        let role = attributes
+            .and_then(|a| a.get("role"))
+            .map(|role| String::from_utf8_lossy(role).to_string());
+        self.0.lock().unwrap().push(role);
+        Err(ApiError::generic("the webhook is down"))
+    }
+}
+
+#[ockam_macros::test(timeout = 5000)]
+async fn enrollments_are_notified(context: &mut Context) -> ockam::Result<()> {
+    let (handle, controller, authenticator) = start_mock_controller_for_tests(context).await?;
+    let claims = EnrollClaims {
+        #[cfg(feature = "tag")]
+        tag: TypeTag,
+        identity: Some(handle.identifier.clone()),
+        attributes: Some(attributes("device")),
+        expires_at: None,
+        credential_expires_at: None,
+    };
+    authenticator.set_mode(MockMode::Accept(Some(claims)));
+    let notifier = Arc::new(FailingNotifier::default());
+    handle
+        .node_manager
+        .write()
+        .await
+        .enroll_options
+        .enroll_notifier = notifier.clone();
+
+    let node_manager = handle.node_manager.read().await;
+    let token = AuthenticateToken::Auth0(oidc_token(None));
+    // the notifier failure doesn't fail the enrollment
+    assert!(node_manager
+        .authenticate_token(context, None, &controller, token, None)
+        .await
+        .is_ok());
+    assert_eq!(
+        *notifier.0.lock().unwrap(),
+        vec![Some("device".to_string())]
+    );
+
+    drop(node_manager);
+    context.stop().await
+}
+
+#[test]
+fn unexpected_authenticator_bodies_are_kept_raw() {
+    let raw = Response::ok(Id::fresh()).body("enrolled").to_vec().unwrap();
+    let res = EnrollResponse::new(raw.clone());
+    assert_eq!(res.claims, None);
+    assert_eq!(res.raw, raw);
+
+    let res = EnrollResponse::new(Response::ok(Id::fresh()).to_vec().unwrap());
+    assert_eq!(res.claims, None);
+}
+
+#[test]
+fn enroll_claims_match_their_schema() {
+    let identity = IdentityIdentifier::from_str(
+        "Pe92f183eb4c324804ef4d62962dea94cf095a265d4d28500c34e1a4e0d5ef638",
+    )
+    .unwrap();
+    let claims = EnrollClaims {
+        #[cfg(feature = "tag")]
+        tag: TypeTag,
+        identity: Some(identity),
+        attributes: Some(attributes("device")),
+        expires_at: Some(100),
+        credential_expires_at: Some(200),
+    };
+    let cbor = minicbor::to_vec(claims).unwrap();
+    validate_cbor_bytes("enroll_claims", SCHEMA, &cbor).unwrap();
+}
+
+#[ockam_macros::test(timeout = 5000)]
+async fn tokens_can_be_sent_over_an_existing_channel(context: &mut Context) -> ockam::Result<()> {
+    let (handle, controller, _) = start_mock_controller_for_tests(context).await?;
+
+    let node_manager = handle.node_manager.read().await;
+    let sc = node_manager
+        .create_controller_secure_channel(context, None, &controller)
+        .await?;
+    let token = AuthenticateToken::Auth0(oidc_token(None));
+    for _ in 0..2 {
+        node_manager
+            .authenticate_token_over(context, &SecureChannelAddress::of(&sc), &token, None)
+            .await?;
+    }
+
+    // the channel is left open for the caller
+    let registry = handle.secure_channels.secure_channel_registry();
+    assert!(registry
+        .get_channel_list()
+        .iter()
+        .any(|channel| channel.is_initiator()));
+    handle
+        .secure_channels
+        .stop_secure_channel(context, sc.encryptor_address())
+        .await?;
+
+    drop(node_manager);
+    context.stop().await
+}
+
+/// Forwards the messages to the next hop of their onward route, counting them
+struct CountingRelay(Arc<Mutex<usize>>);
+
+#[async_trait]
+impl Worker for CountingRelay {
+    type Context = Context;
+    type Message = Any;
+
+    async fn handle_message(
+        &mut self,
+        ctx: &mut Context,
+        msg: Routed<Self::Message>,
+    ) -> ockam::Result<()> {
+        *self.0.lock().unwrap() += 1;
+        let mut message = msg.into_local_message();
+        message.transport_mut().onward_route.step()?;
+        ctx.forward(message).await
+    }
+}
+
+#[ockam_macros::test(timeout = 5000)]
+async fn authenticator_routes_can_be_customized(context: &mut Context) -> ockam::Result<()> {
+    let handle = start_manager_for_tests(context).await?;
+    let api_service = "auth0_authenticator";
+    let controller = start_controller_for_tests(context, &handle, &["relay", api_service]).await?;
+    let relayed = Arc::new(Mutex::new(0));
+    context
+        .start_worker("relay", CountingRelay(relayed.clone()))
+        .await?;
+    MockAuthenticator::default()
+        .start_at(context, api_service)
+        .await?;
+    handle
+        .node_manager
+        .write()
+        .await
+        .enroll_options
+        .route_builder = Arc::new(|channel: &SecureChannelAddress, service: &str| {
+        route![channel.address().clone(), "relay", service]
+    });
+
+    let node_manager = handle.node_manager.read().await;
+    let token = AuthenticateToken::Auth0(oidc_token(None));
+    node_manager
+        .authenticate_token(context, None, &controller, token, None)
+        .await?;
+    assert_eq!(*relayed.lock().unwrap(), 1);
+
+    drop(node_manager);
+    context.stop().await
+}
+
+/// Stands for an authenticator reached outside of the secure channel recorded by
+/// the node route builder, stopping that channel before answering
+struct ChannelStopping(Arc<Mutex<Option<Address>>>);
+
+#[async_trait]
+impl Worker for ChannelStopping {
+    type Context = Context;
+    type Message = Vec<u8>;
+
+    async fn handle_message(
+        &mut self,
+        ctx: &mut Context,
+        msg: Routed<Self::Message>,
+    ) -> ockam::Result<()> {
+        let req: Request = Decoder::new(msg.as_body()).decode()?;
+        let channel = self.0.lock().unwrap().take();
+        if let Some(channel) = channel {
+            ctx.stop_worker(channel).await?;
+            // wait for the channel to be unregistered, so that stopping it again fails
+            sleep(Duration::from_millis(100)).await;
+        }
+        ctx.send(msg.return_route(), Response::ok(req.id()).to_vec()?)
+            .await
+    }
+}
+
+#[ockam_macros::test(timeout = 5000)]
+async fn secure_channel_stop_failures_dont_fail_enrollments(
+    context: &mut Context,
+) -> ockam::Result<()> {
+    let handle = start_manager_for_tests(context).await?;
+    let controller = start_controller_for_tests(context, &handle, &[]).await?;
+    let channel = Arc::new(Mutex::new(None));
+    context
+        .start_worker("auth0_authenticator", ChannelStopping(channel.clone()))
+        .await?;
+    handle
+        .node_manager
+        .write()
+        .await
+        .enroll_options
+        .route_builder = Arc::new(move |sc: &SecureChannelAddress, service: &str| {
+        *channel.lock().unwrap() = Some(sc.address().clone());
+        route![service]
+    });
+
+    let node_manager = handle.node_manager.read().await;
+    let token = AuthenticateToken::Auth0(oidc_token(None));
+    let res = node_manager
+        .authenticate_token(context, None, &controller, token, None)
+        .await?;
+    assert_eq!(res.claims, None);
+
+    drop(node_manager);
+    context.stop().await
+}
+
+/// Answers the requests held by `authenticator` in batches, every 100ms,
+/// until `count` of them were answered, and returns the size of the largest batch
+async fn release_held_requests(
+    ctx: &Context,
+    authenticator: &MockAuthenticator,
+    count: usize,
+) -> ockam::Result<usize> {
+    let (mut released, mut largest_batch) = (0, 0);
+    while released < count {
+        sleep(Duration::from_millis(100)).await;
+        let batch = authenticator.release_held(ctx).await?;
+        largest_batch = largest_batch.max(batch);
+        released += batch;
+    }
+    Ok(largest_batch)
+}
+
+#[ockam_macros::test(timeout = 10000)]
+async fn concurrent_enrollments_are_limited(context: &mut Context) -> ockam::Result<()> {
+    let (handle, controller, authenticator) = start_mock_controller_for_tests(context).await?;
+    authenticator.set_mode(MockMode::Hold);
+    handle.node_manager.write().await.enroll_permits = EnrollPermits::new(2);
+
+    let node_manager = handle.node_manager.read().await;
+    let enrollments = (0..5).map(|_| {
+        let token = AuthenticateToken::Auth0(oidc_token(None));
+        node_manager.authenticate_token(context, None, &controller, token, None)
+    });
+    let (results, largest_batch) = futures::join!(
+        futures::future::join_all(enrollments),
+        release_held_requests(context, &authenticator, 5)
+    );
+    assert!(results.iter().all(Result::is_ok));
+    // the enrollments waiting for a permit didn't reach the authenticator
+    assert_eq!(largest_batch?, 2);
+
+    drop(node_manager);
+    context.stop().await
+}
+
+#[ockam_macros::test(timeout = 5000)]
+async fn enrollments_are_refused_when_too_many_are_waiting(
+    context: &mut Context,
+) -> ockam::Result<()> {
+    let (handle, controller, authenticator) = start_mock_controller_for_tests(context).await?;
+    authenticator.set_mode(MockMode::Hold);
+    handle.node_manager.write().await.enroll_permits = EnrollPermits::new(1).with_max_waiting(1);
+
+    let node_manager = handle.node_manager.read().await;
+    let enrollments = (0..3).map(|_| {
+        let token = AuthenticateToken::Auth0(oidc_token(None));
+        node_manager.authenticate_token(context, None, &controller, token, None)
+    });
+    let (results, _) = futures::join!(
+        futures::future::join_all(enrollments),
+        release_held_requests(context, &authenticator, 2)
+    );
+    let refused: Vec<_> = results.into_iter().filter_map(Result::err).collect();
+    assert_eq!(refused.len(), 1);
+    assert_eq!(refused[0].status(), Status::ServiceUnavailable);
+
+    drop(node_manager);
+    context.stop().await
+}
+
+#[ockam_macros::test(timeout = 5000)]
+async fn cancelled_enrollments_stop_their_secure_channel(
+    context: &mut Context,
+) -> ockam::Result<()> {
+    let (handle, controller, authenticator) = start_mock_controller_for_tests(context).await?;
+    authenticator.set_mode(MockMode::Hold);
+    let registry = handle.secure_channels.secure_channel_registry();
+    let open_channels = || {
+        registry
+            .get_channel_list()
+            .iter()
+            .filter(|channel| channel.is_initiator())
+            .count()
+    };
+
+    let node_manager = handle.node_manager.read().await;
+    let token = AuthenticateToken::Auth0(oidc_token(None));
+    let mut enrollment =
+        Box::pin(node_manager.authenticate_token(context, None, &controller, token, None));
+    let request_received = async {
+        while authenticator.held() == 0 {
+            sleep(Duration::from_millis(10)).await;
+        }
+    };
+    ockam_node::tokio::select! {
+        _ = &mut enrollment => panic!("the request should be held"),
+        _ = request_received => (),
+    }
+    assert_eq!(open_channels(), 1);
+
+    // the enrollment is cancelled while its request is in flight
+    drop(enrollment);
+    while open_channels() > 0 {
+        sleep(Duration::from_millis(10)).await;
+    }
+    assert!(node_manager.route_cache.is_empty());
+
+    drop(node_manager);
+    context.stop().await
+}
+
+#[ockam_macros::test(timeout = 5000)]
+async fn enroll_channels_are_rotated_once_released(context: &mut Context) -> ockam::Result<()> {
+    let (handle, controller, authenticator) = start_mock_controller_for_tests(context).await?;
+    authenticator.set_mode(MockMode::Accept(None));
+    let registry = handle.secure_channels.secure_channel_registry();
+    let is_open = |sc: &SecureChannelAddress| {
+        registry
+            .get_channel_by_encryptor_address(sc.address())
+            .is_some()
+    };
+
+    let node_manager = handle.node_manager.read().await;
+    let token = AuthenticateToken::EnrollmentToken(EnrollmentToken::new(Token::new("token")));
+    let enroll = || node_manager.authenticate_token_over_enroll_channel(context, &token, None);
+    assert!(enroll().await.is_err());
+    let first = node_manager
+        .open_enroll_channel(context, &controller)
+        .await?;
+    enroll().await?;
+
+    // an enrollment is still running over the first channel when it is rotated
+    let in_flight = node_manager.enroll_channel.lease().unwrap();
+    let mut rotation = Box::pin(node_manager.rotate_enroll_channel(context));
+    // the rotation swaps the channels, then waits for the enrollment
+    let second = loop {
+        ockam_node::tokio::select! {
+            _ = &mut rotation => panic!("the first channel is still in use"),
+            _ = sleep(Duration::from_millis(10)) => (),
+        }
+        let lease = node_manager.enroll_channel.lease().unwrap();
+        if lease.address() != &first {
+            break lease.address().clone();
+        }
+    };
+    enroll().await?;
+    assert!(is_open(&first));
+
+    drop(in_flight);
+    assert_eq!(rotation.await?, second);
+    while is_open(&first) {
+        sleep(Duration::from_millis(10)).await;
+    }
+    assert!(is_open(&second));
+
+    node_manager.close_enroll_channel(context).await;
+    while is_open(&second) {
+        sleep(Duration::from_millis(10)).await;
+    }
+    assert!(enroll().await.is_err());
+
+    drop(node_manager);
+    context.stop().await
+}
+
+/// Stands for a transport which can't reach the Orchestrator
+#[derive(Default)]
+struct UnreachableTransport(AtomicUsize);
+
+#[async_trait]
+impl CloudTransport for UnreachableTransport {
+    fn name(&self) -> &str {
+        "unreachable"
+    }
+
+    async fn connect(&self, _ctx: &Context, _cloud_route: &MultiAddr) -> ockam::Result<Route> {
+        self.0.fetch_add(1, Ordering::SeqCst);
+        Err(ApiError::generic("no route to the Orchestrator"))
+    }
+}
+
+#[ockam_macros::test(timeout = 5000)]
+async fn enrollments_fall_back_to_the_next_cloud_transport(
+    context: &mut Context,
+) -> ockam::Result<()> {
+    let (handle, controller, authenticator) = start_mock_controller_for_tests(context).await?;
+    authenticator.accept_api_key(Token::new("key"));
+
+    let unreachable = Arc::new(UnreachableTransport::default());
+    let tcp = handle
+        .node_manager
+        .read()
+        .await
+        .tcp_transport
+        .async_try_clone()
+        .await?;
+    handle.node_manager.write().await.cloud_transports =
+        vec![unreachable.clone(), Arc::new(TcpCloudTransport::new(tcp))];
+    let node_manager = handle.node_manager.read().await;
+    node_manager
+        .enroll_api_key(context, None, &controller, Token::new("key"))
+        .await?;
+    assert_eq!(unreachable.0.load(Ordering::SeqCst), 1);
+    drop(node_manager);
+
+    // the error of the last transport is returned when none of them works
+    handle.node_manager.write().await.cloud_transports = vec![unreachable.clone()];
+    let node_manager = handle.node_manager.read().await;
+    let err = node_manager
+        .enroll_api_key(context, None, &controller, Token::new("key"))
+        .await
+        .unwrap_err();
+    assert!(
+        err.to_string().contains("no route to the Orchestrator"),
+        "{err}"
+    );
+
+    drop(node_manager);
+    context.stop().await
+}
+
+#[ockam_macros::test(timeout = 5000)]
+async fn enrollments_give_up_at_their_deadline(context: &mut Context) -> ockam::Result<()> {
+    let (handle, controller, authenticator) = start_mock_controller_for_tests(context).await?;
+    authenticator.set_mode(MockMode::Hold);
+
+    let node_manager = handle.node_manager.read().await;
+    let token = AuthenticateToken::Auth0(oidc_token(None));
+    let deadline = Duration::from_millis(500);
+    let start = std::time::Instant::now();
+    let err = node_manager
+        .authenticate_token_within(context, None, &controller, token, None, Some(deadline))
+        .await
+        .unwrap_err();
+    assert!(start.elapsed() < Duration::from_secs(2));
+    assert!(matches!(err, EnrollError::DeadlineExceeded(d) if d == deadline));
+    assert_eq!(err.status(), Status::RequestTimeout);
+    assert_eq!(authenticator.held(), 1);
+
+    // the secure channel of the cancelled attempt is stopped
+    sleep(Duration::from_millis(100)).await;
+    let registry = handle.secure_channels.secure_channel_registry();
+    assert!(registry
+        .get_channel_list()
+        .iter()
+        .all(|channel| !channel.is_initiator()));
+
+    drop(node_manager);
+    context.stop().await
+}
+
+/// Metrics keeping the names of the counters in the order they are incremented
+#[derive(Default)]
+struct RecordedMetrics(Mutex<Vec<&'static str>>);
+
+impl RecordedMetrics {
+    fn take(&self) -> Vec<&'static str> {
+        std::mem::take(&mut self.0.lock().unwrap())
+    }
+}
+
+impl EnrollMetrics for RecordedMetrics {
+    fn auth0_enrollment_attempted(&self) {
+        self.0.lock().unwrap().push("auth0_attempted");
+    }
+
+    fn auth0_enrollment_succeeded(&self) {
+        self.0.lock().unwrap().push("auth0_succeeded");
+    }
+
+    fn enrollment_token_rejected(&self) {
+        self.0.lock().unwrap().push("token_rejected");
+    }
+}
+
+#[ockam_macros::test(timeout = 5000)]
+async fn enrollment_outcomes_are_counted(context: &mut Context) -> ockam::Result<()> {
+    let (handle, controller, _) = start_mock_controller_for_tests(context).await?;
+    let metrics = Arc::new(RecordedMetrics::default());
+    handle
+        .node_manager
+        .write()
+        .await
+        .enroll_options
+        .enroll_metrics = metrics.clone();
+
+    let node_manager = handle.node_manager.read().await;
+    let token = OidcToken {
+        token_type: TokenType::Bearer,
+        access_token: Token::new("access"),
+        refresh_token: None,
+        expires_at: None,
+        issued_at: None,
+    };
+    node_manager
+        .enroll_auth0(context, None, &controller, token)
+        .await?;
+    assert_eq!(metrics.take(), vec!["auth0_attempted", "auth0_succeeded"]);
+
+    let req = Request::post("v0/enroll/token").into_parts().0;
+    let token = EnrollmentToken::new(Token::new("token")).with_expires_at(1);
+    node_manager
+        .authenticate_enrollment_token_response(
+            context,
+            &req,
+            CloudRequestWrapper::new(token, &controller, None),
+            None,
+        )
+        .await?;
+    assert_eq!(metrics.take(), vec!["token_rejected"]);
+
+    drop(node_manager);
+    context.stop().await
+}
+
+#[ockam_macros::test(timeout = 5000)]
+async fn enrollment_token_expiry_follows_the_node_clock(
+    context: &mut Context,
+) -> ockam::Result<()> {
+    let (handle, controller, authenticator) = start_mock_controller_for_tests(context).await?;
+    authenticator.set_mode(MockMode::Accept(None));
+    let clock = Arc::new(ManualClock::new(100));
+    handle.node_manager.write().await.enroll_options.clock = clock.clone();
+
+    let node_manager = handle.node_manager.read().await;
+    let req = Request::put("v0/enroll/token").into_parts().0;
+    let authenticate = || {
+        let token = EnrollmentToken::new(Token::new("token")).with_expires_at(150);
+        let req_wrapper = CloudRequestWrapper::new(token, &controller, None);
+        node_manager.authenticate_enrollment_token_response(context, &req, req_wrapper, None)
+    };
+    let status = |res: Vec<u8>| Response::parse_response_header(&res).unwrap().0.status();
+    assert_eq!(status(authenticate().await?), Some(Status::Ok));
+
+    clock.advance(50);
+    assert_eq!(status(authenticate().await?), Some(Status::Unauthorized));
+
+    drop(node_manager);
+    context.stop().await
+}
+
+#[ockam_macros::test(timeout = 5000)]
+async fn enrollment_tokens_can_still_be_requested_for_attributes_only(
+    context: &mut Context,
+) -> ockam::Result<()> {
+    let (handle, controller, authenticator) = start_mock_controller_for_tests(context).await?;
+
+    // the body sent by the clients built before `RequestEnrollmentToken`
+    let legacy = CloudRequestWrapper::new(attributes("device"), &controller, None);
+    let current = CloudRequestWrapper::new(
+        RequestEnrollmentToken::new(attributes("admin")).with_usage_count(2),
+        &controller,
+        None,
+    );
+    let legacy = Request::get("v0/enroll/token").body(legacy).to_vec()?;
+    let current = Request::get("v0/enroll/token").body(current).to_vec()?;
+    for req in [legacy, current] {
+        let res: Vec<u8> = context
+            .send_and_receive(route![NODEMANAGER_ADDR], req)
+            .await?;
+        let token: EnrollmentToken = Response::parse_response_body(&res)?;
+        assert!(authenticator.usage_remaining(&token.token).is_some());
+    }
+
+    let node_manager = handle.node_manager.read().await;
+    let tokens = authenticator.generated_tokens();
+    let claims = |token: &Token| {
+        let token = AuthenticateToken::EnrollmentToken(EnrollmentToken::new(token.clone()));
+        node_manager.authenticate_token(context, None, &controller, token, None)
+    };
+    let claims = claims(&tokens[0]).await.unwrap().claims.unwrap();
+    assert_eq!(claims.attributes, Some(attributes("device")));
+    assert_eq!(authenticator.usage_remaining(&tokens[1]), Some(2));
+
+    drop(node_manager);
+    context.stop().await
+}
+
+#[ockam_macros::test(timeout = 5000)]
+async fn oversized_attributes_only_requests_are_rejected(
+    context: &mut Context,
+) -> ockam::Result<()> {
+    let (_handle, controller, authenticator) = start_mock_controller_for_tests(context).await?;
+
+    let mut oversized = Attributes::new();
+    for key in 0..8 {
+        oversized.put(&format!("key-{key}"), &vec![0; 1024 * 1024]);
+    }
+    let legacy = CloudRequestWrapper::new(oversized.clone(), &controller, None);
+    let current =
+        CloudRequestWrapper::new(RequestEnrollmentToken::new(oversized), &controller, None);
+    let legacy = Request::get("v0/enroll/token").body(legacy).to_vec()?;
+    let current = Request::get("v0/enroll/token").body(current).to_vec()?;
+    for req in [legacy, current] {
+        let res: Vec<u8> = context
+            .send_and_receive(route![NODEMANAGER_ADDR], req)
+            .await?;
+        let (header, mut dec) = Response::parse_response_header(&res)?;
+        assert_eq!(header.status(), Some(Status::BadRequest));
+        let message = dec
+            .decode::<Error>()?
+            .message()
+            .unwrap_or_default()
+            .to_string();
+        // the attributes are rejected at the first value exceeding the limit, before being decoded
+        let size = message
+            .strip_prefix("the attributes are too large: ")
+            .and_then(|rest| rest.split(' ').next())
+            .and_then(|size| size.parse::<usize>().ok());
+        assert!(
+            size.map_or(false, |size| size < 2 * 1024 * 1024),
+            "{message}"
+        );
+    }
+    assert!(authenticator.generation_requests().is_empty());
+
+    context.stop().await
+}
+
+#[ockam_macros::test(timeout = 5000)]
+async fn signed_enrollment_tokens_are_verified_offline(context: &mut Context) -> ockam::Result<()> {
+    let handle = start_manager_for_tests(context).await?;
+    let vault = handle.secure_channels.identities().vault();
+    let key_id = vault
+        .create_ephemeral_secret(SecretAttributes::Ed25519)
+        .await?;
+    let public_key = vault.get_public_key(&key_id).await?;
+
+    let token = EnrollmentToken::new(Token::new("token")).with_attributes(attributes("device"));
+    let signature = vault.sign(&key_id, &token.signed_data().unwrap()).await?;
+    let token = token.with_signature(signature.as_ref().to_vec());
+    // the signature is kept when the token is sent to another node
+    let token: EnrollmentToken = minicbor::decode(&minicbor::to_vec(&token)?)?;
+
+    let node_manager = handle.node_manager.read().await;
+    let verify = |token| node_manager.verify_enrollment_token_offline(token, &public_key);
+    let verified = verify(&token).await?;
+    assert_eq!(verified.get("role"), Some(&b"device"[..]));
+
+    let tampered = token.clone().with_attributes(attributes("admin"));
+    assert!(verify(&tampered).await.is_err());
+
+    let unsigned = EnrollmentToken::new(Token::new("token")).with_attributes(attributes("device"));
+    assert!(verify(&unsigned).await.is_err());
+
+    node_manager.revoked_enrollment_tokens.revoke(
+        &Token::new("token"),
+        node_manager.enroll_options.clock.now()?,
+    );
+    assert!(verify(&token).await.is_err());
+
+    drop(node_manager);
+    context.stop().await
+}
+
+#[ockam_macros::test(timeout = 5000)]
+async fn all_the_fields_of_signed_enrollment_tokens_are_verified_offline(
+    context: &mut Context,
+) -> ockam::Result<()> {
+    let handle = start_manager_for_tests(context).await?;
+    handle.node_manager.write().await.enroll_options.clock = Arc::new(ManualClock::new(100));
+    handle.node_manager.write().await.trust_context =
+        Some(TrustContext::new("project-1".to_string(), None));
+    let vault = handle.secure_channels.identities().vault();
+    let key_id = vault
+        .create_ephemeral_secret(SecretAttributes::Ed25519)
+        .await?;
+    let public_key = vault.get_public_key(&key_id).await?;
+
+    let token = EnrollmentToken::new(Token::new("token"))
+        .with_attributes(attributes("device"))
+        .with_expires_at(200)
+        .with_not_before(50)
+        .with_bound_identifier(Token::new("hw-1"))
+        .with_audience(Token::new("project-1"));
+    let signature = vault.sign(&key_id, &token.signed_data().unwrap()).await?;
+    let token = token
+        .with_signature(signature.as_ref().to_vec())
+        .with_device_identifier(Token::new("hw-1"));
+
+    let node_manager = handle.node_manager.read().await;
+    let verify = |token| node_manager.verify_enrollment_token_offline(token, &public_key);
+    assert!(verify(&token).await.is_ok());
+
+    let tampered = [
+        token.clone().with_version(0),
+        token.clone().with_expires_at(300),
+        token.clone().with_not_before(0),
+        EnrollmentToken {
+            not_before: None,
+            ..token.clone()
+        },
+        token.clone().with_bound_identifier(Token::new("hw-2")),
+        EnrollmentToken {
+            bound_identifier: None,
+            ..token.clone()
+        },
+        token.clone().with_audience(Token::new("project-2")),
+        EnrollmentToken {
+            audience: None,
+            ..token.clone()
+        },
+    ];
+    for tampered in &tampered {
+        let err = verify(tampered).await.unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("invalid enrollment token signature"));
+    }
+
+    // the checks which don't depend on the signature
+    let other_device = token.clone().with_device_identifier(Token::new("hw-2"));
+    let err = verify(&other_device).await.unwrap_err();
+    assert!(err.to_string().contains("bound to another device"));
+    drop(node_manager);
+
+    let verify = || async {
+        let node_manager = handle.node_manager.read().await;
+        node_manager
+            .verify_enrollment_token_offline(&token, &public_key)
+            .await
+            .unwrap_err()
+            .to_string()
+    };
+    handle.node_manager.write().await.trust_context =
+        Some(TrustContext::new("project-2".to_string(), None));
+    assert!(verify().await.contains("meant for another project"));
+    handle.node_manager.write().await.enroll_options.clock = Arc::new(ManualClock::new(10));
+    assert!(verify().await.contains("not valid yet"));
+
+    context.stop().await
+}
+
+#[ockam_macros::test(timeout = 5000)]
+async fn device_flows_check_the_auth0_config(context: &mut Context) -> ockam::Result<()> {
+    let handle = start_manager_for_tests(context).await?;
+    handle
+        .node_manager
+        .write()
+        .await
+        .enroll_options
+        .auth0_config = Auth0Config {
+        client_id: "".to_string(),
+        ..Default::default()
+    };
+
+    let node_manager = handle.node_manager.read().await;
+    let err = node_manager.start_auth0_device_flow().await.unwrap_err();
+    assert!(err.to_string().contains("the client id is missing"));
+
+    drop(node_manager);
+    context.stop().await
+}
+
+#[ockam_macros::test(timeout = 5000)]
+async fn cancelled_device_flows_return_promptly(context: &mut Context) -> ockam::Result<()> {
+    let handle = start_manager_for_tests(context).await?;
+    let node_manager = handle.node_manager.read().await;
+    let device_code = DeviceCode {
+        device_code: "device_code".into(),
+        user_code: "user_code".into(),
+        verification_uri: "https://ockam.io/activate".into(),
+        verification_uri_complete: "https://ockam.io/activate?code=user_code".into(),
+        expires_in: 600,
+        interval: 5,
+        code_verifier: None,
+    };
+    let (cancel, cancelled) = oneshot::channel();
+    cancel.send(()).unwrap();
+
+    let err = node_manager
+        .poll_auth0_device_code(&device_code, cancelled)
+        .await
+        .unwrap_err();
+    assert_eq!(err.tokens_error_kind(), None);
+    assert_eq!(ockam_core::Error::from(err).code().kind, Kind::Cancelled);
+
+    // the flow didn't leave any secure channel behind
+    let registry = handle.secure_channels.secure_channel_registry();
+    assert!(registry.get_channel_list().is_empty());
+
+    drop(node_manager);
+    context.stop().await
+}
+
+#[ockam_macros::test(timeout = 5000)]
+async fn secure_channel_creation_times_out(context: &mut Context) -> ockam::Result<()> {
+    let (handle, _, authenticator) = start_mock_controller_for_tests(context).await?;
+    authenticator.set_mode(MockMode::Unreachable);
+    authenticator.start_at(context, "blackhole").await?;
+    let unreachable = MultiAddr::from_str("/service/blackhole")?;
+    handle
+        .node_manager
+        .write()
+        .await
+        .enroll_options
+        .secure_channel_timeout = Duration::from_millis(200);
+
+    handle
+        .node_manager
+        .write()
+        .await
+        .enroll_options
+        .retry_policy = RetryPolicy::disabled();
+
+    let node_manager = handle.node_manager.read().await;
+    let token = AuthenticateToken::EnrollmentToken(EnrollmentToken::new(Token::new("token")));
+    let err = node_manager
+        .authenticate_token(context, None, &unreachable, token, None)
+        .await
+        .unwrap_err();
+    assert!(matches!(err, EnrollError::SecureChannelTimeout(_)));
+    assert!(err.is_transient());
+    assert_eq!(err.status(), Status::RequestTimeout);
+
+    let req = Request::get("v0/enroll/tokens").into_parts().0;
+    let res = node_manager
+        .generate_enrollment_tokens(context, &req, &unreachable, vec![attributes("device")], 1)
+        .await?;
+    let (header, _) = Response::parse_response_header(&res)?;
+    assert_eq!(header.status(), Some(Status::RequestTimeout));
+
+    drop(node_manager);
+    context.stop().await
+}
+
+#[ockam_macros::test(timeout = 5000)]
+async fn secure_channel_creation_times_out_when_generating_tokens(
+    context: &mut Context,
+) -> ockam::Result<()> {
+    let (handle, _, authenticator) = start_mock_controller_for_tests(context).await?;
+    authenticator.set_mode(MockMode::Unreachable);
+    authenticator.start_at(context, "blackhole").await?;
+    let unreachable = MultiAddr::from_str("/service/blackhole")?;
+    handle
+        .node_manager
+        .write()
+        .await
+        .enroll_options
+        .secure_channel_timeout = Duration::from_millis(200);
+    handle
+        .node_manager
+        .write()
+        .await
+        .enroll_options
+        .retry_policy = RetryPolicy::disabled();
+
+    let node_manager = handle.node_manager.read().await;
+    let req = Request::get("v0/enroll/token").into_parts().0;
+    let body = RequestEnrollmentToken::new(attributes("device"));
+    let res = node_manager
+        .generate_enrollment_token(context, &req, &unreachable, body)
+        .await?;
+    let (header, dec) = Response::parse_response_header(&res)?;
+    assert_eq!(header.re(), req.id());
+    assert_eq!(header.status(), Some(Status::RequestTimeout));
+    let message = Response::parse_err_msg(header, dec);
+    assert!(
+        message.contains("was not established after 200ms"),
+        "{message}"
+    );
+
+    drop(node_manager);
+    context.stop().await
+}
+
+#[ockam_macros::test(timeout = 5000)]
+async fn token_batches_are_retried(context: &mut Context) -> ockam::Result<()> {
+    let (handle, _, authenticator) = start_mock_controller_for_tests(context).await?;
+    authenticator.set_mode(MockMode::Unreachable);
+    authenticator.start_at(context, "blackhole").await?;
+    let unreachable = MultiAddr::from_str("/service/blackhole")?;
+    handle
+        .node_manager
+        .write()
+        .await
+        .enroll_options
+        .secure_channel_timeout = Duration::from_millis(200);
+    handle
+        .node_manager
+        .write()
+        .await
+        .enroll_options
+        .retry_policy = RetryPolicy::new(2, Duration::from_millis(10));
+
+    let node_manager = handle.node_manager.read().await;
+    let req = Request::get("v0/enroll/tokens").into_parts().0;
+    let start = Instant::now();
+    let res = node_manager
+        .generate_enrollment_tokens(context, &req, &unreachable, vec![attributes("device")], 2)
+        .await?;
+    let (header, _) = Response::parse_response_header(&res)?;
+    assert_eq!(header.status(), Some(Status::RequestTimeout));
+    // the creation of the secure channel timed out twice
+    assert!(start.elapsed() >= Duration::from_millis(400));
+
+    drop(node_manager);
+    context.stop().await
+}
+
+#[ockam_macros::test(timeout = 5000)]
+async fn the_node_manager_is_not_locked_between_token_generation_attempts(
+    context: &mut Context,
+) -> ockam::Result<()> {
+    let (handle, _, authenticator) = start_mock_controller_for_tests(context).await?;
+    authenticator.set_mode(MockMode::Unreachable);
+    authenticator.start_at(context, "blackhole").await?;
+    let unreachable = MultiAddr::from_str("/service/blackhole")?;
+    handle
+        .node_manager
+        .write()
+        .await
+        .enroll_options
+        .secure_channel_timeout = Duration::from_millis(200);
+    handle
+        .node_manager
+        .write()
+        .await
+        .enroll_options
+        .retry_policy = RetryPolicy::new(2, Duration::from_millis(10));
+
+    let body = CloudRequestWrapper::new(
+        RequestEnrollmentToken::new(attributes("device")),
+        &unreachable,
+        None,
+    );
+    let req = Request::get("v0/enroll/token").body(body).to_vec()?;
+    let start = Instant::now();
+    let generate = async {
+        let res: Vec<u8> = context
+            .send_and_receive(route![NODEMANAGER_ADDR], req)
+            .await?;
+        Ok::<_, ockam::Error>((Response::parse_response_header(&res)?.0, start.elapsed()))
+    };
+    let write = async {
+        // the first attempt is still creating its secure channel
+        sleep(Duration::from_millis(100)).await;
+        let _node_manager = handle.node_manager.write().await;
+        start.elapsed()
+    };
+    let (generated, written) = futures::join!(generate, write);
+    let (header, generated) = generated?;
+    assert_eq!(header.status(), Some(Status::RequestTimeout));
+    // both attempts timed out, but the write lock was taken after the first one
+    assert!(generated >= Duration::from_millis(400));
+    assert!(written < Duration::from_millis(350));
+
+    context.stop().await
+}
+
+#[ockam_macros::test(timeout = 5000)]
+async fn empty_routes_are_rejected_as_bad_requests(context: &mut Context) -> ockam::Result<()> {
+    let handle = start_manager_for_tests(context).await?;
+    let node_manager = handle.node_manager.read().await;
+    let req = Request::post("v0/enroll").into_parts().0;
+    let empty = MultiAddr::default();
+
+    let auth0 = EnrollBody::auth0(oidc_token(None));
+    let auth0 = minicbor::to_vec(CloudRequestWrapper::new(auth0, &empty, None))?;
+    let token = EnrollBody::enrollment_token(EnrollmentToken::new(Token::new("token")));
+    let enrollment_token = minicbor::to_vec(CloudRequestWrapper::new(token, &empty, None))?;
+    let api_key = EnrollBody::api_key(AuthenticateApiKey::new(Token::new("key")));
+    let api_key = minicbor::to_vec(CloudRequestWrapper::new(api_key, &empty, None))?;
+    for body in [auth0, enrollment_token, api_key] {
+        let res = node_manager
+            .enroll(context, &req, &mut Decoder::new(&body))
+            .await?;
+        let (header, mut dec) = Response::parse_response_header(&res)?;
+        assert_eq!(header.status(), Some(Status::BadRequest));
+        let err: Error = dec.decode()?;
+        assert_eq!(
+            err.message(),
+            Some("the route to the Orchestrator is empty")
+        );
+    }
+
+    drop(node_manager);
+    context.stop().await
+}
+
+#[ockam_macros::test(timeout = 5000)]
+async fn oversized_tokens_are_rejected_locally(context: &mut Context) -> ockam::Result<()> {
+    let (handle, _, authenticator) = start_mock_controller_for_tests(context).await?;
+    // the secure channel creation would make the test time out
+    authenticator.set_mode(MockMode::Unreachable);
+    authenticator.start_at(context, "blackhole").await?;
+    let unreachable = MultiAddr::from_str("/service/blackhole")?;
+
+    let node_manager = handle.node_manager.read().await;
+    let req = Request::post("v0/enroll").into_parts().0;
+    let oversized = Token::new("a".repeat(DEFAULT_MAX_TOKEN_LENGTH + 1));
+    let auth0 = {
+        let mut token = oidc_token(None);
+        token.refresh_token = Some(oversized.clone());
+        let body = EnrollBody::auth0(token);
+        minicbor::to_vec(CloudRequestWrapper::new(body, &unreachable, None))?
+    };
+    let enrollment_token = {
+        let body = EnrollBody::enrollment_token(EnrollmentToken::new(oversized.clone()));
+        minicbor::to_vec(CloudRequestWrapper::new(body, &unreachable, None))?
+    };
+    let api_key = {
+        let body = EnrollBody::api_key(AuthenticateApiKey::new(oversized));
+        minicbor::to_vec(CloudRequestWrapper::new(body, &unreachable, None))?
+    };
+    for body in [auth0, enrollment_token, api_key] {
+        let res = node_manager
+            .enroll(context, &req, &mut Decoder::new(&body))
+            .await?;
+        let (header, mut dec) = Response::parse_response_header(&res)?;
+        assert_eq!(header.status(), Some(Status::BadRequest));
+        let err: Error = dec.decode()?;
+        assert!(err.message().unwrap().contains("the token is too long"));
+    }
+    let registry = handle.secure_channels.secure_channel_registry();
+    assert!(registry.get_channel_list().is_empty());
+
+    drop(node_manager);
+    context.stop().await
+}
+
+#[ockam_macros::test(timeout = 5000)]
+async fn invalid_attributes_are_rejected_locally(context: &mut Context) -> ockam::Result<()> {
+    let (handle, controller, _) = start_mock_controller_for_tests(context).await?;
+
+    let node_manager = handle.node_manager.read().await;
+    let req = Request::get("v0/enroll/tokens").into_parts().0;
+    let mut empty_key = Attributes::new();
+    empty_key.put("", b"device");
+    let attributes_list = vec![attributes("device"), empty_key];
+    let res = node_manager
+        .generate_enrollment_tokens(context, &req, &controller, attributes_list, 1)
+        .await?;
+
+    let (header, dec) = Response::parse_response_header(&res)?;
+    assert_eq!(header.status(), Some(Status::BadRequest));
+    assert!(Response::parse_err_msg(header, dec).contains("invalid attributes 1"));
+
+    let mut reserved = Attributes::new();
+    reserved.put("ockam-role", b"enroller");
+    let res = node_manager
+        .generate_enrollment_tokens(context, &req, &controller, vec![reserved], 1)
+        .await?;
+    let (header, dec) = Response::parse_response_header(&res)?;
+    assert_eq!(header.status(), Some(Status::BadRequest));
+    assert!(Response::parse_err_msg(header, dec).contains("ockam-role is reserved"));
+
+    // no secure channel was created to send the attributes
+    let registry = handle.secure_channels.secure_channel_registry();
+    assert!(registry.get_channel_list().is_empty());
+
+    drop(node_manager);
+    context.stop().await
+}
+
+#[ockam_macros::test(timeout = 5000)]
+async fn enrollment_tokens_are_listed_by_page(context: &mut Context) -> ockam::Result<()> {
+    let (handle, controller, _) = start_mock_controller_for_tests(context).await?;
+
+    let node_manager = handle.node_manager.read().await;
+    let req = Request::get("v0/enroll/tokens").into_parts().0;
+    let generate = Request::get("v0/enroll/tokens").into_parts().0;
+    node_manager
+        .generate_enrollment_tokens(
+            context,
+            &generate,
+            &controller,
+            vec![attributes("device")],
+            3,
+        )
+        .await?;
+    let list = |page| node_manager.list_enrollment_tokens(context, &req, &controller, page, 2);
+    let ids = |page: &EnrollmentTokenPage| -> Vec<String> {
+        page.tokens.iter().map(|t| t.id.clone()).collect()
+    };
+
+    let first: EnrollmentTokenPage = Response::parse_response_body(&list(0).await?)?;
+    assert_eq!(ids(&first), vec!["token-0", "token-1"]);
+    assert_eq!(first.next_page, Some(1));
+
+    let last: EnrollmentTokenPage = Response::parse_response_body(&list(1).await?)?;
+    assert_eq!(ids(&last), vec!["token-2"]);
+    assert_eq!(last.next_page, None);
+
+    sleep(Duration::from_millis(100)).await;
+    let registry = handle.secure_channels.secure_channel_registry();
+    assert!(registry
+        .get_channel_list()
+        .iter()
+        .all(|channel| !channel.is_initiator()));
+
+    drop(node_manager);
+    context.stop().await
+}
+
+#[ockam_macros::test(timeout = 5000)]
+async fn enrollment_token_requests_can_be_validated(context: &mut Context) -> ockam::Result<()> {
+    let (handle, controller, authenticator) = start_mock_controller_for_tests(context).await?;
+    authenticator.set_clock(Arc::new(ManualClock::new(0)));
+
+    let node_manager = handle.node_manager.read().await;
+    let req = Request::post("v0/enroll/token/validate").into_parts().0;
+    let body = RequestEnrollmentToken::builder()
+        .attributes(attributes("device"))
+        .usage_count(5)
+        .expires_in(100)
+        .build()
+        .unwrap();
+    let res = node_manager
+        .validate_enrollment_token_request(context, &req, &controller, body)
+        .await?;
+
+    let validated: ValidatedEnrollmentToken = Response::parse_response_body(&res)?;
+    assert_eq!(validated.attributes.get("role"), Some(&b"device"[..]));
+    assert_eq!(validated.expires_at, Some(100));
+    assert_eq!(validated.usage_count, Some(5));
+
+    drop(node_manager);
+    context.stop().await
+}
+
+#[ockam_macros::test(timeout = 5000)]
+async fn enrollment_tokens_are_generated_with_typed_attributes(
+    context: &mut Context,
+) -> ockam::Result<()> {
+    let (handle, controller, authenticator) = start_mock_controller_for_tests(context).await?;
+
+    let node_manager = handle.node_manager.read().await;
+    let req = Request::get("v0/enroll/token").into_parts().0;
+    let body = RequestEnrollmentToken::new(attributes("device"))
+        .with_typed_attribute("max_connections", 10);
+    let res = node_manager
+        .generate_enrollment_token(context, &req, &controller, body)
+        .await?;
+    let generated: EnrollmentToken = Response::parse_response_body(&res)?;
+    assert_eq!(generated.token, Token::new("token-0"));
+    let (_, sent) = &authenticator.generation_requests()[0];
+    assert_eq!(
+        sent.attribute_value("max_connections"),
+        Some(AttributeValue::Int(10))
+    );
+
+    drop(node_manager);
+    context.stop().await
+}
+
+/// Return the status of the node response to the authentication of `token`
+async fn authentication_status(
+    node_manager: &NodeManager,
+    context: &Context,
+    controller: &MultiAddr,
+    token: &EnrollmentToken,
+) -> ockam::Result<Option<Status>> {
+    authentication_status_of(node_manager, context, controller, token, None).await
+}
+
+/// Return the status of the node response to the authentication of `token`
+/// by the device presenting `device_identifier`
+async fn authentication_status_of(
+    node_manager: &NodeManager,
+    context: &Context,
+    controller: &MultiAddr,
+    token: &EnrollmentToken,
+    device_identifier: Option<Token>,
+) -> ockam::Result<Option<Status>> {
+    let req = Request::put("v0/enroll/token").into_parts().0;
+    let req_wrapper = CloudRequestWrapper::new(token.clone(), controller, None);
+    let res = node_manager
+        .authenticate_enrollment_token_response(context, &req, req_wrapper, device_identifier)
+        .await?;
+    Ok(Response::parse_response_header(&res)?.0.status())
+}
+
+/// Generates an enrollment token for `body`, then authenticates it, and
+/// returns the generated token with the claims of the authenticator
+async fn generate_and_authenticate(
+    node_manager: &NodeManager,
+    context: &Context,
+    controller: &MultiAddr,
+    body: RequestEnrollmentToken,
+) -> ockam::Result<(EnrollmentToken, EnrollClaims)> {
+    let req = Request::get("v0/enroll/token").into_parts().0;
+    let res = node_manager
+        .generate_enrollment_token(context, &req, controller, body)
+        .await?;
+    let token: EnrollmentToken = Response::parse_response_body(&res)?;
+
+    let req = Request::put("v0/enroll/token").into_parts().0;
+    let req_wrapper = CloudRequestWrapper::new(token.clone(), controller, None);
+    let res = node_manager
+        .authenticate_enrollment_token_response(context, &req, req_wrapper, None)
+        .await?;
+    let claims: EnrollClaims = Response::parse_response_body(&res)?;
+    Ok((token, claims))
+}
+
+#[ockam_macros::test(timeout = 5000)]
+async fn enrollment_token_labels_are_listed_and_introspected(
+    context: &mut Context,
+) -> ockam::Result<()> {
+    let (handle, controller, _) = start_mock_controller_for_tests(context).await?;
+
+    let node_manager = handle.node_manager.read().await;
+    let body = RequestEnrollmentToken::builder()
+        .attributes(attributes("device"))
+        .usage_count(2)
+        .label("warehouse-robots-batch-3")
+        .build()
+        .unwrap();
+    let (token, claims) =
+        generate_and_authenticate(&node_manager, context, &controller, body).await?;
+    // the label is not granted to the enrolled identity
+    let granted = claims.attributes.unwrap();
+    assert_eq!(granted.len(), 1);
+    assert_eq!(granted.get("role"), Some(&b"device"[..]));
+
+    let req = Request::post("v0/enroll/token/introspect").into_parts().0;
+    let res = node_manager
+        .introspect_enrollment_token(context, &req, &controller, &token.token)
+        .await?;
+    let introspection: EnrollmentTokenIntrospection = Response::parse_response_body(&res)?;
+    assert_eq!(
+        introspection.label.as_deref(),
+        Some("warehouse-robots-batch-3")
+    );
+
+    let req = Request::get("v0/enroll/tokens").into_parts().0;
+    let res = node_manager
+        .list_enrollment_tokens(context, &req, &controller, 0, 10)
+        .await?;
+    let page: EnrollmentTokenPage = Response::parse_response_body(&res)?;
+    let labels: Vec<_> = page.tokens.iter().map(|t| t.label.as_deref()).collect();
+    assert_eq!(labels, [Some("warehouse-robots-batch-3")]);
+
+    drop(node_manager);
+    context.stop().await
+}
+
+#[ockam_macros::test(timeout = 5000)]
+async fn enrollment_tokens_inherit_the_project_attributes(
+    context: &mut Context,
+) -> ockam::Result<()> {
+    let (handle, controller, _) = start_mock_controller_for_tests(context).await?;
+    let mut project_attributes = Attributes::new();
+    project_attributes.put("role", b"member").put("zone", b"eu");
+    handle
+        .node_manager
+        .write()
+        .await
+        .enroll_options
+        .project_attributes = project_attributes;
+
+    let node_manager = handle.node_manager.read().await;
+    let mut requested = attributes("device");
+    requested.put("rack", b"12");
+    let body = RequestEnrollmentToken::new(requested.clone());
+    let (_, claims) = generate_and_authenticate(&node_manager, context, &controller, body).await?;
+    // the requested values win over the project ones
+    let granted = claims.attributes.unwrap();
+    assert_eq!(granted.len(), 3);
+    assert_eq!(granted.get("role"), Some(&b"device"[..]));
+    assert_eq!(granted.get("rack"), Some(&b"12"[..]));
+    assert_eq!(granted.get("zone"), Some(&b"eu"[..]));
+    drop(node_manager);
+
+    handle
+        .node_manager
+        .write()
+        .await
+        .enroll_options
+        .inherit_project_attributes = false;
+    let node_manager = handle.node_manager.read().await;
+    let body = RequestEnrollmentToken::new(requested);
+    let (_, claims) = generate_and_authenticate(&node_manager, context, &controller, body).await?;
+    let granted = claims.attributes.unwrap();
+    assert_eq!(granted.len(), 2);
+    assert_eq!(granted.get("zone"), None);
+
+    drop(node_manager);
+    context.stop().await
+}
+
+#[ockam_macros::test(timeout = 5000)]
+async fn batch_enrollment_tokens_inherit_the_project_attributes(
+    context: &mut Context,
+) -> ockam::Result<()> {
+    let (handle, controller, authenticator) = start_mock_controller_for_tests(context).await?;
+    let mut project_attributes = Attributes::new();
+    project_attributes.put("role", b"member").put("zone", b"eu");
+    handle
+        .node_manager
+        .write()
+        .await
+        .enroll_options
+        .project_attributes = project_attributes;
+
+    let node_manager = handle.node_manager.read().await;
+    let req = Request::get("v0/enroll/tokens").into_parts().0;
+    node_manager
+        .generate_enrollment_tokens(context, &req, &controller, vec![attributes("device")], 2)
+        .await?;
+    let requests = authenticator.generation_requests();
+    assert_eq!(requests.len(), 2);
+    for (_, sent) in requests {
+        // the requested values win over the project ones
+        assert_eq!(sent.attributes.len(), 2);
+        assert_eq!(sent.attributes.get("role"), Some(&b"device"[..]));
+        assert_eq!(sent.attributes.get("zone"), Some(&b"eu"[..]));
+    }
+
+    drop(node_manager);
+    context.stop().await
+}
+
+#[ockam_macros::test(timeout = 5000)]
+async fn enroll_preflights_measure_the_clock_skew(context: &mut Context) -> ockam::Result<()> {
+    let (handle, controller, authenticator) = start_mock_controller_for_tests(context).await?;
+    let authenticator_clock = Arc::new(ManualClock::new(1100));
+    authenticator.set_clock(authenticator_clock.clone());
+    let clock = Arc::new(ManualClock::new(1000));
+    handle.node_manager.write().await.enroll_options.clock = clock.clone();
+
+    let node_manager = handle.node_manager.read().await;
+    let preflight = node_manager.enroll_preflight(context, &controller).await?;
+    assert_eq!(preflight.skew, 100);
+    assert_eq!(preflight.adjusted_clock(clock.clone()).now()?, 1100);
+
+    // the authenticator is 10 minutes behind
+    authenticator_clock.set(400);
+    let err = node_manager
+        .enroll_preflight(context, &controller)
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("-600s"), "{err}");
+    drop(node_manager);
+
+    handle
+        .node_manager
+        .write()
+        .await
+        .enroll_options
+        .max_clock_skew = Duration::from_secs(600);
+    let node_manager = handle.node_manager.read().await;
+    let preflight = node_manager.enroll_preflight(context, &controller).await?;
+    assert_eq!(preflight.skew, -600);
+
+    let err = node_manager
+        .enroll_preflight(context, &MultiAddr::default())
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("empty"), "{err}");
+
+    drop(node_manager);
+    context.stop().await
+}
+
+#[ockam_macros::test(timeout = 5000)]
+async fn enrollment_authenticators_can_be_pinged(context: &mut Context) -> ockam::Result<()> {
+    let (handle, controller, authenticator) = start_mock_controller_for_tests(context).await?;
+    authenticator.set_clock(Arc::new(ManualClock::new(1000)));
+
+    let node_manager = handle.node_manager.read().await;
+    let rtt = node_manager
+        .ping_enrollment_authenticator(context, &controller)
+        .await?;
+    assert!(rtt < Duration::from_secs(5));
+
+    authenticator.reject_with(Some(Status::ServiceUnavailable));
+    let err = node_manager
+        .ping_enrollment_authenticator(context, &controller)
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("rejected"), "{err}");
+
+    // the secure channels created for the checks are stopped
+    let registry = handle.secure_channels.secure_channel_registry();
+    while registry
+        .get_channel_list()
+        .iter()
+        .any(|channel| channel.is_initiator())
+    {
+        sleep(Duration::from_millis(10)).await;
+    }
+    assert!(node_manager.route_cache.is_empty());
+
+    drop(node_manager);
+    context.stop().await
+}
+
+#[ockam_macros::test(timeout = 5000)]
+async fn generated_tokens_can_be_authenticated(context: &mut Context) -> ockam::Result<()> {
+    let (handle, controller, authenticator) = start_mock_controller_for_tests(context).await?;
+    let clock = Arc::new(ManualClock::new(1000));
+    authenticator.set_clock(clock.clone());
+    handle.node_manager.write().await.enroll_options.clock = clock;
+
+    let node_manager = handle.node_manager.read().await;
+    let body = RequestEnrollmentToken::builder()
+        .attributes(attributes("device"))
+        .typed_attribute("port", 8080i64)
+        .expires_in(60)
+        .build()
+        .unwrap();
+    let (token, claims) =
+        generate_and_authenticate(&node_manager, context, &controller, body).await?;
+
+    let granted = claims.attributes.unwrap();
+    assert_eq!(granted.get("role"), Some(&b"device"[..]));
+    assert_eq!(granted.get("port"), Some(&b"8080"[..]));
+    assert_eq!(claims.expires_at, Some(1060));
+    assert_eq!(token.expires_at, claims.expires_at);
+    assert_eq!(claims.identity, Some(node_manager.identifier()));
+
+    drop(node_manager);
+    context.stop().await
+}
+
+#[ockam_macros::test(timeout = 5000)]
+async fn enrollment_tokens_are_rejected_before_their_not_before_time(
+    context: &mut Context,
+) -> ockam::Result<()> {
+    let (handle, controller, authenticator) = start_mock_controller_for_tests(context).await?;
+    let clock = Arc::new(ManualClock::new(1000));
+    authenticator.set_clock(clock.clone());
+    handle.node_manager.write().await.enroll_options.clock = clock.clone();
+
+    let node_manager = handle.node_manager.read().await;
+    let req = Request::get("v0/enroll/token").into_parts().0;
+    let body = RequestEnrollmentToken::new(attributes("device")).with_not_before(1100);
+    let res = node_manager
+        .generate_enrollment_token(context, &req, &controller, body)
+        .await?;
+    let token: EnrollmentToken = Response::parse_response_body(&res)?;
+    assert_eq!(token.not_before, Some(1100));
+
+    let req = Request::put("v0/enroll/token").into_parts().0;
+    let req_wrapper = CloudRequestWrapper::new(token.clone(), &controller, None);
+    let res = node_manager
+        .authenticate_enrollment_token_response(context, &req, req_wrapper.clone(), None)
+        .await?;
+    let (header, mut dec) = Response::parse_response_header(&res)?;
+    assert_eq!(header.status(), Some(Status::Forbidden));
+    let err: Error = dec.decode()?;
+    assert_eq!(
+        err.message(),
+        Some("the enrollment token can't be used before 1100, 100s from now")
+    );
+
+    // the authenticator rejects the early tokens which don't report their not-before time
+    let stripped = EnrollmentToken {
+        not_before: None,
+        ..token.clone()
+    };
+    let stripped = CloudRequestWrapper::new(stripped, &controller, None);
+    let res = node_manager
+        .authenticate_enrollment_token_response(context, &req, stripped, None)
+        .await?;
+    let (header, mut dec) = Response::parse_response_header(&res)?;
+    assert_eq!(header.status(), Some(Status::Forbidden));
+    let err: Error = dec.decode()?;
+    assert_eq!(
+        err.message(),
+        Some("the token was rejected (403 Forbidden): the enrollment token is not valid yet")
+    );
+
+    // the early attempts didn't use the single-use token up
+    clock.set(1100);
+    let res = node_manager
+        .authenticate_enrollment_token_response(context, &req, req_wrapper, None)
+        .await?;
+    let claims: EnrollClaims = Response::parse_response_body(&res)?;
+    assert_eq!(claims.attributes, Some(attributes("device")));
+
+    drop(node_manager);
+    context.stop().await
+}
+
+#[ockam_macros::test(timeout = 5000)]
+async fn expired_attributes_are_not_granted(context: &mut Context) -> ockam::Result<()> {
+    let (handle, controller, authenticator) = start_mock_controller_for_tests(context).await?;
+    let clock = Arc::new(ManualClock::new(1000));
+    authenticator.set_clock(clock.clone());
+    handle.node_manager.write().await.enroll_options.clock = clock.clone();
+
+    let node_manager = handle.node_manager.read().await;
+    let mut granted = attributes("device");
+    granted.put("admin", b"true");
+    let body = RequestEnrollmentToken::new(granted.clone())
+        .with_usage_count(2)
+        .with_attribute_expires_in("admin", 60);
+    let (token, claims) =
+        generate_and_authenticate(&node_manager, context, &controller, body).await?;
+    assert_eq!(claims.attributes, Some(granted));
+
+    clock.advance(60);
+    let token = AuthenticateToken::EnrollmentToken(token);
+    let enrolled = node_manager
+        .authenticate_token(context, None, &controller, token, None)
+        .await
+        .unwrap();
+    let claims = enrolled.claims.unwrap();
+    assert_eq!(claims.attributes, Some(attributes("device")));
+
+    // attributes can't be granted for longer than the token itself
+    let req = Request::get("v0/enroll/token").into_parts().0;
+    let body = RequestEnrollmentToken::builder()
+        .attributes(attributes("device"))
+        .expires_in(60)
+        .build()
+        .unwrap()
+        .with_attribute_expires_in("role", 120);
+    let res = node_manager
+        .generate_enrollment_token(context, &req, &controller, body)
+        .await?;
+    let (header, mut dec) = Response::parse_response_header(&res)?;
+    assert_eq!(header.status(), Some(Status::BadRequest));
+    let err: Error = dec.decode()?;
+    assert_eq!(
+        err.message(),
+        Some("the attribute role can't expire after the token")
+    );
+
+    drop(node_manager);
+    context.stop().await
+}
+
+#[ockam_macros::test(timeout = 5000)]
+async fn enrollments_report_the_time_spent_in_each_phase(
+    context: &mut Context,
+) -> ockam::Result<()> {
+    let (handle, controller, _) = start_mock_controller_for_tests(context).await?;
+
+    let node_manager = handle.node_manager.read().await;
+    let req = Request::get("v0/enroll/token").into_parts().0;
+    let body = RequestEnrollmentToken::new(attributes("device"));
+    let res = node_manager
+        .generate_enrollment_token(context, &req, &controller, body)
+        .await?;
+    let token: EnrollmentToken = Response::parse_response_body(&res)?;
+
+    let started = std::time::Instant::now();
+    let token = AuthenticateToken::EnrollmentToken(token);
+    let enrolled = node_manager
+        .authenticate_token(context, None, &controller, token, None)
+        .await?;
+    let elapsed = started.elapsed();
+
+    let timings = enrolled.timings.unwrap();
+    assert!(timings.channel_setup > Duration::ZERO, "{timings:?}");
+    assert!(timings.request > Duration::ZERO, "{timings:?}");
+    assert!(timings.total <= elapsed, "{timings:?}");
+    // only the bookkeeping between the phases is not part of any of them
+    assert!(timings.phases() <= timings.total, "{timings:?}");
+    assert!(
+        timings.total - timings.phases() < Duration::from_millis(100),
+        "{timings:?}"
+    );
+
+    drop(node_manager);
+    context.stop().await
+}
+
+#[ockam_macros::test(timeout = 5000)]
+async fn enrollments_report_the_authenticator_identity(context: &mut Context) -> ockam::Result<()> {
+    let (handle, controller, _) = start_mock_controller_for_tests(context).await?;
+
+    let node_manager = handle.node_manager.read().await;
+    let req = Request::get("v0/enroll/token").into_parts().0;
+    let body = RequestEnrollmentToken::new(attributes("device"));
+    let res = node_manager
+        .generate_enrollment_token(context, &req, &controller, body)
+        .await?;
+    let token: EnrollmentToken = Response::parse_response_body(&res)?;
+
+    let token = AuthenticateToken::EnrollmentToken(token);
+    let enrolled = node_manager
+        .authenticate_token(context, None, &controller, token, None)
+        .await
+        .unwrap();
+    // the authenticator is reached through the secure channel listener of the controller
+    assert_eq!(enrolled.authenticator, Some(handle.identifier.clone()));
+    assert_eq!(handle.identifier, node_manager.controller_identifier());
+
+    drop(node_manager);
+    context.stop().await
+}
+
+#[ockam_macros::test(timeout = 5000)]
+async fn enrollments_report_the_credential_expiry(context: &mut Context) -> ockam::Result<()> {
+    let (handle, controller, authenticator) = start_mock_controller_for_tests(context).await?;
+    let clock = Arc::new(ManualClock::new(1000));
+    authenticator.set_clock(clock.clone());
+    handle.node_manager.write().await.enroll_options.clock = clock;
+
+    let node_manager = handle.node_manager.read().await;
+    let body = RequestEnrollmentToken::new(attributes("device")).with_usage_count(2);
+    let (token, claims) =
+        generate_and_authenticate(&node_manager, context, &controller, body).await?;
+    assert_eq!(claims.credential_expires_at, None);
+
+    authenticator.issue_credentials_for(Some(3600));
+    let token = AuthenticateToken::EnrollmentToken(token);
+    let enrolled = node_manager
+        .authenticate_token(context, None, &controller, token, None)
+        .await
+        .unwrap();
+    assert_eq!(enrolled.credential_expires_at(), Some(4600));
+
+    drop(node_manager);
+    context.stop().await
+}
+
+#[ockam_macros::test(timeout = 5000)]
+async fn bound_enrollment_tokens_are_only_usable_by_their_device(
+    context: &mut Context,
+) -> ockam::Result<()> {
+    let (handle, controller, authenticator) = start_mock_controller_for_tests(context).await?;
+
+    let node_manager = handle.node_manager.read().await;
+    let req = Request::get("v0/enroll/token").into_parts().0;
+    let body = RequestEnrollmentToken::new(attributes("device"))
+        .with_usage_count(2)
+        .with_bound_identifier(Token::new("hw-1"));
+    let res = node_manager
+        .generate_enrollment_token(context, &req, &controller, body)
+        .await?;
+    let token: EnrollmentToken = Response::parse_response_body(&res)?;
+    assert_eq!(token.bound_identifier, Some(Token::new("hw-1")));
+
+    let (manager, ctx, controller) = (&*node_manager, &*context, &controller);
+    let status = |token: &EnrollmentToken, device_identifier: Option<&str>| {
+        let token = token.clone();
+        let device_identifier = device_identifier.map(Token::new);
+        async move {
+            authentication_status_of(manager, ctx, controller, &token, device_identifier).await
+        }
+    };
+    let forbidden = Some(Status::Forbidden);
+    assert_eq!(status(&token, None).await?, forbidden);
+    assert_eq!(status(&token, Some("hw-2")).await?, forbidden);
+    // the authenticator also checks the binding of the tokens which don't report it,
+    // so that a device can't strip the binding to pass the check of the node
+    let stripped = EnrollmentToken {
+        bound_identifier: None,
+        ..token.clone()
+    };
+    assert!(stripped.is_usable_by(None));
+    assert_eq!(status(&stripped, None).await?, forbidden);
+    assert_eq!(status(&stripped, Some("hw-2")).await?, forbidden);
+    let unbound = EnrollmentToken::new(token.token.clone());
+    assert_eq!(status(&unbound, Some("hw-2")).await?, forbidden);
+    assert_eq!(authenticator.usage_remaining(&token.token), Some(2));
+
+    let presented = token.clone().with_device_identifier(Token::new("hw-1"));
+    assert_eq!(status(&presented, None).await?, Some(Status::Ok));
+    assert_eq!(status(&token, Some("hw-1")).await?, Some(Status::Ok));
+    assert_eq!(authenticator.usage_remaining(&token.token), Some(0));
+
+    drop(node_manager);
+    context.stop().await
+}
+
+#[ockam_macros::test(timeout = 5000)]
+async fn enrollment_tokens_are_only_usable_in_their_audience(
+    context: &mut Context,
+) -> ockam::Result<()> {
+    let (handle, controller, authenticator) = start_mock_controller_for_tests(context).await?;
+
+    let req = Request::get("v0/enroll/token").into_parts().0;
+    let body = RequestEnrollmentToken::builder()
+        .attributes(attributes("device"))
+        .usage_count(3)
+        .audience(Token::new("project-1"))
+        .build()
+        .unwrap();
+    let res = handle
+        .node_manager
+        .read()
+        .await
+        .generate_enrollment_token(context, &req, &controller, body)
+        .await?;
+    let token: EnrollmentToken = Response::parse_response_body(&res)?;
+    assert_eq!(token.audience, Some(Token::new("project-1")));
+
+    // a node outside of any project can't check the audience
+    let node_manager = handle.node_manager.read().await;
+    let status = authentication_status(&node_manager, context, &controller, &token).await?;
+    assert_eq!(status, Some(Status::Forbidden));
+    drop(node_manager);
+
+    let project = |id: &str| Some(TrustContext::new(id.to_string(), None));
+    handle.node_manager.write().await.trust_context = project("project-2");
+    let node_manager = handle.node_manager.read().await;
+    let status = authentication_status(&node_manager, context, &controller, &token).await?;
+    assert_eq!(status, Some(Status::Forbidden));
+    // the authenticator also checks the audience of the tokens which don't report it
+    authenticator.serve_project(Some("project-2"));
+    let stripped = EnrollmentToken {
+        audience: None,
+        ..token.clone()
+    };
+    let status = authentication_status(&node_manager, context, &controller, &stripped).await?;
+    assert_eq!(status, Some(Status::Forbidden));
+    drop(node_manager);
+    assert_eq!(authenticator.usage_remaining(&token.token), Some(3));
+
+    authenticator.serve_project(Some("project-1"));
+    handle.node_manager.write().await.trust_context = project("project-1");
+    let node_manager = handle.node_manager.read().await;
+    let status = authentication_status(&node_manager, context, &controller, &token).await?;
+    assert_eq!(status, Some(Status::Ok));
+    let status = authentication_status(&node_manager, context, &controller, &stripped).await?;
+    assert_eq!(status, Some(Status::Ok));
+    // tokens without an audience are usable in any project
+    let body = RequestEnrollmentToken::new(attributes("device"));
+    let res = node_manager
+        .generate_enrollment_token(context, &req, &controller, body)
+        .await?;
+    let token: EnrollmentToken = Response::parse_response_body(&res)?;
+    let status = authentication_status(&node_manager, context, &controller, &token).await?;
+    assert_eq!(status, Some(Status::Ok));
+
+    drop(node_manager);
+    context.stop().await
+}
+
+#[ockam_macros::test(timeout = 5000)]
+async fn replayed_enrollment_tokens_are_rejected_while_in_flight(
+    context: &mut Context,
+) -> ockam::Result<()> {
+    let (handle, controller, authenticator) = start_mock_controller_for_tests(context).await?;
+
+    let node_manager = handle.node_manager.read().await;
+    let req = Request::get("v0/enroll/token").into_parts().0;
+    // the authenticator would accept both uses, only the node can tell they are concurrent
+    let body = RequestEnrollmentToken::new(attributes("device")).with_usage_count(2);
+    let res = node_manager
+        .generate_enrollment_token(context, &req, &controller, body)
+        .await?;
+    let token: EnrollmentToken = Response::parse_response_body(&res)?;
+
+    let (first, replayed) = futures::join!(
+        authentication_status(&node_manager, context, &controller, &token),
+        authentication_status(&node_manager, context, &controller, &token)
+    );
+    let mut statuses = vec![first?, replayed?];
+    statuses.sort_by_key(|status| status.map(|s| s as u16));
+    assert_eq!(statuses, vec![Some(Status::Ok), Some(Status::Conflict)]);
+    assert_eq!(authenticator.usage_remaining(&token.token), Some(1));
+
+    // the token is released once its authentication completes
+    assert!(node_manager.enrollment_token_replay_guard.is_empty());
+    let status = authentication_status(&node_manager, context, &controller, &token).await?;
+    assert_eq!(status, Some(Status::Ok));
+
+    drop(node_manager);
+    context.stop().await
+}
+
+#[ockam_macros::test(timeout = 5000)]
+async fn enrollment_tokens_are_checked_by_a_stateful_authenticator(
+    context: &mut Context,
+) -> ockam::Result<()> {
+    let (handle, controller, authenticator) = start_mock_controller_for_tests(context).await?;
+    let clock = Arc::new(ManualClock::new(1000));
+    authenticator.set_clock(clock.clone());
+    handle.node_manager.write().await.enroll_options.clock = clock.clone();
+
+    let node_manager = handle.node_manager.read().await;
+    let generate = Request::get("v0/enroll/token").into_parts().0;
+    let mut generated = vec![];
+    for body in [
+        RequestEnrollmentToken::new(attributes("device")).with_usage_count(2),
+        RequestEnrollmentToken::new(attributes("device")),
+        RequestEnrollmentToken::builder()
+            .attributes(attributes("device"))
+            .expires_in(60)
+            .build()
+            .unwrap(),
+    ] {
+        let res = node_manager
+            .generate_enrollment_token(context, &generate, &controller, body)
+            .await?;
+        generated.push(Response::parse_response_body::<EnrollmentToken>(&res)?);
+    }
+    assert_eq!(authenticator.generated_tokens().len(), 3);
+    let [reusable, revocable, expiring] = &generated[..] else {
+        panic!("unexpected tokens {generated:?}")
+    };
+    let status = |token| authentication_status(&node_manager, context, &controller, token);
+
+    // the usage count is enforced
+    assert_eq!(status(reusable).await?, Some(Status::Ok));
+    assert_eq!(authenticator.usage_remaining(&reusable.token), Some(1));
+    assert_eq!(status(reusable).await?, Some(Status::Ok));
+    assert_eq!(status(reusable).await?, Some(Status::Unauthorized));
+    assert_eq!(authenticator.usage_remaining(&reusable.token), Some(0));
+
+    // revoked tokens can't be used, nor revoked again
+    let revoke = Request::delete("v0/enroll/token").into_parts().0;
+    for expected in [Status::Ok, Status::Conflict] {
+        let res = node_manager
+            .revoke_enrollment_token(context, &revoke, &controller, &revocable.token)
+            .await?;
+        let (header, _) = Response::parse_response_header(&res)?;
+        assert_eq!(header.status(), Some(expected));
+    }
+    assert_eq!(status(revocable).await?, Some(Status::Unauthorized));
+
+    // expiries are checked by the authenticator, even when the node doesn't know about them
+    assert_eq!(expiring.expires_at, Some(1060));
+    authenticator.expire(&expiring.token)?;
+    assert_eq!(status(expiring).await?, Some(Status::Unauthorized));
+    assert_eq!(authenticator.usage_remaining(&expiring.token), Some(1));
+
+    // rejections can be simulated
+    authenticator.reject_with(Some(Status::InternalServerError));
+    let body = RequestEnrollmentToken::new(attributes("device"));
+    let res = node_manager
+        .generate_enrollment_token(context, &generate, &controller, body)
+        .await?;
+    let (header, _) = Response::parse_response_header(&res)?;
+    assert_eq!(header.status(), Some(Status::InternalServerError));
+
+    drop(node_manager);
+    context.stop().await
+}
+
+#[ockam_macros::test(timeout = 5000)]
+async fn enrollment_outcomes_are_streamed_to_subscribers(
+    context: &mut Context,
+) -> ockam::Result<()> {
+    let (handle, controller, _) = start_mock_controller_for_tests(context).await?;
+
+    let node_manager = handle.node_manager.read().await;
+    let mut events = Box::pin(node_manager.subscribe_enroll_events());
+    let generate = Request::get("v0/enroll/token").into_parts().0;
+    let body = RequestEnrollmentToken::new(attributes("device"));
+    let res = node_manager
+        .generate_enrollment_token(context, &generate, &controller, body)
+        .await?;
+    let token: EnrollmentToken = Response::parse_response_body(&res)?;
+
+    let status = |token| authentication_status(&node_manager, context, &controller, token);
+    assert_eq!(status(&token).await?, Some(Status::Ok));
+    let event = events.next().await.unwrap();
+    assert_eq!(event.flow, EnrollFlow::EnrollmentToken);
+    let granted = event.attributes.unwrap();
+    assert_eq!(granted.get("role"), Some(&b"device"[..]));
+    let identity = node_manager.identifier();
+    assert_eq!(
+        event.outcome,
+        EnrollOutcome::Enrolled {
+            identity: Some(identity)
+        }
+    );
+
+    // the token was used up
+    assert_eq!(status(&token).await?, Some(Status::Unauthorized));
+    let event = events.next().await.unwrap();
+    assert!(matches!(
+        event.outcome,
+        EnrollOutcome::Rejected {
+            status: Status::Unauthorized,
+            ..
+        }
+    ));
+
+    drop(node_manager);
+    context.stop().await
+}
+
+#[ockam_macros::test(timeout = 5000)]
+async fn repeated_requests_get_the_same_token_within_the_dedup_window(
+    context: &mut Context,
+) -> ockam::Result<()> {
+    let (handle, controller, authenticator) = start_mock_controller_for_tests(context).await?;
+    let clock = Arc::new(ManualClock::new(1000));
+    authenticator.set_clock(clock.clone());
+    {
+        let mut node_manager = handle.node_manager.write().await;
+        node_manager.enroll_options.clock = clock.clone();
+        node_manager.enroll_options.token_dedup_window = Duration::from_secs(10);
+    }
+
+    let node_manager = handle.node_manager.read().await;
+    let req = Request::get("v0/enroll/token").into_parts().0;
+    let generate = |attributes| async {
+        let body = RequestEnrollmentToken::new(attributes);
+        let res = node_manager
+            .generate_enrollment_token(context, &req, &controller, body)
+            .await?;
+        Response::parse_response_body::<EnrollmentToken>(&res).map(|t| t.token)
+    };
+    let token = generate(attributes("device")).await?;
+    assert_eq!(generate(attributes("device")).await?, token);
+    assert_eq!(authenticator.generated_tokens().len(), 1);
+
+    // other attributes get another token
+    assert_ne!(generate(attributes("gateway")).await?, token);
+    // and so does another usage count for the same attributes
+    let body = RequestEnrollmentToken::new(attributes("device")).with_usage_count(5);
+    let res = node_manager
+        .generate_enrollment_token(context, &req, &controller, body)
+        .await?;
+    let other = Response::parse_response_body::<EnrollmentToken>(&res)?.token;
+    assert_ne!(other, token);
+    assert_eq!(authenticator.usage_remaining(&other), Some(5));
+
+    // a new token is generated once the window is over
+    clock.advance(10);
+    assert_ne!(generate(attributes("device")).await?, token);
+    assert_eq!(authenticator.generated_tokens().len(), 4);
+
+    drop(node_manager);
+    context.stop().await
+}
+
+#[ockam_macros::test(timeout = 5000)]
+async fn token_generation_is_rate_limited_per_tenant(context: &mut Context) -> ockam::Result<()> {
+    let (handle, controller, authenticator) = start_mock_controller_for_tests(context).await?;
+    let clock = Arc::new(ManualClock::new(1000));
+    authenticator.set_clock(clock.clone());
+    let limiter = TokenBucketRateLimiter::new(RateLimit::new(2, Duration::from_secs(60)));
+    {
+        let mut node_manager = handle.node_manager.write().await;
+        node_manager.enroll_options.clock = clock.clone();
+        node_manager.enroll_options.token_rate_limiter = Some(Arc::new(limiter));
+    }
+
+    let node_manager = handle.node_manager.read().await;
+    let req = Request::get("v0/enroll/token").into_parts().0;
+    let generate = |tenant: &'static str| async {
+        let mut attributes = attributes("device");
+        attributes.put("tenant", tenant.as_bytes());
+        let body = RequestEnrollmentToken::new(attributes);
+        let res = node_manager
+            .generate_enrollment_token(context, &req, &controller, body)
+            .await?;
+        let (header, _) = Response::parse_response_header(&res)?;
+        Ok::<_, ockam::Error>(header.status())
+    };
+    for _ in 0..2 {
+        assert_eq!(generate("acme").await?, Some(Status::Ok));
+    }
+    assert_eq!(generate("acme").await?, Some(Status::TooManyRequests));
+    assert_eq!(authenticator.generated_tokens().len(), 2);
+
+    // the other tenants have their own limit
+    assert_eq!(generate("initech").await?, Some(Status::Ok));
+
+    clock.advance(60);
+    assert_eq!(generate("acme").await?, Some(Status::Ok));
+
+    drop(node_manager);
+    context.stop().await
+}
+
+#[ockam_macros::test(timeout = 5000)]
+async fn batch_token_generation_is_rate_limited_per_tenant(
+    context: &mut Context,
+) -> ockam::Result<()> {
+    let (handle, controller, authenticator) = start_mock_controller_for_tests(context).await?;
+    let limiter = TokenBucketRateLimiter::new(RateLimit::new(2, Duration::from_secs(60)));
+    handle
+        .node_manager
+        .write()
+        .await
+        .enroll_options
+        .token_rate_limiter = Some(Arc::new(limiter));
+
+    let node_manager = handle.node_manager.read().await;
+    let req = Request::get("v0/enroll/tokens").into_parts().0;
+    let mut acme = attributes("device");
+    acme.put("tenant", b"acme");
+    let mut initech = attributes("device");
+    initech.put("tenant", b"initech");
+    let res = node_manager
+        .generate_enrollment_tokens(context, &req, &controller, vec![acme, initech], 3)
+        .await?;
+    let report: Vec<GeneratedEnrollmentToken> = Response::parse_response_body(&res)?;
+    let statuses: Vec<Option<Status>> = report.iter().map(|r| r.status).collect();
+    let limited = Some(Status::TooManyRequests);
+    assert_eq!(statuses, vec![None, None, limited, None, None, limited]);
+    assert!(report[2].error.as_ref().unwrap().contains("\"acme\""));
+    assert_eq!(authenticator.generated_tokens().len(), 4);
+
+    drop(node_manager);
+    context.stop().await
+}
+
+#[ockam_macros::test(timeout = 5000)]
+async fn unknown_enrollment_token_versions_are_rejected(
+    context: &mut Context,
+) -> ockam::Result<()> {
+    let (handle, controller, authenticator) = start_mock_controller_for_tests(context).await?;
+
+    let node_manager = handle.node_manager.read().await;
+    let generate = Request::get("v0/enroll/token").into_parts().0;
+    let body = RequestEnrollmentToken::new(attributes("device")).with_usage_count(2);
+    let res = node_manager
+        .generate_enrollment_token(context, &generate, &controller, body)
+        .await?;
+    let token: EnrollmentToken = Response::parse_response_body(&res)?;
+    assert_eq!(token.version, Some(ENROLLMENT_TOKEN_VERSION));
+
+    // the token is rejected before reaching the authenticator
+    let unknown = EnrollmentToken {
+        version: Some(ENROLLMENT_TOKEN_VERSION + 1),
+        ..token.clone()
+    };
+    let req = Request::put("v0/enroll/token").into_parts().0;
+    let req_wrapper = CloudRequestWrapper::new(unknown, &controller, None);
+    let res = node_manager
+        .authenticate_enrollment_token_response(context, &req, req_wrapper, None)
+        .await?;
+    let (header, mut dec) = Response::parse_response_header(&res)?;
+    assert_eq!(header.status(), Some(Status::BadRequest));
+    let err: Error = dec.decode()?;
+    assert!(err
+        .message()
+        .unwrap()
+        .contains("unsupported enrollment token version 2"));
+    assert_eq!(authenticator.usage_remaining(&token.token), Some(2));
+
+    // tokens generated before the version was introduced are still accepted
+    let unversioned = EnrollmentToken {
+        version: None,
+        ..token.clone()
+    };
+    let status = authentication_status(&node_manager, context, &controller, &unversioned);
+    assert_eq!(status.await?, Some(Status::Ok));
+    assert_eq!(authenticator.usage_remaining(&token.token), Some(1));
+
+    drop(node_manager);
+    context.stop().await
+}
+
+#[ockam_macros::test(timeout = 5000)]
+async fn generated_tokens_keep_the_version_of_the_authenticator(
+    context: &mut Context,
+) -> ockam::Result<()> {
+    let (handle, controller, authenticator) = start_mock_controller_for_tests(context).await?;
+    let node_manager = handle.node_manager.read().await;
+    let generate = Request::get("v0/enroll/token").into_parts().0;
+    let generate_batch = Request::get("v0/enroll/tokens").into_parts().0;
+
+    for (version, expected) in [
+        (
+            Some(ENROLLMENT_TOKEN_VERSION + 1),
+            ENROLLMENT_TOKEN_VERSION + 1,
+        ),
+        (None, ENROLLMENT_TOKEN_VERSION),
+    ] {
+        authenticator.generate_version(version);
+        let body = RequestEnrollmentToken::new(attributes("device"));
+        let res = node_manager
+            .generate_enrollment_token(context, &generate, &controller, body)
+            .await?;
+        let token: EnrollmentToken = Response::parse_response_body(&res)?;
+        assert_eq!(token.version, Some(expected));
+
+        let res = node_manager
+            .generate_enrollment_tokens(
+                context,
+                &generate_batch,
+                &controller,
+                vec![attributes("sensor")],
+                1,
+            )
+            .await?;
+        let report: Vec<GeneratedEnrollmentToken> = Response::parse_response_body(&res)?;
+        assert_eq!(report[0].token.as_ref().unwrap().version, Some(expected));
+    }
+
+    drop(node_manager);
+    context.stop().await
+}
+
+#[ockam_macros::test(timeout = 5000)]
+async fn enrollment_tokens_can_be_introspected(context: &mut Context) -> ockam::Result<()> {
+    let (handle, controller, authenticator) = start_mock_controller_for_tests(context).await?;
+    authenticator.set_clock(Arc::new(ManualClock::new(0)));
+
+    let node_manager = handle.node_manager.read().await;
+    let generate = Request::get("v0/enroll/token").into_parts().0;
+    let body = RequestEnrollmentToken::builder()
+        .attributes(attributes("device"))
+        .usage_count(2)
+        .expires_in(100)
+        .build()
+        .unwrap();
+    node_manager
+        .generate_enrollment_token(context, &generate, &controller, body)
+        .await?;
+    let req = Request::post("v0/enroll/token/introspect").into_parts().0;
+    let res = node_manager
+        .introspect_enrollment_token(context, &req, &controller, &Token::new("token-0"))
+        .await?;
+    let introspection: EnrollmentTokenIntrospection = Response::parse_response_body(&res)?;
+    assert!(introspection.active);
+    let attributes = introspection.attributes.unwrap();
+    assert_eq!(attributes.get("role"), Some(&b"device"[..]));
+    assert_eq!(introspection.expires_at, Some(100));
+    assert_eq!(introspection.usage_remaining, Some(2));
+
+    let res = node_manager
+        .introspect_enrollment_token(context, &req, &controller, &Token::new("token-1"))
+        .await?;
+    let introspection: EnrollmentTokenIntrospection = Response::parse_response_body(&res)?;
+    assert!(!introspection.active);
+    assert!(introspection.attributes.is_none());
+
+    drop(node_manager);
+    context.stop().await
+}
+
+#[ockam_macros::test(timeout = 5000)]
+async fn enrollment_tokens_are_generated_from_templates(
+    context: &mut Context,
+) -> ockam::Result<()> {
+    let (handle, controller, _) = start_mock_controller_for_tests(context).await?;
+
+    let node_manager = handle.node_manager.read().await;
+    let template = EnrollmentTokenTemplate::new("sensor", attributes("device"));
+    node_manager.add_enrollment_token_template(template);
+
+    let req = Request::get("v0/enroll/token").into_parts().0;
+    let mut overrides = Attributes::new();
+    overrides.put("zone", b"eu");
+    let res = node_manager
+        .generate_enrollment_token_from_template(context, &req, &controller, "sensor", &overrides)
+        .await?;
+    // the authenticator knows the token with the attributes it was generated for
+    let token: EnrollmentToken = Response::parse_response_body(&res)?;
+    let introspect = Request::post("v0/enroll/token/introspect").into_parts().0;
+    let res = node_manager
+        .introspect_enrollment_token(context, &introspect, &controller, &token.token)
+        .await?;
+    let introspection: EnrollmentTokenIntrospection = Response::parse_response_body(&res)?;
+    let sent = introspection.attributes.unwrap();
+    assert_eq!(sent.get("role"), Some(&b"device"[..]));
+    assert_eq!(sent.get("zone"), Some(&b"eu"[..]));
+
+    let res = node_manager
+        .generate_enrollment_token_from_template(context, &req, &controller, "gateway", &overrides)
+        .await?;
+    let (header, _) = Response::parse_response_header(&res)?;
+    assert_eq!(header.status(), Some(Status::NotFound));
+
+    drop(node_manager);
+    context.stop().await
+}
+
+#[ockam_macros::test(timeout = 5000)]
+async fn delegated_tokens_are_checked_against_their_parent(
+    context: &mut Context,
+) -> ockam::Result<()> {
+    let (handle, controller, authenticator) = start_mock_controller_for_tests(context).await?;
+
+    let node_manager = handle.node_manager.read().await;
+    let req = Request::get("v0/enroll/token").into_parts().0;
+    let parent = EnrollmentToken::new(Token::new("gateway")).with_attributes(attributes("device"));
+    let res = node_manager
+        .generate_delegated_enrollment_token(
+            context,
+            &req,
+            &controller,
+            &parent,
+            RequestEnrollmentToken::new(attributes("device")),
+        )
+        .await?;
+    let child: EnrollmentToken = Response::parse_response_body(&res)?;
+    assert_eq!(child.token, Token::new("token-0"));
+    let (_, sent) = &authenticator.generation_requests()[0];
+    assert_eq!(sent.parent_token, Some(Token::new("gateway")));
+
+    let res = node_manager
+        .generate_delegated_enrollment_token(
+            context,
+            &req,
+            &controller,
+            &parent,
+            RequestEnrollmentToken::new(attributes("admin")),
+        )
+        .await?;
+    let (header, dec) = Response::parse_response_header(&res)?;
+    assert_eq!(header.status(), Some(Status::BadRequest));
+    assert!(Response::parse_err_msg(header, dec).contains("not granted by the parent token"));
+
+    drop(node_manager);
+    context.stop().await
+}
+
+#[ockam_macros::test(timeout = 5000)]
+async fn batch_failures_report_the_failing_index(context: &mut Context) -> ockam::Result<()> {
+    let (handle, controller, authenticator) = start_mock_controller_for_tests(context).await?;
+    authenticator.fail_generation_at(2);
+
+    let node_manager = handle.node_manager.read().await;
+    let req = Request::get("v0/enroll/tokens").into_parts().0;
+    let res = node_manager
+        .generate_enrollment_tokens(context, &req, &controller, vec![attributes("device")], 5)
+        .await?;
+
+    // the other tokens of the batch are still generated, in order
+    let report: Vec<GeneratedEnrollmentToken> = Response::parse_response_body(&res)?;
+    let indices: Vec<u32> = report.iter().map(|generated| generated.index).collect();
+    assert_eq!(indices, vec![0, 1, 2, 3, 4]);
+    let tokens: Vec<Option<String>> = report
+        .iter()
+        .map(|generated| generated.token.as_ref().map(|t| t.token.0.clone()))
+        .collect();
+    assert_eq!(
+        tokens,
+        vec![
+            Some("token-0".to_string()),
+            Some("token-1".to_string()),
+            None,
+            Some("token-2".to_string()),
+            Some("token-3".to_string()),
+        ]
+    );
+    assert_eq!(report[2].status, Some(Status::Unauthorized));
+    assert!(report[2]
+        .error
+        .as_ref()
+        .unwrap()
+        .contains("the token generation failed"));
+    assert!(report[0].status.is_none() && report[0].error.is_none());
+
+    // the shared secure channel is stopped once the batch is done
+    sleep(Duration::from_millis(100)).await;
+    let registry = handle.secure_channels.secure_channel_registry();
+    assert!(registry
+        .get_channel_list()
+        .iter()
+        .all(|channel| !channel.is_initiator()));
+
+    drop(node_manager);
+    context.stop().await
+}
+
+fn oidc_token(refresh_token: Option<Token>) -> AuthenticateOidcToken {
+    AuthenticateOidcToken::new(OidcToken {
+        token_type: TokenType::Bearer,
+        access_token: Token::new("access"),
+        refresh_token,
+        expires_at: None,
+        issued_at: None,
+    })
+}
+
+#[test]
+fn tokens_are_redacted_when_formatted() {
+    let token = Token::new("secret");
+    assert_eq!(format!("{token:?}"), "Token(***)");
+    assert!(!format!("{token}").contains("secret"));
+    let oidc = oidc_token(Some(Token::new("secret")));
+    assert!(!format!("{oidc:?}").contains("secret"));
+    assert_eq!(token.reveal(), "secret");
+}
+
+#[test]
+fn tokens_are_zeroized() {
+    let mut token = Token::new("secret");
+    token.zeroize();
+    assert_eq!(token, Token::new(""));
+}
+
+#[test]
+fn tokens_can_still_be_encoded() {
+    let token = Token::new("secret");
+    let cbor = minicbor::to_vec(&token).unwrap();
+    assert_eq!(minicbor::decode::<Token>(&cbor).unwrap(), token);
+
+    let json = serde_json::to_string(&token).unwrap();
+    assert_eq!(json, "\"secret\"");
+    assert_eq!(serde_json::from_str::<Token>(&json).unwrap(), token);
+}
+
+#[test]
+fn authenticate_tokens_match_their_schema() {
+    let tokens = vec![
+        AuthenticateToken::Auth0(oidc_token(None)),
+        AuthenticateToken::Oidc {
+            authenticator: "okta_authenticator".into(),
+            token: oidc_token(Some(Token::new("refresh"))),
+        },
+        AuthenticateToken::EnrollmentToken(
+            EnrollmentToken::new(Token::new("token")).with_expires_at(100),
+        ),
+        AuthenticateToken::EnrollmentToken(
+            EnrollmentToken::new(Token::new("token"))
+                .with_attributes(attributes("device"))
+                .with_signature(vec![1, 2, 3]),
+        ),
+        AuthenticateToken::ApiKey(AuthenticateApiKey::new(Token::new("key"))),
+        AuthenticateToken::ClientCertificate(
+            AuthenticateClientCertificate::new(&ClientCertificate::Der(vec![1, 2, 3])).unwrap(),
+        ),
+    ];
+    for token in tokens {
+        let cbor = minicbor::to_vec(&token).unwrap();
+        validate_cbor_bytes(token.schema(), SCHEMA, &cbor).unwrap();
+    }
+
+    let body = EnrollBody::enrollment_token(EnrollmentToken::new(Token::new("token")));
+    let cbor = minicbor::to_vec(body).unwrap();
+    validate_cbor_bytes("enroll_body", SCHEMA, &cbor).unwrap();
+    let body = EnrollBody::api_key(AuthenticateApiKey::new(Token::new("key")));
+    let cbor = minicbor::to_vec(body).unwrap();
+    validate_cbor_bytes("enroll_body", SCHEMA, &cbor).unwrap();
+
+    let token = AuthenticateEnrollmentToken::new(EnrollmentToken::new(Token::new("token")));
+    let cbor = minicbor::to_vec(token).unwrap();
+    validate_cbor_bytes("authenticate_enrollment_token", SCHEMA, &cbor).unwrap();
+
+    let cbor = minicbor::to_vec(RevokeEnrollmentToken::new(Token::new("token"))).unwrap();
+    validate_cbor_bytes("revoke_enrollment_token", SCHEMA, &cbor).unwrap();
+
+    let cbor = minicbor::to_vec(IntrospectEnrollmentToken::new(Token::new("token"))).unwrap();
+    validate_cbor_bytes("introspect_enrollment_token", SCHEMA, &cbor).unwrap();
+
+    let introspections = vec![
+        EnrollmentTokenIntrospection::active(attributes("device"))
+            .with_expires_at(100)
+            .with_usage_remaining(2),
+        EnrollmentTokenIntrospection::inactive(),
+    ];
+    for introspection in introspections {
+        let cbor = minicbor::to_vec(introspection).unwrap();
+        validate_cbor_bytes("enrollment_token_introspection", SCHEMA, &cbor).unwrap();
+    }
+
+    let validated = ValidatedEnrollmentToken::new(attributes("device")).with_expires_at(100);
+    let cbor = minicbor::to_vec(validated).unwrap();
+    validate_cbor_bytes("validated_enrollment_token", SCHEMA, &cbor).unwrap();
+
+    let cbor = minicbor::to_vec(ListEnrollmentTokens::new(0, 10)).unwrap();
+    validate_cbor_bytes("list_enrollment_tokens", SCHEMA, &cbor).unwrap();
+
+    let metadata = EnrollmentTokenMetadata::new("id", attributes("device"))
+        .with_expires_at(100)
+        .with_usage_remaining(2);
+    let cbor = minicbor::to_vec(EnrollmentTokenPage::new(vec![metadata], Some(1))).unwrap();
+    validate_cbor_bytes("enrollment_token_page", SCHEMA, &cbor).unwrap();
+
+    let cbor = minicbor::to_vec(ServerTime::new(1000)).unwrap();
+    validate_cbor_bytes("server_time", SCHEMA, &cbor).unwrap();
+}
+
+#[test]
+fn authenticator_services_can_be_overridden() {
+    let services = AuthenticatorServices {
+        enrollment_token: "tenant_enrollment_token_authenticator".into(),
+        ..Default::default()
+    };
+    let token = AuthenticateToken::EnrollmentToken(EnrollmentToken::new(Token::new("token")));
+    assert_eq!(
+        token.api_service(&services),
+        "tenant_enrollment_token_authenticator"
+    );
+    let token = AuthenticateToken::Auth0(oidc_token(None));
+    assert_eq!(token.api_service(&services), "auth0_authenticator");
+}
+
+fn decode_error_message(body: &[u8]) -> String {
+    let req = Request::put("v0/enroll/token").into_parts().0;
+    let res = node::decode_request_body::<CloudRequestWrapper<EnrollmentToken>>(
+        &req,
+        &mut Decoder::new(body),
+    )
+    .unwrap_err()
+    .unwrap();
+    let (header, dec) = Response::parse_response_header(&res).unwrap();
+    assert_eq!(header.status(), Some(Status::BadRequest));
+    Response::parse_err_msg(header, dec)
+}
+
+#[test]
+fn decode_errors_report_the_invalid_field() {
+    // the token is encoded as an integer instead of a string
+    let tagged = cfg!(feature = "tag");
+    let mut body = Vec::new();
+    let mut e = Encoder::new(&mut body);
+    e.map(if tagged { 3 } else { 2 }).unwrap();
+    if tagged {
+        e.u8(0).unwrap().u32(8956240).unwrap();
+    }
+    e.u8(1).unwrap().map(if tagged { 2 } else { 1 }).unwrap();
+    if tagged {
+        e.u8(0).unwrap().u32(8932763).unwrap();
+    }
+    e.u8(1).unwrap().u8(0).unwrap();
+    e.u8(2).unwrap().str("/service/controller_api").unwrap();
+
+    let message = decode_error_message(&body);
+    assert!(
+        message.contains("invalid request body at field 1.1"),
+        "{message}"
+    );
+}
+
+#[test]
+fn decode_errors_report_missing_fields() {
+    let tagged = cfg!(feature = "tag");
+    let mut body = Vec::new();
+    let mut e = Encoder::new(&mut body);
+    e.map(if tagged { 2 } else { 1 }).unwrap();
+    if tagged {
+        e.u8(0).unwrap().u32(8956240).unwrap();
+    }
+    e.u8(1).unwrap().map(if tagged { 2 } else { 1 }).unwrap();
+    if tagged {
+        e.u8(0).unwrap().u32(8932763).unwrap();
+    }
+    e.u8(1).unwrap().str("token").unwrap();
+
+    let message = decode_error_message(&body);
+    assert!(message.contains("missing value at index 2"), "{message}");
+    assert!(message.contains("route"), "{message}");
+}
+
+#[test]
+fn type_tag_mismatches_are_reported() {
+    let body = |tagged: bool| {
+        let mut body = Vec::new();
+        let mut e = Encoder::new(&mut body);
+        e.map(if tagged { 3 } else { 2 }).unwrap();
+        if tagged {
+            e.u8(0).unwrap().u32(8956240).unwrap();
+        }
+        e.u8(1).unwrap().map(1).unwrap().u8(1).unwrap();
+        e.str("token").unwrap();
+        e.u8(2).unwrap().str("/service/controller_api").unwrap();
+        body
+    };
+    let message = node::type_tag_mismatch(&body(true), 0, false).unwrap();
+    assert!(
+        message.contains("built with the `tag` feature"),
+        "{message}"
+    );
+    let message = node::type_tag_mismatch(&body(false), 0, true).unwrap();
+    assert!(
+        message.contains("built without the `tag` feature"),
+        "{message}"
+    );
+    assert_eq!(node::type_tag_mismatch(&body(true), 0, true), None);
+    assert_eq!(node::type_tag_mismatch(&body(false), 0, false), None);
+
+    // the request of a client built unlike this node is rejected before being decoded
+    let message = decode_error_message(&body(!cfg!(feature = "tag")));
+    assert!(message.contains("type tag"), "{message}");
+    assert!(!message.contains("invalid request body"), "{message}");
+}
+
+#[ockam_macros::test(timeout = 5000)]
+async fn malformed_authenticate_token_is_rejected_by_the_schema(
+    context: &mut Context,
+) -> ockam::Result<()> {
+    let (_handle, controller, authenticator) = start_mock_controller_for_tests(context).await?;
+    authenticator.set_mode(MockMode::Hold);
+
+    // the token type is a string rather than an index
+    let mut token = Vec::new();
+    Encoder::new(&mut token)
+        .map(2)?
+        .u8(1)?
+        .str("bearer")?
+        .u8(2)?
+        .str("access")?;
+    let schema = AuthenticateToken::Auth0(oidc_token(None)).schema();
+    assert!(validate_cbor_bytes(schema, SCHEMA, &token).is_err());
+
+    let auth0 = CloudRequestWrapper::new(Cbor(&token), &controller, None);
+    let auth0 = Request::post("v0/enroll/auth0").body(auth0).to_vec()?;
+    let unified = EnrollBody::new(EnrollTokenKind::Auth0, Cbor(&token));
+    let unified = CloudRequestWrapper::new(unified, &controller, None);
+    let unified = Request::post("v0/enroll").body(unified).to_vec()?;
+    for req in [auth0, unified] {
+        let res: Vec<u8> = context
+            .send_and_receive(route![NODEMANAGER_ADDR], req)
+            .await?;
+        let (header, _) = Response::parse_response_header(&res)?;
+        assert_eq!(header.status(), Some(Status::BadRequest));
+    }
+    // nothing was sent to the authenticator
+    sleep(Duration::from_millis(100)).await;
+    assert_eq!(authenticator.held(), 0);
+
+    context.stop().await
+}
+
+#[test]
+fn check_response_accepts_ok_responses() {
+    let res = Response::ok(Id::fresh()).to_vec().unwrap();
+    assert!(EnrollError::check_response(&res).is_ok());
+}
+
+#[test]
+fn check_response_reports_the_rejection_message() {
+    let body = Error::new("v0/enroll").with_message("unknown token");
+    let res = Response::forbidden(Id::fresh())
+        .body(body)
+        .to_vec()
+        .unwrap();
+    let err = EnrollError::check_response(&res).unwrap_err();
+    assert!(matches!(
+        &err,
+        EnrollError::Rejected { status: Status::Forbidden, message: Some(m) } if m == "unknown token"
+    ));
+    assert_eq!(err.status(), Status::Forbidden);
+    assert_eq!(
+        err.to_string(),
+        "the token was rejected (403 Forbidden): unknown token"
+    );
+}
+
+#[test]
+fn rejections_are_reported_as_unauthorized() {
+    let res = Response::bad_request(Id::fresh()).to_vec().unwrap();
+    let err = EnrollError::check_response(&res).unwrap_err();
+    assert_eq!(err.status(), Status::Unauthorized);
+}
+
+#[ockam_macros::test(timeout = 10000)]
+async fn authenticator_errors_are_mapped_to_the_node_response(
+    context: &mut Context,
+) -> ockam::Result<()> {
+    let (handle, controller, authenticator) = start_mock_controller_for_tests(context).await?;
+    let node_manager = handle.node_manager.read().await;
+    let generate = Request::get("v0/enroll/token").into_parts().0;
+    let authenticate = Request::put("v0/enroll/token").into_parts().0;
+
+    for (inner, generated, authenticated) in [
+        (Status::BadRequest, Status::BadRequest, Status::Unauthorized),
+        (
+            Status::Unauthorized,
+            Status::Unauthorized,
+            Status::Unauthorized,
+        ),
+        (Status::Forbidden, Status::Forbidden, Status::Forbidden),
+        (Status::NotFound, Status::NotFound, Status::Unauthorized),
+        (
+            Status::InternalServerError,
+            Status::InternalServerError,
+            Status::InternalServerError,
+        ),
+    ] {
+        authenticator.reject_with(Some(inner));
+        let body = RequestEnrollmentToken::new(attributes("device"));
+        let res = node_manager
+            .generate_enrollment_token(context, &generate, &controller, body)
+            .await?;
+        let (header, dec) = Response::parse_response_header(&res)?;
+        assert_eq!(header.re(), generate.id());
+        assert_eq!(header.status(), Some(generated));
+        assert!(Response::parse_err_msg(header, dec).contains(&format!("rejected with {inner}")));
+
+        let token = EnrollmentToken::new(Token::new("token"));
+        let req_wrapper = CloudRequestWrapper::new(token, &controller, None);
+        let res = node_manager
+            .authenticate_enrollment_token_response(context, &authenticate, req_wrapper, None)
+            .await?;
+        let (header, dec) = Response::parse_response_header(&res)?;
+        assert_eq!(header.status(), Some(authenticated));
+        assert!(Response::parse_err_msg(header, dec).contains(&format!("rejected with {inner}")));
+    }
+
+    drop(node_manager);
+    context.stop().await
+}
+
+#[test]
+fn check_response_fails_on_garbage() {
+    let err = EnrollError::check_response(&[0xff, 0x00]).unwrap_err();
+    assert!(matches!(err, EnrollError::Decode(_)));
+    assert_eq!(err.status(), Status::InternalServerError);
+}
@@ -0,0 +1,22 @@
+use std::time::Duration;
+
+/// Time spent in each phase of an enrollment attempt, to plan the latency
+/// budget of the enrollments
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct EnrollTimings {
+    /// Creation of the secure channel to the controller
+    pub channel_setup: Duration,
+    /// Round trip of the request to the authenticator
+    pub request: Duration,
+    /// Stop of the secure channel, once the response is received
+    pub teardown: Duration,
+    /// Whole attempt, from the creation of the channel until it is stopped
+    pub total: Duration,
+}
+
+impl EnrollTimings {
+    /// Sum of the time spent in the phases, which is at most `total`
+    pub fn phases(&self) -> Duration {
+        self.channel_setup + self.request + self.teardown
+    }
+}
@@ -0,0 +1,192 @@
+use std::collections::HashMap;
+
+use rand::Rng;
+
+use ockam::identity::IdentityIdentifier;
+use ockam_core::compat::sync::RwLock;
+use ockam_core::{async_trait, Result};
+
+use crate::cloud::enroll::oidc::OidcToken;
+
+/// Storage for the tokens obtained by an identity, so that they can be
+/// reused instead of running an authentication flow again.
+#[async_trait]
+pub trait TokenCache: Send + Sync + 'static {
+    /// Return the token stored for `identity`, if any
+    async fn load(&self, identity: &IdentityIdentifier) -> Result<Option<OidcToken>>;
+
+    /// Store `token` for `identity`, replacing the previous one
+    async fn store(&self, identity: &IdentityIdentifier, token: &OidcToken) -> Result<()>;
+
+    /// Remove the token stored for `identity`
+    async fn clear(&self, identity: &IdentityIdentifier) -> Result<()>;
+}
+
+/// Fraction of the lifetime of the cached tokens within which they are refreshed, before their expiry
+pub const DEFAULT_EXPIRY_JITTER: f64 = 0.1;
+
+/// Return the token cached for `identity` unless it is expired at `now`.
+///
+/// A token is considered expired at a random time within the last `jitter` fraction
+/// of its lifetime, so that nodes which enrolled together don't all re-enroll at the
+/// same time. Expired tokens are removed from the cache.
+pub async fn load_valid_token(
+    cache: &dyn TokenCache,
+    identity: &IdentityIdentifier,
+    now: u64,
+    jitter: f64,
+) -> Result<Option<OidcToken>> {
+    match cache.load(identity).await? {
+        Some(token) if token.is_expired(now + early_expiry(&token, jitter)) => {
+            cache.clear(identity).await?;
+            Ok(None)
+        }
+        token => Ok(token),
+    }
+}
+
+/// Random number of seconds by which `token` is considered expired before its expiry
+fn early_expiry(token: &OidcToken, jitter: f64) -> u64 {
+    rand::thread_rng().gen_range(0..=jitter_window(token, jitter))
+}
+
+/// Number of seconds at the end of the lifetime of `token` within which it is refreshed.
+///
+/// Tokens which don't have a known lifetime are only refreshed once expired.
+fn jitter_window(token: &OidcToken, jitter: f64) -> u64 {
+    match (token.issued_at, token.expires_at) {
+        (Some(issued_at), Some(expires_at)) => {
+            let lifetime = expires_at.saturating_sub(issued_at);
+            (lifetime as f64 * jitter.clamp(0.0, 1.0)) as u64
+        }
+        _ => 0,
+    }
+}
+
+/// A cache keeping the tokens for the lifetime of the node
+#[derive(Default)]
+pub struct InMemoryTokenCache {
+    tokens: RwLock<HashMap<IdentityIdentifier, OidcToken>>,
+}
+
+#[async_trait]
+impl TokenCache for InMemoryTokenCache {
+    async fn load(&self, identity: &IdentityIdentifier) -> Result<Option<OidcToken>> {
+        Ok(self.tokens.read().unwrap().get(identity).cloned())
+    }
+
+    async fn store(&self, identity: &IdentityIdentifier, token: &OidcToken) -> Result<()> {
+        self.tokens
+            .write()
+            .unwrap()
+            .insert(identity.clone(), token.clone());
+        Ok(())
+    }
+
+    async fn clear(&self, identity: &IdentityIdentifier) -> Result<()> {
+        self.tokens.write().unwrap().remove(identity);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use crate::cloud::enroll::oidc::TokenType;
+    use crate::cloud::enroll::Token;
+
+    use super::*;
+
+    fn identity() -> IdentityIdentifier {
+        IdentityIdentifier::from_str(
+            "Pe92f183eb4c324804ef4d62962dea94cf095a265d4d28500c34e1a4e0d5ef638",
+        )
+        .unwrap()
+    }
+
+    fn token(expires_at: Option<u64>) -> OidcToken {
+        OidcToken {
+            token_type: TokenType::Bearer,
+            access_token: Token::new("access"),
+            refresh_token: Some(Token::new("refresh")),
+            expires_at,
+            issued_at: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn in_memory_cache_stores_tokens_per_identity() -> Result<()> {
+        let cache = InMemoryTokenCache::default();
+        assert_eq!(cache.load(&identity()).await?, None);
+
+        cache.store(&identity(), &token(None)).await?;
+        assert_eq!(cache.load(&identity()).await?, Some(token(None)));
+
+        cache.clear(&identity()).await?;
+        assert_eq!(cache.load(&identity()).await?, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn expired_tokens_are_not_loaded() -> Result<()> {
+        let cache = InMemoryTokenCache::default();
+        cache.store(&identity(), &token(Some(100))).await?;
+        assert_eq!(
+            load_valid_token(&cache, &identity(), 99, DEFAULT_EXPIRY_JITTER).await?,
+            Some(token(Some(100)))
+        );
+
+        assert_eq!(
+            load_valid_token(&cache, &identity(), 100, DEFAULT_EXPIRY_JITTER).await?,
+            None
+        );
+        assert_eq!(cache.load(&identity()).await?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn tokens_are_refreshed_within_the_jitter_window() {
+        assert_eq!(jitter_window(&token(Some(1000)), 0.1), 0);
+
+        let issued = OidcToken {
+            issued_at: Some(0),
+            ..token(Some(1000))
+        };
+        assert_eq!(jitter_window(&issued, 0.1), 100);
+        assert_eq!(jitter_window(&issued, 2.0), 1000);
+        for _ in 0..100 {
+            let early = early_expiry(&issued, 0.1);
+            assert!(early <= 100);
+            assert!(!issued.is_expired(899 + early));
+        }
+    }
+
+    #[tokio::test]
+    async fn tokens_are_not_refreshed_before_the_jitter_window() -> Result<()> {
+        let cache = InMemoryTokenCache::default();
+        let token = OidcToken {
+            issued_at: Some(0),
+            ..token(Some(1000))
+        };
+        cache.store(&identity(), &token).await?;
+        for _ in 0..100 {
+            assert_eq!(
+                load_valid_token(&cache, &identity(), 899, 0.1).await?,
+                Some(token.clone())
+            );
+        }
+        assert_eq!(
+            load_valid_token(&cache, &identity(), 1000, 0.1).await?,
+            None
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn tokens_can_be_serialized_for_storage() {
+        let json = serde_json::to_string(&token(Some(100))).unwrap();
+        let decoded: OidcToken = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded, token(Some(100)));
+    }
+}
@@ -0,0 +1,48 @@
+use ockam_core::{async_trait, Result, Route};
+use ockam_multiaddr::MultiAddr;
+use ockam_node::Context;
+use ockam_transport_tcp::TcpTransport;
+
+use crate::error::ApiError;
+use crate::multiaddr_to_route;
+
+/// A transport over which the node reaches the Orchestrator, before it
+/// creates a secure channel to the Orchestrator over the returned route.
+///
+/// A node can be given several transports, which are tried in order until a
+/// secure channel can be created over one of them, for instance a portal
+/// first and then a direct TCP connection.
+#[async_trait]
+pub trait CloudTransport: Send + Sync + 'static {
+    /// Name of the transport, for the logs
+    fn name(&self) -> &str;
+
+    /// Return the route to the Orchestrator at `cloud_route`, connecting to it if needed
+    async fn connect(&self, ctx: &Context, cloud_route: &MultiAddr) -> Result<Route>;
+}
+
+/// Connect to the TCP address of the cloud route, the transport used when
+/// a node is not given any
+pub struct TcpCloudTransport {
+    tcp: TcpTransport,
+}
+
+impl TcpCloudTransport {
+    pub fn new(tcp: TcpTransport) -> Self {
+        Self { tcp }
+    }
+}
+
+#[async_trait]
+impl CloudTransport for TcpCloudTransport {
+    fn name(&self) -> &str {
+        "tcp"
+    }
+
+    async fn connect(&self, _ctx: &Context, cloud_route: &MultiAddr) -> Result<Route> {
+        multiaddr_to_route(cloud_route, &self.tcp)
+            .await
+            .map(|resolved| resolved.route)
+            .ok_or_else(|| ApiError::generic("Invalid Multiaddr"))
+    }
+}
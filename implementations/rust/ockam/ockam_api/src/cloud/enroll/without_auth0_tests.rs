@@ -0,0 +1,50 @@
+use std::str::FromStr;
+
+use minicbor::Decoder;
+use ockam_multiaddr::MultiAddr;
+
+use crate::cloud::enroll::events::EnrollFlow;
+use crate::cloud::enroll::oidc::{OidcToken, TokenType};
+use crate::cloud::CloudRequestWrapper;
+
+use super::*;
+
+#[test]
+fn enrollment_tokens_are_still_decoded() {
+    let route = MultiAddr::from_str("/service/controller_api").unwrap();
+    let body = EnrollBody::enrollment_token(EnrollmentToken::new(Token::new("token")));
+    let body = minicbor::to_vec(CloudRequestWrapper::new(body, &route, None)).unwrap();
+    let decoded = try_decode_enroll_body(&Decoder::new(&body)).unwrap();
+    assert!(matches!(decoded.req, AuthenticateToken::EnrollmentToken(_)));
+
+    // the auth0 tokens are not decoded without the auth0 feature
+    let mut body = EnrollBody::enrollment_token(EnrollmentToken::new(Token::new("token")));
+    body.kind = EnrollTokenKind::Auth0;
+    let body = minicbor::to_vec(CloudRequestWrapper::new(body, &route, None)).unwrap();
+    assert!(try_decode_enroll_body(&Decoder::new(&body)).is_none());
+
+    let services = AuthenticatorServices::default();
+    assert_eq!(
+        decoded.req.api_service(&services),
+        "enrollment_token_authenticator"
+    );
+    assert_eq!(EnrollFlow::from(&decoded.req), EnrollFlow::EnrollmentToken);
+}
+
+#[test]
+fn oidc_tokens_need_an_authenticator() {
+    let token = AuthenticateOidcToken::new(OidcToken {
+        token_type: TokenType::Bearer,
+        access_token: Token::new("access"),
+        refresh_token: None,
+        expires_at: None,
+        issued_at: None,
+    });
+    let token = AuthenticateToken::Oidc {
+        authenticator: "okta_authenticator".to_string(),
+        token,
+    };
+    let services = AuthenticatorServices::default();
+    assert_eq!(token.api_service(&services), "okta_authenticator");
+    assert_eq!(token.schema(), "authenticate_oidc_token");
+}
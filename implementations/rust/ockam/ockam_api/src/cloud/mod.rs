@@ -2,13 +2,12 @@ use minicbor::{Decode, Encode};
 use serde::{Deserialize, Serialize};
 
 use ockam_core::compat::str::FromStr;
+use ockam_core::errcode::{Kind, Origin};
 #[cfg(feature = "tag")]
 use ockam_core::TypeTag;
-use ockam_core::{CowStr, Result};
+use ockam_core::{CowStr, Error, Result};
 use ockam_multiaddr::MultiAddr;
 
-use crate::error::ApiError;
-
 use self::share::RoleInShare;
 
 pub mod addon;
@@ -16,6 +15,7 @@ pub mod enroll;
 pub mod lease_manager;
 pub mod operation;
 pub mod project;
+pub mod retry;
 pub mod share;
 pub mod space;
 pub mod subscription;
@@ -35,6 +35,29 @@ pub const ORCHESTRATOR_AWAIT_TIMEOUT_MS: usize = 60 * 10 * 1000;
 
 pub type ProjectAddress = CowStr<'static>;
 
+/// Version of the Orchestrator API used to build the paths of cloud requests
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CloudApiVersion {
+    #[default]
+    V0,
+    V1,
+}
+
+impl CloudApiVersion {
+    /// Path prefix shared by all the endpoints of this version, e.g. `v0`
+    pub fn prefix(&self) -> &'static str {
+        match self {
+            CloudApiVersion::V0 => "v0",
+            CloudApiVersion::V1 => "v1",
+        }
+    }
+
+    /// Build the path of `endpoint` for this version, e.g. `v0/enroll`
+    pub fn path(&self, endpoint: &str) -> String {
+        format!("{}/{endpoint}", self.prefix())
+    }
+}
+
 #[derive(Clone, Debug, Eq, PartialEq, Decode, Deserialize, Encode, Serialize)]
 #[cbor(map)]
 #[rustfmt::skip]
@@ -68,9 +91,41 @@ impl<T> CloudRequestWrapper<T> {
         }
     }
 
+    /// Return the route to the Orchestrator, failing with an `Invalid` error
+    /// if it is empty or can't be parsed
     pub fn multiaddr(&self) -> Result<MultiAddr> {
-        MultiAddr::from_str(self.route.as_ref())
-            .map_err(|_err| ApiError::generic(&format!("Invalid route: {}", self.route)))
+        let invalid = |message: String| Error::new(Origin::Api, Kind::Invalid, message);
+        let multiaddr = MultiAddr::from_str(self.route.trim())
+            .map_err(|err| invalid(format!("invalid route {:?}: {err}", self.route)))?;
+        if multiaddr.is_empty() {
+            return Err(invalid(
+                "the route to the Orchestrator is empty".to_string(),
+            ));
+        }
+        Ok(multiaddr)
+    }
+
+    /// Replace the request with the result of `f`, keeping the other fields
+    pub fn map<U>(self, f: impl FnOnce(T) -> U) -> CloudRequestWrapper<U> {
+        CloudRequestWrapper {
+            #[cfg(feature = "tag")]
+            tag: TypeTag,
+            req: f(self.req),
+            route: self.route,
+            identity_name: self.identity_name,
+        }
+    }
+
+    /// Take the request out of the wrapper, keeping the other fields in a bare wrapper
+    pub fn split(self) -> (T, BareCloudRequestWrapper) {
+        let bare = CloudRequestWrapper {
+            #[cfg(feature = "tag")]
+            tag: TypeTag,
+            req: (),
+            route: self.route,
+            identity_name: self.identity_name,
+        };
+        (self.req, bare)
     }
 }
 
@@ -90,23 +145,98 @@ impl BareCloudRequestWrapper {
 }
 
 mod node {
+    use std::future::Future;
+    use std::panic::{resume_unwind, AssertUnwindSafe};
+    use std::sync::Arc;
     use std::time::Duration;
 
+    use futures::FutureExt;
     use minicbor::Encode;
 
-    use ockam::identity::{IdentityIdentifier, SecureChannelOptions, TrustIdentifierPolicy};
+    use ockam::identity::{
+        IdentityIdentifier, SecureChannel, SecureChannelOptions, SecureChannels,
+        TrustIdentifierPolicy,
+    };
     use ockam_core::api::RequestBuilder;
     use ockam_core::compat::str::FromStr;
     use ockam_core::env::get_env;
     use ockam_core::{self, route, Result};
     use ockam_multiaddr::MultiAddr;
     use ockam_node::api::request_with_options;
+    use ockam_node::tokio::sync::oneshot;
     use ockam_node::{Context, MessageSendReceiveOptions, DEFAULT_TIMEOUT};
 
+    use crate::cloud::enroll::route_builder::RouteCache;
+    use crate::cloud::enroll::secure_channel_address::SecureChannelAddress;
     use crate::cloud::OCKAM_CONTROLLER_IDENTITY_ID;
     use crate::error::ApiError;
     use crate::nodes::{NodeManager, NodeManagerWorker};
 
+    /// Stops a secure channel to the controller if it is dropped before being
+    /// stopped, for instance when the future using the channel is cancelled.
+    ///
+    /// Stopping a channel can't be awaited while dropping, so it is then
+    /// stopped by a task spawned on the runtime of the node, with the node
+    /// background context.
+    struct SecureChannelGuard<'a> {
+        channel: Option<SecureChannelAddress>,
+        background_ctx: &'a Arc<Context>,
+        secure_channels: &'a Arc<SecureChannels>,
+        route_cache: &'a RouteCache,
+    }
+
+    impl<'a> SecureChannelGuard<'a> {
+        fn new(node_manager: &'a NodeManager, channel: &SecureChannelAddress) -> Self {
+            Self {
+                channel: Some(channel.clone()),
+                background_ctx: &node_manager.background_ctx,
+                secure_channels: &node_manager.secure_channels,
+                route_cache: &node_manager.route_cache,
+            }
+        }
+
+        /// Stop the channel now, rather than when dropped
+        async fn stop(mut self, ctx: &Context) {
+            if let Some(channel) = self.channel.take() {
+                self.route_cache.forget(&channel);
+                if let Err(err) = self
+                    .secure_channels
+                    .stop_secure_channel(ctx, channel.address())
+                    .await
+                {
+                    warn!(%err, sc = %channel, "failed to stop the secure channel to the controller");
+                }
+            }
+        }
+    }
+
+    impl Drop for SecureChannelGuard<'_> {
+        fn drop(&mut self) {
+            if let Some(channel) = self.channel.take() {
+                self.route_cache.forget(&channel);
+                let ctx = self.background_ctx.clone();
+                let secure_channels = self.secure_channels.clone();
+                self.background_ctx
+                    .runtime()
+                    .spawn(async move { stop_channel(&ctx, &secure_channels, channel).await });
+            }
+        }
+    }
+
+    /// Stop the secure channel to the controller `channel`, only logging a failure
+    async fn stop_channel(
+        ctx: &Context,
+        secure_channels: &SecureChannels,
+        channel: SecureChannelAddress,
+    ) {
+        if let Err(err) = secure_channels
+            .stop_secure_channel(ctx, channel.address())
+            .await
+        {
+            warn!(%err, sc = %channel, "failed to stop the secure channel to the controller");
+        }
+    }
+
     impl NodeManager {
         /// Load controller identity id from file.
         ///
@@ -167,30 +297,98 @@ mod node {
         where
             T: Encode<()>,
         {
-            let identity_name = ident.clone();
-            let identifier = self.get_identifier(identity_name.clone()).await?;
-
-            let secure_channels = self.secure_channels.clone();
-
-            let sc = {
-                let cloud_route = crate::multiaddr_to_route(cloud_multiaddr, &self.tcp_transport)
-                    .await
-                    .ok_or_else(|| ApiError::generic("Invalid Multiaddr"))?;
-
-                let options = SecureChannelOptions::new()
-                    .with_trust_policy(TrustIdentifierPolicy::new(self.controller_identifier()));
-                secure_channels
-                    .create_secure_channel(ctx, &identifier, cloud_route.route, options)
-                    .await?
-            };
+            let sc = self
+                .create_authenticator_secure_channel(ctx, ident, cloud_multiaddr)
+                .await
+                .map_err(ockam_core::Error::from)?;
 
             let route = route![sc.clone(), api_service];
             let options = MessageSendReceiveOptions::new().with_timeout(timeout);
-            let res = request_with_options(ctx, label, schema, route, req, options).await;
-            secure_channels
-                .stop_secure_channel(ctx, sc.encryptor_address())
-                .await?;
-            res
+            self.stop_secure_channel_after(
+                ctx,
+                &SecureChannelAddress::of(&sc),
+                request_with_options(ctx, label, schema, route, req, options),
+            )
+            .await
+        }
+
+        /// Creates a secure channel to the controller reachable at `cloud_multiaddr`,
+        /// using the identity `ident` or the node default identity.
+        ///
+        /// The cloud transports of the node are tried in order, and the error
+        /// of the last one is returned if the channel can't be created over any.
+        ///
+        /// The channel is created by a task of the node background context, so that
+        /// it is still stopped if the returned future is dropped before the channel
+        /// is established.
+        pub(crate) async fn create_controller_secure_channel(
+            &self,
+            ctx: &Context,
+            ident: Option<String>,
+            cloud_multiaddr: &MultiAddr,
+        ) -> Result<SecureChannel> {
+            let identifier = self.get_identifier(ident).await?;
+            let background_ctx = self.background_ctx.clone();
+            let secure_channels = self.secure_channels.clone();
+            let transports = self.cloud_transports.clone();
+            let trust_policy = TrustIdentifierPolicy::new(self.controller_identifier());
+            let cloud_multiaddr = cloud_multiaddr.clone();
+            let (tx, rx) = oneshot::channel();
+            ctx.runtime().spawn(async move {
+                let ctx = background_ctx.as_ref();
+                let mut last_error = None;
+                for transport in &transports {
+                    let secure_channel = async {
+                        let route = transport.connect(ctx, &cloud_multiaddr).await?;
+                        let options =
+                            SecureChannelOptions::new().with_trust_policy(trust_policy.clone());
+                        secure_channels
+                            .create_secure_channel(ctx, &identifier, route, options)
+                            .await
+                    };
+                    match secure_channel.await {
+                        Ok(sc) => {
+                            if let Err(Ok(sc)) = tx.send(Ok(sc)) {
+                                debug!(sc = %sc.encryptor_address(), "the secure channel creation was cancelled");
+                                stop_channel(ctx, &secure_channels, SecureChannelAddress::of(&sc))
+                                    .await;
+                            }
+                            return;
+                        }
+                        Err(err) => {
+                            debug!(%err, transport = transport.name(), "cloud transport failed");
+                            last_error = Some(err);
+                        }
+                    }
+                }
+                let err = last_error
+                    .unwrap_or_else(|| ApiError::generic("the node has no cloud transport"));
+                let _ = tx.send(Err(err));
+            });
+            rx.await
+                .map_err(|_| ApiError::generic("the secure channel creation was interrupted"))?
+        }
+
+        /// Runs `f` to completion, then stops the secure channel `sc`.
+        ///
+        /// The channel is stopped whether `f` succeeds, fails or panics, so
+        /// callers can use `?` freely inside `f`. It is also stopped in the background
+        /// if the returned future is dropped before completing. A failure to stop the
+        /// channel is only logged, so that it doesn't hide the output of `f`. The routes
+        /// cached for the channel are forgotten in any case.
+        pub(crate) async fn stop_secure_channel_after<T>(
+            &self,
+            ctx: &Context,
+            sc: &SecureChannelAddress,
+            f: impl Future<Output = T>,
+        ) -> T {
+            let guard = SecureChannelGuard::new(self, sc);
+            let res = AssertUnwindSafe(f).catch_unwind().await;
+            guard.stop(ctx).await;
+            match res {
+                Ok(res) => res,
+                Err(panic) => resume_unwind(panic),
+            }
         }
     }
 
@@ -253,3 +451,166 @@ mod node {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use ockam_core::api::Request;
+    use ockam_core::errcode::Kind;
+    use ockam_core::{async_trait, Any, AsyncTryClone, Route, Routed, Worker};
+    use ockam_multiaddr::MultiAddr;
+    use ockam_node::tokio::time::sleep;
+    use ockam_node::Context;
+
+    use crate::cloud::enroll::transport::{CloudTransport, TcpCloudTransport};
+    use crate::test_utils::{start_controller_for_tests, start_manager_for_tests};
+
+    use super::{CloudApiVersion, CloudRequestWrapper};
+
+    /// Forwards the messages to the next hop of their onward route after a delay
+    struct DelayingRelay(Duration);
+
+    #[async_trait]
+    impl Worker for DelayingRelay {
+        type Context = Context;
+        type Message = Any;
+
+        async fn handle_message(
+            &mut self,
+            ctx: &mut Context,
+            msg: Routed<Self::Message>,
+        ) -> ockam::Result<()> {
+            sleep(self.0).await;
+            let mut message = msg.into_local_message();
+            message.transport_mut().onward_route.step()?;
+            ctx.forward(message).await
+        }
+    }
+
+    /// Reaches the controller through the "delaying_relay" worker
+    struct RelayedTransport(TcpCloudTransport);
+
+    #[async_trait]
+    impl CloudTransport for RelayedTransport {
+        fn name(&self) -> &str {
+            "relayed"
+        }
+
+        async fn connect(&self, ctx: &Context, cloud_route: &MultiAddr) -> ockam::Result<Route> {
+            let mut route = self.0.connect(ctx, cloud_route).await?;
+            Ok(route.modify().prepend("delaying_relay").into())
+        }
+    }
+
+    #[test]
+    fn cloud_api_version_defaults_to_v0() {
+        assert_eq!(CloudApiVersion::default(), CloudApiVersion::V0);
+    }
+
+    #[test]
+    fn cloud_api_version_builds_paths() {
+        assert_eq!(CloudApiVersion::V0.path("enroll"), "v0/enroll");
+        assert_eq!(CloudApiVersion::V0.path(""), "v0/");
+        assert_eq!(CloudApiVersion::V1.path("enroll"), "v1/enroll");
+        assert_eq!(CloudApiVersion::V1.path(""), "v1/");
+    }
+
+    #[test]
+    fn empty_and_malformed_routes_are_invalid() {
+        let mut req_wrapper = CloudRequestWrapper::new((), &MultiAddr::default(), None);
+        let err = req_wrapper.multiaddr().unwrap_err();
+        assert_eq!(err.code().kind, Kind::Invalid);
+        assert!(err
+            .to_string()
+            .contains("the route to the Orchestrator is empty"));
+
+        req_wrapper.route = "/unknown/protocol".to_string();
+        let err = req_wrapper.multiaddr().unwrap_err();
+        assert_eq!(err.code().kind, Kind::Invalid);
+        assert!(err
+            .to_string()
+            .contains("invalid route \"/unknown/protocol\""));
+
+        req_wrapper.route = "/service/api".to_string();
+        assert!(req_wrapper.multiaddr().is_ok());
+    }
+
+    #[ockam_macros::test(timeout = 5000)]
+    async fn controller_secure_channel_is_stopped_when_the_request_fails(
+        context: &mut Context,
+    ) -> ockam::Result<()> {
+        let handle = start_manager_for_tests(context).await?;
+        let controller = start_controller_for_tests(context, &handle, &[]).await?;
+        let node_manager = handle.node_manager.read().await;
+
+        // nothing answers on "unknown_service", so the request times out
+        let res = node_manager
+            .request_controller_with_timeout(
+                context,
+                "test",
+                None,
+                &controller,
+                "unknown_service",
+                Request::get("v0/"),
+                None,
+                Duration::from_millis(200),
+            )
+            .await;
+        assert!(res.is_err());
+
+        // the channel workers unregister themselves when they shut down
+        sleep(Duration::from_millis(100)).await;
+        let registry = handle.secure_channels.secure_channel_registry();
+        assert!(registry
+            .get_channel_list()
+            .iter()
+            .all(|channel| !channel.is_initiator()));
+
+        drop(node_manager);
+        context.stop().await
+    }
+
+    #[ockam_macros::test(timeout = 5000)]
+    async fn controller_secure_channel_is_stopped_when_cancelled_during_its_creation(
+        context: &mut Context,
+    ) -> ockam::Result<()> {
+        let handle = start_manager_for_tests(context).await?;
+        let controller = start_controller_for_tests(context, &handle, &[]).await?;
+        let relay = DelayingRelay(Duration::from_millis(300));
+        context.start_worker("delaying_relay", relay).await?;
+        let tcp = handle
+            .node_manager
+            .read()
+            .await
+            .tcp_transport
+            .async_try_clone()
+            .await?;
+        handle.node_manager.write().await.cloud_transports =
+            vec![Arc::new(RelayedTransport(TcpCloudTransport::new(tcp)))];
+        let registry = handle.secure_channels.secure_channel_registry();
+        let open_channels = || {
+            registry
+                .get_channel_list()
+                .iter()
+                .filter(|channel| channel.is_initiator())
+                .count()
+        };
+
+        let node_manager = handle.node_manager.read().await;
+        let creation = node_manager.create_controller_secure_channel(context, None, &controller);
+        // the creation is cancelled while the handshake is delayed by the relay
+        ockam_node::tokio::select! {
+            _ = creation => panic!("the handshake should be delayed"),
+            _ = sleep(Duration::from_millis(100)) => (),
+        }
+
+        // the handshake completes, then its channel is stopped
+        sleep(Duration::from_millis(1000)).await;
+        assert_eq!(open_channels(), 0);
+
+        drop(node_manager);
+        context.stop().await
+    }
+}
@@ -0,0 +1,140 @@
+use std::future::Future;
+use std::time::Duration;
+
+use ockam_core::errcode::Kind;
+use tokio_retry::strategy::jitter;
+use tokio_retry::RetryIf;
+
+/// Policy used to retry Orchestrator requests failing because of transient errors.
+///
+/// The delay between two attempts starts at `base_delay` and doubles after
+/// each attempt, with some jitter added so that several nodes don't retry in lockstep.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Total number of attempts, including the first one
+    pub max_attempts: u32,
+    /// Delay before the first retry
+    pub base_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(500),
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn new(max_attempts: u32, base_delay: Duration) -> Self {
+        Self {
+            max_attempts,
+            base_delay,
+        }
+    }
+
+    /// A policy making a single attempt
+    pub fn disabled() -> Self {
+        Self::new(1, Duration::ZERO)
+    }
+
+    /// Delays to wait before each retry, without jitter
+    fn backoff(&self) -> impl Iterator<Item = Duration> + '_ {
+        (0..self.max_attempts.saturating_sub(1))
+            .map(|attempt| self.base_delay.saturating_mul(2u32.saturating_pow(attempt)))
+    }
+
+    /// Run `action` until it succeeds, `should_retry` returns false for its error,
+    /// or the maximum number of attempts is reached.
+    pub async fn retry<T, E, A, F>(
+        &self,
+        action: A,
+        should_retry: impl FnMut(&E) -> bool,
+    ) -> Result<T, E>
+    where
+        A: FnMut() -> F,
+        F: Future<Output = Result<T, E>>,
+    {
+        let delays: Vec<Duration> = self.backoff().map(jitter).collect();
+        RetryIf::spawn(delays, action, should_retry).await
+    }
+}
+
+/// Return true if `err` is a transport failure which may not happen again on a new attempt
+pub(crate) fn is_transient(err: &ockam_core::Error) -> bool {
+    matches!(err.code().kind, Kind::Io | Kind::Timeout)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_the_delay() {
+        let policy = RetryPolicy::new(4, Duration::from_millis(100));
+        let delays: Vec<Duration> = policy.backoff().collect();
+        assert_eq!(
+            delays,
+            vec![
+                Duration::from_millis(100),
+                Duration::from_millis(200),
+                Duration::from_millis(400)
+            ]
+        );
+        assert_eq!(RetryPolicy::disabled().backoff().count(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retry_stops_after_max_attempts() {
+        let attempts = &Cell::new(0);
+        let res: Result<(), &str> = RetryPolicy::default()
+            .retry(
+                || async move {
+                    attempts.set(attempts.get() + 1);
+                    Err("unreachable")
+                },
+                |_| true,
+            )
+            .await;
+        assert!(res.is_err());
+        assert_eq!(attempts.get(), 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retry_stops_on_definitive_errors() {
+        let attempts = &Cell::new(0);
+        let res: Result<(), &str> = RetryPolicy::default()
+            .retry(
+                || async move {
+                    attempts.set(attempts.get() + 1);
+                    Err("rejected")
+                },
+                |_| false,
+            )
+            .await;
+        assert!(res.is_err());
+        assert_eq!(attempts.get(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retry_returns_the_first_success() {
+        let attempts = &Cell::new(0);
+        let res: Result<u32, &str> = RetryPolicy::default()
+            .retry(
+                || async move {
+                    attempts.set(attempts.get() + 1);
+                    if attempts.get() < 2 {
+                        Err("unreachable")
+                    } else {
+                        Ok(attempts.get())
+                    }
+                },
+                |_| true,
+            )
+            .await;
+        assert_eq!(res, Ok(2));
+    }
+}
@@ -1,6 +1,6 @@
 //! Node Manager (Node Man, the superhero that we deserve)
 
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap};
 use std::error::Error as _;
 use std::net::SocketAddr;
 use std::path::PathBuf;
@@ -20,7 +20,11 @@ use ockam::{
 use ockam_abac::expr::{and, eq, ident, str};
 use ockam_abac::{Action, Env, Expr, PolicyAccessControl, PolicyStorage, Resource};
 use ockam_core::api::{Error, Method, Request, Response, ResponseBuilder, Status};
-use ockam_core::compat::{boxed::Box, string::String, sync::Arc};
+use ockam_core::compat::{
+    boxed::Box,
+    string::String,
+    sync::{Arc, Mutex},
+};
 use ockam_core::errcode::{Kind, Origin};
 use ockam_core::flow_control::FlowControlId;
 use ockam_core::IncomingAccessControl;
@@ -28,10 +32,21 @@ use ockam_core::{AllowAll, AsyncTryClone};
 use ockam_identity::TrustContext;
 use ockam_multiaddr::MultiAddr;
 use ockam_node::compat::asynchronous::RwLock;
+use ockam_node::tokio::sync::broadcast;
 
 use crate::bootstrapped_identities_store::BootstrapedIdentityStore;
 use crate::bootstrapped_identities_store::PreTrustedIdentities;
 use crate::cli_state::{CliState, StateDirTrait, StateItemTrait};
+use crate::cloud::enroll::concurrency::EnrollPermits;
+use crate::cloud::enroll::enrollment_token::EnrollmentTokenTemplate;
+use crate::cloud::enroll::events::{EnrollEvent, DEFAULT_ENROLL_EVENTS_CAPACITY};
+use crate::cloud::enroll::options::EnrollOptions;
+use crate::cloud::enroll::replay_guard::ReplayGuard;
+use crate::cloud::enroll::revocation_list::RevocationList;
+use crate::cloud::enroll::route_builder::RouteCache;
+use crate::cloud::enroll::shared_channel::SharedChannel;
+use crate::cloud::enroll::transport::{CloudTransport, TcpCloudTransport};
+use crate::cloud::CloudApiVersion;
 use crate::config::cli::TrustContextConfig;
 use crate::config::lookup::ProjectLookup;
 use crate::error::ApiError;
@@ -101,18 +116,31 @@ pub struct NodeManager {
     api_transport_flow_control_id: FlowControlId,
     pub(crate) tcp_transport: TcpTransport,
     pub(crate) controller_identity_id: IdentityIdentifier,
+    pub(crate) cloud_api_version: CloudApiVersion,
+    pub(crate) enroll_options: EnrollOptions,
+    pub(crate) revoked_enrollment_tokens: RevocationList,
+    pub(crate) enrollment_token_replay_guard: ReplayGuard,
+    pub(crate) enrollment_token_templates: Mutex<HashMap<String, EnrollmentTokenTemplate>>,
+    pub(crate) enroll_channel: SharedChannel,
+    pub(crate) enroll_permits: EnrollPermits,
+    pub(crate) enroll_events: broadcast::Sender<EnrollEvent>,
+    pub(crate) route_cache: RouteCache,
+    pub(crate) cloud_transports: Vec<Arc<dyn CloudTransport>>,
     skip_defaults: bool,
     enable_credential_checks: bool,
     identifier: IdentityIdentifier,
     pub(crate) secure_channels: Arc<SecureChannels>,
-    trust_context: Option<TrustContext>,
+    /// Context of the tasks which outlive the requests of the node, such as the
+    /// stop of the secure channels of the cancelled requests
+    pub(crate) background_ctx: Arc<Context>,
+    pub(crate) trust_context: Option<TrustContext>,
     pub(crate) registry: Registry,
     medic_handle: MedicHandle,
     policies: Arc<dyn PolicyStorage>,
 }
 
 impl NodeManager {
-    pub(super) fn identifier(&self) -> IdentityIdentifier {
+    pub(crate) fn identifier(&self) -> IdentityIdentifier {
         self.identifier.clone()
     }
 
@@ -250,6 +278,9 @@ pub struct NodeManagerGeneralOptions {
     node_name: String,
     skip_defaults: bool,
     pre_trusted_identities: Option<PreTrustedIdentities>,
+    cloud_api_version: CloudApiVersion,
+    enroll_options: EnrollOptions,
+    cloud_transports: Vec<Arc<dyn CloudTransport>>,
 }
 
 impl NodeManagerGeneralOptions {
@@ -264,8 +295,32 @@ impl NodeManagerGeneralOptions {
             node_name,
             skip_defaults,
             pre_trusted_identities,
+            cloud_api_version: CloudApiVersion::default(),
+            enroll_options: EnrollOptions::default(),
+            cloud_transports: vec![],
         }
     }
+
+    /// Use another version of the Orchestrator API than the default one
+    pub fn with_cloud_api_version(mut self, cloud_api_version: CloudApiVersion) -> Self {
+        self.cloud_api_version = cloud_api_version;
+        self
+    }
+
+    /// Set how the node enrolls and generates enrollment tokens
+    pub fn with_enroll_options(mut self, enroll_options: EnrollOptions) -> Self {
+        self.enroll_options = enroll_options;
+        self
+    }
+
+    /// Set the transports used to reach the Orchestrator, in order of preference.
+    /// The next one is tried when no secure channel can be created over a transport.
+    ///
+    /// The node TCP transport is used when no transport is set.
+    pub fn with_cloud_transports(mut self, cloud_transports: Vec<Arc<dyn CloudTransport>>) -> Self {
+        self.cloud_transports = cloud_transports;
+        self
+    }
 }
 
 #[derive(Clone)]
@@ -357,12 +412,35 @@ impl NodeManager {
         debug!("start the Medic");
         let medic_handle = MedicHandle::start_medic(ctx).await?;
 
+        let mut cloud_transports = general_options.cloud_transports;
+        if cloud_transports.is_empty() {
+            let tcp = transport_options.tcp_transport.async_try_clone().await?;
+            cloud_transports.push(Arc::new(TcpCloudTransport::new(tcp)));
+        }
+
+        let enroll_options = general_options.enroll_options;
+        let enroll_permits = EnrollPermits::new(enroll_options.max_concurrent_enrollments);
+        let enroll_permits = match enroll_options.max_waiting_enrollments {
+            Some(max_waiting) => enroll_permits.with_max_waiting(max_waiting),
+            None => enroll_permits,
+        };
+
         let mut s = Self {
             cli_state,
             node_name: general_options.node_name,
             api_transport_flow_control_id: transport_options.api_transport_flow_control_id,
             tcp_transport: transport_options.tcp_transport,
             controller_identity_id: Self::load_controller_identifier()?,
+            cloud_api_version: general_options.cloud_api_version,
+            revoked_enrollment_tokens: Default::default(),
+            enrollment_token_replay_guard: Default::default(),
+            enrollment_token_templates: Default::default(),
+            enroll_channel: Default::default(),
+            enroll_permits,
+            enroll_events: broadcast::channel(DEFAULT_ENROLL_EVENTS_CAPACITY).0,
+            route_cache: Default::default(),
+            cloud_transports,
+            enroll_options,
             skip_defaults: general_options.skip_defaults,
             enable_credential_checks: trust_options.trust_context_config.is_some()
                 && trust_options
@@ -373,6 +451,7 @@ impl NodeManager {
                     .is_ok(),
             identifier: node_state.config().identifier()?,
             secure_channels,
+            background_ctx: Arc::new(ctx.async_try_clone().await?),
             trust_context: None,
             registry: Default::default(),
             medic_handle,
@@ -801,12 +880,25 @@ impl NodeManagerWorker {
             }
 
             // ==*== Enroll ==*==
-            (Post, ["v0", "enroll", "auth0"]) => {
-                self.enroll_auth0_response(ctx, dec.decode()?).await?
+            (Post, ["v0", "enroll"]) => self.enroll(ctx, req, dec).await?,
+            #[cfg(feature = "auth0")]
+            (Post, ["v0", "enroll", "auth0"]) => self.enroll_auth0_response(ctx, req, dec).await?,
+            (Get, ["v0", "enroll", "token"]) => {
+                self.generate_enrollment_token(ctx, req, dec).await?
             }
-            (Get, ["v0", "enroll", "token"]) => self.generate_enrollment_token(ctx, dec).await?,
             (Put, ["v0", "enroll", "token"]) => {
-                self.authenticate_enrollment_token(ctx, dec).await?
+                self.authenticate_enrollment_token(ctx, req, dec).await?
+            }
+            (Post, ["v0", "enroll", "token", "validate"]) => {
+                self.validate_enrollment_token_request(ctx, req, dec)
+                    .await?
+            }
+            (Post, ["v0", "enroll", "token", "introspect"]) => {
+                self.introspect_enrollment_token(ctx, req, dec).await?
+            }
+            (Get, ["v0", "enroll", "tokens"]) => self.list_enrollment_tokens(ctx, req, dec).await?,
+            (Delete, ["v0", "enroll", "token"]) => {
+                self.revoke_enrollment_token(ctx, req, dec).await?
             }
 
             // ==*== Subscriptions ==*==
@@ -80,9 +80,9 @@ impl Server {
                     debug!("Checking token for project {:?}", self.project);
                     // TODO: check token_type
                     // TODO: it's AuthenticateAuth0Token or something else?.  Probably rename.
-                    let token: crate::cloud::enroll::auth0::AuthenticateOidcToken = dec.decode()?;
+                    let token: crate::cloud::enroll::oidc::AuthenticateOidcToken = dec.decode()?;
                     debug!("device code received: {token:#?}");
-                    if let Some(attrs) = self.check_token(&token.access_token.0).await? {
+                    if let Some(attrs) = self.check_token(token.access_token.reveal()).await? {
                         //TODO in some future, we will want to track that this entry
                         //     was added by the okta addon.
                         //     But for that we would need to give a separate identity to this
@@ -331,7 +331,9 @@ pub fn local_worker(code: &Code) -> Result<bool> {
 
 #[cfg(test)]
 pub mod test_utils {
-    use ockam::identity::SecureChannels;
+    use std::str::FromStr;
+
+    use ockam::identity::{SecureChannelListenerOptions, SecureChannels};
     use ockam::Result;
     use ockam_core::compat::sync::Arc;
     use ockam_core::flow_control::FlowControls;
@@ -339,12 +341,14 @@ pub mod test_utils {
     use ockam_identity::{
         CredentialData, Credentials, Identity, IdentityIdentifier, InMemoryStorage, KeyAttributes,
     };
+    use ockam_multiaddr::MultiAddr;
     use ockam_node::compat::asynchronous::RwLock;
     use ockam_node::{Context, InMemoryKeyValueStorage};
     use ockam_transport_tcp::TcpTransport;
     use ockam_vault::{Secret, SecretAttributes};
 
     use crate::cli_state::{traits::*, CliState, IdentityConfig, NodeConfig, VaultConfig};
+    use crate::cloud::enroll::mock_authenticator::MockAuthenticator;
     use crate::config::cli::{CredentialRetrieverConfig, TrustAuthorityConfig, TrustContextConfig};
     use crate::nodes::service::{
         NodeManagerGeneralOptions, NodeManagerTransportOptions, NodeManagerTrustOptions,
@@ -462,6 +466,47 @@ pub mod test_utils {
         })
     }
 
+    /// Starts a secure channel listener standing for the controller, and makes the
+    /// node manager of `handle` trust it. Returns the address of the listener.
+    ///
+    /// The workers started at `services` can be reached through the secure channels
+    /// created by the node manager, like the Orchestrator services.
+    pub async fn start_controller_for_tests(
+        context: &Context,
+        handle: &NodeManagerHandle,
+        services: &[&str],
+    ) -> Result<MultiAddr> {
+        handle.node_manager.write().await.controller_identity_id = handle.identifier.clone();
+
+        let options = SecureChannelListenerOptions::new();
+        for service in services {
+            context
+                .flow_controls()
+                .add_consumer(*service, &options.spawner_flow_control_id());
+        }
+        handle
+            .secure_channels
+            .create_secure_channel_listener(context, &handle.identifier, "controller_api", options)
+            .await?;
+        Ok(MultiAddr::from_str("/service/controller_api")?)
+    }
+
+    /// Starts a local node manager, and a [`MockAuthenticator`] standing for the
+    /// Orchestrator services of its controller.
+    ///
+    /// Returns the handle to the node manager, the route to the controller and the
+    /// authenticator.
+    pub async fn start_mock_controller_for_tests(
+        context: &mut Context,
+    ) -> Result<(NodeManagerHandle, MultiAddr, MockAuthenticator)> {
+        let handle = start_manager_for_tests(context).await?;
+        let controller =
+            start_controller_for_tests(context, &handle, &MockAuthenticator::SERVICES).await?;
+        let authenticator = MockAuthenticator::default();
+        authenticator.start(context).await?;
+        Ok((handle, controller, authenticator))
+    }
+
     async fn create_identity_zero(secure_channels: &Arc<SecureChannels>) -> Result<Identity> {
         let identity_key_id = secure_channels
             .vault()
@@ -59,7 +59,7 @@ async fn enroll_with_token(app_state: &AppState) -> Result<IdentityIdentifier> {
         let node_manager_worker = app_state.node_manager_worker().await;
         let node_manager = node_manager_worker.inner().read().await;
         node_manager
-            .enroll_auth0(&app_state.context(), &CloudOpts::route(), token)
+            .enroll_auth0(&app_state.context(), None, &CloudOpts::route(), token)
             .await
             .into_diagnostic()?;
     }
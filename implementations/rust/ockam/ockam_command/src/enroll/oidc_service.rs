@@ -1,4 +1,3 @@
-use std::borrow::Borrow;
 use std::io::stdin;
 use std::str::FromStr;
 use std::sync::Arc;
@@ -121,7 +120,7 @@ impl OidcService {
     /// Return the information about a user once authenticated
     pub async fn get_user_info(&self, token: &OidcToken) -> Result<UserInfo> {
         let client = self.provider().build_http_client()?;
-        let access_token = token.access_token.0.clone();
+        let access_token = token.access_token.reveal().to_string();
         let req = || {
             client
                 .get("https://account.ockam.io/userinfo")
@@ -383,53 +382,24 @@ impl OidcService {
     ) -> Result<OidcToken> {
         let provider = self.provider();
         let client = provider.build_http_client()?;
-        let token;
         let spinner_option = opts.terminal.progress_spinner();
         if let Some(spinner) = spinner_option.as_ref() {
             spinner.set_message("Waiting for you to complete authentication using your browser...");
         }
-        loop {
-            let res = client
-                .post(provider.token_request_url())
-                .header("content-type", "application/x-www-form-urlencoded")
-                .form(&[
-                    ("client_id", self.provider().client_id()),
-                    (
-                        "grant_type",
-                        "urn:ietf:params:oauth:grant-type:device_code".to_string(),
-                    ),
-                    ("device_code", dc.device_code.to_string()),
-                ])
-                .send()
-                .await
-                .into_diagnostic()?;
-            match res.status() {
-                StatusCode::OK => {
-                    token = res.json::<OidcToken>().await.into_diagnostic()?;
-                    debug!(?token, "token response received");
-                    if let Some(spinner) = spinner_option.as_ref() {
-                        spinner.finish_and_clear();
-                    }
-                    opts.terminal.write_line(&fmt_para!("Authenticated\n"))?;
-                    return Ok(token);
-                }
-                _ => {
-                    let err = res.json::<TokensError>().await.into_diagnostic()?;
-                    match err.error.borrow() {
-                        "authorization_pending" | "invalid_request" | "slow_down" => {
-                            debug!(?err, "tokens not yet received");
-                            sleep(Duration::from_secs(dc.interval as u64)).await;
-                            continue;
-                        }
-                        _ => {
-                            let err_msg = "failed to receive tokens";
-                            debug!(?err, "{err_msg}");
-                            return Err(miette!(err_msg).into());
-                        }
-                    }
-                }
-            }
+        let token = poll_device_code(
+            &client,
+            &provider.token_request_url(),
+            &provider.client_id(),
+            &dc,
+            DEFAULT_MAX_POLLING_INTERVAL,
+        )
+        .await?;
+        debug!(?token, "token response received");
+        if let Some(spinner) = spinner_option.as_ref() {
+            spinner.finish_and_clear();
         }
+        opts.terminal.write_line(&fmt_para!("Authenticated\n"))?;
+        Ok(token)
     }
 
     // Generate 32 random bytes as a code verifier
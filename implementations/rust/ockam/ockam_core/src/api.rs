@@ -17,6 +17,7 @@ use {
 
 use crate::alloc::string::ToString;
 use crate::compat::boxed::Box;
+use crate::compat::collections::BTreeMap;
 use crate::compat::rand;
 use crate::compat::string::String;
 use crate::compat::vec::Vec;
@@ -98,6 +99,13 @@ pub struct Request {
     #[n(3)] method: Option<Method>,
     /// Indicator if a request body is expected after this header.
     #[n(4)] has_body: bool,
+    /// The identifier of the request which caused this request to be sent, if any.
+    ///
+    /// It allows a single operation spanning several nodes to be traced end to end.
+    #[n(5)] correlation_id: Option<Id>,
+    /// Additional fields set by the sender, like a hint for routing the request
+    /// to a tenant. Absent when there are none.
+    #[n(6)] metadata: Option<BTreeMap<String, String>>,
 }
 
 /// The response header.
@@ -299,8 +307,11 @@ pub enum Status {
     #[n(404)] NotFound,
     #[n(409)] Conflict,
     #[n(405)] MethodNotAllowed,
+    #[n(408)] RequestTimeout,
+    #[n(429)] TooManyRequests,
     #[n(500)] InternalServerError,
     #[n(501)] NotImplemented,
+    #[n(503)] ServiceUnavailable,
 }
 
 impl Display for Status {
@@ -313,8 +324,11 @@ impl Display for Status {
             Status::NotFound => "404 NotFound",
             Status::Conflict => "409 Conflict",
             Status::MethodNotAllowed => "405 MethodNotAllowed",
+            Status::RequestTimeout => "408 RequestTimeout",
+            Status::TooManyRequests => "429 TooManyRequests",
             Status::InternalServerError => "500 InternalServerError",
             Status::NotImplemented => "501 NotImplemented",
+            Status::ServiceUnavailable => "503 ServiceUnavailable",
         })
     }
 }
@@ -347,6 +361,8 @@ impl Request {
             method: Some(method),
             path: path.into(),
             has_body,
+            correlation_id: None,
+            metadata: None,
         }
     }
 
@@ -396,6 +412,15 @@ impl Request {
     pub fn has_body(&self) -> bool {
         self.has_body
     }
+
+    pub fn correlation_id(&self) -> Option<Id> {
+        self.correlation_id
+    }
+
+    /// Return the value of the metadata field `key`, if it is set
+    pub fn metadata(&self, key: &str) -> Option<&str> {
+        self.metadata.as_ref()?.get(key).map(String::as_str)
+    }
 }
 
 impl Response {
@@ -606,6 +631,20 @@ impl<T> RequestBuilder<T> {
         self
     }
 
+    pub fn correlation_id(mut self, id: Id) -> Self {
+        self.header.correlation_id = Some(id);
+        self
+    }
+
+    /// Set the metadata field `key` to `value`, replacing its previous value
+    pub fn metadata(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.header
+            .metadata
+            .get_or_insert_with(BTreeMap::new)
+            .insert(key.into(), value.into());
+        self
+    }
+
     pub fn header(&self) -> &Request {
         &self.header
     }
@@ -954,8 +993,11 @@ mod schema_test {
         Status::BadRequest,
         Status::NotFound,
         Status::MethodNotAllowed,
+        Status::RequestTimeout,
+        Status::TooManyRequests,
         Status::InternalServerError,
         Status::NotImplemented,
+        Status::ServiceUnavailable,
     ];
 
     #[derive(Debug, Clone)]
@@ -969,11 +1011,19 @@ mod schema_test {
 
     impl Arbitrary for Req {
         fn arbitrary(g: &mut Gen) -> Self {
-            Req(Request::new(
+            let mut req = Request::new(
                 *g.choose(METHODS).unwrap(),
                 String::arbitrary(g),
                 bool::arbitrary(g),
-            ))
+            );
+            if bool::arbitrary(g) {
+                req.correlation_id = Some(Id::fresh())
+            }
+            if bool::arbitrary(g) {
+                let (key, value) = <(String, String)>::arbitrary(g);
+                req.metadata = Some(BTreeMap::from([(key, value)]))
+            }
+            Req(req)
         }
     }
 
@@ -313,6 +313,44 @@ impl Attributes {
             .map(|(k, v)| (k.to_string(), v.to_vec()))
             .collect()
     }
+
+    /// Return the keys which changed from the `other` attributes to these ones
+    pub fn diff(&self, other: &Attributes) -> AttributesDiff {
+        let mut diff = AttributesDiff::default();
+        for (key, value) in &self.attrs {
+            match other.attrs.get(key) {
+                None => diff.added.push(key.clone()),
+                Some(previous) if previous != value => diff.changed.push(key.clone()),
+                Some(_) => {}
+            }
+        }
+        diff.removed = other
+            .attrs
+            .keys()
+            .filter(|key| !self.attrs.contains_key(*key))
+            .cloned()
+            .collect();
+        diff
+    }
+}
+
+/// Keys of the attributes which were added, removed or given another value
+/// between two sets of attributes, in the order of the keys
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AttributesDiff {
+    /// Keys which are only in the new attributes
+    pub added: Vec<String>,
+    /// Keys which are only in the previous attributes
+    pub removed: Vec<String>,
+    /// Keys which have a different value in the new attributes
+    pub changed: Vec<String>,
+}
+
+impl AttributesDiff {
+    /// Return true if both sets of attributes are the same
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
 }
 
 /// A Unix timestamp (seconds since 1970-01-01 00:00:00 UTC)
@@ -388,6 +426,35 @@ Attributes: {"name": "value"}
         assert_eq!(actual, expected);
     }
 
+    #[test]
+    fn test_attributes_diff() {
+        let mut previous = Attributes::new();
+        previous
+            .put("role", b"member")
+            .put("project", b"p1")
+            .put("zone", b"eu");
+        let mut current = Attributes::new();
+        current
+            .put("role", b"admin")
+            .put("project", b"p1")
+            .put("tenant", b"acme")
+            .put("account", b"42");
+
+        let diff = current.diff(&previous);
+        assert_eq!(diff.added, ["account", "tenant"]);
+        assert_eq!(diff.removed, ["zone"]);
+        assert_eq!(diff.changed, ["role"]);
+        assert!(!diff.is_empty());
+
+        let reverse = previous.diff(&current);
+        assert_eq!(reverse.added, diff.removed);
+        assert_eq!(reverse.removed, diff.added);
+        assert_eq!(reverse.changed, diff.changed);
+
+        assert!(current.diff(&current.clone()).is_empty());
+        assert!(Attributes::new().diff(&Attributes::new()).is_empty());
+    }
+
     pub(crate) fn make_credential_data() -> CredentialData<Verified> {
         let mut attributes = Attributes::new();
         attributes.put("name", "value".as_bytes());